@@ -2,19 +2,28 @@ use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs;
 use std::io::{Read as _, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::Instant;
 
+mod json;
+mod yaml;
+
+use json::JsonValue;
+
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 // --- Types ---
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub enum LinkStrategy {
+    #[default]
     Auto,
     Hardlink,
     Copy,
+    /// Copy-on-write clone (FICLONE on Linux, clonefile on macOS), copying
+    /// per file where the filesystem cannot share extents
+    Reflink,
 }
 
 impl LinkStrategy {
@@ -23,6 +32,7 @@ impl LinkStrategy {
             "auto" => Some(Self::Auto),
             "hardlink" => Some(Self::Hardlink),
             "copy" => Some(Self::Copy),
+            "reflink" => Some(Self::Reflink),
             _ => None,
         }
     }
@@ -32,6 +42,7 @@ impl LinkStrategy {
             Self::Auto => "auto",
             Self::Hardlink => "hardlink",
             Self::Copy => "copy",
+            Self::Reflink => "reflink",
         }
     }
 }
@@ -62,6 +73,94 @@ impl MaterializeProfile {
     }
 }
 
+/// What `materialize_tree` does with entries that are not regular files,
+/// directories or symlinks (FIFOs, sockets, device nodes).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialFilePolicy {
+    /// Leave them out of the destination and record them in the report
+    #[default]
+    Skip,
+    /// Fail the materialize on the first one
+    Error,
+}
+
+impl SpecialFilePolicy {
+    pub fn from_arg(value: &str) -> Option<Self> {
+        match value {
+            "skip" => Some(Self::Skip),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::Error => "error",
+        }
+    }
+}
+
+/// Knobs for `materialize_tree` beyond strategy/jobs/profile.
+#[derive(Debug, Clone, Copy)]
+pub struct MaterializeOptions {
+    pub special_files: SpecialFilePolicy,
+    /// Keep destination files that already match the source (same inode, or same
+    /// size and mtime) instead of relinking/recopying them. Stale files that
+    /// only exist at the destination are left alone; use a fresh dest to drop them.
+    pub incremental: bool,
+    /// Give copied files the source's permissions; otherwise they get 0644.
+    /// Hardlinks share the source's metadata either way.
+    pub preserve_mode: bool,
+    /// Give copied files the source's modification time instead of the time
+    /// of the copy.
+    pub preserve_mtime: bool,
+    /// Scan and plan only: nothing is created, linked, copied or removed, and
+    /// the stats count the files, directories and symlinks that would be.
+    pub dry_run: bool,
+}
+
+impl Default for MaterializeOptions {
+    fn default() -> Self {
+        Self {
+            special_files: SpecialFilePolicy::default(),
+            incremental: false,
+            preserve_mode: true,
+            preserve_mtime: true,
+            dry_run: false,
+        }
+    }
+}
+
+/// Ordering for package lists in reports (`--sort`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    /// Update type for outdated, license id for license
+    Type,
+    /// Logical bytes, largest first
+    Size,
+}
+
+impl SortKey {
+    pub fn from_arg(value: &str) -> Option<Self> {
+        match value {
+            "name" => Some(Self::Name),
+            "type" => Some(Self::Type),
+            "size" => Some(Self::Size),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Type => "type",
+            Self::Size => "size",
+        }
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct ScanAgg {
     pub logical: u64,
@@ -78,11 +177,26 @@ pub struct MaterializeStats {
     pub files_linked: u64,
     pub files_copied: u64,
     pub link_fallback_copies: u64,
+    pub files_reflinked: u64,
+    /// Source bytes behind each file count: what a hardlink or reflink did not
+    /// have to write, and what a copy did
+    pub bytes_linked: u64,
+    pub bytes_copied: u64,
+    pub bytes_reflinked: u64,
+    /// Files copied because the filesystem refused a reflink
+    pub reflink_fallback_copies: u64,
     pub directories: u64,
     pub symlinks: u64,
     pub fallback_eperm: u64,
     pub fallback_exdev: u64,
     pub fallback_other: u64,
+    /// FIFOs, sockets and device nodes left out under `SpecialFilePolicy::Skip`
+    pub skipped_special: u64,
+    /// Symlinks whose target does not exist in the source; they are still
+    /// recreated as-is
+    pub broken_symlinks: u64,
+    /// Files and symlinks already correct at the destination (incremental mode)
+    pub files_skipped: u64,
 }
 
 #[derive(Default)]
@@ -99,6 +213,11 @@ pub struct MaterializeCounters {
     pub files_linked: AtomicU64,
     pub files_copied: AtomicU64,
     pub link_fallback_copies: AtomicU64,
+    pub files_reflinked: AtomicU64,
+    pub reflink_fallback_copies: AtomicU64,
+    pub bytes_linked: AtomicU64,
+    pub bytes_copied: AtomicU64,
+    pub bytes_reflinked: AtomicU64,
     pub symlinks: AtomicU64,
     pub fallback_eperm: AtomicU64,
     pub fallback_exdev: AtomicU64,
@@ -112,11 +231,19 @@ impl MaterializeCounters {
             files_linked: self.files_linked.load(Ordering::Relaxed),
             files_copied: self.files_copied.load(Ordering::Relaxed),
             link_fallback_copies: self.link_fallback_copies.load(Ordering::Relaxed),
+            files_reflinked: self.files_reflinked.load(Ordering::Relaxed),
+            reflink_fallback_copies: self.reflink_fallback_copies.load(Ordering::Relaxed),
+            bytes_linked: self.bytes_linked.load(Ordering::Relaxed),
+            bytes_copied: self.bytes_copied.load(Ordering::Relaxed),
+            bytes_reflinked: self.bytes_reflinked.load(Ordering::Relaxed),
             directories: 0,
             symlinks: self.symlinks.load(Ordering::Relaxed),
             fallback_eperm: self.fallback_eperm.load(Ordering::Relaxed),
             fallback_exdev: self.fallback_exdev.load(Ordering::Relaxed),
             fallback_other: self.fallback_other.load(Ordering::Relaxed),
+            skipped_special: 0,
+            broken_symlinks: 0,
+            files_skipped: 0,
         }
     }
 }
@@ -148,6 +275,13 @@ pub struct DepthOut {
     pub p95_depth: u64,
 }
 
+/// Dependency edge between two installed packages, by package key (`name@version`)
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GraphEdge {
+    pub from: String,
+    pub to: String,
+}
+
 /// Aggregate return type for analyze()
 pub struct AnalyzeReport {
     pub totals: ScanAgg,
@@ -155,13 +289,90 @@ pub struct AnalyzeReport {
     pub duplicates: Vec<DuplicateOut>,
     pub depth: DepthOut,
     pub node_modules_dir: PathBuf,
+    /// Populated only when the graph is requested
+    pub edges: Vec<GraphEdge>,
+    /// Strongly connected groups of package keys (only when the graph is requested)
+    pub cycles: Vec<Vec<String>>,
+    pub warnings: Vec<Warning>,
 }
 
 /// Aggregate return type for materialize_tree()
 #[derive(Default)]
 pub struct MaterializeReport {
+    /// The strategy files were actually placed with; never `Auto`
+    pub strategy: LinkStrategy,
+    /// Why that strategy was used, see [`resolve_link_strategy`]
+    pub strategy_reason: &'static str,
     pub stats: MaterializeStats,
     pub phases: PhaseDurations,
+    /// Source paths of the special files that were skipped
+    pub skipped_special: Vec<PathBuf>,
+    /// Source paths of the symlinks whose target is missing
+    pub broken_symlinks: Vec<PathBuf>,
+    pub warnings: Vec<Warning>,
+}
+
+/// A non-fatal condition surfaced in a report's `warnings` array. `code` is a
+/// stable kebab-case id consumers can match on; `context` names the path or
+/// package it applies to.
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub code: &'static str,
+    pub message: String,
+    pub context: Option<String>,
+}
+
+impl Warning {
+    pub fn new(code: &'static str, message: impl Into<String>, context: Option<String>) -> Self {
+        Self { code, message: message.into(), context }
+    }
+}
+
+/// What a [`CoreError`] comes from, for callers that react differently to a
+/// registry they could not reach or a tarball that does not match its hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    /// A registry, OSV or GitHub request failed or was refused
+    Network,
+    /// Downloaded content does not match its recorded integrity
+    Integrity,
+}
+
+/// The error of the operations that talk to the network. Converts to and
+/// from the plain `String` errors elsewhere, which count as [`ErrorKind::Other`].
+#[derive(Debug, Clone)]
+pub struct CoreError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl CoreError {
+    pub fn network(message: impl Into<String>) -> Self {
+        Self { kind: ErrorKind::Network, message: message.into() }
+    }
+
+    pub fn integrity(message: impl Into<String>) -> Self {
+        Self { kind: ErrorKind::Integrity, message: message.into() }
+    }
+}
+
+impl From<String> for CoreError {
+    fn from(message: String) -> Self {
+        Self { kind: ErrorKind::Other, message }
+    }
+}
+
+impl From<CoreError> for String {
+    fn from(err: CoreError) -> Self {
+        err.message
+    }
+}
+
+impl std::fmt::Display for CoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
 }
 
 // --- JSON writer (no dependencies) ---
@@ -170,14 +381,35 @@ pub struct JsonWriter {
     pub out: String,
     stack_first: Vec<bool>,
     after_key: bool,
+    /// Spaces per nesting level; 0 writes everything on one line
+    indent: usize,
+}
+
+impl Default for JsonWriter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl JsonWriter {
+    /// Single-line JSON; [`JsonWriter::with_indent`] for pretty output.
     pub fn new() -> Self {
+        Self::with_indent(0)
+    }
+
+    /// Single-line JSON, for files other code reads back and for JSON Lines.
+    pub fn compact() -> Self {
+        Self::with_indent(0)
+    }
+
+    /// One member per line, nested `indent` spaces per level; 0 is
+    /// [`JsonWriter::compact`].
+    pub fn with_indent(indent: usize) -> Self {
         Self {
             out: String::new(),
             stack_first: Vec::new(),
             after_key: false,
+            indent,
         }
     }
 
@@ -185,6 +417,13 @@ impl JsonWriter {
         self.out
     }
 
+    fn newline(&mut self) {
+        if self.indent > 0 {
+            self.out.push('\n');
+            self.out.extend(std::iter::repeat_n(' ', self.indent * self.stack_first.len()));
+        }
+    }
+
     fn push_comma_if_needed(&mut self) {
         if let Some(top) = self.stack_first.last_mut() {
             if !*top {
@@ -192,9 +431,18 @@ impl JsonWriter {
             } else {
                 *top = false;
             }
+            self.newline();
         }
     }
 
+    /// Close a container, on its own line unless it is empty.
+    fn close(&mut self, bracket: char) {
+        if self.stack_first.pop() == Some(false) {
+            self.newline();
+        }
+        self.out.push(bracket);
+    }
+
     pub fn begin_object(&mut self) {
         if self.after_key {
             self.after_key = false;
@@ -206,8 +454,7 @@ impl JsonWriter {
     }
 
     pub fn end_object(&mut self) {
-        self.out.push('}');
-        self.stack_first.pop();
+        self.close('}');
     }
 
     pub fn begin_array(&mut self) {
@@ -221,14 +468,16 @@ impl JsonWriter {
     }
 
     pub fn end_array(&mut self) {
-        self.out.push(']');
-        self.stack_first.pop();
+        self.close(']');
     }
 
     pub fn key(&mut self, k: &str) {
         self.push_comma_if_needed();
         self.string(k);
         self.out.push(':');
+        if self.indent > 0 {
+            self.out.push(' ');
+        }
         self.after_key = true;
     }
 
@@ -353,16 +602,8 @@ pub fn physical_len(md: &fs::Metadata) -> u64 {
         if blocks > 0 {
             return blocks.saturating_mul(512);
         }
-        return md.len();
-    }
-    #[cfg(windows)]
-    {
-        return md.len();
-    }
-    #[cfg(not(any(unix, windows)))]
-    {
-        return md.len();
     }
+    md.len()
 }
 
 pub fn is_dir_or_symlink_to_dir(path: &Path, entry: &fs::DirEntry) -> bool {
@@ -377,47 +618,29 @@ pub fn is_dir_or_symlink_to_dir(path: &Path, entry: &fs::DirEntry) -> bool {
     false
 }
 
+/// Top-level `name` and `version` from `<pkg_dir>/package.json`. A package without
+/// a name is keyed by its directory (`@scope/name` inside a scope directory); a
+/// missing or empty version means the directory is not treated as a package.
 pub fn read_package_identity(pkg_dir: &Path) -> Option<(String, String)> {
-    let pkg_json = pkg_dir.join("package.json");
-    let raw = fs::read_to_string(pkg_json).ok()?;
-    fn extract_str(raw: &str, key: &str) -> Option<String> {
-        let needle = format!("\"{key}\"");
-        let start = raw.find(&needle)?;
-        let after = &raw[start + needle.len()..];
-        let colon = after.find(':')?;
-        let mut s = after[colon + 1..].trim_start();
-        if !s.starts_with('"') {
-            return None;
-        }
-        s = &s[1..];
-        let mut out = String::new();
-        let mut chars = s.chars();
-        while let Some(c) = chars.next() {
-            match c {
-                '"' => break,
-                '\\' => {
-                    if let Some(esc) = chars.next() {
-                        out.push(match esc {
-                            '"' => '"',
-                            '\\' => '\\',
-                            'n' => '\n',
-                            'r' => '\r',
-                            't' => '\t',
-                            other => other,
-                        });
-                    }
-                }
-                other => out.push(other),
+    let raw = fs::read_to_string(pkg_dir.join("package.json")).ok()?;
+    let doc = JsonValue::parse(&raw).ok()?;
+    let field = |key: &str| {
+        doc.get(key)
+            .and_then(|v| v.as_str())
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+    };
+    let version = field("version")?;
+    let name = match field("name") {
+        Some(name) => name,
+        None => {
+            let dir_name = pkg_dir.file_name()?.to_string_lossy().into_owned();
+            match pkg_dir.parent().filter(|p| is_scope_dir(p)).and_then(|p| p.file_name()) {
+                Some(scope) => format!("{}/{}", scope.to_string_lossy(), dir_name),
+                None => dir_name,
             }
         }
-        if out.is_empty() {
-            None
-        } else {
-            Some(out)
-        }
-    }
-    let name = extract_str(&raw, "name")?;
-    let version = extract_str(&raw, "version")?;
+    };
     Some((name, version))
 }
 
@@ -542,6 +765,49 @@ pub fn list_packages_in_node_modules(node_modules_dir: &Path) -> Result<Vec<Path
 
 // --- Symlink / file helpers ---
 
+/// Apply the Windows extended-length prefix (`\\?\`) to an absolute path so deep
+/// node_modules trees are not capped at MAX_PATH (260 chars). Relative and
+/// already-prefixed paths are returned unchanged. Paths joined onto the result
+/// later are normalized by `PathBuf::push`, so `/`-separated lockfile paths work.
+#[cfg(windows)]
+pub fn long_path(p: &Path) -> PathBuf {
+    use std::path::{Component, Prefix};
+    if !p.is_absolute() {
+        return p.to_path_buf();
+    }
+    let mut comps = p.components();
+    let mut out = match comps.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) => PathBuf::from(format!(r"\\?\{}:\", letter as char)),
+            Prefix::UNC(server, share) => {
+                let mut s = std::ffi::OsString::from(r"\\?\UNC\");
+                s.push(server);
+                s.push(r"\");
+                s.push(share);
+                s.push(r"\");
+                PathBuf::from(s)
+            }
+            _ => return p.to_path_buf(),
+        },
+        _ => return p.to_path_buf(),
+    };
+    for comp in comps {
+        match comp {
+            Component::Normal(part) => out.push(part),
+            Component::ParentDir => {
+                out.pop();
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(not(windows))]
+pub fn long_path(p: &Path) -> PathBuf {
+    p.to_path_buf()
+}
+
 pub fn remove_path_if_exists(p: &Path) -> Result<(), String> {
     match fs::symlink_metadata(p) {
         Ok(md) => {
@@ -605,6 +871,32 @@ pub fn copy_file_with_retry(src: &Path, dst: &Path) -> Result<(), String> {
     }
 }
 
+/// Carry `src`'s mode and mtime over to its copy `dst`, as `options` asks.
+fn copy_file_metadata(src: &Path, dst: &Path, options: &MaterializeOptions) -> Result<(), String> {
+    let md = fs::metadata(src).map_err(|e| e.to_string())?;
+    if options.preserve_mode {
+        fs::set_permissions(dst, md.permissions()).map_err(|e| e.to_string())?;
+    } else {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dst, fs::Permissions::from_mode(0o644)).map_err(|e| e.to_string())?;
+        }
+    }
+    if options.preserve_mtime {
+        let modified = md.modified().map_err(|e| e.to_string())?;
+        // On Unix the owner may set times through a read-only handle, which
+        // still opens when the copied mode is read-only; Windows wants write access
+        let file = fs::OpenOptions::new()
+            .write(cfg!(windows))
+            .read(!cfg!(windows))
+            .open(dst)
+            .map_err(|e| e.to_string())?;
+        file.set_times(fs::FileTimes::new().set_modified(modified)).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
 pub fn hardlink_with_retry(src: &Path, dst: &Path) -> Result<(), String> {
     match fs::hard_link(src, dst) {
         Ok(()) => Ok(()),
@@ -618,6 +910,177 @@ pub fn hardlink_with_retry(src: &Path, dst: &Path) -> Result<(), String> {
     }
 }
 
+/// Reflink `src` to `dst`, replacing whatever is already at `dst`. False when
+/// the filesystem cannot clone (EOPNOTSUPP, EXDEV, ...); the caller copies.
+fn reflink_replacing(src: &Path, dst: &Path) -> bool {
+    if fs::symlink_metadata(dst).is_ok() && remove_path_if_exists(dst).is_err() {
+        return false;
+    }
+    try_reflink_file(src, dst)
+}
+
+/// Temp file that is removed on drop unless `keep()` is called after the final
+/// rename, so failed downloads/ingests never leave `*.tmp` files behind.
+pub struct TmpFileGuard {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl TmpFileGuard {
+    /// Reserve a unique tmp path next to `base` (`<base>.tmp-<pid>-<n>`).
+    pub fn beside(base: &Path) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let mut name = base.as_os_str().to_owned();
+        name.push(format!(".tmp-{}-{}", std::process::id(), n));
+        Self { path: PathBuf::from(name), armed: true }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The tmp file was renamed into place; don't remove anything on drop.
+    pub fn keep(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for TmpFileGuard {
+    fn drop(&mut self) {
+        if self.armed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Rename `from` to `to`, falling back to copy + remove when they sit on different
+/// devices (EXDEV), e.g. a cache root whose `tmp/` is a mount or symlink elsewhere.
+/// The copy lands in a tmp file beside `to` first so readers never see a partial file.
+pub fn rename_across_devices(from: &Path, to: &Path) -> std::io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            let tmp = TmpFileGuard::beside(to);
+            fs::copy(from, tmp.path())?;
+            fs::rename(tmp.path(), to)?;
+            tmp.keep();
+            fs::remove_file(from)
+        }
+        other => other,
+    }
+}
+
+/// Tmp files older than this are assumed orphaned by a crashed process.
+pub const STALE_TMP_MAX_AGE_SECS: u64 = 60 * 60;
+
+/// Remove `*.tmp` / `*.tmp-*` files in `dir` older than `max_age`. Younger files are
+/// left alone since they may belong to a concurrent install. Returns the number removed.
+pub fn sweep_stale_tmp_files(dir: &Path, max_age: std::time::Duration) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_) => return 0,
+    };
+    let now = std::time::SystemTime::now();
+    let mut removed = 0u64;
+    for ent in entries.flatten() {
+        let name = ent.file_name();
+        let name = name.to_string_lossy();
+        if !(name.ends_with(".tmp") || name.contains(".tmp-")) {
+            continue;
+        }
+        let stale = ent
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| now.duration_since(t).ok())
+            .map(|age| age >= max_age)
+            .unwrap_or(false);
+        if stale && fs::remove_file(ent.path()).is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+/// One filesystem change a mutating command made, or would make under `--dry-run`.
+#[derive(Debug, Clone)]
+pub struct PlannedChange {
+    /// `mkdir`, `create`, `overwrite`, `replace`, `remove`, `fetch` or `materialize`
+    pub action: &'static str,
+    pub path: PathBuf,
+}
+
+/// Plan-then-execute helper shared by the mutating commands: every change goes
+/// through here and is recorded, and none is performed when `dry_run` is set.
+#[derive(Debug, Default)]
+pub struct FsPlan {
+    pub dry_run: bool,
+    pub changes: Vec<PlannedChange>,
+}
+
+impl FsPlan {
+    pub fn new(dry_run: bool) -> Self {
+        Self { dry_run, changes: Vec::new() }
+    }
+
+    /// Record a change performed elsewhere (or skipped by the caller on a dry run).
+    pub fn record(&mut self, action: &'static str, path: &Path) {
+        self.changes.push(PlannedChange { action, path: path.to_path_buf() });
+    }
+
+    pub fn create_dir_all(&mut self, dir: &Path) -> std::io::Result<()> {
+        if dir.is_dir() {
+            return Ok(());
+        }
+        self.record("mkdir", dir);
+        if self.dry_run {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)
+    }
+
+    pub fn write(&mut self, path: &Path, contents: impl AsRef<[u8]>) -> std::io::Result<()> {
+        self.record(if path.exists() { "overwrite" } else { "create" }, path);
+        if self.dry_run {
+            return Ok(());
+        }
+        fs::write(path, contents)
+    }
+
+    pub fn remove_file(&mut self, path: &Path) -> std::io::Result<()> {
+        self.record("remove", path);
+        if self.dry_run {
+            return Ok(());
+        }
+        fs::remove_file(path)
+    }
+
+    /// `chmod 755` on unix; part of the preceding `write`, so not recorded.
+    pub fn set_executable(&mut self, path: &Path) -> std::io::Result<()> {
+        #[cfg(unix)]
+        if !self.dry_run {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        }
+        #[cfg(not(unix))]
+        let _ = path;
+        Ok(())
+    }
+}
+
+/// Write `dryRun` and the `plan` array into the current object.
+pub fn write_plan_json(w: &mut JsonWriter, plan: &FsPlan) {
+    w.key("dryRun"); w.value_bool(plan.dry_run);
+    w.key("plan"); w.begin_array();
+    for change in &plan.changes {
+        w.begin_object();
+        w.key("action"); w.value_string(change.action);
+        w.key("path"); w.value_string(&change.path.to_string_lossy());
+        w.end_object();
+    }
+    w.end_array();
+}
+
 #[derive(Clone)]
 pub struct MaterializeFileTask {
     pub src: PathBuf,
@@ -676,6 +1139,31 @@ pub fn try_clonefile(_src: &Path, _dst: &Path) -> bool {
     false
 }
 
+/// Copy-on-write clone of a single file: FICLONE on Linux (btrfs, XFS, bcachefs),
+/// clonefile(2) on macOS. `dst` must not exist; returns false when unsupported.
+#[cfg(target_os = "linux")]
+pub fn try_reflink_file(src: &Path, dst: &Path) -> bool {
+    use std::os::fd::AsRawFd;
+    use std::os::raw::{c_int, c_ulong};
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+    const FICLONE: c_ulong = 0x4004_9409;
+    let Ok(src_file) = fs::File::open(src) else { return false };
+    let Ok(dst_file) = fs::OpenOptions::new().write(true).create_new(true).open(dst) else { return false };
+    let cloned = unsafe { ioctl(dst_file.as_raw_fd(), FICLONE, src_file.as_raw_fd()) } == 0;
+    drop(dst_file);
+    if !cloned {
+        let _ = fs::remove_file(dst);
+    }
+    cloned
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn try_reflink_file(src: &Path, dst: &Path) -> bool {
+    try_clonefile(src, dst)
+}
+
 /// Try to clone a directory using clonefile. If clonefile fails (e.g. dest exists),
 /// remove dest first and retry once.
 pub fn try_clonefile_dir(src: &Path, dst: &Path) -> bool {
@@ -694,86 +1182,507 @@ pub fn try_clonefile_dir(src: &Path, dst: &Path) -> bool {
 
 // --- Core functions ---
 
-pub fn scan_tree(
-    root: &Path,
-    exclude_dir_names: &HashSet<&'static str>,
-    mut seen_identities: Option<&mut HashSet<(u64, u64)>>,
-) -> Result<ScanAgg, String> {
-    let mut agg = ScanAgg::default();
-    let mut stack: Vec<PathBuf> = vec![root.to_path_buf()];
+/// Receives what [`walk_node_modules`] finds.
+pub trait NodeModulesVisitor {
+    /// What files are attributed to, such as an index into a package list.
+    type Owner: Copy;
 
-    while let Some(dir) = stack.pop() {
-        let entries = match stable_list_dir(&dir) {
-            Ok(v) => v,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::NotADirectory {
-                    continue;
-                }
-                return Err(e.to_string());
-            }
-        };
+    /// A directory [`is_package_dir`] accepts, before anything below it.
+    /// Files below it, outside nested packages, go to the returned owner.
+    fn package(&mut self, dir: &Path) -> Option<Self::Owner>;
 
-        for ent in entries {
-            let name = ent.file_name();
-            let name_str = name.to_string_lossy();
-            if exclude_dir_names.contains(name_str.as_ref()) {
-                continue;
-            }
-            let full = dir.join(&name);
-            let ft = ent.file_type().map_err(|e| e.to_string())?;
+    /// A file, or a symlink the walk does not enter: one that does not point
+    /// at a directory, or any symlink when not following them.
+    fn file(&mut self, path: &Path, metadata: &fs::Metadata, owner: Option<Self::Owner>);
 
-            if ft.is_dir() || (ft.is_symlink() && fs::metadata(&full).map(|m| m.is_dir()).unwrap_or(false)) {
-                if is_package_dir(&full) {
-                    agg.package_count += 1;
-                }
-                stack.push(full);
-                continue;
-            }
+    /// A `node_modules` directory left unwalked because it is deeper than
+    /// [`WalkOptions::max_depth`].
+    fn depth_limited(&mut self, _dir: &Path) {}
+}
+
+/// The entries a tree walk skips: exact names at any depth, and
+/// gitignore-style globs over the path relative to the walk root. A glob
+/// with `*`, `?` and `**` segments is anchored at the root when it contains a
+/// `/` (`packages/*/dist`) and matches a name at any depth when it does not
+/// (`.cache` is `**/.cache`). Matching an entry skips it and all below it.
+#[derive(Debug, Clone, Default)]
+pub struct ExcludeMatcher {
+    names: HashSet<String>,
+    patterns: Vec<String>,
+}
 
-            agg.file_count += 1;
-            let md = fs::symlink_metadata(&full).map_err(|e| e.to_string())?;
-            let logical_len = md.len();
-            let phys_len = physical_len(&md);
-            agg.logical += logical_len;
+impl ExcludeMatcher {
+    /// Skip entries with one of these exact names.
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        Self { names: names.into_iter().map(str::to_string).collect(), patterns: Vec::new() }
+    }
 
-            let (a, b, reliable) = identity_key(&md);
-            if !reliable {
-                agg.approx = true;
+    /// Also skip paths matching these globs. Errors on an empty pattern.
+    pub fn with_patterns(mut self, patterns: &[String]) -> Result<Self, String> {
+        for raw in patterns {
+            let trimmed = raw.trim().trim_end_matches('/');
+            let anchored = trimmed.strip_prefix('/');
+            let pattern = anchored.unwrap_or(trimmed);
+            if pattern.is_empty() {
+                return Err(format!("invalid exclude pattern '{}'", raw));
             }
-
-            if let Some(seen) = seen_identities.as_deref_mut() {
-                let key = (a, b);
-                if a == 0 && b == 0 {
-                    agg.approx = true;
-                    agg.physical += phys_len;
-                } else if seen.insert(key) {
-                    agg.physical += phys_len;
-                } else {
-                    agg.shared += phys_len;
-                }
+            if anchored.is_some() || pattern.contains('/') {
+                self.patterns.push(pattern.to_string());
             } else {
-                agg.physical += phys_len;
+                self.patterns.push(format!("**/{}", pattern));
             }
         }
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.patterns.is_empty()
     }
 
-    Ok(agg)
+    /// Whether the entry at `rel`, a `/`-separated path below the walk root, is skipped.
+    pub fn excludes(&self, rel: &str) -> bool {
+        let name = rel.rsplit('/').next().unwrap_or(rel);
+        self.names.contains(name) || self.patterns.iter().any(|p| glob_match(p, rel))
+    }
+
+    /// [`Self::excludes`] for `path` below `root`.
+    fn excludes_path(&self, root: &Path, path: &Path) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        self.excludes(&rel.to_string_lossy().replace('\\', "/"))
+    }
 }
 
-pub fn run_materialize_tasks_parallel(
-    tasks: Vec<MaterializeTask>,
-    strategy: LinkStrategy,
-    jobs: usize,
-    counters: &MaterializeCounters,
-) -> Result<(), String> {
-    if tasks.is_empty() {
-        return Ok(());
+/// How [`walk_node_modules`] and [`scan_tree`] walk a tree.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Entries to skip
+    pub exclude: ExcludeMatcher,
+    /// Enter symlinks to directories. Off, such a symlink is reported as a
+    /// file of its own (link) size and nothing behind it is counted.
+    pub follow_symlinks: bool,
+    /// Do not enter `node_modules` directories nested deeper than this,
+    /// counting those in the path below the root and the root itself (so 1
+    /// is only the top-level packages of a project or its node_modules).
+    pub max_depth: Option<u64>,
+}
+
+impl WalkOptions {
+    /// Whether `dir`, about to be entered below `root`, is past `max_depth`.
+    fn too_deep(&self, root: &Path, dir: &Path) -> bool {
+        let Some(max_depth) = self.max_depth else { return false };
+        if dir.file_name().is_none_or(|n| n != "node_modules") {
+            return false;
+        }
+        let below = depth_from_path(dir.strip_prefix(root).unwrap_or(dir));
+        let own = root.file_name().is_some_and(|n| n == "node_modules") as u64;
+        below + own > max_depth
     }
-    let queue = Arc::new(Mutex::new(VecDeque::from(tasks)));
-    let first_error = Arc::new(Mutex::new(None::<String>));
-    let worker_count = jobs.max(1).min(queue.lock().map(|g| g.len()).unwrap_or(1).max(1));
+}
 
-    std::thread::scope(|scope| {
+/// The (device, inode) of `root` and each of its parents, skipping those
+/// without a reliable identity.
+fn walk_lineage(root: &Path) -> Vec<(u64, u64)> {
+    let canonical_root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    canonical_root
+        .ancestors()
+        .filter_map(|dir| fs::metadata(dir).ok().map(|md| identity_key(&md)).filter(|k| k.2).map(|(a, b, _)| (a, b)))
+        .collect()
+}
+
+/// The identity of the directory `ent` is, or leads to through a symlink
+/// when `follow_symlinks`, with `(0, 0)` when it is not reliable; `None` for
+/// anything the walk does not enter.
+fn walk_dir_identity(ent: &fs::DirEntry, full: &Path, follow_symlinks: bool) -> Result<Option<(u64, u64)>, String> {
+    let ft = ent.file_type().map_err(|e| e.to_string())?;
+    let dir_md = if ft.is_dir() {
+        ent.metadata().ok()
+    } else if ft.is_symlink() && follow_symlinks {
+        fs::metadata(full).ok().filter(|m| m.is_dir())
+    } else {
+        None
+    };
+    if !ft.is_dir() && dir_md.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(dir_md.map(|md| identity_key(&md)).filter(|k| k.2).map(|(a, b, _)| (a, b)).unwrap_or((0, 0))))
+}
+
+/// Walk `root` depth-first in a stable order and report packages and files
+/// to `visitor`. Entries `options.exclude` matches are skipped. Symlinks to
+/// directories are entered only with `options.follow_symlinks`; then each
+/// real directory is walked once, the first time the walk reaches it, and
+/// `root` and its parents are never re-entered (a workspace linking back to
+/// its own project root, say), so cycles end and nothing is counted twice.
+pub fn walk_node_modules<V: NodeModulesVisitor>(
+    root: &Path,
+    options: &WalkOptions,
+    visitor: &mut V,
+) -> Result<(), String> {
+    walk_node_modules_with(root, options, visitor, |visitor, path, owner| {
+        let md = fs::symlink_metadata(&path).map_err(|e| e.to_string())?;
+        visitor.file(&path, &md, owner);
+        Ok(())
+    })
+}
+
+/// [`walk_node_modules`] with the per-file stat calls spread over the rayon
+/// pool. Directories are still listed once each, in order, on this thread;
+/// the files found are then stat'ed in parallel and reported to `visitor` in
+/// walk order, so the results match. The price is holding every file's
+/// metadata at once. With a single rayon thread this is just
+/// [`walk_node_modules`].
+pub fn walk_node_modules_parallel<V: NodeModulesVisitor>(
+    root: &Path,
+    options: &WalkOptions,
+    visitor: &mut V,
+) -> Result<(), String> {
+    use rayon::prelude::*;
+
+    if rayon::current_num_threads() <= 1 {
+        return walk_node_modules(root, options, visitor);
+    }
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut owners: Vec<Option<V::Owner>> = Vec::new();
+    walk_node_modules_with(root, options, visitor, |_, path, owner| {
+        paths.push(path);
+        owners.push(owner);
+        Ok(())
+    })?;
+    let metadata = paths
+        .par_iter()
+        .map(|path| fs::symlink_metadata(path).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, String>>()?;
+    for ((path, md), owner) in paths.iter().zip(&metadata).zip(owners) {
+        visitor.file(path, md, owner);
+    }
+    Ok(())
+}
+
+/// The walk behind [`walk_node_modules`]: packages go to `visitor` as they
+/// are entered, and every file, unstat'ed, to `file` in walk order.
+fn walk_node_modules_with<V: NodeModulesVisitor>(
+    root: &Path,
+    options: &WalkOptions,
+    visitor: &mut V,
+    mut file: impl FnMut(&mut V, PathBuf, Option<V::Owner>) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut visited: HashSet<(u64, u64)> = walk_lineage(root).into_iter().collect();
+    let mut stack: Vec<(PathBuf, Option<V::Owner>)> = vec![(root.to_path_buf(), None)];
+
+    while let Some((dir, owner)) = stack.pop() {
+        let entries = match stable_list_dir(&dir) {
+            Ok(v) => v,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::NotADirectory {
+                    continue;
+                }
+                return Err(e.to_string());
+            }
+        };
+
+        for ent in entries {
+            let name = ent.file_name();
+            let full = dir.join(&name);
+            if options.exclude.excludes_path(root, &full) {
+                continue;
+            }
+            if let Some(id) = walk_dir_identity(&ent, &full, options.follow_symlinks)? {
+                if options.too_deep(root, &full) {
+                    visitor.depth_limited(&full);
+                    continue;
+                }
+                if id != (0, 0) && !visited.insert(id) {
+                    continue;
+                }
+                let next_owner = if is_package_dir(&full) { visitor.package(&full) } else { owner };
+                stack.push((full, next_owner));
+                continue;
+            }
+
+            file(visitor, full, owner)?;
+        }
+    }
+    Ok(())
+}
+
+/// How [`ScanAgg::add_file`] counted a file's physical size.
+pub enum FileShare {
+    /// The first sighting of this file: counted as physical
+    Unique,
+    /// Another link to a file already seen: counted as shared
+    Shared,
+    /// No usable identity, so it cannot be deduplicated: counted as physical
+    Unknown,
+}
+
+impl ScanAgg {
+    /// Count one file. With `seen`, hardlinks to a file already counted add
+    /// to `shared` instead of `physical`. Returns the file's logical and
+    /// physical sizes and how the latter was counted.
+    pub fn add_file(&mut self, md: &fs::Metadata, seen: Option<&mut HashSet<(u64, u64)>>) -> (u64, u64, FileShare) {
+        let logical_len = md.len();
+        let phys_len = physical_len(md);
+        self.file_count += 1;
+        self.logical = self.logical.saturating_add(logical_len);
+
+        let (a, b, reliable) = identity_key(md);
+        if !reliable {
+            self.approx = true;
+        }
+        let share = match seen {
+            Some(_) if a == 0 && b == 0 => FileShare::Unknown,
+            Some(seen) if seen.contains(&(a, b)) => FileShare::Shared,
+            Some(seen) => {
+                seen.insert((a, b));
+                FileShare::Unique
+            }
+            None => FileShare::Unique,
+        };
+        match share {
+            FileShare::Shared => self.shared = self.shared.saturating_add(phys_len),
+            _ => self.physical = self.physical.saturating_add(phys_len),
+        }
+        (logical_len, phys_len, share)
+    }
+}
+
+/// [`scan_tree`] skipping only entries with the given exact names.
+pub fn scan_tree_excluding_names(
+    root: &Path,
+    exclude_dir_names: &HashSet<&'static str>,
+    seen_identities: Option<&mut HashSet<(u64, u64)>>,
+) -> Result<ScanAgg, String> {
+    let options = WalkOptions { exclude: ExcludeMatcher::from_names(exclude_dir_names.iter().copied()), ..WalkOptions::default() };
+    scan_tree(root, &options, seen_identities)
+}
+
+/// Sizes and counts for everything below `root`, walked as `options` says.
+pub fn scan_tree(
+    root: &Path,
+    options: &WalkOptions,
+    seen_identities: Option<&mut HashSet<(u64, u64)>>,
+) -> Result<ScanAgg, String> {
+    struct Scan<'a> {
+        agg: ScanAgg,
+        seen: Option<&'a mut HashSet<(u64, u64)>>,
+    }
+    impl NodeModulesVisitor for Scan<'_> {
+        type Owner = ();
+        fn package(&mut self, _dir: &Path) -> Option<()> {
+            self.agg.package_count += 1;
+            None
+        }
+        fn file(&mut self, _path: &Path, md: &fs::Metadata, _owner: Option<()>) {
+            self.agg.add_file(md, self.seen.as_deref_mut());
+        }
+        fn depth_limited(&mut self, _dir: &Path) {
+            self.agg.approx = true;
+        }
+    }
+
+    let mut scan = Scan { agg: ScanAgg::default(), seen: seen_identities };
+    walk_node_modules(root, options, &mut scan)?;
+    Ok(scan.agg)
+}
+
+// --- Scan cache ---
+
+const SCAN_CACHE_VERSION: u64 = 1;
+
+/// A directory changed this recently is walked but not cached, since a
+/// later change within the same mtime tick would go unnoticed.
+const SCAN_CACHE_SETTLE: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// What the scan cache keeps per directory: its mtime, the sizes of the files
+/// directly in it (and how many of its subdirectories are packages), and the
+/// subdirectories the walk went into.
+struct ScanCacheDir {
+    mtime: String,
+    agg: ScanAgg,
+    subdirs: Vec<String>,
+}
+
+impl ScanAgg {
+    fn merge(&mut self, other: &ScanAgg) {
+        self.logical = self.logical.saturating_add(other.logical);
+        self.physical = self.physical.saturating_add(other.physical);
+        self.shared = self.shared.saturating_add(other.shared);
+        self.file_count += other.file_count;
+        self.package_count += other.package_count;
+        self.approx |= other.approx;
+    }
+}
+
+/// [`scan_tree`] that remembers each directory in `cache_path` and, on the
+/// next run, takes the files of a directory whose mtime is unchanged from the
+/// cache instead of listing and stat-ing them. Every directory is still
+/// stat-ed, so a change anywhere below is found. Two things the cache cannot
+/// see: a file rewritten in place (its directory's mtime stays put), and
+/// hardlinks between a cached directory and a walked one, so any cache hit
+/// marks the totals `approx`. A missing or unreadable cache means a full walk.
+/// Symlinks are not followed, as with [`scan_tree`]'s default options.
+pub fn scan_tree_cached(root: &Path, cache_path: &Path) -> Result<ScanAgg, String> {
+    let mut previous = read_scan_cache(cache_path, root);
+    let mut next: BTreeMap<String, ScanCacheDir> = BTreeMap::new();
+    let mut total = ScanAgg::default();
+    let mut seen: HashSet<(u64, u64)> = HashSet::new();
+    let settled = std::time::SystemTime::now() - SCAN_CACHE_SETTLE;
+    let identity = |md: &fs::Metadata| Some(identity_key(md)).filter(|k| k.2).map(|(a, b, _)| (a, b)).unwrap_or((0, 0));
+
+    // Like walk_node_modules, never re-enter a directory the walk is inside
+    let canonical_root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let lineage: Vec<(u64, u64)> = canonical_root.ancestors().skip(1).map(|dir| fs::metadata(dir).map(|md| identity(&md)).unwrap_or((0, 0))).collect();
+    // (directory, its cache key, identities of the directories above it)
+    let mut stack = vec![(root.to_path_buf(), String::new(), lineage)];
+
+    while let Some((dir, rel, mut above)) = stack.pop() {
+        let md = match fs::metadata(&dir) {
+            Ok(md) => md,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::NotADirectory => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        above.push(identity(&md));
+        let modified = md.modified().ok();
+        let mtime = modified
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| format!("{}.{:09}", d.as_secs(), d.subsec_nanos()));
+        let child = |name: &str| if rel.is_empty() { name.to_string() } else { format!("{}/{}", rel, name) };
+
+        if let Some(cached) = previous.remove(&rel).filter(|c| mtime.as_deref() == Some(c.mtime.as_str())) {
+            total.merge(&cached.agg);
+            total.approx = true;
+            for name in &cached.subdirs {
+                stack.push((dir.join(name), child(name), above.clone()));
+            }
+            next.insert(rel, cached);
+            continue;
+        }
+
+        let entries = match stable_list_dir(&dir) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::NotADirectory => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        let mut own = ScanAgg::default();
+        let mut subdirs = Vec::new();
+        for ent in entries {
+            let name = ent.file_name().to_string_lossy().to_string();
+            let full = dir.join(&name);
+            if let Some(id) = walk_dir_identity(&ent, &full, false)? {
+                if id != (0, 0) && above.contains(&id) {
+                    continue;
+                }
+                if is_package_dir(&full) {
+                    own.package_count += 1;
+                }
+                stack.push((full, child(&name), above.clone()));
+                subdirs.push(name);
+                continue;
+            }
+            let file_md = fs::symlink_metadata(&full).map_err(|e| e.to_string())?;
+            own.add_file(&file_md, Some(&mut seen));
+        }
+        total.merge(&own);
+        if let (Some(mtime), true) = (mtime, modified.is_some_and(|t| t < settled)) {
+            next.insert(rel, ScanCacheDir { mtime, agg: own, subdirs });
+        }
+    }
+
+    // Best effort: a cache that cannot be written only costs a full walk
+    let _ = write_scan_cache(cache_path, root, &next);
+    Ok(total)
+}
+
+/// The directories of a scan cache written for `root`; empty when the file is
+/// missing, unreadable or for another root.
+fn read_scan_cache(cache_path: &Path, root: &Path) -> HashMap<String, ScanCacheDir> {
+    let mut dirs = HashMap::new();
+    let Some(doc) = fs::read_to_string(cache_path).ok().and_then(|c| JsonValue::parse(&c).ok()) else { return dirs };
+    if doc.get("version").and_then(JsonValue::as_u64) != Some(SCAN_CACHE_VERSION)
+        || doc.get("root").and_then(JsonValue::as_str) != Some(root.to_string_lossy().as_ref())
+    {
+        return dirs;
+    }
+    for (rel, entry) in doc.get("dirs").and_then(JsonValue::as_object).into_iter().flatten() {
+        let number = |key: &str| entry.get(key).and_then(JsonValue::as_u64);
+        let (Some(mtime), Some(logical), Some(physical), Some(shared), Some(file_count), Some(package_count)) = (
+            entry.get("mtime").and_then(JsonValue::as_str),
+            number("logical"),
+            number("physical"),
+            number("shared"),
+            number("files"),
+            number("packages"),
+        ) else {
+            continue;
+        };
+        let subdirs = entry
+            .get("subdirs")
+            .and_then(JsonValue::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|s| s.as_str().map(str::to_string))
+            .collect();
+        let approx = entry.get("approx").and_then(JsonValue::as_bool).unwrap_or(false);
+        let agg = ScanAgg { logical, physical, shared, file_count, package_count, approx };
+        dirs.insert(rel.clone(), ScanCacheDir { mtime: mtime.to_string(), agg, subdirs });
+    }
+    dirs
+}
+
+fn write_scan_cache(cache_path: &Path, root: &Path, dirs: &BTreeMap<String, ScanCacheDir>) -> Result<(), String> {
+    let mut w = JsonWriter::compact();
+    w.begin_object();
+    w.key("version"); w.value_u64(SCAN_CACHE_VERSION);
+    w.key("root"); w.value_string(&root.to_string_lossy());
+    w.key("dirs"); w.begin_object();
+    for (rel, dir) in dirs {
+        w.key(rel); w.begin_object();
+        w.key("mtime"); w.value_string(&dir.mtime);
+        w.key("logical"); w.value_u64(dir.agg.logical);
+        w.key("physical"); w.value_u64(dir.agg.physical);
+        w.key("shared"); w.value_u64(dir.agg.shared);
+        w.key("files"); w.value_u64(dir.agg.file_count);
+        w.key("packages"); w.value_u64(dir.agg.package_count);
+        w.key("approx"); w.value_bool(dir.agg.approx);
+        w.key("subdirs"); w.begin_array();
+        for name in &dir.subdirs {
+            w.value_string(name);
+        }
+        w.end_array();
+        w.end_object();
+    }
+    w.end_object();
+    w.end_object();
+    w.out.push('\n');
+
+    if let Some(parent) = cache_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let tmp = TmpFileGuard::beside(cache_path);
+    fs::write(tmp.path(), w.finish()).map_err(|e| e.to_string())?;
+    fs::rename(tmp.path(), cache_path).map_err(|e| e.to_string())?;
+    tmp.keep();
+    Ok(())
+}
+
+pub fn run_materialize_tasks_parallel(
+    tasks: Vec<MaterializeTask>,
+    strategy: LinkStrategy,
+    jobs: usize,
+    options: &MaterializeOptions,
+    counters: &MaterializeCounters,
+) -> Result<(), String> {
+    if tasks.is_empty() {
+        return Ok(());
+    }
+    let queue = Arc::new(Mutex::new(VecDeque::from(tasks)));
+    let first_error = Arc::new(Mutex::new(None::<String>));
+    let worker_count = jobs.max(1).min(queue.lock().map(|g| g.len()).unwrap_or(1).max(1));
+
+    std::thread::scope(|scope| {
         for _ in 0..worker_count {
             let queue = Arc::clone(&queue);
             let first_error = Arc::clone(&first_error);
@@ -797,12 +1706,33 @@ pub fn run_materialize_tasks_parallel(
                     let task_result = match task {
                         MaterializeTask::File(task) => {
                             counters.files.fetch_add(1, Ordering::Relaxed);
+                            let bytes = fs::metadata(&task.src).map(|md| md.len()).unwrap_or(0);
                             match strategy {
                                 LinkStrategy::Copy => {
-                                    if let Err(err) = copy_file_with_retry(&task.src, &task.dst) {
+                                    if let Err(err) = copy_file_with_retry(&task.src, &task.dst)
+                                        .and_then(|()| copy_file_metadata(&task.src, &task.dst, options))
+                                    {
+                                        Err(err)
+                                    } else {
+                                        counters.files_copied.fetch_add(1, Ordering::Relaxed);
+                                        counters.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+                                        Ok(())
+                                    }
+                                }
+                                LinkStrategy::Reflink => {
+                                    if reflink_replacing(&task.src, &task.dst) {
+                                        copy_file_metadata(&task.src, &task.dst, options).map(|()| {
+                                            counters.files_reflinked.fetch_add(1, Ordering::Relaxed);
+                                            counters.bytes_reflinked.fetch_add(bytes, Ordering::Relaxed);
+                                        })
+                                    } else if let Err(err) = copy_file_with_retry(&task.src, &task.dst)
+                                        .and_then(|()| copy_file_metadata(&task.src, &task.dst, options))
+                                    {
                                         Err(err)
                                     } else {
                                         counters.files_copied.fetch_add(1, Ordering::Relaxed);
+                                        counters.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+                                        counters.reflink_fallback_copies.fetch_add(1, Ordering::Relaxed);
                                         Ok(())
                                     }
                                 }
@@ -810,6 +1740,7 @@ pub fn run_materialize_tasks_parallel(
                                     match hardlink_with_retry(&task.src, &task.dst) {
                                         Ok(()) => {
                                             counters.files_linked.fetch_add(1, Ordering::Relaxed);
+                                            counters.bytes_linked.fetch_add(bytes, Ordering::Relaxed);
                                             Ok(())
                                         }
                                         Err(link_err) => {
@@ -820,10 +1751,13 @@ pub fn run_materialize_tasks_parallel(
                                             } else {
                                                 counters.fallback_other.fetch_add(1, Ordering::Relaxed);
                                             }
-                                            if let Err(err) = copy_file_with_retry(&task.src, &task.dst) {
+                                            if let Err(err) = copy_file_with_retry(&task.src, &task.dst)
+                                                .and_then(|()| copy_file_metadata(&task.src, &task.dst, options))
+                                            {
                                                 Err(err)
                                             } else {
                                                 counters.files_copied.fetch_add(1, Ordering::Relaxed);
+                                                counters.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
                                                 counters
                                                     .link_fallback_copies
                                                     .fetch_add(1, Ordering::Relaxed);
@@ -866,42 +1800,105 @@ pub fn run_materialize_tasks_parallel(
     result
 }
 
+/// `bundleDependencies` (or `bundledDependencies`) of the package at `pkg_dir`.
+/// `true` bundles every entry of `dependencies`.
+pub fn read_bundled_dependencies(pkg_dir: &Path) -> Vec<String> {
+    let content = match fs::read_to_string(pkg_dir.join("package.json")) {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+    let fields = json_object_entries(&content);
+    let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+    let raw = match field("bundleDependencies").or_else(|| field("bundledDependencies")) {
+        Some(raw) => raw,
+        None => return Vec::new(),
+    };
+    if raw == "true" {
+        return field("dependencies").map(json_object_entries).unwrap_or_default().into_iter().map(|(k, _)| k).collect();
+    }
+    json_array_elements(raw)
+        .iter()
+        .filter_map(|e| e.strip_prefix('"').and_then(|e| e.strip_suffix('"')))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether `rel` (a `/`-separated path inside a package) is kept by the walkers
+/// that skip `node_modules`: only the package's own top-level `node_modules`
+/// entries named in `bundled` survive, with everything inside them.
+fn bundled_entry_allowed(rel: &str, bundled: &[String]) -> bool {
+    let segs: Vec<&str> = rel.split('/').collect();
+    match segs.iter().position(|s| *s == "node_modules") {
+        None => true,
+        Some(0) => match segs.get(1) {
+            None => !bundled.is_empty(),
+            Some(scope) if scope.starts_with('@') => match segs.get(2) {
+                None => bundled.iter().any(|b| b.starts_with(&format!("{}/", scope))),
+                Some(name) => bundled.iter().any(|b| *b == format!("{}/{}", scope, name)),
+            },
+            Some(name) => bundled.iter().any(|b| b == name),
+        },
+        Some(_) => false,
+    }
+}
+
 pub fn materialize_tree(
     src_root: &Path,
     dst_root: &Path,
     strategy: LinkStrategy,
     jobs: usize,
     profile: MaterializeProfile,
+    options: MaterializeOptions,
 ) -> Result<MaterializeReport, String> {
     let total_start = Instant::now();
     let mut phases = PhaseDurations::default();
+    let src_root = &long_path(src_root);
+    let dst_root = &long_path(dst_root);
 
     // Scan phase
     let scan_start = Instant::now();
     let mut directories: Vec<PathBuf> = vec![dst_root.to_path_buf()];
     let mut tasks: Vec<MaterializeTask> = Vec::new();
+    let mut skipped_special: Vec<PathBuf> = Vec::new();
+    let mut broken_symlinks: Vec<PathBuf> = Vec::new();
+    let mut files_skipped = 0u64;
     let mut stack: Vec<(PathBuf, PathBuf)> = vec![(src_root.to_path_buf(), dst_root.to_path_buf())];
+    let bundled = read_bundled_dependencies(src_root);
 
     while let Some((src_dir, dst_dir)) = stack.pop() {
         let entries = stable_list_dir(&src_dir).map_err(|e| e.to_string())?;
         for ent in entries {
             let name = ent.file_name();
-            let name_str = name.to_string_lossy();
-            if name_str == "node_modules" || name_str == ".better_extracted" {
+            let src = src_dir.join(&name);
+            let rel = src.strip_prefix(src_root).unwrap_or(&src).to_string_lossy().replace('\\', "/");
+            if name == ".better_extracted" || !bundled_entry_allowed(&rel, &bundled) {
                 continue;
             }
 
-            let src = src_dir.join(&name);
             let dst = dst_dir.join(&name);
             let ft = ent.file_type().map_err(|e| e.to_string())?;
 
+            let existing = if options.incremental { fs::symlink_metadata(&dst).ok() } else { None };
+
             if ft.is_dir() {
+                if !options.dry_run && existing.as_ref().is_some_and(|md| !md.is_dir()) {
+                    remove_path_if_exists(&dst)?;
+                }
                 directories.push(dst.clone());
                 stack.push((src, dst));
                 continue;
             }
             if ft.is_symlink() {
                 let target = fs::read_link(&src).map_err(|e| e.to_string())?;
+                if fs::metadata(&src).is_err() {
+                    broken_symlinks.push(src.clone());
+                }
+                if existing.as_ref().is_some_and(|md| md.file_type().is_symlink())
+                    && fs::read_link(&dst).is_ok_and(|t| t == target)
+                {
+                    files_skipped += 1;
+                    continue;
+                }
                 tasks.push(MaterializeTask::Symlink(MaterializeSymlinkTask {
                     src,
                     dst,
@@ -910,9 +1907,23 @@ pub fn materialize_tree(
                 continue;
             }
             if ft.is_file() {
+                if let Some(dst_md) = existing.as_ref() {
+                    let src_md = ent.metadata().map_err(|e| e.to_string())?;
+                    if dest_file_current(&src_md, dst_md, options.preserve_mtime) {
+                        files_skipped += 1;
+                        continue;
+                    }
+                }
                 tasks.push(MaterializeTask::File(MaterializeFileTask { src, dst }));
                 continue;
             }
+            // FIFO, socket or device node: never recreated at the destination.
+            match options.special_files {
+                SpecialFilePolicy::Skip => skipped_special.push(src),
+                SpecialFilePolicy::Error => {
+                    return Err(format!("Refusing to materialize special file {}", src.display()));
+                }
+            }
         }
     }
     phases.scan_ms = scan_start.elapsed().as_millis() as u64;
@@ -921,7 +1932,7 @@ pub fn materialize_tree(
     let mkdir_start = Instant::now();
     directories.sort();
     directories.dedup();
-    for dir in &directories {
+    for dir in directories.iter().filter(|_| !options.dry_run) {
         fs::create_dir_all(dir).map_err(|e| e.to_string())?;
     }
     phases.mkdir_ms = mkdir_start.elapsed().as_millis() as u64;
@@ -935,15 +1946,97 @@ pub fn materialize_tree(
 
     // Link/copy phase
     let link_start = Instant::now();
+    let (strategy, strategy_reason) = resolve_link_strategy(strategy, src_root, dst_root);
     let counters = MaterializeCounters::default();
-    run_materialize_tasks_parallel(tasks, strategy, effective_jobs, &counters)?;
+    if options.dry_run {
+        for task in &tasks {
+            match task {
+                MaterializeTask::File(_) => counters.files.fetch_add(1, Ordering::Relaxed),
+                MaterializeTask::Symlink(_) => counters.symlinks.fetch_add(1, Ordering::Relaxed),
+            };
+        }
+    } else {
+        run_materialize_tasks_parallel(tasks, strategy, effective_jobs, &options, &counters)?;
+    }
     phases.link_copy_ms = link_start.elapsed().as_millis() as u64;
 
     phases.total_ms = total_start.elapsed().as_millis() as u64;
 
     let mut stats = counters.snapshot();
     stats.directories = directories.len().saturating_sub(1) as u64;
-    Ok(MaterializeReport { stats, phases })
+    stats.skipped_special = skipped_special.len() as u64;
+    stats.broken_symlinks = broken_symlinks.len() as u64;
+    stats.files_skipped = files_skipped;
+
+    let mut warnings = Vec::new();
+    if stats.link_fallback_copies > 0 {
+        warnings.push(Warning::new(
+            "link-fallback-copy",
+            format!(
+                "{} file(s) were copied because hardlinking failed (eperm {}, exdev {}, other {})",
+                stats.link_fallback_copies, stats.fallback_eperm, stats.fallback_exdev, stats.fallback_other
+            ),
+            Some(dst_root.to_string_lossy().to_string()),
+        ));
+    }
+    for path in &skipped_special {
+        warnings.push(Warning::new(
+            "special-file-skipped",
+            "Skipped a FIFO, socket or device node",
+            Some(path.to_string_lossy().to_string()),
+        ));
+    }
+    for path in &broken_symlinks {
+        warnings.push(Warning::new(
+            "broken-symlink",
+            "Symlink target does not exist; recreated as a dangling link",
+            Some(path.to_string_lossy().to_string()),
+        ));
+    }
+    Ok(MaterializeReport { strategy, strategy_reason, stats, phases, skipped_special, broken_symlinks, warnings })
+}
+
+/// Settle `Auto` once per materialization instead of per file: hardlinks
+/// between devices always fail with EXDEV, so a destination on another device
+/// than the source is copied outright. Both roots must already exist.
+///
+/// The reason is "requested" for an explicit strategy, "cross-device",
+/// "same-device", or "device-unknown" when the filesystem gives no reliable
+/// device id (hardlink is tried, with the per-file copy fallback).
+pub fn resolve_link_strategy(strategy: LinkStrategy, src_root: &Path, dst_root: &Path) -> (LinkStrategy, &'static str) {
+    if !matches!(strategy, LinkStrategy::Auto) {
+        return (strategy, "requested");
+    }
+    let device = |p: &Path| fs::metadata(p).ok().map(|md| identity_key(&md)).filter(|(_, _, reliable)| *reliable).map(|(dev, _, _)| dev);
+    match (device(src_root), device(dst_root)) {
+        (Some(src), Some(dst)) if src != dst => (LinkStrategy::Copy, "cross-device"),
+        (Some(_), Some(_)) => (LinkStrategy::Hardlink, "same-device"),
+        _ => (LinkStrategy::Hardlink, "device-unknown"),
+    }
+}
+
+/// Whether an existing destination file can stand in for `src`: the same inode
+/// (an earlier hardlink), or a regular file of the same size and mtime (an
+/// earlier copy, which keeps the source mtime). Without `preserve_mtime` a copy
+/// is stamped with the time it was made, so a same-size file not older than the
+/// source has to do.
+fn dest_file_current(src: &fs::Metadata, dst: &fs::Metadata, preserve_mtime: bool) -> bool {
+    if !dst.is_file() {
+        return false;
+    }
+    let (src_dev, src_ino, src_reliable) = identity_key(src);
+    let (dst_dev, dst_ino, dst_reliable) = identity_key(dst);
+    if src_reliable && dst_reliable && src_dev == dst_dev && src_ino == dst_ino {
+        return true;
+    }
+    if src.len() != dst.len() {
+        return false;
+    }
+    match (src.modified(), dst.modified()) {
+        (Ok(s), Ok(d)) if preserve_mtime => d == s,
+        (Ok(s), Ok(d)) => d >= s,
+        _ => false,
+    }
 }
 
 fn ensure_pkg_idx(
@@ -1000,93 +2093,80 @@ fn ensure_pkg_idx(
     Some(idx)
 }
 
-pub fn analyze(root: &Path, _include_graph: bool) -> Result<AnalyzeReport, String> {
-    let node_modules_dir = root.join("node_modules");
-    if !node_modules_dir.exists() {
-        return Err("node_modules_not_found".to_string());
+impl AnalyzeReport {
+    /// Reorder `packages` by key (name@version) or by logical size, largest
+    /// first. `Type` has no meaning here and keeps traversal order.
+    pub fn sort_packages(&mut self, key: SortKey) {
+        match key {
+            SortKey::Name => self.packages.sort_by(|a, b| a.key.cmp(&b.key)),
+            SortKey::Size => self.packages.sort_by(|a, b| b.logical.cmp(&a.logical).then_with(|| a.key.cmp(&b.key))),
+            SortKey::Type => {}
+        }
     }
+}
 
-    let mut totals = ScanAgg::default();
-    let mut seen_global: HashSet<(u64, u64)> = HashSet::new();
-
-    let mut by_key: HashMap<String, usize> = HashMap::new();
-    let mut packages: Vec<PackageOut> = Vec::new();
-    let mut depths: Vec<u64> = Vec::new();
-    let mut pkg_dir_to_idx: HashMap<PathBuf, Option<usize>> = HashMap::new();
-
-    let mut stack: Vec<(PathBuf, Option<usize>)> = vec![(node_modules_dir.clone(), None)];
-    while let Some((dir, owner_idx)) = stack.pop() {
-        let entries = match stable_list_dir(&dir) {
-            Ok(v) => v,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::NotFound || e.kind() == std::io::ErrorKind::NotADirectory {
-                    continue;
-                }
-                return Err(e.to_string());
-            }
-        };
-
-        for ent in entries {
-            let name = ent.file_name();
-            let full = dir.join(&name);
-            let ft = ent.file_type().map_err(|e| e.to_string())?;
-
-            if ft.is_dir() || (ft.is_symlink() && fs::metadata(&full).map(|m| m.is_dir()).unwrap_or(false)) {
-                let next_owner = if is_package_dir(&full) {
-                    ensure_pkg_idx(&full, &mut pkg_dir_to_idx, &mut by_key, &mut packages, &mut depths)
-                } else {
-                    owner_idx
-                };
-                stack.push((full, next_owner));
-                continue;
-            }
-
-            totals.file_count += 1;
-            let md = fs::symlink_metadata(&full).map_err(|e| e.to_string())?;
-            let logical_len = md.len();
-            let phys_len = physical_len(&md);
-            totals.logical = totals.logical.saturating_add(logical_len);
+/// With `max_depth`, nested node_modules deeper than that are not walked
+/// (see [`WalkOptions::max_depth`]) and the totals are marked approximate.
+pub fn analyze(root: &Path, include_graph: bool, max_depth: Option<u64>) -> Result<AnalyzeReport, String> {
+    analyze_node_modules(&root.join("node_modules"), include_graph, max_depth)
+}
 
-            let (a, b, reliable) = identity_key(&md);
-            if !reliable {
-                totals.approx = true;
-            }
+/// [`analyze`] over the `node_modules` directory itself, wherever it lives.
+pub fn analyze_node_modules(node_modules_dir: &Path, include_graph: bool, max_depth: Option<u64>) -> Result<AnalyzeReport, String> {
+    if !node_modules_dir.exists() {
+        return Err("node_modules_not_found".to_string());
+    }
 
-            if let Some(idx) = owner_idx {
-                let pkg = &mut packages[idx];
-                pkg.file_count = pkg.file_count.saturating_add(1);
-                pkg.logical = pkg.logical.saturating_add(logical_len);
-                if !reliable {
-                    pkg.approx = true;
-                }
+    struct Analyze {
+        totals: ScanAgg,
+        seen: HashSet<(u64, u64)>,
+        by_key: HashMap<String, usize>,
+        packages: Vec<PackageOut>,
+        depths: Vec<u64>,
+        pkg_dir_to_idx: HashMap<PathBuf, Option<usize>>,
+    }
+    impl NodeModulesVisitor for Analyze {
+        type Owner = usize;
+        fn package(&mut self, dir: &Path) -> Option<usize> {
+            self.totals.package_count += 1;
+            ensure_pkg_idx(&dir.to_path_buf(), &mut self.pkg_dir_to_idx, &mut self.by_key, &mut self.packages, &mut self.depths)
+        }
+        fn file(&mut self, _path: &Path, md: &fs::Metadata, owner: Option<usize>) {
+            let (logical_len, phys_len, share) = self.totals.add_file(md, Some(&mut self.seen));
+            let Some(idx) = owner else { return };
+            let pkg = &mut self.packages[idx];
+            pkg.file_count = pkg.file_count.saturating_add(1);
+            pkg.logical = pkg.logical.saturating_add(logical_len);
+            if !identity_key(md).2 {
+                pkg.approx = true;
             }
-
-            if a == 0 && b == 0 {
-                totals.approx = true;
-                totals.physical = totals.physical.saturating_add(phys_len);
-                if let Some(idx) = owner_idx {
-                    let pkg = &mut packages[idx];
+            match share {
+                FileShare::Shared => pkg.shared = pkg.shared.saturating_add(phys_len),
+                FileShare::Unique => pkg.physical = pkg.physical.saturating_add(phys_len),
+                FileShare::Unknown => {
                     pkg.approx = true;
                     pkg.physical = pkg.physical.saturating_add(phys_len);
                 }
-                continue;
-            }
-
-            let first = seen_global.insert((a, b));
-            if first {
-                totals.physical = totals.physical.saturating_add(phys_len);
-                if let Some(idx) = owner_idx {
-                    packages[idx].physical = packages[idx].physical.saturating_add(phys_len);
-                }
-            } else {
-                totals.shared = totals.shared.saturating_add(phys_len);
-                if let Some(idx) = owner_idx {
-                    packages[idx].shared = packages[idx].shared.saturating_add(phys_len);
-                }
             }
         }
+        fn depth_limited(&mut self, _dir: &Path) {
+            self.totals.approx = true;
+        }
     }
 
+    let mut walk = Analyze {
+        totals: ScanAgg::default(),
+        seen: HashSet::new(),
+        by_key: HashMap::new(),
+        packages: Vec::new(),
+        depths: Vec::new(),
+        pkg_dir_to_idx: HashMap::new(),
+    };
+    // Workspace packages and pnpm-style layouts live behind symlinks
+    let options = WalkOptions { follow_symlinks: true, max_depth, ..WalkOptions::default() };
+    walk_node_modules_parallel(node_modules_dir, &options, &mut walk)?;
+    let Analyze { totals, packages, depths, pkg_dir_to_idx, .. } = walk;
+
     // Duplicates.
     let mut by_name: BTreeMap<String, Vec<&PackageOut>> = BTreeMap::new();
     for p in &packages {
@@ -1121,30 +2201,311 @@ pub fn analyze(root: &Path, _include_graph: bool) -> Result<AnalyzeReport, Strin
         p95_depth,
     };
 
+    let (edges, cycles) = if include_graph {
+        let edges = build_graph_edges(&packages, &pkg_dir_to_idx);
+        let cycles = detect_cycles(&edges);
+        (edges, cycles)
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    let warnings = sizing_warnings(&totals, node_modules_dir);
     Ok(AnalyzeReport {
         totals,
         packages,
         duplicates,
         depth: depth_out,
-        node_modules_dir,
+        node_modules_dir: node_modules_dir.to_path_buf(),
+        edges,
+        cycles,
+        warnings,
     })
 }
 
-// --- JSON serialization functions (used by binary) ---
+// --- Analyze baselines ---
+
+/// How node_modules changed between a saved analyze report and now.
+pub struct AnalyzeDiff {
+    pub baseline: ScanAgg,
+    pub current: ScanAgg,
+    /// Package keys (name@version) installed now but not in the baseline
+    pub added: Vec<PackageOut>,
+    /// Package keys in the baseline that are no longer installed
+    pub removed: Vec<PackageOut>,
+    /// Names installed in several versions now that had one in the baseline
+    pub new_duplicates: Vec<DuplicateOut>,
+}
+
+/// Read a report written by `analyze --baseline` (or saved analyze output)
+/// back into its totals, packages, duplicates and depth. The graph and
+/// warnings are not kept.
+pub fn read_analyze_baseline(path: &Path) -> Result<AnalyzeReport, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let doc = JsonValue::parse(&content).map_err(|e| format!("{} is not valid JSON: {}", path.display(), e))?;
+    if doc.get("kind").and_then(JsonValue::as_str) != Some("better.analyze.report") {
+        return Err(format!("{} is not an analyze report", path.display()));
+    }
+    let field = |value: Option<&JsonValue>, key: &str| value.and_then(|v| v.get(key)).and_then(JsonValue::as_u64).unwrap_or(0);
+    let strings = |value: Option<&JsonValue>| -> Vec<String> {
+        value
+            .and_then(JsonValue::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect()
+    };
 
-pub fn write_analyze_json(
-    project_root: &Path,
-    totals: &ScanAgg,
-    node_modules_dir: &Path,
-    packages: &Vec<PackageOut>,
-    duplicates: &Vec<DuplicateOut>,
-    depth: &DepthOut,
-    include_graph: bool,
-) -> String {
-    let mut w = JsonWriter::new();
-    w.begin_object();
-    w.key("ok");
-    w.value_bool(true);
+    let mut packages = Vec::new();
+    for p in doc.get("packages").and_then(JsonValue::as_array).unwrap_or_default() {
+        let text = |key: &str| p.get(key).and_then(JsonValue::as_str).unwrap_or("").to_string();
+        let sizes = p.get("sizes");
+        packages.push(PackageOut {
+            key: text("key"),
+            name: text("name"),
+            version: text("version"),
+            paths: strings(p.get("paths")),
+            min_depth: field(p.get("depthStats"), "minDepth"),
+            max_depth: field(p.get("depthStats"), "maxDepth"),
+            logical: field(sizes, "logicalBytes"),
+            physical: field(sizes, "physicalBytes"),
+            shared: field(sizes, "sharedBytes"),
+            file_count: field(sizes, "fileCount"),
+            approx: sizes.and_then(|s| s.get("physicalBytesApprox")).and_then(JsonValue::as_bool).unwrap_or(false),
+        });
+    }
+    let duplicates = doc
+        .get("duplicates")
+        .and_then(JsonValue::as_array)
+        .unwrap_or_default()
+        .iter()
+        .map(|d| DuplicateOut {
+            name: d.get("name").and_then(JsonValue::as_str).unwrap_or("").to_string(),
+            versions: strings(d.get("versions")),
+            majors: strings(d.get("majors")),
+            count: field(Some(d), "count"),
+        })
+        .collect();
+
+    let node_modules = doc.get("nodeModules");
+    // Reports from before packageCount was written: one package per install path
+    let package_count = node_modules
+        .and_then(|n| n.get("packageCount"))
+        .and_then(JsonValue::as_u64)
+        .unwrap_or_else(|| packages.iter().map(|p| p.paths.len() as u64).sum());
+    let totals = ScanAgg {
+        logical: field(node_modules, "logicalBytes"),
+        physical: field(node_modules, "physicalBytes"),
+        shared: 0,
+        file_count: field(node_modules, "fileCount"),
+        package_count,
+        approx: node_modules.and_then(|n| n.get("physicalBytesApprox")).and_then(JsonValue::as_bool).unwrap_or(false),
+    };
+    Ok(AnalyzeReport {
+        totals,
+        packages,
+        duplicates,
+        depth: DepthOut {
+            max_depth: field(doc.get("depth"), "maxDepth"),
+            p95_depth: field(doc.get("depth"), "p95Depth"),
+        },
+        node_modules_dir: PathBuf::from(node_modules.and_then(|n| n.get("path")).and_then(JsonValue::as_str).unwrap_or("")),
+        edges: Vec::new(),
+        cycles: Vec::new(),
+        warnings: Vec::new(),
+    })
+}
+
+/// Compare the current analyze report against a baseline. Packages match by
+/// key, so an upgrade shows as one removal and one addition.
+pub fn diff_analyze(baseline: &AnalyzeReport, current: &AnalyzeReport) -> AnalyzeDiff {
+    let baseline_keys: HashSet<&str> = baseline.packages.iter().map(|p| p.key.as_str()).collect();
+    let current_keys: HashSet<&str> = current.packages.iter().map(|p| p.key.as_str()).collect();
+    let mut added: Vec<PackageOut> = current.packages.iter().filter(|p| !baseline_keys.contains(p.key.as_str())).cloned().collect();
+    let mut removed: Vec<PackageOut> = baseline.packages.iter().filter(|p| !current_keys.contains(p.key.as_str())).cloned().collect();
+    added.sort_by(|a, b| a.key.cmp(&b.key));
+    removed.sort_by(|a, b| a.key.cmp(&b.key));
+
+    let duplicated: HashSet<&str> = baseline.duplicates.iter().map(|d| d.name.as_str()).collect();
+    let new_duplicates = current
+        .duplicates
+        .iter()
+        .filter(|d| !duplicated.contains(d.name.as_str()))
+        .map(|d| DuplicateOut { name: d.name.clone(), versions: d.versions.clone(), majors: d.majors.clone(), count: d.count })
+        .collect();
+
+    AnalyzeDiff {
+        baseline: baseline.totals.clone(),
+        current: current.totals.clone(),
+        added,
+        removed,
+        new_duplicates,
+    }
+}
+
+/// Warn when physical sizes had to be estimated because the filesystem gave no
+/// usable file identity (dev/inode), so hardlinked files may be double counted.
+pub fn sizing_warnings(agg: &ScanAgg, root: &Path) -> Vec<Warning> {
+    if !agg.approx {
+        return Vec::new();
+    }
+    vec![Warning::new(
+        "approx-size",
+        "Physical sizes are approximate: file identities are unavailable on this filesystem",
+        Some(root.to_string_lossy().to_string()),
+    )]
+}
+
+/// Read the dependency names a package declares (`dependencies` and `optionalDependencies`).
+pub fn parse_package_deps(pkg_dir: &Path) -> Vec<String> {
+    let content = match fs::read_to_string(pkg_dir.join("package.json")) {
+        Ok(c) => c,
+        Err(_) => return Vec::new(),
+    };
+    let mut names: Vec<String> = Vec::new();
+    for section in ["dependencies", "optionalDependencies"] {
+        for (name, _) in extract_json_object_pairs(&content, section).unwrap_or_default() {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Resolve each package's declared dependencies to installed package keys the way
+/// Node does: the nearest `node_modules/<dep>` walking up from the package directory.
+/// A package the walk reached under another path (a symlink into a pnpm store,
+/// say) is matched by its real path.
+fn build_graph_edges(
+    packages: &[PackageOut],
+    pkg_dir_to_idx: &HashMap<PathBuf, Option<usize>>,
+) -> Vec<GraphEdge> {
+    let by_real_path: HashMap<PathBuf, usize> = pkg_dir_to_idx
+        .iter()
+        .filter_map(|(dir, idx)| Some((fs::canonicalize(dir).ok()?, (*idx)?)))
+        .collect();
+    let lookup = |candidate: &Path| match pkg_dir_to_idx.get(candidate) {
+        Some(idx) => *idx,
+        None if candidate.is_dir() => fs::canonicalize(candidate).ok().and_then(|real| by_real_path.get(&real).copied()),
+        None => None,
+    };
+    let mut edges: BTreeSet<GraphEdge> = BTreeSet::new();
+    for pkg in packages {
+        for path in &pkg.paths {
+            let pkg_dir = Path::new(path);
+            for dep in parse_package_deps(pkg_dir) {
+                let mut cur = Some(pkg_dir);
+                while let Some(dir) = cur {
+                    let candidate = dir.join("node_modules").join(&dep);
+                    if let Some(idx) = lookup(&candidate) {
+                        edges.insert(GraphEdge {
+                            from: pkg.key.clone(),
+                            to: packages[idx].key.clone(),
+                        });
+                        break;
+                    }
+                    cur = dir.parent();
+                }
+            }
+        }
+    }
+    edges.into_iter().collect()
+}
+
+/// Find dependency cycles with Tarjan's SCC algorithm. Each cycle is the sorted list
+/// of member keys; self-dependencies count as a single-member cycle.
+pub fn detect_cycles(edges: &[GraphEdge]) -> Vec<Vec<String>> {
+    let mut adj: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for e in edges {
+        adj.entry(e.from.as_str()).or_default().push(e.to.as_str());
+        adj.entry(e.to.as_str()).or_default();
+    }
+
+    struct Tarjan<'a> {
+        adj: &'a BTreeMap<&'a str, Vec<&'a str>>,
+        index: HashMap<&'a str, usize>,
+        low: HashMap<&'a str, usize>,
+        on_stack: HashSet<&'a str>,
+        stack: Vec<&'a str>,
+        next: usize,
+        sccs: Vec<Vec<String>>,
+    }
+
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, v: &'a str) {
+            self.index.insert(v, self.next);
+            self.low.insert(v, self.next);
+            self.next += 1;
+            self.stack.push(v);
+            self.on_stack.insert(v);
+
+            for &w in self.adj.get(v).map(|l| l.as_slice()).unwrap_or(&[]) {
+                if !self.index.contains_key(w) {
+                    self.visit(w);
+                    let low = self.low[v].min(self.low[w]);
+                    self.low.insert(v, low);
+                } else if self.on_stack.contains(w) {
+                    let low = self.low[v].min(self.index[w]);
+                    self.low.insert(v, low);
+                }
+            }
+
+            if self.low[v] == self.index[v] {
+                let mut members: Vec<String> = Vec::new();
+                while let Some(w) = self.stack.pop() {
+                    self.on_stack.remove(w);
+                    members.push(w.to_string());
+                    if w == v {
+                        break;
+                    }
+                }
+                let self_loop = self.adj.get(v).map(|l| l.contains(&v)).unwrap_or(false);
+                if members.len() > 1 || self_loop {
+                    members.sort();
+                    self.sccs.push(members);
+                }
+            }
+        }
+    }
+
+    let mut t = Tarjan {
+        adj: &adj,
+        index: HashMap::new(),
+        low: HashMap::new(),
+        on_stack: HashSet::new(),
+        stack: Vec::new(),
+        next: 0,
+        sccs: Vec::new(),
+    };
+    for &v in adj.keys() {
+        if !t.index.contains_key(v) {
+            t.visit(v);
+        }
+    }
+    let mut cycles = t.sccs;
+    cycles.sort();
+    cycles
+}
+
+// --- JSON serialization functions (used by binary) ---
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_analyze_json(
+    project_root: &Path,
+    totals: &ScanAgg,
+    node_modules_dir: &Path,
+    packages: &Vec<PackageOut>,
+    duplicates: &Vec<DuplicateOut>,
+    depth: &DepthOut,
+    graph_edges: Option<&[GraphEdge]>,
+    dedupe: Option<&DedupeReport>,
+    warnings: &[Warning],
+    indent: usize,
+) -> String {
+    let mut w = JsonWriter::with_indent(indent);
+    w.begin_object();
+    w.key("ok");
+    w.value_bool(true);
     w.key("kind");
     w.value_string("better.analyze.report");
     w.key("schemaVersion");
@@ -1164,6 +2525,8 @@ pub fn write_analyze_json(
     w.value_bool(totals.approx);
     w.key("fileCount");
     w.value_u64(totals.file_count);
+    w.key("packageCount");
+    w.value_u64(totals.package_count);
     w.end_object();
 
     w.key("packages");
@@ -1239,7 +2602,7 @@ pub fn write_analyze_json(
     w.end_object();
 
     w.key("graph");
-    if include_graph {
+    if let Some(edges) = graph_edges {
         w.begin_object();
         w.key("nodes");
         w.begin_object();
@@ -1261,12 +2624,29 @@ pub fn write_analyze_json(
         w.end_object();
         w.key("edges");
         w.begin_array();
+        for edge in edges {
+            w.begin_object();
+            w.key("from");
+            w.value_string(&edge.from);
+            w.key("to");
+            w.value_string(&edge.to);
+            w.end_object();
+        }
         w.end_array();
         w.end_object();
     } else {
         w.value_null();
     }
 
+    if let Some(report) = dedupe {
+        w.key("dedupe");
+        w.begin_object();
+        write_dedupe_fields(&mut w, report);
+        w.end_object();
+    }
+
+    write_warnings(&mut w, warnings);
+
     w.key("extensions");
     w.begin_object();
     w.key("generatedBy");
@@ -1283,8 +2663,75 @@ pub fn write_analyze_json(
     w.finish()
 }
 
-pub fn write_scan_json(root: &Path, agg: &ScanAgg, ok: bool, reason: Option<String>) -> String {
-    let mut w = JsonWriter::new();
+/// Emit `"warnings": [{code, message, context}]`; always present, possibly empty.
+pub fn write_warnings(w: &mut JsonWriter, warnings: &[Warning]) {
+    w.key("warnings");
+    w.begin_array();
+    for warning in warnings {
+        w.begin_object();
+        w.key("code");
+        w.value_string(warning.code);
+        w.key("message");
+        w.value_string(&warning.message);
+        w.key("context");
+        match &warning.context {
+            Some(c) => w.value_string(c),
+            None => w.value_null(),
+        }
+        w.end_object();
+    }
+    w.end_array();
+}
+
+// --- Text tables ---
+
+/// Cells longer than this many characters are cut short and end in "...".
+pub const TABLE_MAX_CELL: usize = 48;
+
+/// Plain ASCII table for `--human` output: a header row, a dashed rule and the
+/// rows, columns padded to their widest cell and two spaces apart. Short rows
+/// get empty cells; cells over [`TABLE_MAX_CELL`] characters are truncated.
+pub fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let columns = rows.iter().map(Vec::len).chain([headers.len()]).max().unwrap_or(0);
+    let fit = |cell: &str| -> String {
+        if cell.chars().count() <= TABLE_MAX_CELL {
+            cell.to_string()
+        } else {
+            cell.chars().take(TABLE_MAX_CELL - 3).chain("...".chars()).collect()
+        }
+    };
+    let cells: Vec<Vec<String>> = std::iter::once(headers.iter().map(|h| fit(h)).collect::<Vec<_>>())
+        .chain(rows.iter().map(|row| row.iter().map(|c| fit(c)).collect()))
+        .map(|mut row: Vec<String>| {
+            row.resize(columns, String::new());
+            row
+        })
+        .collect();
+    let widths: Vec<usize> = (0..columns)
+        .map(|col| cells.iter().map(|row| row[col].chars().count()).max().unwrap_or(0))
+        .collect();
+    let line = |row: &[String]| -> String {
+        let mut out = String::new();
+        for (col, cell) in row.iter().enumerate() {
+            if col > 0 {
+                out.push_str("  ");
+            }
+            out.push_str(cell);
+            out.extend(std::iter::repeat_n(' ', widths[col] - cell.chars().count()));
+        }
+        out.trim_end().to_string() + "\n"
+    };
+    let mut out = line(&cells[0]);
+    let rule: Vec<String> = widths.iter().map(|&w| "-".repeat(w)).collect();
+    out.push_str(&line(&rule));
+    for row in &cells[1..] {
+        out.push_str(&line(row));
+    }
+    out
+}
+
+pub fn write_scan_json(root: &Path, agg: &ScanAgg, ok: bool, reason: Option<String>, indent: usize) -> String {
+    let mut w = JsonWriter::with_indent(indent);
     w.begin_object();
     w.key("ok");
     w.value_bool(ok);
@@ -1308,25 +2755,31 @@ pub fn write_scan_json(root: &Path, agg: &ScanAgg, ok: bool, reason: Option<Stri
     w.value_u64(agg.file_count);
     w.key("packageCount");
     w.value_u64(agg.package_count);
+    write_warnings(&mut w, &sizing_warnings(agg, root));
     w.end_object();
     w.out.push('\n');
     w.finish()
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn write_materialize_json(
     src: &Path,
     dest: &Path,
     strategy: LinkStrategy,
+    decision: Option<(LinkStrategy, &str)>,
     jobs: usize,
     profile: MaterializeProfile,
     effective_jobs: usize,
+    dry_run: bool,
     ok: bool,
     reason: Option<String>,
     duration_ms: u64,
     stats: &MaterializeStats,
     phases: &PhaseDurations,
+    warnings: &[Warning],
+    indent: usize,
 ) -> String {
-    let mut w = JsonWriter::new();
+    let mut w = JsonWriter::with_indent(indent);
     w.begin_object();
     w.key("ok");
     w.value_bool(ok);
@@ -1340,8 +2793,20 @@ pub fn write_materialize_json(
     w.value_string(&dest.to_string_lossy());
     w.key("strategy");
     w.value_string(strategy.as_str());
+    w.key("effectiveStrategy");
+    match decision {
+        Some((s, _)) => w.value_string(s.as_str()),
+        None => w.value_null(),
+    }
+    w.key("strategyReason");
+    match decision {
+        Some((_, reason)) => w.value_string(reason),
+        None => w.value_null(),
+    }
     w.key("jobs");
     w.value_u64(jobs as u64);
+    w.key("dryRun");
+    w.value_bool(dry_run);
     w.key("durationMs");
     w.value_u64(duration_ms);
     w.key("reason");
@@ -1360,10 +2825,26 @@ pub fn write_materialize_json(
     w.value_u64(stats.files_copied);
     w.key("linkFallbackCopies");
     w.value_u64(stats.link_fallback_copies);
+    w.key("filesReflinked");
+    w.value_u64(stats.files_reflinked);
+    w.key("reflinkFallbackCopies");
+    w.value_u64(stats.reflink_fallback_copies);
+    w.key("bytesLinked");
+    w.value_u64(stats.bytes_linked);
+    w.key("bytesCopied");
+    w.value_u64(stats.bytes_copied);
+    w.key("bytesReflinked");
+    w.value_u64(stats.bytes_reflinked);
     w.key("directories");
     w.value_u64(stats.directories);
     w.key("symlinks");
     w.value_u64(stats.symlinks);
+    w.key("skippedSpecial");
+    w.value_u64(stats.skipped_special);
+    w.key("brokenSymlinks");
+    w.value_u64(stats.broken_symlinks);
+    w.key("filesSkipped");
+    w.value_u64(stats.files_skipped);
     w.end_object();
     w.key("profile");
     w.value_string(profile.as_str());
@@ -1387,6 +2868,7 @@ pub fn write_materialize_json(
     w.key("other");
     w.value_u64(stats.fallback_other);
     w.end_object();
+    write_warnings(&mut w, warnings);
     w.end_object();
     w.out.push('\n');
     w.finish()
@@ -1401,6 +2883,23 @@ pub struct ResolvedPackage {
     pub rel_path: String,
     pub resolved_url: String,
     pub integrity: String,
+    /// The ranges this package depends on, sorted by name. Only registry
+    /// resolution fills this in; lockfile parsing leaves it empty.
+    pub dependencies: Vec<(String, String)>,
+    /// The bin names its manifest declares. Also only filled in by registry
+    /// resolution, as are the flags.
+    pub bins: Vec<String>,
+    pub flags: DepFlags,
+}
+
+/// npm's lockfile flags: `dev` when only dev dependencies lead to a package,
+/// `optional` when only optional ones do, and `dev_optional` when it takes
+/// both kinds together to rule out every production path.
+#[derive(Clone, Copy, Default)]
+pub struct DepFlags {
+    pub dev: bool,
+    pub optional: bool,
+    pub dev_optional: bool,
 }
 
 #[derive(Clone)]
@@ -1409,8 +2908,48 @@ pub struct ResolveResult {
     pub lockfile_version: u64,
 }
 
-/// Parse package-lock.json and extract packages to install
+/// The lockfile npm would read in `project_root`: `npm-shrinkwrap.json` when
+/// present, otherwise `package-lock.json`.
+pub fn default_lockfile(project_root: &Path) -> PathBuf {
+    let shrinkwrap = project_root.join("npm-shrinkwrap.json");
+    if shrinkwrap.is_file() {
+        shrinkwrap
+    } else {
+        project_root.join("package-lock.json")
+    }
+}
+
+/// The lockfile `install` reads: [`default_lockfile`] if it exists, else the
+/// project's `yarn.lock`, else its `pnpm-lock.yaml`.
+pub fn install_lockfile(project_root: &Path) -> PathBuf {
+    let npm = default_lockfile(project_root);
+    if npm.exists() {
+        return npm;
+    }
+    ["yarn.lock", "pnpm-lock.yaml"]
+        .iter()
+        .map(|name| project_root.join(name))
+        .find(|path| path.is_file())
+        .unwrap_or(npm)
+}
+
+/// yarn.lock and pnpm-lock.yaml go to their own resolvers; None for an npm
+/// lockfile.
+fn resolve_from_non_npm_lockfile(lockfile_path: &Path) -> Option<Result<ResolveResult, String>> {
+    match lockfile_path.file_name()?.to_str()? {
+        "yarn.lock" => Some(resolve_from_yarn_lock(lockfile_path)),
+        "pnpm-lock.yaml" => Some(resolve_from_pnpm_lock(lockfile_path)),
+        _ => None,
+    }
+}
+
+/// Parse package-lock.json and extract packages to install. A `yarn.lock` or
+/// `pnpm-lock.yaml` path is read with [`resolve_from_yarn_lock`] or
+/// [`resolve_from_pnpm_lock`] instead.
 pub fn resolve_from_lockfile(lockfile_path: &Path) -> Result<ResolveResult, String> {
+    if let Some(result) = resolve_from_non_npm_lockfile(lockfile_path) {
+        return result;
+    }
     let content = fs::read_to_string(lockfile_path).map_err(|e| e.to_string())?;
 
     // Simple JSON parsing without serde
@@ -1422,231 +2961,756 @@ pub fn resolve_from_lockfile(lockfile_path: &Path) -> Result<ResolveResult, Stri
     })
 }
 
-fn parse_npm_lockfile(json: &str) -> Result<Vec<ResolvedPackage>, String> {
-    let mut packages = Vec::new();
+const RESOLVE_CACHE_HEADER: &str = "better-resolve v1";
 
-    // Find the "packages" object
-    let packages_start = json
-        .find(r#""packages""#)
-        .ok_or_else(|| "Missing 'packages' field in lockfile".to_string())?;
+/// Like `resolve_from_lockfile`, but reuses the parse of an identical lockfile
+/// from `<cache_root>/resolve/<sha256>`. Any change to the lockfile bytes is a
+/// different key, so stale entries are never read. Returns the result and
+/// whether it came from the cache. A yarn.lock or pnpm-lock.yaml is always
+/// parsed afresh, since where its packages go also depends on package.json.
+pub fn resolve_from_lockfile_cached(lockfile_path: &Path, cache_root: &Path) -> Result<(ResolveResult, bool), String> {
+    use sha2::{Digest, Sha256};
 
-    let after_packages = &json[packages_start..];
-    let obj_start = after_packages
-        .find('{')
-        .ok_or_else(|| "Malformed packages object".to_string())?;
-
-    // Simple state machine to parse package entries
-    let packages_str = &after_packages[obj_start..];
-    let mut current_key = String::new();
-    let mut in_string = false;
-    let mut escape_next = false;
-    let mut brace_depth = 0i32;
-    let mut collecting_entry = false;
-    let mut entry_data = String::new();
-    // State for key tracking at depth 1:
-    // 0 = waiting for opening quote, 1 = reading key, 2 = key done (waiting for ':' then value)
-    let mut key_state = 0u8;
-
-    for ch in packages_str.chars() {
-        if escape_next {
-            if key_state == 1 {
-                current_key.push(ch);
-            } else if collecting_entry {
-                entry_data.push(ch);
-            }
-            escape_next = false;
-            continue;
-        }
+    if let Some(result) = resolve_from_non_npm_lockfile(lockfile_path) {
+        return result.map(|result| (result, false));
+    }
 
-        if ch == '\\' && in_string {
-            escape_next = true;
-            if key_state == 1 {
-                current_key.push(ch);
-            } else if collecting_entry {
-                entry_data.push(ch);
-            }
-            continue;
-        }
+    let content = fs::read(lockfile_path).map_err(|e| e.to_string())?;
+    let key = format!("{:x}", Sha256::digest(&content));
+    let layout = CasLayout::new(&long_path(cache_root));
+    let cache_file = layout.resolve_dir.join(&key);
 
-        if ch == '"' {
-            in_string = !in_string;
-
-            if brace_depth == 1 && !collecting_entry {
-                // Key tracking at depth 1
-                if key_state == 0 && in_string {
-                    // Opening quote of a key
-                    key_state = 1;
-                    current_key.clear();
-                } else if key_state == 1 && !in_string {
-                    // Closing quote of a key
-                    key_state = 2;
-                } else if key_state == 2 && in_string {
-                    // Opening quote of a string value at depth 1 — skip
-                } else if key_state == 2 && !in_string {
-                    // Closing quote of a string value at depth 1
-                }
-            } else if collecting_entry {
-                entry_data.push(ch);
-            }
-            continue;
-        }
+    if let Some(cached) = fs::read_to_string(&cache_file).ok().and_then(|c| decode_resolve_cache(&c)) {
+        return Ok((cached, true));
+    }
 
-        if in_string {
-            if key_state == 1 {
-                current_key.push(ch);
-            } else if collecting_entry {
-                entry_data.push(ch);
-            }
-            continue;
-        }
+    let content = String::from_utf8(content).map_err(|e| e.to_string())?;
+    let result = ResolveResult { packages: parse_npm_lockfile(&content)?, lockfile_version: 3 };
 
-        // Not in string
-        if ch == '{' {
-            brace_depth += 1;
-            if brace_depth == 2 {
-                if !current_key.is_empty()
-                    && current_key.starts_with("node_modules/")
-                {
-                    collecting_entry = true;
-                    entry_data.clear();
-                }
-                key_state = 0;
-            }
-            if collecting_entry && brace_depth > 2 {
-                entry_data.push(ch);
-            }
-        } else if ch == '}' {
-            if collecting_entry && brace_depth == 2 {
-                // Parse this entry
-                if let Ok(pkg) = parse_package_entry(&current_key, &entry_data) {
-                    packages.push(pkg);
-                }
-                collecting_entry = false;
-                entry_data.clear();
-            } else if collecting_entry {
-                entry_data.push(ch);
-            }
-            brace_depth -= 1;
-            if brace_depth == 0 {
-                break;
-            }
-            if brace_depth == 1 {
-                key_state = 0; // Ready for next key
-            }
-        } else if ch == ',' && brace_depth == 1 && !collecting_entry {
-            key_state = 0; // Ready for next key after comma
-        } else if collecting_entry {
-            entry_data.push(ch);
+    // Best effort: a failed write only costs a re-parse next time.
+    if let Some(encoded) = encode_resolve_cache(&result) {
+        let _ = fs::create_dir_all(&layout.resolve_dir);
+        let _ = fs::create_dir_all(&layout.tmp_dir);
+        let tmp = TmpFileGuard::beside(&layout.tmp_dir.join(&key));
+        if fs::write(tmp.path(), encoded).is_ok() && rename_across_devices(tmp.path(), &cache_file).is_ok() {
+            tmp.keep();
         }
     }
-
-    Ok(packages)
+    Ok((result, false))
 }
 
-fn parse_package_entry(rel_path: &str, entry_json: &str) -> Result<ResolvedPackage, String> {
-    let name = extract_json_field(entry_json, "name")
-        .unwrap_or_else(|| package_name_from_path(rel_path));
-    let version = extract_json_field(entry_json, "version")
-        .ok_or_else(|| format!("Missing version for {}", rel_path))?;
-    let resolved = extract_json_field(entry_json, "resolved")
-        .ok_or_else(|| format!("Missing resolved URL for {}", rel_path))?;
-    let integrity = extract_json_field(entry_json, "integrity")
-        .ok_or_else(|| format!("Missing integrity for {}", rel_path))?;
-
-    Ok(ResolvedPackage {
-        name,
-        version,
-        rel_path: rel_path.to_string(),
-        resolved_url: resolved,
-        integrity,
-    })
+/// One tab-separated line per package after a header line. Returns `None`
+/// if a field would break the line format, in which case nothing is cached.
+fn encode_resolve_cache(result: &ResolveResult) -> Option<String> {
+    let mut out = format!("{}\t{}\n", RESOLVE_CACHE_HEADER, result.lockfile_version);
+    for p in &result.packages {
+        let fields = [&p.name, &p.version, &p.rel_path, &p.resolved_url, &p.integrity];
+        if fields.iter().any(|f| f.contains('\t') || f.contains('\n')) {
+            return None;
+        }
+        out.push_str(&fields.iter().map(|f| f.as_str()).collect::<Vec<_>>().join("\t"));
+        out.push('\n');
+    }
+    Some(out)
 }
 
-fn extract_json_field(json: &str, field_name: &str) -> Option<String> {
-    let needle = format!("\"{}\"", field_name);
-    let start = json.find(&needle)?;
-    let after = &json[start + needle.len()..];
-    let colon = after.find(':')?;
-    let mut rest = after[colon + 1..].trim_start();
-
-    if !rest.starts_with('"') {
+fn decode_resolve_cache(content: &str) -> Option<ResolveResult> {
+    let mut lines = content.lines();
+    let (header, version) = lines.next()?.split_once('\t')?;
+    if header != RESOLVE_CACHE_HEADER {
         return None;
     }
-
-    rest = &rest[1..];
-    let mut result = String::new();
-    let mut chars = rest.chars();
-
-    while let Some(c) = chars.next() {
-        match c {
-            '"' => break,
-            '\\' => {
-                if let Some(esc) = chars.next() {
-                    result.push(match esc {
-                        '"' => '"',
-                        '\\' => '\\',
-                        'n' => '\n',
-                        'r' => '\r',
-                        't' => '\t',
-                        '/' => '/',
-                        other => other,
-                    });
-                }
-            }
-            other => result.push(other),
+    let lockfile_version = version.parse().ok()?;
+    let mut packages = Vec::new();
+    for line in lines {
+        let f: Vec<&str> = line.split('\t').collect();
+        if f.len() != 5 {
+            return None;
         }
+        packages.push(ResolvedPackage {
+            name: f[0].to_string(),
+            version: f[1].to_string(),
+            rel_path: f[2].to_string(),
+            resolved_url: f[3].to_string(),
+            integrity: f[4].to_string(),
+            dependencies: Vec::new(),
+            bins: Vec::new(),
+            flags: DepFlags::default(),
+        });
     }
-
-    if result.is_empty() {
-        None
-    } else {
-        Some(result)
-    }
+    Some(ResolveResult { packages, lockfile_version })
 }
 
-fn package_name_from_path(rel_path: &str) -> String {
-    let parts: Vec<&str> = rel_path.split('/').collect();
-    if let Some(idx) = parts.iter().position(|&p| p == "node_modules") {
-        if idx + 1 < parts.len() {
-            let first = parts[idx + 1];
-            if first.starts_with('@') && idx + 2 < parts.len() {
-                return format!("{}/{}", first, parts[idx + 2]);
-            }
-            return first.to_string();
+fn parse_npm_lockfile(json: &str) -> Result<Vec<ResolvedPackage>, String> {
+    parse_npm_lockfile_checked(json).map(|(packages, _)| packages)
+}
+
+/// Parse the lockfile, also returning the entries that were skipped because
+/// they could not be turned into a package. Bundled and linked entries are
+/// skipped on purpose and not reported, as are the root `""` entry and
+/// workspace sources, which live outside node_modules.
+fn parse_npm_lockfile_checked(json: &str) -> Result<(Vec<ResolvedPackage>, Vec<LockfileProblem>), String> {
+    let doc = JsonValue::parse(json).map_err(|e| format!("Malformed lockfile: {}", e))?;
+    let entries = doc
+        .get("packages")
+        .and_then(JsonValue::as_object)
+        .ok_or_else(|| "Missing 'packages' field in lockfile".to_string())?;
+
+    let mut packages = Vec::new();
+    let mut problems = Vec::new();
+    for (rel_path, entry) in entries {
+        if !rel_path.starts_with("node_modules/") {
+            continue;
+        }
+        match parse_package_entry(rel_path, entry) {
+            Ok(pkg) => packages.push(pkg),
+            Err(_) if entry_flag(entry, "inBundle") || entry_flag(entry, "link") => {}
+            Err(reason) => problems.push(LockfileProblem { rel_path: rel_path.clone(), reason }),
         }
     }
-    "unknown".to_string()
+    Ok((packages, problems))
 }
 
-#[derive(Clone)]
-pub struct FetchResult {
-    pub packages_fetched: u64,
-    pub packages_cached: u64,
-    pub bytes_downloaded: u64,
+/// A boolean field of a lockfile entry; false when absent or not a boolean.
+fn entry_flag(entry: &JsonValue, field: &str) -> bool {
+    entry.get(field).and_then(JsonValue::as_bool).unwrap_or(false)
 }
 
-/// Content-addressed store layout
-pub struct CasLayout {
-    pub tarballs_dir: PathBuf,
-    pub unpacked_dir: PathBuf,
-    pub tmp_dir: PathBuf,
+/// A non-empty string field of a lockfile entry.
+fn entry_string(entry: &JsonValue, field: &str) -> Option<String> {
+    entry.get(field).and_then(JsonValue::as_str).filter(|s| !s.is_empty()).map(str::to_string)
 }
 
-impl CasLayout {
-    pub fn new(cache_dir: &Path) -> Self {
-        Self {
-            tarballs_dir: cache_dir.join("store").join("tarballs"),
-            unpacked_dir: cache_dir.join("store").join("unpacked"),
-            tmp_dir: cache_dir.join("tmp"),
+// --- Lockfiles without an install layout (yarn.lock, pnpm-lock.yaml) ---
+
+/// A package pinned by a lockfile that, unlike package-lock.json, does not
+/// record where in node_modules it is installed.
+#[derive(Debug, Default)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    resolved: String,
+    integrity: String,
+    /// Indexes of the packages this one depends on
+    dependencies: Vec<usize>,
+}
+
+/// Lay `packages` out the way Node will look them up: each of `roots` (what
+/// the project's package.json asks for) goes to `node_modules/<name>`, a
+/// dependency goes to the top level when that slot is free, and a dependent
+/// that needs a different version than the one Node would find gets its own
+/// copy nested under it. Packages nothing leads to are hoisted where there is
+/// room. Sorted by path.
+fn hoist_locked_packages(packages: &[LockedPackage], roots: &[usize]) -> Result<Vec<ResolvedPackage>, String> {
+    let mut placed: BTreeMap<String, usize> = BTreeMap::new();
+    let mut queue: VecDeque<String> = VecDeque::new();
+    for &root in roots {
+        let rel_path = format!("node_modules/{}", packages[root].name);
+        if !placed.contains_key(&rel_path) {
+            placed.insert(rel_path.clone(), root);
+            queue.push_back(rel_path);
         }
     }
+    let mut next_orphan = 0;
+    loop {
+        while let Some(parent) = queue.pop_front() {
+            for &dep in &packages[placed[&parent]].dependencies {
+                let Some(rel_path) = hoist_placement(&placed, &parent, &packages[dep].name, dep)? else { continue };
+                placed.insert(rel_path.clone(), dep);
+                queue.push_back(rel_path);
+            }
+        }
+        let unplaced = (next_orphan..packages.len()).find(|i| {
+            let top = format!("node_modules/{}", packages[*i].name);
+            !placed.values().any(|p| p == i) && !placed.contains_key(&top)
+        });
+        let Some(orphan) = unplaced else { break };
+        next_orphan = orphan + 1;
+        let rel_path = format!("node_modules/{}", packages[orphan].name);
+        placed.insert(rel_path.clone(), orphan);
+        queue.push_back(rel_path);
+    }
+
+    Ok(placed
+        .into_iter()
+        .map(|(rel_path, index)| {
+            let pkg = &packages[index];
+            ResolvedPackage {
+                name: pkg.name.clone(),
+                version: pkg.version.clone(),
+                rel_path,
+                resolved_url: pkg.resolved.clone(),
+                integrity: pkg.integrity.clone(),
+                dependencies: Vec::new(),
+                bins: Vec::new(),
+                flags: DepFlags::default(),
+            }
+        })
+        .collect())
 }
 
-/// Parse integrity string (e.g., "sha512-base64...") into (algorithm, hex_string)
-pub fn cas_key_from_integrity(integrity: &str) -> Option<(String, String)> {
-    let parts: Vec<&str> = integrity.splitn(2, '-').collect();
-    if parts.len() != 2 {
-        return None;
+/// Where `parent`'s dependency `name` (package `index`) must go, or None when
+/// Node would already find that package from `parent`. Like Node, the lookup
+/// walks up from `parent` and stops at the nearest `node_modules/<name>`.
+fn hoist_placement(placed: &BTreeMap<String, usize>, parent: &str, name: &str, index: usize) -> Result<Option<String>, String> {
+    let segments: Vec<&str> = parent.trim_start_matches("node_modules/").split("/node_modules/").collect();
+    if segments.len() > 64 {
+        return Err(format!("{} would be nested too deeply under {}", name, parent));
+    }
+    for depth in (0..=segments.len()).rev() {
+        let mut candidate = String::from("node_modules/");
+        for segment in &segments[..depth] {
+            candidate.push_str(segment);
+            candidate.push_str("/node_modules/");
+        }
+        candidate.push_str(name);
+        if let Some(&found) = placed.get(&candidate) {
+            return Ok(if found == index { None } else { Some(format!("{}/node_modules/{}", parent, name)) });
+        }
+    }
+    Ok(Some(format!("node_modules/{}", name)))
+}
+
+/// `name -> spec` for every dependency the package.json beside `lockfile_path`
+/// declares, dev and optional included.
+fn project_root_deps(lockfile_path: &Path) -> Vec<(String, String)> {
+    let manifest = lockfile_path.parent().and_then(|dir| fs::read_to_string(dir.join("package.json")).ok()).unwrap_or_default();
+    ["dependencies", "devDependencies", "optionalDependencies"]
+        .iter()
+        .flat_map(|section| dep_section(&manifest, section))
+        .collect()
+}
+
+// --- yarn.lock (Yarn classic, v1) ---
+
+/// One block of a yarn.lock: every `name@range` key that resolved to it and
+/// the fields install needs.
+#[derive(Debug, Default)]
+struct YarnBlock {
+    name: String,
+    version: String,
+    resolved: String,
+    integrity: String,
+    /// `dependencies` and `optionalDependencies`, as `(name, range)`
+    dependencies: Vec<(String, String)>,
+}
+
+/// Resolve a Yarn classic lockfile. yarn.lock records which version each
+/// `name@range` resolved to but not where it is installed, so packages are
+/// laid out with [`hoist_locked_packages`], starting from the ranges the
+/// project's package.json asks for. Git dependencies cannot be installed from
+/// a tarball and fail the resolve with an error naming them.
+pub fn resolve_from_yarn_lock(path: &Path) -> Result<ResolveResult, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let (blocks, by_key) = parse_yarn_lock(&content)?;
+
+    let git: Vec<String> = blocks
+        .iter()
+        .filter(|b| is_git_resolution(&b.resolved))
+        .map(|b| format!("{} ({})", b.name, b.resolved))
+        .collect();
+    if !git.is_empty() {
+        return Err(format!("yarn.lock has git dependencies, which are not supported: {}", git.join(", ")));
+    }
+
+    // An optional dependency yarn skipped on this platform has no block
+    let lookup = |name: &str, range: &str| by_key.get(&format!("{}@{}", name, range)).copied();
+    let packages: Vec<LockedPackage> = blocks
+        .into_iter()
+        .map(|b| LockedPackage {
+            dependencies: b.dependencies.iter().filter_map(|(name, range)| lookup(name, range)).collect(),
+            name: b.name,
+            version: b.version,
+            resolved: b.resolved,
+            integrity: b.integrity,
+        })
+        .collect();
+    let roots: Vec<usize> = project_root_deps(path).iter().filter_map(|(name, range)| lookup(name, range)).collect();
+    Ok(ResolveResult { packages: hoist_locked_packages(&packages, &roots)?, lockfile_version: 1 })
+}
+
+// --- pnpm-lock.yaml ---
+
+/// Resolve a pnpm lockfile (v5 to v9). Packages come from its `packages`
+/// map, keyed `/name@version` (v6), `name@version` (v9) or `/name/version`
+/// (v5); the peer-dependency suffix of a key, `(react@18.0.0)` or
+/// `_react@18.0.0`, is dropped, so a package pnpm installs once per peer set
+/// is installed once. Entries without a `resolution.tarball` are fetched from
+/// the registry npmrc configures for them. They are laid out with
+/// [`hoist_locked_packages`], starting from the root importer's dependencies.
+pub fn resolve_from_pnpm_lock(path: &Path) -> Result<ResolveResult, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let lock = yaml::parse(&content).map_err(|e| format!("Malformed pnpm-lock.yaml: {}", e))?;
+    let lockfile_version = lock
+        .get("lockfileVersion")
+        .and_then(JsonValue::as_str)
+        .and_then(|v| v.split('.').next()?.parse::<u64>().ok())
+        .ok_or("Malformed pnpm-lock.yaml: missing lockfileVersion")?;
+    let npmrc = parse_npmrc(path.parent().unwrap_or(Path::new(".")));
+
+    let mut packages: Vec<LockedPackage> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut unsupported: Vec<String> = Vec::new();
+    let entries = lock.get("packages").and_then(JsonValue::as_object).into_iter().flatten();
+    for (key, entry) in entries {
+        let Some((name, version)) = pnpm_key(key) else {
+            return Err(format!("Malformed pnpm-lock.yaml: cannot read package key {}", key));
+        };
+        if index.contains_key(&format!("{}@{}", name, version)) {
+            continue;
+        }
+        let resolution = entry.get("resolution");
+        let field = |name: &str| resolution.and_then(|r| r.get(name)).and_then(JsonValue::as_str).unwrap_or_default().to_string();
+        let integrity = field("integrity");
+        let resolved = match field("tarball") {
+            tarball if !tarball.is_empty() => tarball,
+            // git, directory and other resolutions carry no integrity
+            _ if integrity.is_empty() => {
+                unsupported.push(format!("{}@{}", name, version));
+                continue;
+            }
+            _ => {
+                let (registry, _) = registry_for_package(&npmrc, &name);
+                let basename = name.rsplit('/').next().unwrap_or(&name);
+                format!("{}/{}/-/{}-{}.tgz", registry.trim_end_matches('/'), name, basename, version)
+            }
+        };
+        index.insert(format!("{}@{}", name, version), packages.len());
+        packages.push(LockedPackage { name, version, resolved, integrity, dependencies: Vec::new() });
+    }
+    if !unsupported.is_empty() {
+        return Err(format!("pnpm-lock.yaml has dependencies that are not registry tarballs, which are not supported: {}", unsupported.join(", ")));
+    }
+
+    // Dependencies are on the package entries, or on `snapshots` from v9
+    let lookup = |name: &str, reference: &JsonValue| {
+        let reference = reference.get("version").unwrap_or(reference).as_str()?;
+        let (name, version) = pnpm_dependency(name, reference)?;
+        index.get(&format!("{}@{}", name, version)).copied()
+    };
+    let mut edges: Vec<(usize, usize)> = Vec::new();
+    for section in ["packages", "snapshots"] {
+        for (key, entry) in lock.get(section).and_then(JsonValue::as_object).into_iter().flatten() {
+            let Some(from) = pnpm_key(key).and_then(|(name, version)| index.get(&format!("{}@{}", name, version)).copied()) else { continue };
+            for deps in ["dependencies", "optionalDependencies"] {
+                for (name, reference) in entry.get(deps).and_then(JsonValue::as_object).into_iter().flatten() {
+                    edges.extend(lookup(name, reference).map(|to| (from, to)));
+                }
+            }
+        }
+    }
+    for (from, to) in edges {
+        if !packages[from].dependencies.contains(&to) {
+            packages[from].dependencies.push(to);
+        }
+    }
+
+    // The project's own dependencies: top level before v9, under importers["."] since
+    let importer = lock.get("importers").and_then(|i| i.get(".")).unwrap_or(&lock);
+    let roots: Vec<usize> = ["dependencies", "devDependencies", "optionalDependencies"]
+        .iter()
+        .filter_map(|section| importer.get(section).and_then(JsonValue::as_object))
+        .flatten()
+        .filter_map(|(name, reference)| lookup(name, reference))
+        .collect();
+    Ok(ResolveResult { packages: hoist_locked_packages(&packages, &roots)?, lockfile_version })
+}
+
+/// `(name, version)` of a pnpm `packages` key, without its peer suffix.
+fn pnpm_key(key: &str) -> Option<(String, String)> {
+    let key = key.strip_prefix('/').unwrap_or(key);
+    let key = key.split('(').next()?;
+    // v5: /name/version_peer@1.0.0, whose peer suffix has an '@' of its own
+    if let Some((name, version)) = key.rsplit_once('/').filter(|(_, v)| v.starts_with(|c: char| c.is_ascii_digit())) {
+        return Some((name.to_string(), version.split('_').next()?.to_string()));
+    }
+    let at = key.get(1..)?.rfind('@')?;
+    Some((key[..at + 1].to_string(), key[at + 2..].to_string()))
+}
+
+/// The package a dependency `name: reference` points at. The reference is a
+/// version, possibly with a peer suffix, or else a whole key, as for an
+/// aliased package. `link:` references to workspace folders match no key.
+fn pnpm_dependency(name: &str, reference: &str) -> Option<(String, String)> {
+    if !reference.starts_with(|c: char| c.is_ascii_digit()) {
+        return pnpm_key(reference);
+    }
+    let version = reference.split(['(', '_']).next()?;
+    Some((name.to_string(), version.to_string()))
+}
+
+fn is_git_resolution(resolved: &str) -> bool {
+    resolved.starts_with("git+") || resolved.starts_with("git://") || resolved.starts_with("github:")
+}
+
+/// The blocks of a yarn.lock in file order, and every `name@range` key mapped
+/// to its block.
+fn parse_yarn_lock(content: &str) -> Result<(Vec<YarnBlock>, HashMap<String, usize>), String> {
+    if content.lines().any(|line| line.starts_with("__metadata:")) {
+        return Err("yarn.lock is in the Yarn 2+ format; only Yarn classic (v1) lockfiles are supported".to_string());
+    }
+    let mut blocks: Vec<YarnBlock> = Vec::new();
+    let mut by_key: HashMap<String, usize> = HashMap::new();
+    let mut in_deps = false;
+    for (number, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - line.trim_start().len();
+        if indent == 0 {
+            // `"foo@^1.0.0", foo@~1.2.0:` starts a block
+            let keys = trimmed
+                .strip_suffix(':')
+                .ok_or_else(|| format!("yarn.lock line {}: expected a package key", number + 1))?;
+            let keys: Vec<String> = keys.split(", ").map(|k| yarn_unquote(k.trim()).to_string()).collect();
+            let name = keys.first().and_then(|k| yarn_key_name(k)).unwrap_or_default().to_string();
+            for key in keys {
+                by_key.insert(key, blocks.len());
+            }
+            blocks.push(YarnBlock { name, ..Default::default() });
+            in_deps = false;
+            continue;
+        }
+        let Some(block) = blocks.last_mut() else {
+            return Err(format!("yarn.lock line {}: field outside of a package", number + 1));
+        };
+        if indent <= 2 {
+            in_deps = trimmed == "dependencies:" || trimmed == "optionalDependencies:";
+            if trimmed.ends_with(':') {
+                continue;
+            }
+            let (field, value) = yarn_split(trimmed);
+            match field.as_str() {
+                "version" => block.version = value,
+                "resolved" => {
+                    // The `#<sha1>` fragment is the tarball hash of lockfiles older than yarn 1.10
+                    let (url, hash) = value.split_once('#').unwrap_or((&value, ""));
+                    if block.integrity.is_empty() && hash.len() == 40 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        block.integrity = sha1_hex_integrity(hash);
+                    }
+                    block.resolved = if is_git_resolution(&value) { value.clone() } else { url.to_string() };
+                }
+                "integrity" => block.integrity = value,
+                _ => {}
+            }
+        } else if in_deps {
+            block.dependencies.push(yarn_split(trimmed));
+        }
+    }
+    Ok((blocks, by_key))
+}
+
+/// `name` of a `name@range` key; the leading `@` of a scope is part of the name.
+fn yarn_key_name(key: &str) -> Option<&str> {
+    let at = key.get(1..)?.find('@')? + 1;
+    Some(&key[..at])
+}
+
+fn yarn_unquote(value: &str) -> &str {
+    value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value)
+}
+
+/// `field value` or `"quoted field" "quoted value"`, unquoted.
+fn yarn_split(line: &str) -> (String, String) {
+    let (field, rest) = match line.strip_prefix('"').and_then(|l| l.split_once('"')) {
+        Some((field, rest)) => (field, rest),
+        None => line.split_once(' ').unwrap_or((line, "")),
+    };
+    (field.to_string(), yarn_unquote(rest.trim()).to_string())
+}
+
+fn sha1_hex_integrity(hex: &str) -> String {
+    let bytes: Vec<u8> = (0..hex.len() / 2).filter_map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()).collect();
+    format!("sha1-{}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, bytes))
+}
+
+/// A lockfile entry that install would fail on, or silently skip.
+#[derive(Debug, Clone)]
+pub struct LockfileProblem {
+    pub rel_path: String,
+    pub reason: String,
+}
+
+/// Check that every package has an integrity `cas_key_from_integrity` can
+/// parse, with a digest of the right length, and an http(s) resolved URL.
+pub fn validate_resolved_packages(packages: &[ResolvedPackage]) -> Vec<LockfileProblem> {
+    let mut problems = Vec::new();
+    for pkg in packages {
+        let problem = |reason: String| LockfileProblem { rel_path: pkg.rel_path.clone(), reason };
+        match cas_key_from_integrity(&pkg.integrity) {
+            None => problems.push(problem(format!("Invalid integrity format: {}", pkg.integrity))),
+            Some((algo, hex)) => {
+                let expected = match algo.as_str() {
+                    "sha1" => Some(40),
+                    "sha256" => Some(64),
+                    "sha384" => Some(96),
+                    "sha512" => Some(128),
+                    _ => None,
+                };
+                match expected {
+                    None => problems.push(problem(format!("Unsupported integrity algorithm: {}", algo))),
+                    Some(len) if hex.len() != len => problems.push(problem(format!(
+                        "Integrity digest is {} bytes, expected {} for {}",
+                        hex.len() / 2,
+                        len / 2,
+                        algo
+                    ))),
+                    Some(_) => {}
+                }
+            }
+        }
+        if !(pkg.resolved_url.starts_with("https://") || pkg.resolved_url.starts_with("http://")) {
+            problems.push(problem(format!("Unsupported resolved URL: {}", pkg.resolved_url)));
+        }
+    }
+    problems
+}
+
+/// Resolve `lockfile_path` and report every entry install would trip over:
+/// entries missing a version, resolved URL or integrity, and packages whose
+/// integrity or URL is malformed. Problems come back sorted by path.
+pub fn check_lockfile(lockfile_path: &Path) -> Result<(ResolveResult, Vec<LockfileProblem>), String> {
+    let content = fs::read_to_string(lockfile_path).map_err(|e| e.to_string())?;
+    let (packages, mut problems) = parse_npm_lockfile_checked(&content)?;
+    problems.extend(validate_resolved_packages(&packages));
+    problems.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    Ok((ResolveResult { packages, lockfile_version: 3 }, problems))
+}
+
+/// One `duplicate-lockfile-key` warning per lockfile entry that repeats a key
+/// with a different value. Such entries come from bad merges or hand edits;
+/// the last value is the one used (see [`parse_package_entry`]). Repeats with
+/// identical values are harmless and not reported. Empty if the lockfile
+/// cannot be read.
+pub fn lockfile_duplicate_keys(lockfile_path: &Path) -> Vec<Warning> {
+    let Some(packages) = fs::read_to_string(lockfile_path).ok().and_then(|lock| lockfile_packages(&lock)) else {
+        return Vec::new();
+    };
+    let mut warnings = Vec::new();
+    for (rel_path, entry) in packages {
+        let mut seen: HashMap<String, String> = HashMap::new();
+        let mut conflicting: BTreeSet<String> = BTreeSet::new();
+        for (key, raw) in json_object_entries(&entry) {
+            if seen.get(&key).is_some_and(|prev| *prev != raw) {
+                conflicting.insert(key.clone());
+            }
+            seen.insert(key, raw);
+        }
+        for key in conflicting {
+            warnings.push(Warning::new(
+                "duplicate-lockfile-key",
+                format!("\"{}\" is set more than once with different values; the last one is used", key),
+                Some(rel_path.clone()),
+            ));
+        }
+    }
+    warnings
+}
+
+/// `name -> spec` for one dependency section of a package.json-shaped object.
+fn dep_section(obj: &str, section: &str) -> BTreeMap<String, String> {
+    json_object_entries(obj)
+        .into_iter()
+        .find(|(key, _)| key == section)
+        .map(|(_, raw)| {
+            json_object_entries(&raw)
+                .into_iter()
+                .filter_map(|(name, spec)| Some((name, json_string_value(&spec)?)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The `packages` section of an npm v2/v3 lockfile as `(path, entry)` pairs.
+fn lockfile_packages(lock: &str) -> Option<Vec<(String, String)>> {
+    json_object_entries(lock)
+        .into_iter()
+        .find(|(key, _)| key == "packages")
+        .map(|(_, raw)| json_object_entries(&raw))
+}
+
+/// Where package.json and the lockfile disagree, the way `npm ci` checks it:
+/// the lockfile root must declare the same dependency specs, and every direct
+/// dependency must be locked at a version its range accepts. Empty when in sync.
+pub fn lockfile_sync_problems(project_root: &Path, lockfile_path: &Path) -> Result<Vec<String>, String> {
+    let manifest = fs::read_to_string(project_root.join("package.json"))
+        .map_err(|e| format!("Failed to read package.json: {}", e))?;
+    let lock = fs::read_to_string(lockfile_path)
+        .map_err(|e| format!("Failed to read {}: {}", lockfile_path.display(), e))?;
+    let packages = lockfile_packages(&lock)
+        .ok_or_else(|| format!("{} has no packages section; regenerate it with npm 7 or later", lockfile_path.display()))?;
+    let root = packages.iter().find(|(key, _)| key.is_empty()).map(|(_, raw)| raw.as_str()).unwrap_or("{}");
+
+    let mut problems = Vec::new();
+    for section in ["dependencies", "devDependencies", "optionalDependencies", "peerDependencies"] {
+        let wanted = dep_section(&manifest, section);
+        let locked = dep_section(root, section);
+        for (name, spec) in &wanted {
+            match locked.get(name) {
+                None => problems.push(format!("{}.{}: {} is missing from the lockfile", section, name, spec)),
+                Some(l) if l != spec => {
+                    problems.push(format!("{}.{}: package.json wants {}, lockfile has {}", section, name, spec, l))
+                }
+                Some(_) => {}
+            }
+        }
+        for name in locked.keys().filter(|name| !wanted.contains_key(*name)) {
+            problems.push(format!("{}.{}: in the lockfile but not in package.json", section, name));
+        }
+        if section == "peerDependencies" {
+            continue;
+        }
+        for (name, spec) in &wanted {
+            let rel_path = format!("node_modules/{}", name);
+            let entry = packages.iter().find(|(key, _)| *key == rel_path).map(|(_, raw)| raw.as_str());
+            let Some(entry) = entry else {
+                if section != "optionalDependencies" {
+                    problems.push(format!("{}: {} has no entry in the lockfile", name, rel_path));
+                }
+                continue;
+            };
+            // Only plain semver ranges are checked; tags, aliases, git and file specs are not
+            let plain_range = spec.chars().all(|c| c.is_ascii_digit() || " .^~<>=|*xX-".contains(c));
+            let version = json_object_entries(entry)
+                .into_iter()
+                .find(|(key, _)| key == "version")
+                .and_then(|(_, raw)| json_string_value(&raw));
+            if let (true, Some(version)) = (plain_range, version) {
+                if parse_semver(&version).is_some_and(|v| !check_semver_range(&v, spec)) {
+                    problems.push(format!("{}: locked version {} does not satisfy {}", name, version, spec));
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// When a key appears more than once in the entry the last value wins, as it
+/// does for `JSON.parse` and so for npm; [`lockfile_duplicate_keys`] reports
+/// the entries where that mattered.
+fn parse_package_entry(rel_path: &str, entry: &JsonValue) -> Result<ResolvedPackage, String> {
+    // Bundled deps ship inside their parent's tarball
+    if entry_flag(entry, "inBundle") {
+        return Err(format!("{} is bundled with its parent", rel_path));
+    }
+    let name = entry_string(entry, "name")
+        .unwrap_or_else(|| package_name_from_path(rel_path));
+    let version = entry_string(entry, "version")
+        .ok_or_else(|| format!("Missing version for {}", rel_path))?;
+    let resolved = entry_string(entry, "resolved")
+        .ok_or_else(|| format!("Missing resolved URL for {}", rel_path))?;
+    let integrity = entry_string(entry, "integrity")
+        .ok_or_else(|| format!("Missing integrity for {}", rel_path))?;
+
+    Ok(ResolvedPackage {
+        name,
+        version,
+        rel_path: rel_path.to_string(),
+        resolved_url: resolved,
+        integrity,
+        dependencies: Vec::new(),
+        bins: Vec::new(),
+        flags: DepFlags::default(),
+    })
+}
+
+fn extract_json_field(json: &str, field_name: &str) -> Option<String> {
+    let needle = format!("\"{}\"", field_name);
+    let start = json.find(&needle)?;
+    let after = &json[start + needle.len()..];
+    let colon = after.find(':')?;
+    let mut rest = after[colon + 1..].trim_start();
+
+    if !rest.starts_with('"') {
+        return None;
+    }
+
+    rest = &rest[1..];
+    let mut result = String::new();
+    let mut chars = rest.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => {
+                if let Some(esc) = chars.next() {
+                    result.push(match esc {
+                        '"' => '"',
+                        '\\' => '\\',
+                        'n' => '\n',
+                        'r' => '\r',
+                        't' => '\t',
+                        '/' => '/',
+                        other => other,
+                    });
+                }
+            }
+            other => result.push(other),
+        }
+    }
+
+    if result.is_empty() {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+/// The package name a lockfile key installs: what follows its last
+/// `node_modules/`, so nested entries name the package and not its parent.
+fn package_name_from_path(rel_path: &str) -> String {
+    let parts: Vec<&str> = rel_path.split('/').collect();
+    if let Some(idx) = parts.iter().rposition(|&p| p == "node_modules") {
+        if idx + 1 < parts.len() {
+            let first = parts[idx + 1];
+            if first.starts_with('@') && idx + 2 < parts.len() {
+                return format!("{}/{}", first, parts[idx + 2]);
+            }
+            return first.to_string();
+        }
+    }
+    "unknown".to_string()
+}
+
+#[derive(Clone, Default)]
+pub struct FetchResult {
+    pub packages_fetched: u64,
+    pub packages_cached: u64,
+    pub bytes_downloaded: u64,
+}
+
+/// Content-addressed store layout
+pub struct CasLayout {
+    pub tarballs_dir: PathBuf,
+    pub unpacked_dir: PathBuf,
+    pub tmp_dir: PathBuf,
+    /// Parsed lockfiles keyed by the SHA-256 of their bytes
+    pub resolve_dir: PathBuf,
+    /// Sidecar `url<TAB>algo<TAB>hex` lines mapping resolved URLs to tarball keys
+    pub url_index: PathBuf,
+    /// Registry metadata kept by [`MetadataCache`]
+    pub registry_dir: PathBuf,
+}
+
+impl CasLayout {
+    pub fn new(cache_dir: &Path) -> Self {
+        Self {
+            tarballs_dir: cache_dir.join("store").join("tarballs"),
+            unpacked_dir: cache_dir.join("store").join("unpacked"),
+            tmp_dir: cache_dir.join("tmp"),
+            resolve_dir: cache_dir.join("resolve"),
+            url_index: cache_dir.join("store").join("url-index"),
+            registry_dir: cache_dir.join("registry"),
+        }
+    }
+}
+
+/// Parse integrity string (e.g., "sha512-base64...") into (algorithm, hex_string)
+pub fn cas_key_from_integrity(integrity: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = integrity.splitn(2, '-').collect();
+    if parts.len() != 2 {
+        return None;
     }
 
     let algo = parts[0];
@@ -1668,6 +3732,54 @@ pub fn tarball_path(layout: &CasLayout, algo: &str, hex: &str) -> PathBuf {
     layout.tarballs_dir.join(algo).join(aa).join(bb).join(format!("{}.tgz", hex))
 }
 
+/// Resolved tarball URL -> (algorithm, hex) for every tarball fetch has stored.
+pub fn read_url_index(layout: &CasLayout) -> BTreeMap<String, (String, String)> {
+    let content = fs::read_to_string(&layout.url_index).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, '\t');
+            let url = parts.next()?;
+            let algo = parts.next()?;
+            let hex = parts.next()?;
+            Some((url.to_string(), (algo.to_string(), hex.to_string())))
+        })
+        .collect()
+}
+
+/// The cached tarball key for `url`, if fetch has stored it.
+pub fn lookup_tarball_by_url(cache_dir: &Path, url: &str) -> Option<(String, String)> {
+    read_url_index(&CasLayout::new(cache_dir)).remove(url)
+}
+
+/// Merge `entries` into the URL index; rewritten (atomically) only when it changes.
+fn update_url_index(layout: &CasLayout, entries: Vec<(String, String, String)>) -> Result<(), String> {
+    let mut index = read_url_index(layout);
+    let mut changed = false;
+    for (url, algo, hex) in entries {
+        if url.is_empty() || url.contains(['\t', '\n']) {
+            continue;
+        }
+        let key = (algo, hex);
+        if index.get(&url) != Some(&key) {
+            index.insert(url, key);
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+    let mut out = String::new();
+    for (url, (algo, hex)) in &index {
+        out.push_str(&format!("{}\t{}\t{}\n", url, algo, hex));
+    }
+    let tmp = TmpFileGuard::beside(&layout.tmp_dir.join("url-index"));
+    fs::write(tmp.path(), out).map_err(|e| format!("Failed to write url index: {}", e))?;
+    rename_across_devices(tmp.path(), &layout.url_index).map_err(|e| format!("Failed to write url index: {}", e))?;
+    tmp.keep();
+    Ok(())
+}
+
 /// Get unpacked path in CAS layout: unpacked_dir/algo/aa/bb/hex
 pub fn unpacked_path(layout: &CasLayout, algo: &str, hex: &str) -> PathBuf {
     let aa = &hex[0..2.min(hex.len())];
@@ -1675,29 +3787,210 @@ pub fn unpacked_path(layout: &CasLayout, algo: &str, hex: &str) -> PathBuf {
     layout.unpacked_dir.join(algo).join(aa).join(bb).join(hex)
 }
 
+/// What `install` would do for `packages` without touching the cache or project:
+/// a `fetch` for each tarball not yet in the store, a `materialize` per package
+/// directory, and a `replace` when an existing `node_modules` is swapped out.
+pub fn plan_install(packages: &[ResolvedPackage], cache_dir: &Path, project_root: &Path, staged: bool, plan: &mut FsPlan) {
+    let layout = CasLayout::new(cache_dir);
+    let node_modules = project_root.join("node_modules");
+    if staged && node_modules.exists() {
+        plan.record("replace", &node_modules);
+    } else if !node_modules.exists() {
+        plan.record("mkdir", &node_modules);
+    }
+    for pkg in packages {
+        if let Some((algo, hex)) = cas_key_from_integrity(&pkg.integrity) {
+            let tarball = tarball_path(&layout, &algo, &hex);
+            let cached = tarball.with_extension("tgz.verified").exists()
+                && unpacked_path(&layout, &algo, &hex).join(".better_extracted").exists();
+            if !cached {
+                plan.record("fetch", &tarball);
+            }
+        }
+        let rel = pkg.rel_path.strip_prefix("node_modules/").unwrap_or(&pkg.rel_path);
+        plan.record("materialize", &node_modules.join(rel));
+    }
+}
+
 /// Fetch tarballs for resolved packages with parallel downloads and CAS storage
+/// Progress of one package through [`fetch_packages_with_progress`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FetchEvent {
+    /// Work on the package began.
+    Started { name: String, version: String },
+    /// Its tarball was downloaded.
+    Downloaded { name: String, bytes: u64 },
+    /// Its unpacked files were already in the store. Terminal.
+    Cached { name: String },
+    /// The downloaded tarball matched its sha512 integrity.
+    Verified { name: String },
+    /// Its tarball was unpacked into the store. Terminal.
+    Extracted { name: String },
+}
+
+impl FetchEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FetchEvent::Started { .. } => "started",
+            FetchEvent::Downloaded { .. } => "downloaded",
+            FetchEvent::Cached { .. } => "cached",
+            FetchEvent::Verified { .. } => "verified",
+            FetchEvent::Extracted { .. } => "extracted",
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            FetchEvent::Started { name, .. }
+            | FetchEvent::Downloaded { name, .. }
+            | FetchEvent::Cached { name }
+            | FetchEvent::Verified { name }
+            | FetchEvent::Extracted { name } => name,
+        }
+    }
+
+    /// Whether this is the package's last event: every package that fetches
+    /// successfully ends with exactly one `Cached` or `Extracted`.
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, FetchEvent::Cached { .. } | FetchEvent::Extracted { .. })
+    }
+}
+
+/// How [`fetch_packages_with_progress`] retries a failed download, and
+/// whether it may download at all.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Attempts after the first. Only connection errors and HTTP 429, 500,
+    /// 502, 503 and 504 are retried; a 404 or an integrity mismatch never is.
+    pub retries: u32,
+    /// Backoff before the first retry (ms), doubled for each one after.
+    pub retry_min_timeout_ms: u64,
+    /// Cap on any one backoff (ms).
+    pub retry_max_timeout_ms: u64,
+    /// Never touch the network: a package without a verified tarball in the
+    /// cache fails with `offline: <name>@<version> not cached`. Cache hits
+    /// still count towards `packages_cached`; `packages_fetched` stays 0.
+    pub offline: bool,
+    /// Downloads in flight at once; 0 for no limit. Verifying and extracting
+    /// are not limited.
+    pub max_concurrent_downloads: usize,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(4, |n| n.get());
+        Self {
+            retries: 3,
+            retry_min_timeout_ms: 200,
+            retry_max_timeout_ms: 10_000,
+            offline: false,
+            max_concurrent_downloads: cpus.saturating_mul(2).min(16),
+        }
+    }
+}
+
+impl FetchOptions {
+    /// The defaults, overridden by npmrc's `fetch-retries`,
+    /// `fetch-retry-mintimeout`, `fetch-retry-maxtimeout` and `maxsockets`.
+    pub fn from_npmrc(npmrc: Option<&NpmrcConfig>) -> Self {
+        let defaults = Self::default();
+        let Some(cfg) = npmrc else { return defaults };
+        Self {
+            retries: cfg.fetch_retries.unwrap_or(defaults.retries),
+            retry_min_timeout_ms: cfg.fetch_retry_mintimeout_ms.unwrap_or(defaults.retry_min_timeout_ms),
+            retry_max_timeout_ms: cfg.fetch_retry_maxtimeout_ms.unwrap_or(defaults.retry_max_timeout_ms),
+            max_concurrent_downloads: cfg.maxsockets.unwrap_or(defaults.max_concurrent_downloads),
+            ..defaults
+        }
+    }
+
+    /// How long to wait before retry number `retry` (0-based).
+    pub fn backoff(&self, retry: u32) -> std::time::Duration {
+        let ms = self.retry_min_timeout_ms.saturating_mul(1u64 << retry.min(32));
+        std::time::Duration::from_millis(ms.min(self.retry_max_timeout_ms))
+    }
+}
+
+/// Counts downloads in flight so [`FetchOptions::max_concurrent_downloads`]
+/// can hold rayon workers back until one finishes.
+struct DownloadSlots {
+    limit: usize,
+    in_use: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl DownloadSlots {
+    fn new(limit: usize) -> Self {
+        Self { limit, in_use: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    /// Wait for a free slot; it is given back when the guard drops.
+    fn acquire(&self) -> DownloadSlot<'_> {
+        if self.limit > 0 {
+            let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+            while *in_use >= self.limit {
+                in_use = self.freed.wait(in_use).unwrap_or_else(|e| e.into_inner());
+            }
+            *in_use += 1;
+        }
+        DownloadSlot(self)
+    }
+}
+
+struct DownloadSlot<'a>(&'a DownloadSlots);
+
+impl Drop for DownloadSlot<'_> {
+    fn drop(&mut self) {
+        if self.0.limit > 0 {
+            *self.0.in_use.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+            self.0.freed.notify_one();
+        }
+    }
+}
+
+/// Fetch with the retry policy npmrc configures (see [`FetchOptions::from_npmrc`]).
 pub fn fetch_packages(
     packages: &[ResolvedPackage],
     cache_dir: &Path,
     npmrc: Option<&NpmrcConfig>,
-) -> Result<FetchResult, String> {
+) -> Result<FetchResult, CoreError> {
+    fetch_packages_with_progress(packages, cache_dir, npmrc, &FetchOptions::from_npmrc(npmrc), |_| {})
+}
+
+/// [`fetch_packages`] with explicit `options`, reporting each package's
+/// progress to `on_event`. Packages are fetched in parallel and `on_event` is
+/// called from rayon worker threads: one package's events arrive in order,
+/// but events of different packages interleave in no particular order. A
+/// package that fails gets no terminal event, and packages not yet started
+/// when another fails may get no events at all.
+pub fn fetch_packages_with_progress(
+    packages: &[ResolvedPackage],
+    cache_dir: &Path,
+    npmrc: Option<&NpmrcConfig>,
+    options: &FetchOptions,
+    on_event: impl Fn(FetchEvent) + Sync,
+) -> Result<FetchResult, CoreError> {
     use rayon::prelude::*;
     use sha2::{Digest, Sha512};
 
-    let layout = CasLayout::new(cache_dir);
+    let layout = CasLayout::new(&long_path(cache_dir));
 
     // Ensure directories exist
     fs::create_dir_all(&layout.tarballs_dir).map_err(|e| format!("Failed to create tarballs dir: {}", e))?;
     fs::create_dir_all(&layout.unpacked_dir).map_err(|e| format!("Failed to create unpacked dir: {}", e))?;
     fs::create_dir_all(&layout.tmp_dir).map_err(|e| format!("Failed to create tmp dir: {}", e))?;
+    sweep_stale_tmp_files(&layout.tmp_dir, std::time::Duration::from_secs(STALE_TMP_MAX_AGE_SECS));
 
     // Shared statistics
     let packages_fetched = AtomicU64::new(0);
     let packages_cached = AtomicU64::new(0);
     let bytes_downloaded = AtomicU64::new(0);
+    let stored: Mutex<Vec<(String, String, String)>> = Mutex::new(Vec::new());
+    let agent = build_http_agent(npmrc);
+    let slots = DownloadSlots::new(options.max_concurrent_downloads);
 
     // Process packages in parallel
-    packages.par_iter().try_for_each(|pkg| -> Result<(), String> {
+    let fetched = packages.par_iter().try_for_each(|pkg| -> Result<(), CoreError> {
         // Parse integrity
         let (algo, hex) = cas_key_from_integrity(&pkg.integrity)
             .ok_or_else(|| format!("Invalid integrity format: {}", pkg.integrity))?;
@@ -1706,133 +3999,279 @@ pub fn fetch_packages(
         let unpacked = unpacked_path(&layout, &algo, &hex);
         let verified_marker = tarball.with_extension("tgz.verified");
         let extracted_marker = unpacked.join(".better_extracted");
+        on_event(FetchEvent::Started { name: pkg.name.clone(), version: pkg.version.clone() });
 
         // Check if already cached and verified
         if verified_marker.exists() && extracted_marker.exists() {
             packages_cached.fetch_add(1, Ordering::Relaxed);
+            on_event(FetchEvent::Cached { name: pkg.name.clone() });
+            if let Ok(mut guard) = stored.lock() {
+                guard.push((pkg.resolved_url.clone(), algo, hex));
+            }
             return Ok(());
         }
 
         // Download if needed
         if !tarball.exists() || !verified_marker.exists() {
+            if options.offline {
+                return Err(format!("offline: {}@{} not cached", pkg.name, pkg.version).into());
+            }
             // Ensure parent directory exists
             if let Some(parent) = tarball.parent() {
                 fs::create_dir_all(parent).map_err(|e| format!("Failed to create tarball parent dir: {}", e))?;
             }
 
             // Download to temporary file
-            let tmp_file = layout.tmp_dir.join(format!("{}.tgz.tmp", hex));
-            let agent = ureq::AgentBuilder::new().build();
+            let tmp_guard = TmpFileGuard::beside(&layout.tmp_dir.join(format!("{}.tgz", hex)));
+            let tmp_file = tmp_guard.path();
 
             let mut download_url = pkg.resolved_url.clone();
             let mut auth_token: Option<&str> = None;
             if let Some(cfg) = npmrc {
-                let (_reg, tok) = registry_for_package(cfg, &pkg.name);
-                auth_token = tok;
-                if !cfg.default_registry.starts_with("https://registry.npmjs.org")
-                    && download_url.starts_with("https://registry.npmjs.org/")
-                {
-                    download_url = download_url.replacen(
-                        "https://registry.npmjs.org/",
-                        cfg.default_registry.trim_end_matches('/').to_string().as_str(),
-                        1,
-                    );
-                    if !download_url.contains("://") {
-                        download_url = format!("{}/{}", cfg.default_registry.trim_end_matches('/'), &download_url);
+                // Lockfiles written against the public registry fetch from the
+                // configured one, or the package scope's
+                let (registry, _) = registry_for_package(cfg, &pkg.name);
+                if let Some(path) = download_url.strip_prefix("https://registry.npmjs.org/") {
+                    if !registry.starts_with("https://registry.npmjs.org") {
+                        download_url = format!("{}/{}", registry.trim_end_matches('/'), path);
                     }
                 }
+                // Tarballs can live on a different host than the packument.
+                auth_token = find_auth_token(cfg, &download_url);
             }
 
-            let mut request = agent.get(&download_url);
-            if let Some(token) = auth_token {
-                request = request.set("Authorization", &format!("Bearer {}", token));
-            }
-            let response = request
-                .call()
-                .map_err(|e| format!("Failed to download {}: {}", pkg.name, e))?;
+            let mut retry = 0;
+            let bytes_written = loop {
+                // Held for one attempt, not across the backoff
+                let attempt = {
+                    let _slot = slots.acquire();
+                    download_tarball(&agent, &download_url, auth_token, tmp_file)
+                };
+                match attempt {
+                    Ok(bytes) => break bytes,
+                    Err(failure) if failure.retryable && retry < options.retries => {
+                        std::thread::sleep(options.backoff(retry));
+                        retry += 1;
+                    }
+                    Err(failure) => {
+                        let message = format!("Failed to download {}: {}", pkg.name, failure.message);
+                        return Err(CoreError { kind: failure.kind, message });
+                    }
+                }
+            };
 
-            let mut file = fs::File::create(&tmp_file)
-                .map_err(|e| format!("Failed to create tmp file: {}", e))?;
+            bytes_downloaded.fetch_add(bytes_written, Ordering::Relaxed);
+            on_event(FetchEvent::Downloaded { name: pkg.name.clone(), bytes: bytes_written });
 
-            let mut bytes_written = 0u64;
+            // Verify integrity
+            let mut file = fs::File::open(tmp_file)
+                .map_err(|e| format!("Failed to open tmp file for verification: {}", e))?;
+            let mut hasher = Sha512::new();
             let mut buffer = vec![0u8; 8192];
-            let mut reader = response.into_reader();
 
             loop {
-                let n = reader.read(&mut buffer)
-                    .map_err(|e| format!("Failed to read download: {}", e))?;
+                let n = file.read(&mut buffer)
+                    .map_err(|e| format!("Failed to read for hash: {}", e))?;
                 if n == 0 {
                     break;
                 }
-                file.write_all(&buffer[..n])
-                    .map_err(|e| format!("Failed to write to tmp file: {}", e))?;
-                bytes_written += n as u64;
+                hasher.update(&buffer[..n]);
             }
 
-            bytes_downloaded.fetch_add(bytes_written, Ordering::Relaxed);
+            let computed_hex = format!("{:x}", hasher.finalize());
 
-            // Verify integrity
-            let mut file = fs::File::open(&tmp_file)
-                .map_err(|e| format!("Failed to open tmp file for verification: {}", e))?;
-            let mut hasher = Sha512::new();
-            let mut buffer = vec![0u8; 8192];
+            if algo == "sha512" {
+                if computed_hex != hex {
+                    return Err(CoreError::integrity(format!("Integrity mismatch for {}: expected {}, got {}", pkg.name, hex, computed_hex)));
+                }
+                on_event(FetchEvent::Verified { name: pkg.name.clone() });
+            }
+
+            // Move to final location
+            rename_across_devices(tmp_file, &tarball)
+                .map_err(|e| format!("Failed to move tarball to CAS: {}", e))?;
+            tmp_guard.keep();
+
+            // Write verified marker
+            fs::write(&verified_marker, "")
+                .map_err(|e| format!("Failed to write verified marker: {}", e))?;
+
+            packages_fetched.fetch_add(1, Ordering::Relaxed);
+        } else {
+            packages_cached.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Extract if needed
+        if !extracted_marker.exists() {
+            extract_to_unpacked(&tarball, &unpacked)?;
+            on_event(FetchEvent::Extracted { name: pkg.name.clone() });
+        } else {
+            on_event(FetchEvent::Cached { name: pkg.name.clone() });
+        }
+
+        if let Ok(mut guard) = stored.lock() {
+            guard.push((pkg.resolved_url.clone(), algo, hex));
+        }
+        Ok(())
+    });
+
+    // Index whatever made it into the store, even when another package failed
+    update_url_index(&layout, stored.into_inner().unwrap_or_default())?;
+    fetched?;
+
+    Ok(FetchResult {
+        packages_fetched: packages_fetched.load(Ordering::Relaxed),
+        packages_cached: packages_cached.load(Ordering::Relaxed),
+        bytes_downloaded: bytes_downloaded.load(Ordering::Relaxed),
+    })
+}
+
+/// Why a download attempt failed, whether another attempt could succeed, and
+/// whether the network or the local tmp file was to blame.
+struct DownloadFailure {
+    message: String,
+    retryable: bool,
+    kind: ErrorKind,
+}
+
+/// One attempt at downloading `url` into `dest`, replacing whatever an
+/// earlier attempt left there. Returns the bytes written.
+fn download_tarball(agent: &ureq::Agent, url: &str, auth_token: Option<&str>, dest: &Path) -> Result<u64, DownloadFailure> {
+    let mut request = agent.get(url);
+    if let Some(token) = auth_token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    let response = request.call().map_err(|e| DownloadFailure {
+        retryable: match &e {
+            ureq::Error::Status(code, _) => matches!(code, 429 | 500 | 502 | 503 | 504),
+            ureq::Error::Transport(_) => true,
+        },
+        message: redact_url_credentials(&e.to_string()),
+        kind: ErrorKind::Network,
+    })?;
+
+    let local = |message: String| DownloadFailure { message, retryable: false, kind: ErrorKind::Other };
+    let mut file = fs::File::create(dest).map_err(|e| local(format!("Failed to create tmp file: {}", e)))?;
+    let mut bytes_written = 0u64;
+    let mut buffer = vec![0u8; 8192];
+    let mut reader = response.into_reader();
+    loop {
+        // A connection dropped mid-body is as transient as one that never opened
+        let n = reader.read(&mut buffer).map_err(|e| DownloadFailure {
+            message: format!("Failed to read download: {}", e),
+            retryable: true,
+            kind: ErrorKind::Network,
+        })?;
+        if n == 0 {
+            return Ok(bytes_written);
+        }
+        file.write_all(&buffer[..n]).map_err(|e| local(format!("Failed to write to tmp file: {}", e)))?;
+        bytes_written += n as u64;
+    }
+}
 
-            loop {
-                let n = file.read(&mut buffer)
-                    .map_err(|e| format!("Failed to read for hash: {}", e))?;
-                if n == 0 {
-                    break;
-                }
-                hasher.update(&buffer[..n]);
-            }
+/// Unpack a stored tarball into its `unpacked` directory and mark it extracted.
+fn extract_to_unpacked(tarball: &Path, unpacked: &Path) -> Result<(), String> {
+    fs::create_dir_all(unpacked)
+        .map_err(|e| format!("Failed to create unpacked dir: {}", e))?;
 
-            let computed_hex = format!("{:x}", hasher.finalize());
+    let file = fs::File::open(tarball)
+        .map_err(|e| format!("Failed to open tarball for extraction: {}", e))?;
 
-            if algo == "sha512" && computed_hex != hex {
-                return Err(format!("Integrity mismatch for {}: expected {}, got {}", pkg.name, hex, computed_hex));
-            }
+    let gz = flate2::read::GzDecoder::new(file);
+    let mut archive = tar::Archive::new(gz);
 
-            // Move to final location
-            fs::rename(&tmp_file, &tarball)
-                .map_err(|e| format!("Failed to move tarball to CAS: {}", e))?;
+    archive.unpack(unpacked)
+        .map_err(|e| format!("Failed to extract tarball: {}", e))?;
 
-            // Write verified marker
-            fs::write(&verified_marker, "")
-                .map_err(|e| format!("Failed to write verified marker: {}", e))?;
+    fs::write(unpacked.join(".better_extracted"), "")
+        .map_err(|e| format!("Failed to write extracted marker: {}", e))
+}
 
-            packages_fetched.fetch_add(1, Ordering::Relaxed);
-        } else {
-            packages_cached.fetch_add(1, Ordering::Relaxed);
-        }
+// --- Staged install: atomic node_modules swap ---
 
-        // Extract if needed
-        if !extracted_marker.exists() {
-            // Ensure unpacked directory exists
-            fs::create_dir_all(&unpacked)
-                .map_err(|e| format!("Failed to create unpacked dir: {}", e))?;
+fn path_with_suffix(p: &Path, suffix: &str) -> PathBuf {
+    let mut name = p.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
 
-            let file = fs::File::open(&tarball)
-                .map_err(|e| format!("Failed to open tarball for extraction: {}", e))?;
+/// Sibling directory a staged install materializes into (`node_modules.better-tmp`)
+pub fn staging_dir_for(node_modules: &Path) -> PathBuf {
+    path_with_suffix(node_modules, ".better-tmp")
+}
 
-            let gz = flate2::read::GzDecoder::new(file);
-            let mut archive = tar::Archive::new(gz);
+#[derive(Debug, Default)]
+pub struct SwapResult {
+    /// false when the cross-device fallback copied the tree instead of renaming it
+    pub atomic: bool,
+    /// An existing node_modules was replaced (and removed)
+    pub replaced_existing: bool,
+}
 
-            archive.unpack(&unpacked)
-                .map_err(|e| format!("Failed to extract tarball: {}", e))?;
+/// Move a fully staged node_modules into place.
+///
+/// Normally the old tree is renamed aside to `<target>.better-old`, the staged tree
+/// is renamed onto `<target>`, and the old tree is removed. Each rename is atomic, so
+/// readers see the old or the new tree, never a half-populated one. If the second
+/// rename fails, the old tree is renamed back.
+///
+/// Fallback: if the target cannot be renamed, e.g. node_modules is a mount point
+/// (EXDEV/EBUSY), its contents are removed and the staged tree is copied in. This is
+/// not atomic. A crash mid-copy leaves a partial target, but the staged tree is only
+/// removed after the copy completes, so the install can be retried.
+pub fn swap_staged_node_modules(staging: &Path, target: &Path) -> Result<SwapResult, String> {
+    let backup = path_with_suffix(target, ".better-old");
+    remove_path_if_exists(&backup)?;
 
-            // Write extracted marker
-            fs::write(&extracted_marker, "")
-                .map_err(|e| format!("Failed to write extracted marker: {}", e))?;
+    let had_target = fs::symlink_metadata(target).is_ok();
+    if had_target {
+        if let Err(err) = fs::rename(target, &backup) {
+            copy_staged_into(staging, target)
+                .map_err(|e| format!("Failed to swap node_modules ({}): {}", err, e))?;
+            remove_path_if_exists(staging)?;
+            return Ok(SwapResult { atomic: false, replaced_existing: true });
         }
+    }
 
-        Ok(())
-    })?;
+    if let Err(err) = fs::rename(staging, target) {
+        if had_target {
+            let _ = fs::rename(&backup, target);
+        }
+        return Err(format!("Failed to move staged node_modules into place: {}", err));
+    }
 
-    Ok(FetchResult {
-        packages_fetched: packages_fetched.load(Ordering::Relaxed),
-        packages_cached: packages_cached.load(Ordering::Relaxed),
-        bytes_downloaded: bytes_downloaded.load(Ordering::Relaxed),
-    })
+    if had_target {
+        remove_path_if_exists(&backup)?;
+    }
+    Ok(SwapResult { atomic: true, replaced_existing: had_target })
+}
+
+/// Non-atomic fallback: empty `target` in place and copy `staging` into it.
+fn copy_staged_into(staging: &Path, target: &Path) -> Result<(), String> {
+    for ent in stable_list_dir(target).map_err(|e| e.to_string())? {
+        remove_path_if_exists(&ent.path())?;
+    }
+    copy_dir_recursive(staging, target)
+}
+
+/// Recursively copy a directory, recreating symlinks rather than following them.
+fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<(), String> {
+    fs::create_dir_all(dst).map_err(|e| e.to_string())?;
+    for ent in stable_list_dir(src).map_err(|e| e.to_string())? {
+        let from = ent.path();
+        let to = dst.join(ent.file_name());
+        let ft = ent.file_type().map_err(|e| e.to_string())?;
+        if ft.is_symlink() {
+            let link_target = fs::read_link(&from).map_err(|e| e.to_string())?;
+            create_symlink(&link_target, &to, &from).map_err(|e| e.to_string())?;
+        } else if ft.is_dir() {
+            copy_dir_recursive(&from, &to)?;
+        } else {
+            copy_file_with_retry(&from, &to)?;
+        }
+    }
+    Ok(())
 }
 
 // --- File-level CAS (Content Addressable Store) ---
@@ -1853,6 +4292,9 @@ pub struct FileCasMaterializeResult {
     pub linked: u64,
     pub copied: u64,
     pub symlinks: u64,
+    /// Manifest paths whose blobs are absent from the store. When non-empty
+    /// nothing was written and `ok` is false.
+    pub missing_blobs: Vec<String>,
 }
 
 /// Get the store path for a file by its SHA-256 content hash.
@@ -1879,6 +4321,13 @@ fn package_manifest_dir(store_root: &Path, algorithm: &str, pkg_hex: &str) -> Pa
         .join(pkg_hex)
 }
 
+/// Where the file store stages blobs and manifests before renaming them in,
+/// so [`sweep_stale_tmp_files`] finds what a crashed ingest left in one
+/// directory instead of across the sharded tree.
+pub fn file_store_tmp_dir(store_root: &Path) -> PathBuf {
+    store_root.join("tmp")
+}
+
 /// Get the manifest path for a package.
 fn package_manifest_path(store_root: &Path, algorithm: &str, pkg_hex: &str) -> PathBuf {
     package_manifest_dir(store_root, algorithm, pkg_hex).join("manifest.json")
@@ -1906,15 +4355,23 @@ fn hash_file(path: &Path) -> Result<String, String> {
     Ok(format!("{:x}", hasher.finalize()))
 }
 
+/// (rel_path, sha256 hex, size, mode, newly stored)
+type IngestedFile = (String, String, u64, u32, bool);
+
 /// Ingest an unpacked package directory into the file-level CAS.
 /// Hashes each file with SHA-256, stores unique files in the global store,
 /// and writes a package manifest mapping relative paths -> file hashes.
+/// With `verify`, every stored blob is re-hashed after the copy-and-rename (or
+/// when an existing blob is reused); a mismatched blob is removed and reported.
 pub fn ingest_to_file_cas(
     store_root: &Path,
     pkg_algorithm: &str,
     pkg_hex: &str,
     unpacked_dir: &Path,
+    verify: bool,
 ) -> Result<FileCasIngestResult, String> {
+    let store_root = &long_path(store_root);
+    let unpacked_dir = &long_path(unpacked_dir);
     let manifest_path = package_manifest_path(store_root, pkg_algorithm, pkg_hex);
 
     // If manifest already exists, return early with reused flag
@@ -1941,6 +4398,7 @@ pub fn ingest_to_file_cas(
     fn walk_dir(
         dir: &Path,
         rel_prefix: &str,
+        bundled: &[String],
         files: &mut Vec<(PathBuf, String)>,
     ) -> Result<(), String> {
         let entries = fs::read_dir(dir)
@@ -1951,11 +4409,6 @@ pub fn ingest_to_file_cas(
             let file_name = entry.file_name();
             let name = file_name.to_string_lossy();
 
-            // Skip node_modules and .better_extracted
-            if name == "node_modules" || name == ".better_extracted" {
-                continue;
-            }
-
             let full_path = entry.path();
             let rel_path = if rel_prefix.is_empty() {
                 name.to_string()
@@ -1963,11 +4416,16 @@ pub fn ingest_to_file_cas(
                 format!("{}/{}", rel_prefix, name)
             };
 
+            // Skip .better_extracted and node_modules other than bundled deps
+            if name == ".better_extracted" || !bundled_entry_allowed(&rel_path, bundled) {
+                continue;
+            }
+
             let metadata = entry.metadata()
                 .map_err(|e| format!("Failed to read metadata: {}", e))?;
 
             if metadata.is_dir() {
-                walk_dir(&full_path, &rel_path, files)?;
+                walk_dir(&full_path, &rel_path, bundled, files)?;
             } else if metadata.is_file() {
                 files.push((full_path, rel_path));
             }
@@ -1977,14 +4435,17 @@ pub fn ingest_to_file_cas(
         Ok(())
     }
 
-    walk_dir(unpacked_dir, "", &mut files_to_process)?;
+    let bundled = read_bundled_dependencies(unpacked_dir);
+    walk_dir(unpacked_dir, "", &bundled, &mut files_to_process)?;
+    let tmp_dir = file_store_tmp_dir(store_root);
+    fs::create_dir_all(&tmp_dir).map_err(|e| format!("Failed to create store tmp dir: {}", e))?;
 
     // Process files in parallel using rayon
     use rayon::prelude::*;
 
-    let results: Vec<Result<(String, String, u64, u32, bool), String>> = files_to_process
+    let results: Vec<Result<IngestedFile, String>> = files_to_process
         .par_iter()
-        .map(|(full_path, rel_path)| -> Result<(String, String, u64, u32, bool), String> {
+        .map(|(full_path, rel_path)| -> Result<IngestedFile, String> {
             let hex = hash_file(full_path)?;
             let store_path = file_store_path(store_root, &hex);
 
@@ -2002,22 +4463,34 @@ pub fn ingest_to_file_cas(
                 }
 
                 // Atomic write: write to tmp, then rename
-                let tmp_path = format!("{}.tmp-{}", store_path.display(), std::process::id());
-                fs::copy(full_path, &tmp_path)
+                let tmp = TmpFileGuard::beside(&tmp_dir.join(&hex));
+                fs::copy(full_path, tmp.path())
                     .map_err(|e| format!("Failed to copy file to store: {}", e))?;
 
-                match fs::rename(&tmp_path, &store_path) {
-                    Ok(_) => true,
-                    Err(_) => {
-                        // Another process may have created it - that's fine
-                        let _ = fs::remove_file(&tmp_path);
-                        false
+                match fs::rename(tmp.path(), &store_path) {
+                    Ok(_) => {
+                        tmp.keep();
+                        true
                     }
+                    // Another process may have created it - that's fine (guard removes the tmp)
+                    Err(_) => false,
                 }
             } else {
                 false
             };
 
+            // Opt-in: the blob's name is only a claim until its bytes are re-hashed
+            if verify {
+                let stored = hash_file(&store_path)?;
+                if stored != hex {
+                    let _ = fs::remove_file(&store_path);
+                    return Err(format!(
+                        "Store blob for {} failed verification: expected sha256 {}, got {}",
+                        rel_path, hex, stored
+                    ));
+                }
+            }
+
             Ok((rel_path.clone(), hex, size, mode, is_new))
         })
         .collect();
@@ -2049,6 +4522,7 @@ pub fn ingest_to_file_cas(
     fn collect_symlinks(
         dir: &Path,
         rel_prefix: &str,
+        bundled: &[String],
         symlinks: &mut Vec<(String, String)>,
     ) -> Result<(), String> {
         let entries = fs::read_dir(dir)
@@ -2059,10 +4533,6 @@ pub fn ingest_to_file_cas(
             let file_name = entry.file_name();
             let name = file_name.to_string_lossy();
 
-            if name == "node_modules" || name == ".better_extracted" {
-                continue;
-            }
-
             let full_path = entry.path();
             let rel_path = if rel_prefix.is_empty() {
                 name.to_string()
@@ -2070,11 +4540,15 @@ pub fn ingest_to_file_cas(
                 format!("{}/{}", rel_prefix, name)
             };
 
+            if name == ".better_extracted" || !bundled_entry_allowed(&rel_path, bundled) {
+                continue;
+            }
+
             let metadata = entry.metadata()
                 .map_err(|e| format!("Failed to read metadata: {}", e))?;
 
             if metadata.is_dir() {
-                collect_symlinks(&full_path, &rel_path, symlinks)?;
+                collect_symlinks(&full_path, &rel_path, bundled, symlinks)?;
             } else if metadata.file_type().is_symlink() {
                 let target = fs::read_link(&full_path)
                     .map_err(|e| format!("Failed to read symlink: {}", e))?;
@@ -2085,10 +4559,16 @@ pub fn ingest_to_file_cas(
         Ok(())
     }
 
-    collect_symlinks(unpacked_dir, "", &mut symlink_entries)?;
+    collect_symlinks(unpacked_dir, "", &bundled, &mut symlink_entries)?;
 
-    // Build manifest JSON using JsonWriter
-    let mut jw = JsonWriter::new();
+    // Directory read order differs between filesystems; sort so the same package
+    // contents always produce a byte-identical manifest.
+    file_entries.sort_by(|a, b| a.0.cmp(&b.0));
+    symlink_entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // Build manifest JSON using JsonWriter. No timestamp: it would make identical
+    // packages serialize differently (the file's mtime records when it was written).
+    let mut jw = JsonWriter::compact();
     jw.begin_object();
 
     jw.key("version");
@@ -2131,9 +4611,6 @@ pub fn ingest_to_file_cas(
 
     jw.end_object(); // files
 
-    jw.key("createdAt");
-    jw.value_string(&chrono_now());
-
     jw.key("fileCount");
     jw.value_u64(total_files);
 
@@ -2146,12 +4623,13 @@ pub fn ingest_to_file_cas(
     fs::create_dir_all(&manifest_dir)
         .map_err(|e| format!("Failed to create manifest directory: {}", e))?;
 
-    let tmp_manifest = format!("{}.tmp-{}", manifest_path.display(), std::process::id());
-    fs::write(&tmp_manifest, manifest_json)
+    let tmp_manifest = TmpFileGuard::beside(&tmp_dir.join(format!("{}.json", pkg_hex)));
+    fs::write(tmp_manifest.path(), manifest_json)
         .map_err(|e| format!("Failed to write manifest: {}", e))?;
 
-    fs::rename(&tmp_manifest, &manifest_path)
+    fs::rename(tmp_manifest.path(), &manifest_path)
         .map_err(|e| format!("Failed to rename manifest: {}", e))?;
+    tmp_manifest.keep();
 
     Ok(FileCasIngestResult {
         total_files,
@@ -2162,30 +4640,17 @@ pub fn ingest_to_file_cas(
     })
 }
 
-/// Materialize a package from file CAS to a destination directory.
-/// Creates hardlinks from the global store, falling back to copy.
-pub fn materialize_from_file_cas(
+/// (rel path, blob hash) file entries and (rel path, target) symlink entries.
+type FileCasManifestEntries = (Vec<(String, String)>, Vec<(String, String)>);
+
+/// Read a package manifest from the file CAS. `None` if the manifest does
+/// not exist or cannot be read.
+fn read_file_cas_manifest(
     store_root: &Path,
     pkg_algorithm: &str,
     pkg_hex: &str,
-    dest_dir: &Path,
-    link_strategy: LinkStrategy,
-) -> Result<FileCasMaterializeResult, String> {
-    let manifest_path = package_manifest_path(store_root, pkg_algorithm, pkg_hex);
-
-    // Read manifest
-    let manifest_content = match fs::read_to_string(&manifest_path) {
-        Ok(content) => content,
-        Err(_) => {
-            return Ok(FileCasMaterializeResult {
-                ok: false,
-                files: 0,
-                linked: 0,
-                copied: 0,
-                symlinks: 0,
-            });
-        }
-    };
+) -> Option<FileCasManifestEntries> {
+    let content = fs::read_to_string(package_manifest_path(store_root, pkg_algorithm, pkg_hex)).ok()?;
 
     // Parse manifest to extract file entries from the "files" object.
     // Works with single-line JSON (produced by JsonWriter).
@@ -2195,8 +4660,8 @@ pub fn materialize_from_file_cas(
     let mut symlink_entries = Vec::new();
 
     // Find the "files" object
-    if let Some(files_start) = manifest_content.find("\"files\"") {
-        let after_files = &manifest_content[files_start + 7..]; // skip "files"
+    if let Some(files_start) = content.find("\"files\"") {
+        let after_files = &content[files_start + 7..]; // skip "files"
         if let Some(obj_start) = after_files.find('{') {
             let files_section = &after_files[obj_start..];
 
@@ -2311,13 +4776,90 @@ pub fn materialize_from_file_cas(
         }
     }
 
+    Some((file_entries, symlink_entries))
+}
+
+/// List manifest paths of a stored package whose blobs are missing from the
+/// file CAS. Errors when the package manifest itself is missing.
+pub fn missing_store_blobs(
+    store_root: &Path,
+    pkg_algorithm: &str,
+    pkg_hex: &str,
+) -> Result<Vec<String>, String> {
+    let store_root = &long_path(store_root);
+    let (file_entries, _) = read_file_cas_manifest(store_root, pkg_algorithm, pkg_hex)
+        .ok_or_else(|| format!("No store manifest for {}-{}", pkg_algorithm, pkg_hex))?;
+    Ok(absent_blobs(store_root, &file_entries))
+}
+
+fn absent_blobs(store_root: &Path, file_entries: &[(String, String)]) -> Vec<String> {
+    let mut missing: Vec<String> = file_entries
+        .iter()
+        .filter(|(_, hash)| hash.len() < 4 || !file_store_path(store_root, hash).is_file())
+        .map(|(rel, _)| rel.clone())
+        .collect();
+    missing.sort();
+    missing
+}
+
+/// Drop a package manifest so the next ingest rebuilds it (and re-adds any
+/// blobs that have gone missing) from the unpacked tree.
+pub fn remove_file_cas_manifest(store_root: &Path, pkg_algorithm: &str, pkg_hex: &str) -> Result<(), String> {
+    let manifest_path = package_manifest_path(&long_path(store_root), pkg_algorithm, pkg_hex);
+    match fs::remove_file(&manifest_path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to remove {}: {}", manifest_path.display(), e)),
+    }
+}
+
+/// Materialize a package from file CAS to a destination directory.
+/// Creates hardlinks from the global store, falling back to copy.
+pub fn materialize_from_file_cas(
+    store_root: &Path,
+    pkg_algorithm: &str,
+    pkg_hex: &str,
+    dest_dir: &Path,
+    link_strategy: LinkStrategy,
+) -> Result<FileCasMaterializeResult, String> {
+    let store_root = &long_path(store_root);
+    let dest_dir = &long_path(dest_dir);
+
+    let (file_entries, symlink_entries) = match read_file_cas_manifest(store_root, pkg_algorithm, pkg_hex) {
+        Some(entries) => entries,
+        None => {
+            return Ok(FileCasMaterializeResult {
+                ok: false,
+                files: 0,
+                linked: 0,
+                copied: 0,
+                symlinks: 0,
+                missing_blobs: Vec::new(),
+            });
+        }
+    };
+
+    // Check every blob up front so a pruned or damaged store never leaves a
+    // half-populated package behind.
+    let missing_blobs = absent_blobs(store_root, &file_entries);
+    if !missing_blobs.is_empty() {
+        return Ok(FileCasMaterializeResult {
+            ok: false,
+            files: 0,
+            linked: 0,
+            copied: 0,
+            symlinks: 0,
+            missing_blobs,
+        });
+    }
+
     // Collect all directories needed (sorted shortest-first)
     let mut dirs_needed = HashSet::new();
     dirs_needed.insert(dest_dir.to_path_buf());
 
     for (rel_path, _) in &file_entries {
         if let Some(parent_str) = Path::new(rel_path).parent() {
-            if parent_str.as_os_str().len() > 0 {
+            if !parent_str.as_os_str().is_empty() {
                 dirs_needed.insert(dest_dir.join(parent_str));
             }
         }
@@ -2325,7 +4867,7 @@ pub fn materialize_from_file_cas(
 
     for (rel_path, _) in &symlink_entries {
         if let Some(parent_str) = Path::new(rel_path).parent() {
-            if parent_str.as_os_str().len() > 0 {
+            if !parent_str.as_os_str().is_empty() {
                 dirs_needed.insert(dest_dir.join(parent_str));
             }
         }
@@ -2342,6 +4884,7 @@ pub fn materialize_from_file_cas(
 
     // Materialize files in parallel using rayon
     use rayon::prelude::*;
+    let (link_strategy, _) = resolve_link_strategy(link_strategy, store_root, dest_dir);
 
     let file_count = AtomicU64::new(0);
     let linked_count = AtomicU64::new(0);
@@ -2361,6 +4904,13 @@ pub fn materialize_from_file_cas(
                         copied_count.fetch_add(1, Ordering::Relaxed);
                     }
                 }
+                LinkStrategy::Reflink => {
+                    if try_reflink_file(&store_path, &dest_path) {
+                        linked_count.fetch_add(1, Ordering::Relaxed);
+                    } else if fs::copy(&store_path, &dest_path).is_ok() {
+                        copied_count.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
                 LinkStrategy::Hardlink | LinkStrategy::Auto => {
                     match fs::hard_link(&store_path, &dest_path) {
                         Ok(_) => {
@@ -2382,6 +4932,7 @@ pub fn materialize_from_file_cas(
         linked: linked_count.load(Ordering::Relaxed),
         copied: copied_count.load(Ordering::Relaxed),
         symlinks: 0,
+        missing_blobs: Vec::new(),
     };
 
     // Create symlinks
@@ -2427,11 +4978,261 @@ pub fn materialize_from_file_cas(
 pub struct BinLinkResult {
     pub links_created: u64,
     pub links_failed: u64,
+    /// Bins whose target script does not exist in the package.
+    pub links_skipped: u64,
+    pub conflicts: Vec<BinLinkConflict>,
 }
 
-/// Parse the "bin" field from a package.json string.
+/// Two packages declaring the same bin name. `winner` and `loser` are
+/// `name@version`; only the winner's link is created.
+#[derive(Debug, Clone)]
+pub struct BinLinkConflict {
+    pub name: String,
+    pub winner: String,
+    pub loser: String,
+}
+
+/// Parse the bins a package.json declares.
 /// Returns Vec<(bin_name, relative_script_path)>.
+///
+/// Sources, first non-empty wins: `publishConfig.bin` (the override npm
+/// applies at publish time), the top-level `bin` field, then `bin`
+/// conditions inside `exports`.
 fn parse_bin_field(pkg_json: &str, pkg_name: &str) -> Vec<(String, String)> {
+    let fields = json_object_entries(pkg_json);
+    let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+
+    if let Some(publish_config) = field("publishConfig") {
+        if let Some((_, bin)) = json_object_entries(publish_config).into_iter().find(|(k, _)| k == "bin") {
+            let bins = parse_bin_value(&format!("{{\"bin\":{}}}", bin), pkg_name);
+            if !bins.is_empty() {
+                return bins;
+            }
+        }
+    }
+
+    if let Some(bin) = field("bin") {
+        let bins = parse_bin_value(&format!("{{\"bin\":{}}}", bin), pkg_name);
+        if !bins.is_empty() {
+            return bins;
+        }
+    }
+
+    let mut bins = Vec::new();
+    if let Some(exports) = field("exports") {
+        collect_exports_bins(exports, ".", pkg_name, &mut bins);
+    }
+    bins
+}
+
+/// The bins of the package in `pkg_dir`: the declared ones (see
+/// `parse_bin_field`), else one per file under `directories.bin`, named after
+/// the file. Hidden files are skipped, as npm does.
+fn package_bins(pkg_dir: &Path, pkg_json: &str, pkg_name: &str) -> Vec<(String, String)> {
+    let mut bins = parse_bin_field(pkg_json, pkg_name);
+    if !bins.is_empty() {
+        return bins;
+    }
+    let Some((_, directories)) = json_object_entries(pkg_json).into_iter().find(|(k, _)| k == "directories") else {
+        return bins;
+    };
+    let Some((_, raw_bin_dir)) = json_object_entries(&directories).into_iter().find(|(k, _)| k == "bin") else {
+        return bins;
+    };
+    // Only a string names a directory; `"bin": null` or an object is ignored.
+    let Some(bin_dir) = raw_bin_dir.strip_prefix('"').and_then(|d| d.strip_suffix('"')) else {
+        return bins;
+    };
+    let bin_dir = clean_bin_target(bin_dir).trim_end_matches('/').to_string();
+    if bin_dir.is_empty() {
+        return bins;
+    }
+    let root = pkg_dir.join(&bin_dir);
+    // Keep the walk inside the package even for "directories": {"bin": "../.."}.
+    let (Ok(root_abs), Ok(pkg_abs)) = (fs::canonicalize(&root), fs::canonicalize(pkg_dir)) else {
+        return bins;
+    };
+    if !root_abs.starts_with(&pkg_abs) {
+        return bins;
+    }
+
+    let mut stack = vec![PathBuf::new()];
+    while let Some(rel) = stack.pop() {
+        let Ok(entries) = fs::read_dir(root.join(&rel)) else { continue };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            let child = rel.join(&name);
+            match entry.file_type() {
+                Ok(ft) if ft.is_dir() => stack.push(child),
+                Ok(ft) if ft.is_file() || ft.is_symlink() => {
+                    let target = format!("{}/{}", bin_dir, child.to_string_lossy().replace('\\', "/"));
+                    if !bins.iter().any(|(n, _)| n == &name) {
+                        bins.push((name, target));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    bins.sort();
+    bins
+}
+
+/// Normalize a bin script path: `./bin/cli.js` and `bin/cli.js` name the same file.
+fn clean_bin_target(target: &str) -> String {
+    let mut t = target.trim();
+    while let Some(rest) = t.strip_prefix("./") {
+        t = rest.trim_start_matches('/');
+    }
+    t.to_string()
+}
+
+/// Walk an `exports` object and collect every `bin` condition. A string
+/// under `bin` is named after its subpath (the package name for `.`); an
+/// object under `bin` is a name -> file map like the top-level field.
+fn collect_exports_bins(exports: &str, subpath: &str, pkg_name: &str, bins: &mut Vec<(String, String)>) {
+    for (key, value) in json_object_entries(exports) {
+        if key == "bin" {
+            let bin_name = if subpath == "." {
+                pkg_name
+            } else {
+                match Path::new(subpath).file_stem().and_then(|s| s.to_str()) {
+                    Some(stem) => stem,
+                    None => continue,
+                }
+            };
+            for (name, file) in parse_bin_value(&format!("{{\"bin\":{}}}", value), bin_name) {
+                if !bins.iter().any(|(n, _)| n == &name) {
+                    bins.push((name, file));
+                }
+            }
+        } else if value.starts_with('{') {
+            // Keys starting with "." are subpaths; anything else is a
+            // condition (node, import, ...) that keeps the current subpath.
+            let next = if key.starts_with('.') { key.as_str() } else { subpath };
+            collect_exports_bins(&value, next, pkg_name, bins);
+        }
+    }
+}
+
+/// Split a JSON object into its top-level `(key, raw_value)` entries.
+/// String values keep their quotes; nested objects/arrays are returned verbatim.
+fn json_object_entries(obj: &str) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let body = obj.trim();
+    if !body.starts_with('{') {
+        return entries;
+    }
+
+    let mut depth = 0i32;
+    let mut in_str = false;
+    let mut esc = false;
+    let mut key: Option<String> = None;
+    let mut key_start = 0usize;
+    let mut value_start: Option<usize> = None;
+
+    for (i, ch) in body.char_indices() {
+        if esc {
+            esc = false;
+            continue;
+        }
+        if ch == '\\' && in_str {
+            esc = true;
+            continue;
+        }
+        if ch == '"' {
+            in_str = !in_str;
+            if depth == 1 && key.is_none() && value_start.is_none() {
+                if in_str {
+                    key_start = i + 1;
+                } else {
+                    key = Some(body[key_start..i].to_string());
+                }
+            }
+            continue;
+        }
+        if in_str {
+            continue;
+        }
+        match ch {
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    if let (Some(k), Some(start)) = (key.take(), value_start.take()) {
+                        entries.push((k, body[start..i].trim().to_string()));
+                    }
+                    break;
+                }
+            }
+            ':' if depth == 1 && key.is_some() && value_start.is_none() => value_start = Some(i + 1),
+            ',' if depth == 1 => {
+                if let (Some(k), Some(start)) = (key.take(), value_start.take()) {
+                    entries.push((k, body[start..i].trim().to_string()));
+                }
+            }
+            _ => {}
+        }
+    }
+    entries
+}
+
+/// Split a JSON array into its top-level raw elements.
+fn json_array_elements(arr: &str) -> Vec<String> {
+    let mut elements = Vec::new();
+    let body = arr.trim();
+    if !body.starts_with('[') {
+        return elements;
+    }
+
+    let mut depth = 0i32;
+    let mut in_str = false;
+    let mut esc = false;
+    let mut start = 1usize;
+
+    for (i, ch) in body.char_indices() {
+        if esc {
+            esc = false;
+            continue;
+        }
+        if ch == '\\' && in_str {
+            esc = true;
+            continue;
+        }
+        if ch == '"' {
+            in_str = !in_str;
+            continue;
+        }
+        if in_str {
+            continue;
+        }
+        match ch {
+            '{' | '[' => depth += 1,
+            '}' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    let last = body[start..i].trim();
+                    if !last.is_empty() {
+                        elements.push(last.to_string());
+                    }
+                    break;
+                }
+            }
+            ',' if depth == 1 => {
+                elements.push(body[start..i].trim().to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    elements
+}
+
+/// Parse the first "bin" value (string or object form) in a JSON string.
+fn parse_bin_value(pkg_json: &str, pkg_name: &str) -> Vec<(String, String)> {
     let mut bins = Vec::new();
 
     // Try "bin": "file.js" (string form)
@@ -2446,7 +5247,10 @@ fn parse_bin_field(pkg_json: &str, pkg_name: &str) -> Vec<(String, String)> {
             } else {
                 pkg_name
             };
-            bins.push((bin_name.to_string(), trimmed.to_string()));
+            let target = clean_bin_target(trimmed);
+            if !target.is_empty() {
+                bins.push((bin_name.to_string(), target));
+            }
             return bins;
         }
     }
@@ -2536,8 +5340,9 @@ fn parse_bin_field(pkg_json: &str, pkg_name: &str) -> Vec<(String, String)> {
                                 if reading_val {
                                     reading_val = false;
                                     after_key_colon = false;
-                                    if !key.is_empty() && !val.is_empty() {
-                                        bins.push((key.clone(), val.clone()));
+                                    let target = clean_bin_target(&val);
+                                    if !key.is_empty() && !target.is_empty() {
+                                        bins.push((key.clone(), target));
                                     }
                                     key.clear();
                                     val.clear();
@@ -2573,83 +5378,210 @@ fn parse_bin_field(pkg_json: &str, pkg_name: &str) -> Vec<(String, String)> {
 
 /// Create bin links in node_modules/.bin/ for all installed packages.
 /// Scans each package's package.json for "bin" entries and creates symlinks.
+///
+/// Packages are linked shallowest first, then by path, and the first package to
+/// claim a bin name keeps it; later claimants are recorded as conflicts.
 pub fn create_bin_links(
     node_modules_dir: &Path,
     packages: &[ResolvedPackage],
 ) -> Result<BinLinkResult, String> {
+    let node_modules_dir = &long_path(node_modules_dir);
     let bin_dir = node_modules_dir.join(".bin");
     fs::create_dir_all(&bin_dir).map_err(|e| format!("Failed to create .bin dir: {}", e))?;
 
     let mut result = BinLinkResult::default();
+    let mut claimed: HashMap<String, String> = HashMap::new();
 
-    for pkg in packages {
+    let mut ordered: Vec<&ResolvedPackage> = packages.iter().collect();
+    ordered.sort_by(|a, b| {
+        let depth = |p: &ResolvedPackage| p.rel_path.matches("node_modules/").count();
+        depth(a).cmp(&depth(b)).then_with(|| a.rel_path.cmp(&b.rel_path))
+    });
+    for pkg in ordered {
         // Determine package directory
         let pkg_dir = if pkg.rel_path.starts_with("node_modules/") {
             node_modules_dir.join(&pkg.rel_path[13..])
         } else {
             node_modules_dir.join(&pkg.rel_path)
         };
+        let owner = format!("{}@{}", pkg.name, pkg.version);
+        link_package_bins(&pkg_dir, &pkg.name, &owner, &bin_dir, &mut claimed, &mut result);
+    }
 
-        let pkg_json_path = pkg_dir.join("package.json");
-        let pkg_json = match fs::read_to_string(&pkg_json_path) {
-            Ok(s) => s,
-            Err(_) => continue,
-        };
+    Ok(result)
+}
+
+/// Link every bin `pkg_dir` declares into `bin_dir`, returning the bin names.
+/// `claimed` maps bin names already linked to their owner; a bin claimed by
+/// another owner, or whose script is missing, is not linked.
+fn link_package_bins(
+    pkg_dir: &Path,
+    pkg_name: &str,
+    owner: &str,
+    bin_dir: &Path,
+    claimed: &mut HashMap<String, String>,
+    result: &mut BinLinkResult,
+) -> Vec<String> {
+    let pkg_json_path = pkg_dir.join("package.json");
+    let pkg_json = match fs::read_to_string(&pkg_json_path) {
+        Ok(s) => s,
+        Err(_) => return Vec::new(),
+    };
+
+    let bins = package_bins(pkg_dir, &pkg_json, pkg_name);
+
+    let mut linked = Vec::new();
+    for (bin_name, bin_script) in bins {
+        let bin_target = pkg_dir.join(&bin_script);
+        let bin_link = bin_dir.join(&bin_name);
 
-        let bins = parse_bin_field(&pkg_json, &pkg.name);
-        if bins.is_empty() {
+        match claimed.get(&bin_name) {
+            Some(winner) if winner != owner => {
+                result.conflicts.push(BinLinkConflict { name: bin_name, winner: winner.clone(), loser: owner.to_string() });
+                continue;
+            }
+            Some(_) => continue,
+            None => {}
+        }
+        if !bin_target.is_file() {
+            result.links_skipped += 1;
             continue;
         }
+        claimed.insert(bin_name.clone(), owner.to_string());
+        linked.push(bin_name.clone());
 
-        for (bin_name, bin_script) in &bins {
-            let bin_target = pkg_dir.join(bin_script);
-            let bin_link = bin_dir.join(bin_name);
-
-            // Remove existing link/file
-            let _ = fs::remove_file(&bin_link);
+        // Remove existing link/file
+        let _ = fs::remove_file(&bin_link);
 
-            #[cfg(unix)]
-            {
-                // Make the target executable
-                if let Ok(md) = fs::metadata(&bin_target) {
-                    use std::os::unix::fs::PermissionsExt;
-                    let mut perms = md.permissions();
-                    let mode = perms.mode() | 0o111;
-                    perms.set_mode(mode);
-                    let _ = fs::set_permissions(&bin_target, perms);
-                }
-
-                // Create relative symlink from .bin/name -> ../pkg/script
-                let rel_target = pathdiff_relative(&bin_dir, &bin_target);
-                match std::os::unix::fs::symlink(&rel_target, &bin_link) {
-                    Ok(()) => result.links_created += 1,
-                    Err(_) => result.links_failed += 1,
-                }
+        #[cfg(unix)]
+        {
+            // Make the target executable
+            if let Ok(md) = fs::metadata(&bin_target) {
+                use std::os::unix::fs::PermissionsExt;
+                let mut perms = md.permissions();
+                let mode = perms.mode() | 0o111;
+                perms.set_mode(mode);
+                let _ = fs::set_permissions(&bin_target, perms);
             }
 
-            #[cfg(windows)]
-            {
-                // On Windows, create a .cmd shim
-                let cmd_link = bin_dir.join(format!("{}.cmd", bin_name));
-                let rel_target = pathdiff_relative(&bin_dir, &bin_target);
-                let shim_content = format!(
-                    "@ECHO off\r\n\"%~dp0\\{}\" %*\r\n",
-                    rel_target.to_string_lossy().replace('/', "\\")
-                );
-                match fs::write(&cmd_link, shim_content) {
-                    Ok(()) => result.links_created += 1,
-                    Err(_) => result.links_failed += 1,
-                }
+            // Create relative symlink from .bin/name -> ../pkg/script
+            let rel_target = pathdiff_relative(bin_dir, &bin_target);
+            match std::os::unix::fs::symlink(&rel_target, &bin_link) {
+                Ok(()) => result.links_created += 1,
+                Err(_) => result.links_failed += 1,
             }
+        }
 
-            #[cfg(not(any(unix, windows)))]
-            {
+        #[cfg(windows)]
+        {
+            // On Windows, write npm's shim trio: .cmd, .ps1 and an sh script.
+            // The bin counts once, as failed when any of its shims is
+            let rel_target = pathdiff_relative(bin_dir, &bin_target);
+            let shims = windows_bin_shims(bin_dir, &bin_name, &bin_target, &rel_target);
+            let written = shims.into_iter().fold(true, |ok, (path, content)| fs::write(&path, content).is_ok() && ok);
+            if written {
+                result.links_created += 1;
+            } else {
                 result.links_failed += 1;
             }
         }
+
+        #[cfg(not(any(unix, windows)))]
+        {
+            result.links_failed += 1;
+        }
     }
 
-    Ok(result)
+    linked
+}
+
+/// The program (and its arguments) a Windows shim runs a bin target with;
+/// `None` for targets Windows can execute directly. A shebang line wins, so
+/// `#!/usr/bin/env python3` runs `python3`; otherwise the extension decides
+/// and anything unrecognized is assumed to be a node script.
+#[cfg(windows)]
+fn shim_interpreter(target: &Path) -> Option<(String, String)> {
+    if let Some(shebang) = read_shebang(target) {
+        return Some(shebang);
+    }
+    let ext = target.extension().map(|e| e.to_string_lossy().to_ascii_lowercase()).unwrap_or_default();
+    let prog = match ext.as_str() {
+        "cmd" | "bat" | "exe" | "com" => return None,
+        "sh" => "sh",
+        "ps1" => "pwsh",
+        _ => "node",
+    };
+    Some((prog.to_string(), String::new()))
+}
+
+/// Parse `#!/usr/bin/env [-S] prog args` or `#!/path/to/prog args` from the
+/// first line of `target` into the bare program name and its arguments.
+#[cfg(windows)]
+fn read_shebang(target: &Path) -> Option<(String, String)> {
+    use std::io::{BufRead, BufReader};
+    let mut first = String::new();
+    BufReader::new(fs::File::open(target).ok()?).take(1024).read_line(&mut first).ok()?;
+    let line = first.strip_prefix('#')?.strip_prefix('!')?.trim();
+    let mut words = line.split_whitespace();
+    let mut prog = words.next()?;
+    if prog.rsplit('/').next() == Some("env") {
+        prog = words.next()?;
+        if prog == "-S" {
+            prog = words.next()?;
+        }
+    }
+    let prog = prog.rsplit('/').next().unwrap_or(prog);
+    let args: Vec<&str> = words.collect();
+    Some((prog.to_string(), args.join(" ")))
+}
+
+/// The `.cmd`, `.ps1` and extensionless sh shims for the bin at `bin_target`,
+/// which is `rel_target` relative to `bin_dir`. Each resolves the target
+/// against its own directory, so the shims survive the project being moved.
+#[cfg(windows)]
+fn windows_bin_shims(bin_dir: &Path, bin_name: &str, bin_target: &Path, rel_target: &Path) -> Vec<(PathBuf, String)> {
+    let target = rel_target.to_string_lossy().replace('\\', "/");
+    let interpreter = shim_interpreter(bin_target);
+    let interpreter = interpreter.as_ref().map(|(prog, args)| {
+        (prog.as_str(), if args.is_empty() { String::new() } else { format!("{} ", args) })
+    });
+
+    // cmd.exe: backslashes, and % must be doubled inside a batch file
+    let cmd_target = format!("%~dp0\\{}", target.replace('/', "\\").replace('%', "%%"));
+    let cmd = match &interpreter {
+        Some((prog, args)) => format!("@ECHO off\r\nSETLOCAL\r\nIF EXIST \"%~dp0\\{prog}.exe\" (\r\n  SET \"_prog=%~dp0\\{prog}.exe\"\r\n) ELSE (\r\n  SET \"_prog={prog}\"\r\n)\r\nENDLOCAL & \"%_prog%\" {args}\"{cmd_target}\" %*\r\n"),
+        None => format!("@ECHO off\r\n\"{cmd_target}\" %*\r\n"),
+    };
+
+    // PowerShell: backtick escapes `, $ and " inside a double-quoted string
+    let ps_target = format!("$basedir/{}", target.replace('`', "``").replace('$', "`$").replace('"', "`\""));
+    let ps1 = match &interpreter {
+        Some((prog, args)) => format!(
+            "#!/usr/bin/env pwsh\n$basedir=Split-Path $MyInvocation.MyCommand.Definition -Parent\n\n$exe=\"\"\nif ($PSVersionTable.PSVersion -lt \"6.0\" -or $IsWindows) {{\n  $exe=\".exe\"\n}}\n$prog=\"{prog}$exe\"\nif (Test-Path \"$basedir/{prog}$exe\") {{\n  $prog=\"$basedir/{prog}$exe\"\n}}\nif ($MyInvocation.ExpectingInput) {{\n  $input | & $prog {args}\"{ps_target}\" $args\n}} else {{\n  & $prog {args}\"{ps_target}\" $args\n}}\nexit $LASTEXITCODE\n"
+        ),
+        None => format!(
+            "#!/usr/bin/env pwsh\n$basedir=Split-Path $MyInvocation.MyCommand.Definition -Parent\n\n& \"{ps_target}\" $args\nexit $LASTEXITCODE\n"
+        ),
+    };
+
+    // sh (Git Bash, MSYS, Cygwin): $, ` and " are special inside double quotes
+    let sh_target = format!(
+        "$basedir/{}",
+        target.replace('$', "\\$").replace('`', "\\`").replace('"', "\\\"")
+    );
+    let sh_header = "#!/bin/sh\nbasedir=$(dirname \"$(echo \"$0\" | sed -e 's,\\\\,/,g')\")\n\ncase `uname` in\n    *CYGWIN*|*MINGW*|*MSYS*)\n        if command -v cygpath > /dev/null 2>&1; then\n            basedir=`cygpath -w \"$basedir\"`\n        fi\n    ;;\nesac\n\n";
+    let sh = match &interpreter {
+        Some((prog, args)) => format!(
+            "{sh_header}if [ -x \"$basedir/{prog}\" ]; then\n  exec \"$basedir/{prog}\" {args}\"{sh_target}\" \"$@\"\nelse\n  exec {prog} {args}\"{sh_target}\" \"$@\"\nfi\n"
+        ),
+        None => format!("{sh_header}exec \"{sh_target}\" \"$@\"\n"),
+    };
+
+    vec![
+        (bin_dir.join(format!("{}.cmd", bin_name)), cmd),
+        (bin_dir.join(format!("{}.ps1", bin_name)), ps1),
+        (bin_dir.join(bin_name), sh),
+    ]
 }
 
 /// Compute a relative path from `base` to `target`.
@@ -2709,6 +5641,97 @@ pub struct LifecycleRunResult {
     pub scripts_failed: u64,
     pub skipped_reason: Option<String>,
     pub rebuild_exit_code: Option<i32>,
+    /// Scripts that exited non-zero or could not start, in run order
+    pub failures: Vec<LifecycleScriptFailure>,
+    /// Of `scripts_failed`, the scripts killed for running past the timeout
+    pub scripts_timed_out: u64,
+    /// Scripts the script policy let run
+    pub allowed_scripts: u64,
+    /// Scripts the script policy kept from running
+    pub blocked_scripts: u64,
+    pub blocked: Vec<LifecycleScriptBlock>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LifecycleScriptBlock {
+    pub package_name: String,
+    pub script_name: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LifecycleScriptOutcome {
+    /// Exited non-zero, was killed by a signal, or could not start
+    Failed,
+    /// Ran past `LifecycleRunOptions::timeout` and was killed
+    TimedOut,
+}
+
+impl LifecycleScriptOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Failed => "failed",
+            Self::TimedOut => "timed-out",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LifecycleScriptFailure {
+    pub package_name: String,
+    pub script_name: String,
+    pub outcome: LifecycleScriptOutcome,
+    /// `None` when the script could not be started or was killed by a signal
+    pub exit_code: Option<i32>,
+    /// The last `LIFECYCLE_OUTPUT_TAIL` bytes the script wrote, when output
+    /// was captured (`LifecycleRunOptions::capture_output`)
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+}
+
+/// How much of each stream a captured lifecycle script keeps: its last 64 KiB.
+pub const LIFECYCLE_OUTPUT_TAIL: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct LifecycleRunOptions {
+    /// Packages whose scripts may run at the same time
+    pub jobs: usize,
+    /// Only run the scripts this policy permits (see `check_lifecycle_permission`);
+    /// `None` runs every script
+    pub policy: Option<ScriptPolicy>,
+    /// Run scripts of packages outside the allowed list too
+    pub allow_all: bool,
+    /// Capture each script's stdout and stderr for the result instead of
+    /// passing them through (stdout to stderr)
+    pub capture_output: bool,
+    /// Kill a script (and everything it started) that runs longer than this
+    pub timeout: Option<std::time::Duration>,
+}
+
+/// How long a lifecycle script may run unless package.json sets
+/// `better.scriptTimeoutMs`: five minutes.
+pub const DEFAULT_SCRIPT_TIMEOUT_MS: u64 = 300_000;
+
+/// The lifecycle script timeout for the project: package.json
+/// `better.scriptTimeoutMs`, else `DEFAULT_SCRIPT_TIMEOUT_MS`. 0 disables it.
+pub fn script_timeout(project_root: &Path) -> Option<std::time::Duration> {
+    let content = fs::read_to_string(project_root.join("package.json")).unwrap_or_default();
+    let ms = extract_json_object_raw(&content, "better")
+        .and_then(|better| extract_json_number(&better, "scriptTimeoutMs"))
+        .unwrap_or(DEFAULT_SCRIPT_TIMEOUT_MS);
+    (ms > 0).then(|| std::time::Duration::from_millis(ms))
+}
+
+impl Default for LifecycleRunOptions {
+    fn default() -> Self {
+        Self {
+            jobs: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+            policy: None,
+            allow_all: false,
+            capture_output: false,
+            timeout: Some(std::time::Duration::from_millis(DEFAULT_SCRIPT_TIMEOUT_MS)),
+        }
+    }
 }
 
 /// Detect lifecycle scripts (install, preinstall, postinstall) and binding.gyp
@@ -2747,6 +5770,7 @@ pub fn detect_lifecycle_scripts(
         }
 
         // Check for lifecycle scripts
+        let first_script = result.scripts.len();
         for script_name in &lifecycle_names {
             // Look for "scripts": { ... "install": "command" ... }
             if let Some(pos) = pkg_json.find("\"scripts\"") {
@@ -2767,16 +5791,285 @@ pub fn detect_lifecycle_scripts(
                 }
             }
         }
+
+        // npm's default: a binding.gyp with no preinstall or install script
+        // is built with `node-gyp rebuild` as the install script
+        let own = &result.scripts[first_script..];
+        if pkg_dir.join("binding.gyp").exists() && !own.iter().any(|s| s.script_name == "preinstall" || s.script_name == "install") {
+            let at = first_script + own.iter().filter(|s| s.script_name == "preinstall").count();
+            result.scripts.insert(at, LifecycleScriptInfo {
+                package_name: pkg.name.clone(),
+                package_dir: pkg_dir.clone(),
+                script_name: "install".to_string(),
+                script_command: "node-gyp rebuild".to_string(),
+            });
+        }
     }
 
     result
 }
 
-/// Run lifecycle scripts by delegating to `npm rebuild`.
-/// Only runs if native addons were detected, saving ~600ms on projects without them.
+/// Run the detected lifecycle scripts natively, without npm.
+///
+/// Each package's scripts run in its own directory, in preinstall, install,
+/// postinstall order, stopping at the first that fails. A package's scripts
+/// start only after those of every package it (transitively) depends on have
+/// finished; packages with no such dependency between them run in parallel,
+/// up to `options.jobs` at a time. Scripts run through `sh -c` (`cmd /d /s /c`
+/// on Windows) with the `node_modules/.bin` directories from the package up
+/// to `project_root` on PATH and npm's `npm_package_*`/`npm_lifecycle_*`
+/// variables set. Script stdout goes to stderr so JSON on stdout stays intact,
+/// or with `options.capture_output` both streams are kept for the failures.
+/// With `options.policy`, scripts the policy does not permit are recorded in
+/// `blocked` and never run.
 pub fn run_lifecycle_scripts(
     project_root: &Path,
     detection: &LifecycleDetectionResult,
+    options: &LifecycleRunOptions,
+) -> LifecycleRunResult {
+    if detection.scripts.is_empty() {
+        return LifecycleRunResult {
+            skipped_reason: Some("no_native_addons".to_string()),
+            ..Default::default()
+        };
+    }
+
+    // Permitted scripts grouped per package, keeping detection (lifecycle) order
+    let mut result = LifecycleRunResult::default();
+    let mut by_dir: BTreeMap<PathBuf, Vec<&LifecycleScriptInfo>> = BTreeMap::new();
+    for script in &detection.scripts {
+        if let Some(policy) = &options.policy {
+            if let Err(reason) = check_lifecycle_permission(policy, options.allow_all, &script.package_name, &script.script_name) {
+                result.blocked_scripts += 1;
+                result.blocked.push(LifecycleScriptBlock {
+                    package_name: script.package_name.clone(),
+                    script_name: script.script_name.clone(),
+                    reason,
+                });
+                continue;
+            }
+        }
+        result.allowed_scripts += 1;
+        by_dir.entry(script.package_dir.clone()).or_default().push(script);
+    }
+
+    let mut levels: HashMap<PathBuf, usize> = HashMap::new();
+    let mut waves: BTreeMap<usize, Vec<&PathBuf>> = BTreeMap::new();
+    for dir in by_dir.keys() {
+        let level = lifecycle_level(dir, project_root, &by_dir, &mut levels, &mut HashSet::new());
+        waves.entry(level).or_default().push(dir);
+    }
+
+    let outcomes: Mutex<Vec<(usize, LifecycleRunResult)>> = Mutex::new(Vec::new());
+    let mut order = 0usize;
+    for wave in waves.values() {
+        let next = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..options.jobs.clamp(1, wave.len()) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let Some(dir) = wave.get(i) else { break };
+                    let run = run_package_lifecycle(project_root, dir, &by_dir[*dir], options);
+                    outcomes.lock().unwrap().push((order + i, run));
+                });
+            }
+        });
+        order += wave.len();
+    }
+
+    let mut outcomes = outcomes.into_inner().unwrap_or_default();
+    outcomes.sort_by_key(|(i, _)| *i);
+    for (_, run) in outcomes {
+        result.scripts_run += run.scripts_run;
+        result.scripts_succeeded += run.scripts_succeeded;
+        result.scripts_failed += run.scripts_failed;
+        result.scripts_timed_out += run.scripts_timed_out;
+        result.failures.extend(run.failures);
+    }
+    result
+}
+
+/// How many rounds of scripts must finish before the package in `dir` can run
+/// its own: one more than the deepest package with scripts among its installed
+/// dependencies, found the way Node resolves them. Dependency cycles are cut
+/// where they close.
+fn lifecycle_level(
+    dir: &Path,
+    project_root: &Path,
+    scripted: &BTreeMap<PathBuf, Vec<&LifecycleScriptInfo>>,
+    memo: &mut HashMap<PathBuf, usize>,
+    visiting: &mut HashSet<PathBuf>,
+) -> usize {
+    if let Some(&level) = memo.get(dir) {
+        return level;
+    }
+    if !visiting.insert(dir.to_path_buf()) {
+        return 0;
+    }
+    let mut deepest = 0;
+    for dep in parse_package_deps(dir) {
+        let mut cur = Some(dir);
+        while let Some(d) = cur.filter(|d| d.starts_with(project_root)) {
+            let candidate = d.join("node_modules").join(&dep);
+            if candidate.join("package.json").is_file() {
+                deepest = deepest.max(lifecycle_level(&candidate, project_root, scripted, memo, visiting));
+                break;
+            }
+            cur = d.parent();
+        }
+    }
+    visiting.remove(dir);
+    let level = if scripted.contains_key(dir) { deepest + 1 } else { deepest };
+    memo.insert(dir.to_path_buf(), level);
+    level
+}
+
+/// Run one package's lifecycle scripts in order, stopping at the first failure.
+fn run_package_lifecycle(
+    project_root: &Path,
+    pkg_dir: &Path,
+    scripts: &[&LifecycleScriptInfo],
+    options: &LifecycleRunOptions,
+) -> LifecycleRunResult {
+    let mut result = LifecycleRunResult::default();
+    let version = read_package_identity(pkg_dir).map(|(_, v)| v).unwrap_or_default();
+
+    let mut path_dirs: Vec<PathBuf> = pkg_dir
+        .ancestors()
+        .take_while(|d| d.starts_with(project_root))
+        .filter(|d| d.file_name().is_some_and(|n| n != "node_modules"))
+        .map(|d| d.join("node_modules").join(".bin"))
+        .collect();
+    if let Some(path_var) = std::env::var_os("PATH") {
+        path_dirs.extend(std::env::split_paths(&path_var));
+    }
+    let path = std::env::join_paths(path_dirs).unwrap_or_default();
+
+    for script in scripts {
+        let mut cmd = if cfg!(windows) {
+            let mut c = std::process::Command::new("cmd");
+            c.args(["/d", "/s", "/c", &script.script_command]);
+            c
+        } else {
+            let mut c = std::process::Command::new("sh");
+            c.args(["-c", &script.script_command]);
+            c
+        };
+        // Own process group, so a timeout can kill everything the script started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        cmd.current_dir(pkg_dir)
+            .env("PATH", &path)
+            .env("INIT_CWD", project_root)
+            .env("npm_package_name", &script.package_name)
+            .env("npm_package_version", &version)
+            .env("npm_package_json", pkg_dir.join("package.json"))
+            .env("npm_lifecycle_event", &script.script_name)
+            .env("npm_lifecycle_script", &script.script_command)
+            .stdin(std::process::Stdio::null());
+        if options.capture_output {
+            cmd.stdout(std::process::Stdio::piped()).stderr(std::process::Stdio::piped());
+        } else {
+            cmd.stdout(std::process::Stdio::from(std::io::stderr())).stderr(std::process::Stdio::inherit());
+        }
+
+        result.scripts_run += 1;
+        let (status, timed_out, stdout, stderr) = match cmd.spawn() {
+            Ok(mut child) => {
+                // Drain both pipes at once so a chatty script cannot block on a full one
+                let out = child.stdout.take().map(|r| std::thread::spawn(move || read_output_tail(r)));
+                let err = child.stderr.take().map(|r| std::thread::spawn(move || read_output_tail(r)));
+                let (status, timed_out) = wait_with_timeout(&mut child, options.timeout);
+                let join = |h: Option<std::thread::JoinHandle<String>>| h.map(|h| h.join().unwrap_or_default());
+                (status.filter(|_| !timed_out), timed_out, join(out), join(err))
+            }
+            Err(e) => (None, false, None, options.capture_output.then(|| format!("Failed to start: {}", e))),
+        };
+        let exit_code = match status {
+            Some(status) if status.success() => {
+                result.scripts_succeeded += 1;
+                continue;
+            }
+            Some(status) => status.code(),
+            None => None,
+        };
+        result.scripts_failed += 1;
+        if timed_out {
+            result.scripts_timed_out += 1;
+        }
+        result.failures.push(LifecycleScriptFailure {
+            package_name: script.package_name.clone(),
+            script_name: script.script_name.clone(),
+            outcome: if timed_out { LifecycleScriptOutcome::TimedOut } else { LifecycleScriptOutcome::Failed },
+            exit_code,
+            stdout: stdout.or_else(|| options.capture_output.then(String::new)),
+            stderr,
+        });
+        break;
+    }
+    result
+}
+
+/// Wait for `child`, killing its process group (process tree on Windows) once
+/// `timeout` passes. Returns the exit status and whether it timed out; the
+/// child is always reaped.
+fn wait_with_timeout(child: &mut std::process::Child, timeout: Option<std::time::Duration>) -> (Option<std::process::ExitStatus>, bool) {
+    let Some(timeout) = timeout else { return (child.wait().ok(), false) };
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return (Some(status), false),
+            Ok(None) if started.elapsed() < timeout => std::thread::sleep(std::time::Duration::from_millis(20)),
+            Ok(None) => break,
+            Err(_) => return (None, false),
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        extern "C" {
+            fn kill(pid: std::os::raw::c_int, sig: std::os::raw::c_int) -> std::os::raw::c_int;
+        }
+        const SIGKILL: std::os::raw::c_int = 9;
+        // The script leads its own group (see `process_group(0)`), so -pid is all of it
+        unsafe { kill(-(child.id() as std::os::raw::c_int), SIGKILL) };
+    }
+    #[cfg(windows)]
+    {
+        let _ = std::process::Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &child.id().to_string()])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status();
+    }
+    let _ = child.kill();
+    (child.wait().ok(), true)
+}
+
+/// Read `reader` to the end, keeping only its last `LIFECYCLE_OUTPUT_TAIL` bytes.
+fn read_output_tail(mut reader: impl std::io::Read) -> String {
+    let mut tail: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 8192];
+    while let Ok(n) = reader.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        tail.extend_from_slice(&buf[..n]);
+        if tail.len() > 2 * LIFECYCLE_OUTPUT_TAIL {
+            tail.drain(..tail.len() - LIFECYCLE_OUTPUT_TAIL);
+        }
+    }
+    if tail.len() > LIFECYCLE_OUTPUT_TAIL {
+        tail.drain(..tail.len() - LIFECYCLE_OUTPUT_TAIL);
+    }
+    String::from_utf8_lossy(&tail).into_owned()
+}
+
+/// Run lifecycle scripts by delegating to `npm rebuild` (`install --use-npm-rebuild`).
+/// Only runs if native addons were detected, saving ~600ms on projects without them.
+pub fn run_lifecycle_scripts_npm_rebuild(
+    project_root: &Path,
+    detection: &LifecycleDetectionResult,
 ) -> LifecycleRunResult {
     if !detection.has_native_addons {
         return LifecycleRunResult {
@@ -2802,6 +6095,7 @@ pub fn run_lifecycle_scripts(
                 scripts_failed: if code != 0 { 1 } else { 0 },
                 skipped_reason: None,
                 rebuild_exit_code: Some(code),
+                ..Default::default()
             }
         }
         Err(e) => LifecycleRunResult {
@@ -2810,6 +6104,7 @@ pub fn run_lifecycle_scripts(
             scripts_failed: 1,
             skipped_reason: Some(format!("npm_not_found: {}", e)),
             rebuild_exit_code: None,
+            ..Default::default()
         },
     }
 }
@@ -2826,37 +6121,156 @@ fn get_file_mode(_metadata: &fs::Metadata) -> u32 {
     0o644 // Default mode for non-Unix systems
 }
 
-// Helper function to get current timestamp in ISO format
-fn chrono_now() -> String {
-    use std::time::SystemTime;
+// === Phase B: High-Value Commands ===
 
-    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(duration) => {
-            let secs = duration.as_secs();
-            let nanos = duration.subsec_nanos();
+// --- Project context (embedding API) ---
+
+/// Inputs shared by the analyzers. Everything is derived from the project
+/// root unless overridden: `node_modules` defaults to `<root>/node_modules`
+/// and `lockfile` to [`default_lockfile`] (`npm-shrinkwrap.json` over
+/// `package-lock.json`).
+///
+/// ```no_run
+/// use better_core::ProjectContext;
+///
+/// let ctx = ProjectContext::new("path/to/project");
+/// let licenses = ctx.scan_licenses(&[], &["GPL-3.0".to_string()]).unwrap();
+/// let why = ctx.trace_dependency("lodash").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProjectContext {
+    pub root: PathBuf,
+    pub node_modules: PathBuf,
+    pub lockfile: PathBuf,
+    /// Lockfile sections the license and audit scans skip
+    pub omit: OmitSet,
+    /// Overrides npmrc `fetch-timeout` for the network commands
+    pub fetch_timeout_ms: Option<u64>,
+    /// Where `check_outdated` keeps registry metadata between runs; None
+    /// fetches everything afresh
+    pub metadata_cache: Option<MetadataCache>,
+    /// Advisory database `run_audit` queries
+    pub audit_source: AuditSource,
+}
 
-            // Simple ISO 8601 formatting
-            let days_since_epoch = secs / 86400;
-            let year = 1970 + (days_since_epoch / 365); // Rough approximation
-            let month = ((days_since_epoch % 365) / 30) + 1;
-            let day = ((days_since_epoch % 365) % 30) + 1;
+impl ProjectContext {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        let root = root.as_ref().to_path_buf();
+        ProjectContext {
+            node_modules: root.join("node_modules"),
+            lockfile: default_lockfile(&root),
+            omit: OmitSet::default(),
+            fetch_timeout_ms: None,
+            metadata_cache: None,
+            audit_source: AuditSource::default(),
+            root,
+        }
+    }
 
-            let time_of_day = secs % 86400;
-            let hour = time_of_day / 3600;
-            let minute = (time_of_day % 3600) / 60;
-            let second = time_of_day % 60;
-            let millis = nanos / 1_000_000;
+    pub fn with_metadata_cache(mut self, cache: Option<MetadataCache>) -> Self {
+        self.metadata_cache = cache;
+        self
+    }
 
-            format!(
-                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
-                year, month, day, hour, minute, second, millis
-            )
+    pub fn with_fetch_timeout(mut self, ms: Option<u64>) -> Self {
+        self.fetch_timeout_ms = ms;
+        self
+    }
+
+    /// The project's npmrc settings with this context's overrides applied.
+    pub fn npmrc(&self) -> NpmrcConfig {
+        let mut npmrc = parse_npmrc(&self.root);
+        if let Some(ms) = self.fetch_timeout_ms {
+            npmrc.fetch_timeout_ms = ms;
+        }
+        npmrc
+    }
+
+    pub fn with_lockfile(mut self, lockfile: impl AsRef<Path>) -> Self {
+        self.lockfile = lockfile.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn with_node_modules(mut self, node_modules: impl AsRef<Path>) -> Self {
+        self.node_modules = node_modules.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn with_omit(mut self, omit: OmitSet) -> Self {
+        self.omit = omit;
+        self
+    }
+
+    pub fn with_audit_source(mut self, source: AuditSource) -> Self {
+        self.audit_source = source;
+        self
+    }
+
+    /// Lockfile paths (`node_modules/...`) that `omit` excludes. Empty when
+    /// nothing is omitted; an error if sections are omitted but the lockfile
+    /// cannot be read, since the scan would otherwise silently include them.
+    fn omitted_lockfile_paths(&self) -> Result<HashSet<String>, String> {
+        if self.omit.is_empty() {
+            return Ok(HashSet::new());
         }
-        Err(_) => "1970-01-01T00:00:00.000Z".to_string(),
+        let content = fs::read_to_string(&self.lockfile)
+            .map_err(|e| format!("--omit needs a lockfile to classify packages: {}", e))?;
+        let graph = parse_lockfile_graph(&content)?;
+        Ok(graph.into_iter()
+            .filter(|(path, node)| !path.is_empty() && self.omit.omits(node))
+            .map(|(path, _)| path)
+            .collect())
     }
 }
 
-// === Phase B: High-Value Commands ===
+/// Dependency sections to leave out of a scan, from `--omit dev,optional,peer`.
+/// `--prod` is shorthand for omitting dev.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OmitSet {
+    pub dev: bool,
+    pub optional: bool,
+    pub peer: bool,
+}
+
+impl OmitSet {
+    pub fn prod() -> Self {
+        OmitSet { dev: true, ..OmitSet::default() }
+    }
+
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut omit = OmitSet::default();
+        for part in list.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part {
+                "dev" => omit.dev = true,
+                "optional" => omit.optional = true,
+                "peer" => omit.peer = true,
+                other => return Err(format!("unknown --omit section '{}'", other)),
+            }
+        }
+        Ok(omit)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.dev && !self.optional && !self.peer
+    }
+
+    pub fn sections(&self) -> Vec<&'static str> {
+        let mut out = Vec::new();
+        if self.dev { out.push("dev"); }
+        if self.optional { out.push("optional"); }
+        if self.peer { out.push("peer"); }
+        out
+    }
+
+    /// Same rules as npm: `devOptional` packages go only when both dev and
+    /// optional are omitted.
+    fn omits(&self, node: &LockfileNode) -> bool {
+        (self.dev && node.dev)
+            || (self.optional && node.optional)
+            || (self.peer && node.peer)
+            || (self.dev && self.optional && node.dev_optional)
+    }
+}
 
 // --- B.1: Script Runner ---
 
@@ -3010,6 +6424,8 @@ pub fn run_script(project_root: &Path, script_name: &str, extra_args: &[String])
     })
 }
 
+/// Run every script at once. Results are in `script_names` order, whatever
+/// order the scripts finish in.
 pub fn run_scripts_parallel(project_root: &Path, script_names: &[String]) -> Vec<Result<ScriptRunResult, String>> {
     let handles: Vec<_> = script_names.iter().map(|name| {
         let root = project_root.to_path_buf();
@@ -3021,6 +6437,22 @@ pub fn run_scripts_parallel(project_root: &Path, script_names: &[String]) -> Vec
         .collect()
 }
 
+/// Run the scripts one after another, stopping after the first that fails or
+/// cannot start, like chaining them with `&&`. Scripts after that one are not
+/// run and have no result.
+pub fn run_scripts_sequential(project_root: &Path, script_names: &[String]) -> Vec<Result<ScriptRunResult, String>> {
+    let mut results = Vec::with_capacity(script_names.len());
+    for name in script_names {
+        let result = run_script(project_root, name, &[]);
+        let failed = result.as_ref().map_or(true, |r| r.exit_code != 0);
+        results.push(result);
+        if failed {
+            break;
+        }
+    }
+    results
+}
+
 // --- B.2: License Scanner ---
 
 #[derive(Debug, Clone)]
@@ -3036,44 +6468,78 @@ pub struct LicenseReport {
     pub by_license: BTreeMap<String, u64>,
     pub total_packages: u64,
     pub violations: Vec<LicenseInfo>,
+    /// Installed packages skipped by the context's `omit` set
+    pub omitted: u64,
+}
+
+impl LicenseReport {
+    /// Reorder `packages` by name (then version) or by license id (then name).
+    /// `Size` has no meaning here and keeps scan order.
+    pub fn sort_packages(&mut self, key: SortKey) {
+        match key {
+            SortKey::Name => self.packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version))),
+            SortKey::Type => self.packages.sort_by(|a, b| (&a.license, &a.name).cmp(&(&b.license, &b.name))),
+            SortKey::Size => {}
+        }
+    }
 }
 
+#[deprecated(note = "use ProjectContext::scan_licenses, which takes the project root")]
 pub fn scan_licenses(node_modules: &Path, allow: &[String], deny: &[String]) -> Result<LicenseReport, String> {
-    let pkg_dirs = list_packages_in_node_modules(node_modules)?;
-    let mut packages = Vec::new();
-    let mut by_license: BTreeMap<String, u64> = BTreeMap::new();
-    let mut violations = Vec::new();
+    let root = node_modules.parent().unwrap_or(node_modules);
+    ProjectContext::new(root).with_node_modules(node_modules).scan_licenses(allow, deny)
+}
+
+impl ProjectContext {
+    /// License of every package under `node_modules`, with `allow`/`deny` violations.
+    pub fn scan_licenses(&self, allow: &[String], deny: &[String]) -> Result<LicenseReport, String> {
+        let node_modules = &self.node_modules;
+        let pkg_dirs = list_packages_in_node_modules(node_modules)?;
+        let omitted_paths = self.omitted_lockfile_paths()?;
+        let mut packages = Vec::new();
+        let mut by_license: BTreeMap<String, u64> = BTreeMap::new();
+        let mut violations = Vec::new();
+        let mut omitted = 0u64;
+
+        for pkg_dir in &pkg_dirs {
+            if !omitted_paths.is_empty() {
+                let rel = pkg_dir.strip_prefix(node_modules).unwrap_or(pkg_dir);
+                let lock_path = format!("node_modules/{}", rel.to_string_lossy().replace('\\', "/"));
+                if omitted_paths.contains(&lock_path) {
+                    omitted += 1;
+                    continue;
+                }
+            }
+            let pkg_json = pkg_dir.join("package.json");
+            let content = match fs::read_to_string(&pkg_json) {
+                Ok(c) => c,
+                Err(_) => continue,
+            };
+            let name = extract_json_field(&content, "name").unwrap_or_else(|| "unknown".to_string());
+            let version = extract_json_field(&content, "version").unwrap_or_else(|| "0.0.0".to_string());
+            let license = extract_json_field(&content, "license").unwrap_or_else(|| "UNLICENSED".to_string());
 
-    for pkg_dir in &pkg_dirs {
-        let pkg_json = pkg_dir.join("package.json");
-        let content = match fs::read_to_string(&pkg_json) {
-            Ok(c) => c,
-            Err(_) => continue,
-        };
-        let name = extract_json_field(&content, "name").unwrap_or_else(|| "unknown".to_string());
-        let version = extract_json_field(&content, "version").unwrap_or_else(|| "0.0.0".to_string());
-        let license = extract_json_field(&content, "license").unwrap_or_else(|| "UNLICENSED".to_string());
+            *by_license.entry(license.clone()).or_insert(0) += 1;
 
-        *by_license.entry(license.clone()).or_insert(0) += 1;
+            let info = LicenseInfo { name, version, license: license.clone() };
 
-        let info = LicenseInfo { name, version, license: license.clone() };
+            let is_violation = if !deny.is_empty() {
+                deny.iter().any(|d| d.eq_ignore_ascii_case(&license))
+            } else if !allow.is_empty() {
+                !allow.iter().any(|a| a.eq_ignore_ascii_case(&license))
+            } else {
+                false
+            };
+            if is_violation {
+                violations.push(info.clone());
+            }
 
-        let is_violation = if !deny.is_empty() {
-            deny.iter().any(|d| d.eq_ignore_ascii_case(&license))
-        } else if !allow.is_empty() {
-            !allow.iter().any(|a| a.eq_ignore_ascii_case(&license))
-        } else {
-            false
-        };
-        if is_violation {
-            violations.push(info.clone());
+            packages.push(info);
         }
 
-        packages.push(info);
+        let total = packages.len() as u64;
+        Ok(LicenseReport { packages, by_license, total_packages: total, violations, omitted })
     }
-
-    let total = packages.len() as u64;
-    Ok(LicenseReport { packages, by_license, total_packages: total, violations })
 }
 
 // --- B.3: Dedupe Checker ---
@@ -3085,6 +6551,12 @@ pub struct DedupeEntry {
     pub instances: u64,
     pub can_dedupe: bool,
     pub saved_instances: u64,
+    /// Logical bytes freed by collapsing every instance onto one copy of the kept version
+    pub saved_bytes: u64,
+    /// Highest installed version satisfying every requirer's range in the
+    /// lockfile, the project's own included.
+    /// `None` without a lockfile, or when the entry cannot be deduped.
+    pub target_version: Option<String>,
 }
 
 #[derive(Debug)]
@@ -3093,335 +6565,699 @@ pub struct DedupeReport {
     pub total_duplicates: u64,
     pub deduplicatable: u64,
     pub estimated_saved: u64,
+    pub estimated_saved_bytes: u64,
 }
 
+#[deprecated(note = "use ProjectContext::check_dedupe")]
 pub fn check_dedupe(root: &Path) -> Result<DedupeReport, String> {
-    let report = analyze(root, false)?;
+    ProjectContext::new(root).check_dedupe()
+}
+
+impl ProjectContext {
+    /// Duplicate packages in the installed tree and what deduping them would save.
+    pub fn check_dedupe(&self) -> Result<DedupeReport, String> {
+        let report = analyze_node_modules(&self.node_modules, false, None)?;
+        Ok(dedupe_from_analyze(&report, Some(&self.lockfile)))
+    }
+}
+
+/// Build the dedupe report from an existing analyze pass, reusing its per-package
+/// sizes so no second walk of node_modules is needed.
+///
+/// With a readable `lockfile`, each single-major duplicate is checked against
+/// the ranges every requirer declares: the highest installed version satisfying
+/// all of them becomes `target_version`, and the entry is marked not dedupable
+/// when no installed version does.
+pub fn dedupe_from_analyze(report: &AnalyzeReport, lockfile: Option<&Path>) -> DedupeReport {
+    let content = lockfile.and_then(|p| fs::read_to_string(p).ok());
+    let graph = content.as_deref().and_then(|content| parse_lockfile_graph(content).ok());
+    // The project's own ranges bind too, and the graph leaves out its "" entry
+    let root_entry = content
+        .as_deref()
+        .and_then(lockfile_packages)
+        .and_then(|packages| packages.into_iter().find(|(key, _)| key.is_empty()))
+        .map(|(_, raw)| raw)
+        .unwrap_or_default();
+
     let mut entries = Vec::new();
     let mut total_dup = 0u64;
     let mut dedup_count = 0u64;
     let mut estimated_saved = 0u64;
+    let mut estimated_saved_bytes = 0u64;
+
+    for d in &report.duplicates {
+        let mut can_dedupe = d.majors.len() == 1;
+        let mut target_version = None;
+        if can_dedupe {
+            if let Some(graph) = &graph {
+                target_version = dedupe_target_version(graph, &root_entry, &d.name, &d.versions);
+                can_dedupe = target_version.is_some();
+            }
+        }
+        let saved = if can_dedupe { d.count.saturating_sub(1) } else { 0 };
+        let saved_bytes = if can_dedupe {
+            dedupe_saved_bytes(&report.packages, &d.name, target_version.as_deref())
+        } else {
+            0
+        };
+
+        total_dup += 1;
+        if can_dedupe { dedup_count += 1; }
+        estimated_saved += saved;
+        estimated_saved_bytes += saved_bytes;
+
+        entries.push(DedupeEntry {
+            name: d.name.clone(),
+            versions: d.versions.clone(),
+            instances: d.count,
+            can_dedupe,
+            saved_instances: saved,
+            saved_bytes,
+            target_version,
+        });
+    }
+
+    DedupeReport {
+        duplicates: entries,
+        total_duplicates: total_dup,
+        deduplicatable: dedup_count,
+        estimated_saved,
+        estimated_saved_bytes,
+    }
+}
+
+/// Highest of `versions` that satisfies every range the lockfile declares for
+/// `name`, in the project's own entry (`root_entry`, raw JSON) and in every package.
+fn dedupe_target_version(graph: &LockfileGraph, root_entry: &str, name: &str, versions: &[String]) -> Option<String> {
+    let mut ranges: Vec<String> = ["dependencies", "devDependencies", "optionalDependencies", "peerDependencies"]
+        .iter()
+        .filter_map(|section| dep_section(root_entry, section).remove(name))
+        .collect();
+    ranges.extend(
+        graph
+            .values()
+            .flat_map(|node| node.ranges.iter())
+            .filter(|(dep, _)| dep == name)
+            .map(|(_, range)| range.clone()),
+    );
+
+    versions
+        .iter()
+        .filter_map(|v| parse_semver(v).map(|sv| (sv, v)))
+        .filter(|(sv, _)| ranges.iter().all(|range| check_semver_range(sv, range)))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, v)| v.clone())
+}
+
+/// Bytes freed by keeping one copy of `keep` (the highest version when `None`)
+/// and dropping every other instance of `name`.
+fn dedupe_saved_bytes(packages: &[PackageOut], name: &str, keep: Option<&str>) -> u64 {
+    let instances: Vec<&PackageOut> = packages.iter().filter(|p| p.name == name).collect();
+    let total: u64 = instances.iter().map(|p| p.logical).sum();
+    let kept = match keep {
+        Some(version) => instances.iter().find(|p| p.version == version).copied(),
+        None => instances.iter().max_by(|a, b| {
+            let va = parse_semver(&a.version);
+            let vb = parse_semver(&b.version);
+            va.cmp(&vb)
+        }).copied(),
+    }
+    .map(|p| p.logical / (p.paths.len().max(1) as u64))
+    .unwrap_or(0);
+    total.saturating_sub(kept)
+}
+
+/// Write the dedupe `duplicates` array and `summary` object into an open JSON object.
+pub fn write_dedupe_fields(w: &mut JsonWriter, report: &DedupeReport) {
+    w.key("duplicates");
+    w.begin_array();
+    for d in &report.duplicates {
+        w.begin_object();
+        w.key("name");
+        w.value_string(&d.name);
+        w.key("versions");
+        w.begin_array();
+        for v in &d.versions {
+            w.value_string(v);
+        }
+        w.end_array();
+        w.key("instances");
+        w.value_u64(d.instances);
+        w.key("canDedupe");
+        w.value_bool(d.can_dedupe);
+        w.key("savedInstances");
+        w.value_u64(d.saved_instances);
+        w.key("savedBytes");
+        w.value_u64(d.saved_bytes);
+        w.key("targetVersion");
+        match &d.target_version {
+            Some(v) => w.value_string(v),
+            None => w.value_null(),
+        }
+        w.end_object();
+    }
+    w.end_array();
+    w.key("summary");
+    w.begin_object();
+    w.key("totalDuplicates");
+    w.value_u64(report.total_duplicates);
+    w.key("deduplicatable");
+    w.value_u64(report.deduplicatable);
+    w.key("estimatedSavedPackages");
+    w.value_u64(report.estimated_saved);
+    w.key("estimatedSavedBytes");
+    w.value_u64(report.estimated_saved_bytes);
+    w.end_object();
+}
 
-    for d in &report.duplicates {
-        let can_dedupe = d.majors.len() == 1;
-        let saved = if can_dedupe { d.count.saturating_sub(1) } else { 0 };
+// --- B.4: Dependency Tracer (why) ---
 
-        total_dup += 1;
-        if can_dedupe { dedup_count += 1; }
-        estimated_saved += saved;
+/// Which lockfile section a dependency edge comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepKind {
+    Prod,
+    Dev,
+    Optional,
+    Peer,
+    /// Peer dependency marked optional via `peerDependenciesMeta`
+    PeerOptional,
+}
 
-        entries.push(DedupeEntry {
-            name: d.name.clone(),
-            versions: d.versions.clone(),
-            instances: d.count,
-            can_dedupe,
-            saved_instances: saved,
-        });
+impl DepKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Prod => "prod",
+            Self::Dev => "dev",
+            Self::Optional => "optional",
+            Self::Peer => "peer",
+            Self::PeerOptional => "peerOptional",
+        }
     }
-
-    Ok(DedupeReport {
-        duplicates: entries,
-        total_duplicates: total_dup,
-        deduplicatable: dedup_count,
-        estimated_saved,
-    })
 }
 
-// --- B.4: Dependency Tracer (why) ---
-
 #[derive(Debug)]
 pub struct WhyReport {
     pub package: String,
     pub version: Option<String>,
     pub is_direct: bool,
     pub dependency_paths: Vec<Vec<String>>,
+    /// Edge kind for each hop of the matching `dependency_paths` entry
+    pub dependency_path_kinds: Vec<Vec<DepKind>>,
     pub depended_on_by: Vec<(String, String)>,
     pub total_paths: u64,
+    /// `<dependent> requires <range>` for each request that conflicts with
+    /// another, so no one installed version can serve both: why the package
+    /// is installed more than once instead of hoisted
+    pub hoist_blockers: Vec<String>,
 }
 
+#[deprecated(note = "use ProjectContext::trace_dependency")]
 pub fn trace_dependency(project_root: &Path, lockfile: &Path, target: &str) -> Result<WhyReport, String> {
-    let content = fs::read_to_string(lockfile)
-        .map_err(|e| format!("Failed to read lockfile: {}", e))?;
-
-    // Check if direct dependency
-    let pkg_json_path = project_root.join("package.json");
-    let pkg_json = fs::read_to_string(&pkg_json_path).unwrap_or_default();
-
-    // Look in dependencies and devDependencies
-    let is_direct = {
-        let dep_check = format!("\"{}\"", target);
-        let in_deps = if let Some(pos) = pkg_json.find("\"dependencies\"") {
-            let section = &pkg_json[pos..];
-            let end = section.find('}').unwrap_or(section.len());
-            section[..end].contains(&dep_check)
-        } else { false };
-        let in_dev = if let Some(pos) = pkg_json.find("\"devDependencies\"") {
-            let section = &pkg_json[pos..];
-            let end = section.find('}').unwrap_or(section.len());
-            section[..end].contains(&dep_check)
-        } else { false };
-        in_deps || in_dev
-    };
-
-    // Parse lockfile to build dependency graph
-    let graph = parse_lockfile_graph(&content)?;
+    ProjectContext::new(project_root).with_lockfile(lockfile).trace_dependency(target)
+}
+
+/// How many dependency paths `why` reports unless told otherwise.
+pub const WHY_DEFAULT_MAX_PATHS: usize = 10;
+
+impl ProjectContext {
+    /// The first [`WHY_DEFAULT_MAX_PATHS`] lockfile paths from the project root to `target`.
+    pub fn trace_dependency(&self, target: &str) -> Result<WhyReport, String> {
+        self.trace_dependency_limited(target, WHY_DEFAULT_MAX_PATHS)
+    }
+
+    /// Up to `max_paths` lockfile paths from the project root to `target`,
+    /// shortest first, each hop an installed `name@version`. Dependencies
+    /// resolve the way Node does from where their dependent is installed, so a
+    /// path never jumps to a version of a package its parent would not load.
+    pub fn trace_dependency_limited(&self, target: &str, max_paths: usize) -> Result<WhyReport, String> {
+        let project_root = &self.root;
+        let lockfile = &self.lockfile;
+        let content = fs::read_to_string(lockfile)
+            .map_err(|e| format!("Failed to read lockfile: {}", e))?;
+
+        // Direct if the lockfile root declares it; package.json only stands in
+        // for lockfiles without a root entry
+        let root_entry = lockfile_packages(&content)
+            .and_then(|packages| packages.into_iter().find(|(key, _)| key.is_empty()))
+            .map(|(_, raw)| raw)
+            .or_else(|| fs::read_to_string(project_root.join("package.json")).ok())
+            .unwrap_or_default();
+        let is_direct = ["dependencies", "devDependencies", "optionalDependencies", "peerDependencies"]
+            .iter()
+            .any(|section| dep_section(&root_entry, section).contains_key(target));
 
-    // Find target version
-    let target_version = graph.iter()
-        .find(|(_, (name, _, _))| name == target)
-        .map(|(_, (_, ver, _))| ver.clone());
+        // Parse lockfile to build dependency graph
+        let graph = parse_lockfile_graph(&content)?;
 
-    // Find all packages that depend on target
-    let mut depended_on_by = Vec::new();
-    for (_, (name, version, deps)) in &graph {
-        if deps.iter().any(|d| d == target) {
-            depended_on_by.push((name.clone(), version.clone()));
+        // A workspace link stands for the workspace package it points at
+        let follow_link = |key: String| match graph.get(&key).and_then(|node| node.link.as_ref()) {
+            Some(target) if graph.contains_key(target) => target.clone(),
+            _ => key,
+        };
+        let label = |key: &str| format!("{}@{}", graph[key].name, graph[key].version);
+
+        // Edges between lockfile entries, each dependency resolved the way
+        // Node would from where its dependent is installed
+        let mut adj: HashMap<&str, Vec<(String, DepKind)>> = HashMap::new();
+        let mut root_deps: Vec<(String, DepKind)> = Vec::new();
+        for (path, node) in &graph {
+            // Hoisted entries ("node_modules/foo", no nested node_modules) hang off the root
+            if let Some(rest) = path.strip_prefix("node_modules/") {
+                if !rest.contains("node_modules/") {
+                    root_deps.push((follow_link(path.clone()), node.root_edge_kind()));
+                }
+            }
+            let deps = node.deps.iter().filter_map(|(name, kind)| {
+                resolve_lockfile_key(path, name, |key| graph.contains_key(key)).map(|key| (follow_link(key), *kind))
+            });
+            adj.entry(path.as_str()).or_default().extend(deps);
+        }
+        root_deps.sort_by(|a, b| a.0.cmp(&b.0));
+        for deps in adj.values_mut() {
+            deps.sort_by(|a, b| a.0.cmp(&b.0));
         }
-    }
 
-    // Build adjacency map: name -> [dep_names]
-    let mut adj: HashMap<String, Vec<String>> = HashMap::new();
-    let mut root_deps: Vec<String> = Vec::new();
-    for (path, (name, _, deps)) in &graph {
-        // Direct deps: paths like "node_modules/foo" (no nested node_modules)
-        let segments: Vec<&str> = path.split("node_modules/").filter(|s| !s.is_empty()).collect();
-        if segments.len() == 1 {
-            root_deps.push(name.clone());
+        // The hoisted copy's version, else the shallowest
+        let target_version = graph
+            .iter()
+            .filter(|(_, node)| node.name == target)
+            .min_by_key(|(path, _)| (path.matches("node_modules/").count(), path.as_str()))
+            .map(|(_, node)| node.version.clone());
+
+        let mut dependents: BTreeSet<(String, String)> = BTreeSet::new();
+        for (path, deps) in &adj {
+            if deps.iter().any(|(dep, _)| graph[dep].name == target) {
+                dependents.insert((graph[*path].name.clone(), graph[*path].version.clone()));
+            }
         }
-        adj.entry(name.clone()).or_default().extend(deps.clone());
-    }
-    adj.insert("(root)".to_string(), root_deps);
+        let depended_on_by: Vec<(String, String)> = dependents.into_iter().collect();
 
-    // BFS to find paths from root to target (limit to 10)
-    let mut paths: Vec<Vec<String>> = Vec::new();
-    let mut queue: VecDeque<Vec<String>> = VecDeque::new();
-    queue.push_back(vec!["(root)".to_string()]);
+        // BFS over entries to find paths from the root to target, shortest first
+        const ROOT: &str = "";
+        let mut found: Vec<Vec<&str>> = Vec::new();
+        let mut path_kinds: Vec<Vec<DepKind>> = Vec::new();
+        let mut queue: VecDeque<(Vec<&str>, Vec<DepKind>)> = VecDeque::new();
+        queue.push_back((vec![ROOT], Vec::new()));
 
-    while let Some(path) = queue.pop_front() {
-        if paths.len() >= 10 { break; }
-        if path.len() > 10 { continue; }
+        while let Some((path, kinds)) = queue.pop_front() {
+            if found.len() >= max_paths { break; }
+            if path.len() > 10 { continue; }
 
-        let current = path.last().unwrap().clone();
-        if let Some(deps) = adj.get(&current) {
-            for dep in deps {
+            let current = *path.last().unwrap();
+            let deps = if current == ROOT { root_deps.as_slice() } else { adj.get(current).map(Vec::as_slice).unwrap_or_default() };
+            for (dep, kind) in deps {
                 let mut new_path = path.clone();
-                new_path.push(dep.clone());
-                if dep == target {
-                    paths.push(new_path);
-                } else if !path.contains(dep) {
-                    queue.push_back(new_path);
+                new_path.push(dep.as_str());
+                let mut new_kinds = kinds.clone();
+                new_kinds.push(*kind);
+                if graph[dep].name == target {
+                    found.push(new_path);
+                    path_kinds.push(new_kinds);
+                } else if !path.contains(&dep.as_str()) {
+                    queue.push_back((new_path, new_kinds));
+                }
+            }
+        }
+        found.truncate(max_paths);
+        path_kinds.truncate(max_paths);
+        let paths: Vec<Vec<String>> = found
+            .iter()
+            .map(|path| path.iter().map(|&key| if key == ROOT { "(root)".to_string() } else { label(key) }).collect())
+            .collect();
+
+        // Requests no single installed version satisfies together are what keep
+        // the package from being hoisted once; registry-less specs (aliases,
+        // git, file:) are left out
+        let versions: Vec<SemVer> = graph.values().filter(|node| node.name == target).filter_map(|node| parse_semver(&node.version)).collect();
+        let mut requests: Vec<(String, String)> = ["dependencies", "devDependencies", "optionalDependencies", "peerDependencies"]
+            .iter()
+            .filter_map(|section| dep_section(&root_entry, section).remove(target))
+            .map(|range| ("(root)".to_string(), range))
+            .collect();
+        let mut dependent_keys: Vec<&String> = graph.keys().collect();
+        dependent_keys.sort();
+        for key in dependent_keys {
+            for (name, range) in &graph[key].ranges {
+                if name == target && !requests.iter().any(|(by, r)| *by == label(key) && r == range) {
+                    requests.push((label(key), range.clone()));
+                }
+            }
+        }
+        requests.retain(|(_, range)| !range.contains(':'));
+        let mut hoist_blockers: Vec<String> = Vec::new();
+        for (i, (by_a, a)) in requests.iter().enumerate() {
+            for (by_b, b) in &requests[i + 1..] {
+                if versions.iter().any(|v| check_semver_range(v, a) && check_semver_range(v, b)) {
+                    continue;
+                }
+                for blocker in [format!("{} requires {}", by_a, a), format!("{} requires {}", by_b, b)] {
+                    if !hoist_blockers.contains(&blocker) {
+                        hoist_blockers.push(blocker);
+                    }
                 }
             }
         }
+
+        let total = paths.len() as u64;
+        Ok(WhyReport {
+            package: target.to_string(),
+            version: target_version,
+            is_direct,
+            dependency_paths: paths,
+            dependency_path_kinds: path_kinds,
+            depended_on_by,
+            total_paths: total,
+            hoist_blockers,
+        })
     }
+}
 
-    let total = paths.len() as u64;
-    Ok(WhyReport {
-        package: target.to_string(),
-        version: target_version,
-        is_direct,
-        dependency_paths: paths,
-        depended_on_by,
-        total_paths: total,
-    })
+/// One `packages` entry of a lockfile, as seen by the graph commands
+#[derive(Debug, Clone)]
+struct LockfileNode {
+    name: String,
+    version: String,
+    /// Declared dependencies across `dependencies`, `optionalDependencies` and `peerDependencies`
+    deps: Vec<(String, DepKind)>,
+    dev: bool,
+    optional: bool,
+    peer: bool,
+    /// Reachable only through dev or optional edges (npm's `devOptional`)
+    dev_optional: bool,
+    /// Declared `(name, range)` requirements, dev dependencies included
+    ranges: Vec<(String, String)>,
+    /// For a workspace link (`"link": true`), the key of the entry it points at
+    link: Option<String>,
+}
+
+impl LockfileNode {
+    /// Kind of the edge from the project root to a hoisted (top-level) entry, from its lockfile flags
+    fn root_edge_kind(&self) -> DepKind {
+        if self.peer && self.optional {
+            DepKind::PeerOptional
+        } else if self.peer {
+            DepKind::Peer
+        } else if self.optional {
+            DepKind::Optional
+        } else if self.dev {
+            DepKind::Dev
+        } else {
+            DepKind::Prod
+        }
+    }
 }
 
-fn parse_lockfile_graph(json: &str) -> Result<HashMap<String, (String, String, Vec<String>)>, String> {
-    let mut graph = HashMap::new();
+/// Lockfile path -> entry
+type LockfileGraph = HashMap<String, LockfileNode>;
 
-    let packages_start = json.find("\"packages\"")
-        .ok_or_else(|| "Missing packages in lockfile".to_string())?;
-    let after = &json[packages_start..];
-    let obj_start = after.find('{').ok_or_else(|| "Malformed lockfile".to_string())?;
-    let packages_str = &after[obj_start..];
-
-    let mut current_key = String::new();
-    let mut in_string = false;
-    let mut escape_next = false;
-    let mut brace_depth = 0i32;
-    let mut collecting_entry = false;
-    let mut entry_data = String::new();
-    let mut key_state = 0u8;
-
-    for ch in packages_str.chars() {
-        if escape_next {
-            if key_state == 1 { current_key.push(ch); }
-            else if collecting_entry { entry_data.push(ch); }
-            escape_next = false;
-            continue;
-        }
-        if ch == '\\' && in_string {
-            escape_next = true;
-            if key_state == 1 { current_key.push(ch); }
-            else if collecting_entry { entry_data.push(ch); }
-            continue;
-        }
-        if ch == '"' {
-            in_string = !in_string;
-            if brace_depth == 1 && !collecting_entry {
-                if key_state == 0 && in_string { key_state = 1; current_key.clear(); }
-                else if key_state == 1 && !in_string { key_state = 2; }
-            } else if collecting_entry { entry_data.push(ch); }
-            continue;
-        }
-        if in_string {
-            if key_state == 1 { current_key.push(ch); }
-            else if collecting_entry { entry_data.push(ch); }
-            continue;
+/// Node resolution over lockfile keys: the nearest `node_modules/<name>`
+/// walking up from the entry at `from` for which `exists` holds.
+fn resolve_lockfile_key(from: &str, name: &str, exists: impl Fn(&str) -> bool) -> Option<String> {
+    let mut dir = from;
+    loop {
+        if !dir.ends_with("node_modules") {
+            let key = if dir.is_empty() { format!("node_modules/{}", name) } else { format!("{}/node_modules/{}", dir, name) };
+            if exists(&key) {
+                return Some(key);
+            }
         }
-        if ch == '{' {
-            brace_depth += 1;
-            if brace_depth == 2 && !current_key.is_empty() {
-                collecting_entry = true;
-                entry_data.clear();
-                key_state = 0;
-            }
-            if collecting_entry && brace_depth > 2 { entry_data.push(ch); }
-        } else if ch == '}' {
-            if collecting_entry && brace_depth == 2 {
-                let name = extract_json_field(&entry_data, "name")
-                    .unwrap_or_else(|| package_name_from_path(&current_key));
-                let version = extract_json_field(&entry_data, "version").unwrap_or_default();
-                let deps = extract_dep_names(&entry_data);
-
-                if !current_key.is_empty() {
-                    graph.insert(current_key.clone(), (name, version, deps));
-                }
-
-                collecting_entry = false;
-                entry_data.clear();
-            } else if collecting_entry { entry_data.push(ch); }
-            brace_depth -= 1;
-            if brace_depth == 0 { break; }
-            if brace_depth == 1 { key_state = 0; }
-        } else if ch == ',' && brace_depth == 1 && !collecting_entry {
-            key_state = 0;
-        } else if collecting_entry {
-            entry_data.push(ch);
+        if dir.is_empty() {
+            return None;
         }
+        dir = dir.rfind('/').map(|i| &dir[..i]).unwrap_or("");
     }
+}
+
+fn parse_lockfile_graph(json: &str) -> Result<LockfileGraph, String> {
+    let doc = JsonValue::parse(json).map_err(|e| format!("Malformed lockfile: {}", e))?;
+    let entries = doc
+        .get("packages")
+        .and_then(JsonValue::as_object)
+        .ok_or_else(|| "Missing packages in lockfile".to_string())?;
 
+    let mut graph = HashMap::new();
+    // The root "" entry is the project itself, not a node
+    for (key, entry) in entries.iter().filter(|(key, _)| !key.is_empty()) {
+        let ranges = ["dependencies", "optionalDependencies", "peerDependencies", "devDependencies"]
+            .iter()
+            .flat_map(|section| dep_specs(entry, section))
+            .collect();
+        graph.insert(key.clone(), LockfileNode {
+            name: entry_string(entry, "name").unwrap_or_else(|| package_name_from_path(key)),
+            version: entry_string(entry, "version").unwrap_or_default(),
+            deps: extract_dep_names(entry),
+            dev: entry_flag(entry, "dev"),
+            optional: entry_flag(entry, "optional"),
+            peer: entry_flag(entry, "peer"),
+            dev_optional: entry_flag(entry, "devOptional"),
+            ranges,
+            link: entry_string(entry, "resolved").filter(|_| entry_flag(entry, "link")),
+        });
+    }
     Ok(graph)
 }
 
-fn extract_dep_names(entry_json: &str) -> Vec<String> {
-    let needle = "\"dependencies\"";
-    let start = match entry_json.find(needle) {
-        Some(pos) => pos,
-        None => return Vec::new(),
-    };
-    let after = &entry_json[start + needle.len()..];
-    let obj_start = match after.find('{') {
-        Some(pos) => pos,
-        None => return Vec::new(),
-    };
-    let section = &after[obj_start..];
-
-    let mut names = Vec::new();
-    let mut depth = 0i32;
-    let mut in_str = false;
-    let mut esc = false;
-    let mut current = String::new();
-    let mut reading_key = false;
-    let mut key_done = false;
+/// `(name, spec)` pairs of one dependency section of a lockfile entry.
+fn dep_specs(entry: &JsonValue, section: &str) -> Vec<(String, String)> {
+    entry
+        .get(section)
+        .and_then(JsonValue::as_object)
+        .map(|deps| deps.iter().filter_map(|(name, spec)| Some((name.clone(), spec.as_str()?.to_string()))).collect())
+        .unwrap_or_default()
+}
 
-    for ch in section.chars() {
-        if esc { if reading_key { current.push(ch); } esc = false; continue; }
-        if ch == '\\' && in_str { esc = true; continue; }
-        if ch == '"' {
-            in_str = !in_str;
-            if depth == 1 {
-                if !key_done && in_str { reading_key = true; current.clear(); }
-                else if reading_key && !in_str {
-                    reading_key = false; key_done = true;
-                    if !current.is_empty() { names.push(current.clone()); }
-                    current.clear();
-                }
-                else if key_done && !in_str { key_done = false; }
-            }
-            continue;
+/// Dependency names of a lockfile entry, tagged with the section they were declared in.
+/// Peers listed as optional in `peerDependenciesMeta` are tagged `PeerOptional`.
+fn extract_dep_names(entry: &JsonValue) -> Vec<(String, DepKind)> {
+    let names = |section: &str| -> Vec<String> {
+        entry.get(section).and_then(JsonValue::as_object).map(|deps| deps.keys().cloned().collect()).unwrap_or_default()
+    };
+    let mut deps: Vec<(String, DepKind)> = Vec::new();
+    for name in names("dependencies") {
+        deps.push((name, DepKind::Prod));
+    }
+    for name in names("optionalDependencies") {
+        if !deps.iter().any(|(n, _)| n == &name) {
+            deps.push((name, DepKind::Optional));
         }
-        if in_str { if reading_key { current.push(ch); } continue; }
-        match ch {
-            '{' => depth += 1,
-            '}' => { depth -= 1; if depth == 0 { break; } }
-            ',' if depth == 1 => { key_done = false; }
-            _ => {}
+    }
+    for name in names("peerDependencies") {
+        if deps.iter().any(|(n, _)| n == &name) {
+            continue;
         }
+        let optional = entry
+            .get("peerDependenciesMeta")
+            .and_then(|meta| meta.get(&name))
+            .is_some_and(|meta| entry_flag(meta, "optional"));
+        deps.push((name, if optional { DepKind::PeerOptional } else { DepKind::Peer }));
     }
-    names
+    deps
 }
 
 // --- B.5: Outdated Checker ---
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 struct SemVer {
     major: u64,
     minor: u64,
     patch: u64,
+    /// Dot-separated identifiers after `-`; empty for a release
+    prerelease: Vec<Identifier>,
+}
+
+/// One pre-release identifier. Numeric ones sort numerically and before
+/// alphanumeric ones, which sort as ASCII (semver 2.0.0, section 11).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    Numeric(u64),
+    Alpha(String),
+}
+
+impl SemVer {
+    fn triple(&self) -> (u64, u64, u64) {
+        (self.major, self.minor, self.patch)
+    }
+}
+
+impl Ord for SemVer {
+    /// Semver precedence: a pre-release sorts below its release, and
+    /// pre-releases compare identifier by identifier, a shorter list first
+    /// when one is a prefix of the other.
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.triple().cmp(&other.triple()).then_with(|| match (self.prerelease.is_empty(), other.prerelease.is_empty()) {
+            (true, true) => std::cmp::Ordering::Equal,
+            (true, false) => std::cmp::Ordering::Greater,
+            (false, true) => std::cmp::Ordering::Less,
+            (false, false) => self.prerelease.cmp(&other.prerelease),
+        })
+    }
+}
+
+impl PartialOrd for SemVer {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
+/// Parse `X.Y.Z[-pre]`, ignoring a leading `v` and any `+build` metadata.
 fn parse_semver(v: &str) -> Option<SemVer> {
     let v = v.trim_start_matches('v');
-    let parts: Vec<&str> = v.split('.').collect();
+    let v = v.split_once('+').map_or(v, |(v, _build)| v);
+    let (core, prerelease) = match v.split_once('-') {
+        Some((core, pre)) => (core, Some(pre)),
+        None => (v, None),
+    };
+    let parts: Vec<&str> = core.split('.').collect();
     if parts.len() < 3 { return None; }
+    let prerelease = match prerelease {
+        Some(pre) => pre
+            .split('.')
+            .map(|id| match id {
+                "" => None,
+                _ if id.bytes().all(|b| b.is_ascii_digit()) => id.parse().ok().map(Identifier::Numeric),
+                _ => Some(Identifier::Alpha(id.to_string())),
+            })
+            .collect::<Option<Vec<_>>>()?,
+        None => Vec::new(),
+    };
     Some(SemVer {
         major: parts[0].parse().ok()?,
         minor: parts[1].parse().ok()?,
-        patch: parts[2].split('-').next()?.parse().ok()?,
+        patch: parts[2].parse().ok()?,
+        prerelease,
     })
 }
 
+/// A version in a range that may leave out or wildcard its minor and patch
+/// (`1`, `1.x`, `1.2.*`); `None` parts match anything.
+struct PartialVersion {
+    major: Option<u64>,
+    minor: Option<u64>,
+    patch: Option<u64>,
+    prerelease: Vec<Identifier>,
+}
+
+impl PartialVersion {
+    fn parse(v: &str) -> Option<PartialVersion> {
+        let v = v.trim_start_matches('v');
+        let core = v.split(['-', '+']).next().unwrap_or("");
+        let parts: Vec<&str> = core.split('.').collect();
+        if core.is_empty() || parts.len() > 3 { return None; }
+        let mut nums = [None; 3];
+        for (slot, part) in nums.iter_mut().zip(&parts) {
+            match *part {
+                "x" | "X" | "*" => break,
+                _ => *slot = Some(part.parse().ok()?),
+            }
+        }
+        // Only a complete version carries a pre-release
+        let prerelease = match nums[2] {
+            Some(_) => parse_semver(v)?.prerelease,
+            None => Vec::new(),
+        };
+        Some(PartialVersion { major: nums[0], minor: nums[1], patch: nums[2], prerelease })
+    }
+
+    /// The lowest version in range, with missing parts as 0.
+    fn floor(&self) -> SemVer {
+        SemVer {
+            major: self.major.unwrap_or(0),
+            minor: self.minor.unwrap_or(0),
+            patch: self.patch.unwrap_or(0),
+            prerelease: self.prerelease.clone(),
+        }
+    }
+
+    /// The exclusive upper bound of a partial version (`1.2` → `1.3.0-0`),
+    /// None when it is complete or has no bound at all (`*`).
+    fn ceiling(&self) -> Option<SemVer> {
+        let (major, minor) = match (self.major, self.minor, self.patch) {
+            (Some(_), Some(_), Some(_)) | (None, _, _) => return None,
+            (Some(major), None, _) => (major + 1, 0),
+            (Some(major), Some(minor), None) => (major, minor + 1),
+        };
+        Some(SemVer { major, minor, patch: 0, prerelease: vec![Identifier::Numeric(0)] })
+    }
+}
+
 /// Check if a version satisfies a semver constraint string.
-/// Supports: >=X.Y.Z, >X.Y.Z, <=X.Y.Z, <X.Y.Z, ^X.Y.Z (same major), ~X.Y.Z (same major.minor), exact.
+/// Supports: >=X.Y.Z, >X.Y.Z, <=X.Y.Z, <X.Y.Z, ^X.Y.Z (same major), ~X.Y.Z (same major.minor), exact,
+/// x-ranges (`1.x`, `1.2.*`, `1`), hyphen ranges (`1.2.0 - 1.3.0`),
+/// space-separated comparators that must all hold, and `||` alternatives.
+/// `*`, `latest` and an empty constraint match anything; `+build` metadata is ignored.
+/// As with npm, a pre-release version only satisfies a set of comparators
+/// that names a pre-release of the same major.minor.patch, so `^1.0.0` does
+/// not take `1.1.0-beta.1` while `>=1.1.0-beta.0 <2.0.0` does.
 fn check_semver_range(version: &SemVer, constraint: &str) -> bool {
     let constraint = constraint.trim();
-    if constraint.is_empty() { return true; }
+    if matches!(constraint, "" | "*" | "latest") { return true; }
     // Handle || (OR) ranges
     if constraint.contains("||") {
         return constraint.split("||").any(|part| check_semver_range(version, part.trim()));
     }
-    // Handle space-separated (AND) ranges
-    if constraint.contains(' ') && !constraint.starts_with('>') && !constraint.starts_with('<') && !constraint.starts_with('^') && !constraint.starts_with('~') {
-        let parts: Vec<&str> = constraint.split_whitespace().collect();
-        if parts.len() >= 2 { return parts.iter().all(|p| check_semver_range(version, p)); }
-    }
-    if let Some(rest) = constraint.strip_prefix(">=") {
-        if let Some(req) = parse_semver(rest.trim()) {
-            return (version.major, version.minor, version.patch) >= (req.major, req.minor, req.patch);
-        }
-    } else if let Some(rest) = constraint.strip_prefix('>') {
-        if let Some(req) = parse_semver(rest.trim()) {
-            return (version.major, version.minor, version.patch) > (req.major, req.minor, req.patch);
-        }
-    } else if let Some(rest) = constraint.strip_prefix("<=") {
-        if let Some(req) = parse_semver(rest.trim()) {
-            return (version.major, version.minor, version.patch) <= (req.major, req.minor, req.patch);
-        }
-    } else if let Some(rest) = constraint.strip_prefix('<') {
-        if let Some(req) = parse_semver(rest.trim()) {
-            return (version.major, version.minor, version.patch) < (req.major, req.minor, req.patch);
-        }
-    } else if let Some(rest) = constraint.strip_prefix('^') {
-        if let Some(req) = parse_semver(rest.trim()) {
-            return version.major == req.major
-                && (version.major, version.minor, version.patch) >= (req.major, req.minor, req.patch);
+    let words: Vec<&str> = constraint.split_whitespace().collect();
+    let comparators: Vec<String> = if let [lower, "-", upper] = words[..] {
+        vec![format!(">={lower}"), format!("<={upper}")]
+    } else {
+        // Space-separated (AND) comparators; an operator written apart from its
+        // version (">= 1.2.3") belongs to the next word
+        let mut comparators = Vec::new();
+        let mut pending = String::new();
+        for word in words {
+            pending.push_str(word);
+            if !word.bytes().all(|b| matches!(b, b'<' | b'>' | b'=' | b'^' | b'~')) {
+                comparators.push(std::mem::take(&mut pending));
+            }
         }
-    } else if let Some(rest) = constraint.strip_prefix('~') {
-        if let Some(req) = parse_semver(rest.trim()) {
-            return version.major == req.major && version.minor == req.minor && version.patch >= req.patch;
+        comparators
+    };
+    if !version.prerelease.is_empty() {
+        let names_prerelease = comparators.iter().any(|c| {
+            parse_semver(c.trim_start_matches(['<', '>', '=', '^', '~']))
+                .is_some_and(|req| !req.prerelease.is_empty() && req.triple() == version.triple())
+        });
+        if !names_prerelease {
+            return false;
         }
-    } else if let Some(req) = parse_semver(constraint) {
-        return version.major == req.major && version.minor == req.minor && version.patch == req.patch;
     }
-    true // unparseable constraint → pass
+    comparators.iter().all(|c| check_semver_comparator(version, c))
 }
 
+/// One comparator of a [`check_semver_range`] constraint. A partial version
+/// covers every version it matches: `>1.2` is `>=1.3.0-0`, `<=1.2` is
+/// `<1.3.0-0` and a bare `1.2` is both `>=1.2.0` and `<1.3.0-0`.
+fn check_semver_comparator(version: &SemVer, constraint: &str) -> bool {
+    let op_len = constraint.len() - constraint.trim_start_matches(['<', '>', '=', '^', '~']).len();
+    let (op, rest) = constraint.split_at(op_len);
+    let Some(req) = PartialVersion::parse(rest.trim()) else {
+        return true; // unparseable constraint → pass
+    };
+    let floor = req.floor();
+    let ceiling = req.ceiling();
+    match op {
+        ">=" => *version >= floor,
+        ">" if req.patch.is_some() => *version > floor,
+        ">" => ceiling.is_some_and(|c| *version >= c),
+        "<=" if req.patch.is_some() => *version <= floor,
+        "<=" => ceiling.is_none_or(|c| *version < c),
+        "<" => *version < floor,
+        "^" => req.major.is_none() || (version.major == floor.major && *version >= floor),
+        "~" if req.patch.is_some() => version.major == floor.major && version.minor == floor.minor && *version >= floor,
+        "" | "=" | "~" if req.patch.is_some() => *version == floor,
+        "" | "=" | "~" => *version >= floor && ceiling.is_none_or(|c| *version < c),
+        _ => true,
+    }
+}
+
+/// How big a step `latest` is from `current`: "major", "minor" or "patch" by
+/// the first part that grew, "prerelease" between versions that differ only
+/// there (`1.0.0-rc.1` to `1.0.0`), or "current" when `latest` is not newer.
 fn classify_update(current: &SemVer, latest: &SemVer) -> &'static str {
-    if latest.major > current.major { "major" }
-    else if latest.minor > current.minor { "minor" }
-    else if latest.patch > current.patch { "patch" }
-    else { "current" }
+    if latest <= current { "current" }
+    else if latest.major != current.major { "major" }
+    else if latest.minor != current.minor { "minor" }
+    else if latest.patch != current.patch { "patch" }
+    else { "prerelease" }
 }
 
 #[derive(Debug, Clone)]
@@ -3429,9 +7265,31 @@ pub struct OutdatedEntry {
     pub name: String,
     pub current: String,
     pub latest: String,
+    /// With [`ProjectContext::check_outdated_wanted`], the newest published
+    /// version the declared range allows; `update_type` is then against this
+    pub wanted: Option<String>,
     pub update_type: String,
 }
 
+/// The version npm would install for `range` from the packument `body`:
+/// `latest` when it satisfies the range, else the newest published version
+/// that does (pre-releases only when the range names one). Falls back to `latest` when nothing does (a tag or URL spec).
+fn wanted_version(body: &str, range: &str, latest: &str) -> Result<String, String> {
+    let satisfies = |v: &str| parse_semver(v).is_some_and(|sv| check_semver_range(&sv, range));
+    if satisfies(latest) {
+        return Ok(latest.to_string());
+    }
+    let doc = JsonValue::parse(body).map_err(|e| format!("malformed metadata: {}", e))?;
+    let versions = doc.get("versions").and_then(JsonValue::as_object).ok_or_else(|| "malformed metadata: missing versions".to_string())?;
+    let newest = versions
+        .keys()
+        .filter(|v| satisfies(v))
+        .filter_map(|v| parse_semver(v).map(|sv| (sv, v)))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, v)| v.clone());
+    Ok(newest.unwrap_or_else(|| latest.to_string()))
+}
+
 #[derive(Debug)]
 pub struct OutdatedReport {
     pub packages: Vec<OutdatedEntry>,
@@ -3440,84 +7298,258 @@ pub struct OutdatedReport {
     pub major: u64,
     pub minor: u64,
     pub patch: u64,
+    /// `(name, reason)` for packages whose registry metadata could not be
+    /// fetched or read; these are neither outdated nor known to be current
+    pub errors: Vec<(String, String)>,
+}
+
+impl OutdatedReport {
+    /// Reorder `packages` by name or by update type (major, minor, patch,
+    /// unknown; then name). `Size` has no meaning here and keeps name order.
+    pub fn sort_packages(&mut self, key: SortKey) {
+        let rank = |t: &str| match t {
+            "major" => 0,
+            "minor" => 1,
+            "patch" => 2,
+            _ => 3,
+        };
+        match key {
+            SortKey::Name => self.packages.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortKey::Type => self.packages.sort_by(|a, b| {
+                rank(&a.update_type).cmp(&rank(&b.update_type)).then_with(|| a.name.cmp(&b.name))
+            }),
+            SortKey::Size => {}
+        }
+    }
 }
 
-pub fn check_outdated(_project_root: &Path, lockfile: &Path) -> Result<OutdatedReport, String> {
-    use rayon::prelude::*;
+pub const METADATA_CACHE_DEFAULT_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+const METADATA_CACHE_HEADER: &str = "better-registry v1";
+
+/// Registry metadata kept between runs under
+/// `<cache_root>/registry/<host>/<name>.json`. An entry younger than `ttl` is
+/// used without a request; an older one is revalidated with its `ETag` and
+/// `Last-Modified`, and a 304 keeps it for another `ttl`.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    pub dir: PathBuf,
+    pub ttl: std::time::Duration,
+}
 
-    // Get packages from lockfile
-    let resolve_result = resolve_from_lockfile(lockfile)?;
+/// A cached response: a `header<TAB>fetched<TAB>etag<TAB>last-modified`
+/// line, with `fetched` in seconds since the epoch, then the body as served.
+struct MetadataEntry {
+    fetched: u64,
+    etag: String,
+    last_modified: String,
+    body: String,
+}
 
-    // Deduplicate by name (only check each package once)
-    let mut unique: HashMap<String, String> = HashMap::new();
-    for pkg in &resolve_result.packages {
-        unique.entry(pkg.name.clone()).or_insert_with(|| pkg.version.clone());
+impl MetadataCache {
+    pub fn new(cache_root: &Path, ttl: std::time::Duration) -> Self {
+        MetadataCache { dir: CasLayout::new(&long_path(cache_root)).registry_dir, ttl }
     }
-    let pkg_list: Vec<(String, String)> = unique.into_iter().collect();
 
-    let agent = ureq::AgentBuilder::new()
-        .timeout(std::time::Duration::from_secs(10))
-        .build();
+    /// `http://host:port/@scope%2Fname` → `<dir>/host_port/@scope%2Fname.json`;
+    /// a registry with a path keeps it as directories under the host.
+    fn entry_path(&self, url: &str) -> PathBuf {
+        let mut path = self.dir.clone();
+        for segment in strip_url_scheme(url).split('/').filter(|s| !matches!(*s, "" | "." | "..")) {
+            path.push(segment.replace(':', "_"));
+        }
+        path.with_extension("json")
+    }
 
-    // Fetch latest versions in parallel
-    let results: Vec<Option<OutdatedEntry>> = pkg_list.par_iter().map(|(name, current_version)| {
-        let url = if name.starts_with('@') {
-            format!("https://registry.npmjs.org/{}", name.replace('/', "%2F"))
-        } else {
-            format!("https://registry.npmjs.org/{}", name)
-        };
+    fn read(&self, path: &Path) -> Option<MetadataEntry> {
+        let content = fs::read_to_string(path).ok()?;
+        let (header, body) = content.split_once('\n')?;
+        let mut fields = header.split('\t');
+        if fields.next()? != METADATA_CACHE_HEADER {
+            return None;
+        }
+        Some(MetadataEntry {
+            fetched: fields.next()?.parse().ok()?,
+            etag: fields.next()?.to_string(),
+            last_modified: fields.next()?.to_string(),
+            body: body.to_string(),
+        })
+    }
 
-        let resp = match agent.get(&url).call() {
-            Ok(r) => r,
-            Err(_) => return None,
-        };
-        let body = match resp.into_string() {
-            Ok(b) => b,
-            Err(_) => return None,
-        };
+    /// Best effort, like the resolve cache: a failed write only costs a
+    /// request next time.
+    fn write(&self, path: &Path, entry: &MetadataEntry) {
+        let clean = |v: &str| if v.contains(['\t', '\n']) { String::new() } else { v.to_string() };
+        let content = format!(
+            "{}\t{}\t{}\t{}\n{}",
+            METADATA_CACHE_HEADER, entry.fetched, clean(&entry.etag), clean(&entry.last_modified), entry.body
+        );
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let tmp = TmpFileGuard::beside(path);
+        if fs::write(tmp.path(), content).is_ok() && fs::rename(tmp.path(), path).is_ok() {
+            tmp.keep();
+        }
+    }
+}
 
-        // Extract dist-tags.latest
-        let dist_tags_pos = match body.find("\"dist-tags\"") {
-            Some(p) => p,
-            None => return None,
-        };
-        let dist_section = &body[dist_tags_pos..];
-        let latest = match extract_json_field(dist_section, "latest") {
-            Some(v) => v,
-            None => return None,
-        };
+fn unix_secs_now() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
 
-        if latest == *current_version {
-            return None;
+/// GET registry metadata at `url`, through `cache` when given.
+fn fetch_registry_metadata(agent: &ureq::Agent, url: &str, token: Option<&str>, cache: Option<&MetadataCache>) -> Result<String, String> {
+    let path = cache.map(|c| c.entry_path(url));
+    let cached = cache.zip(path.as_deref()).and_then(|(c, path)| c.read(path));
+    if let (Some(cache), Some(entry)) = (cache, &cached) {
+        if unix_secs_now().saturating_sub(entry.fetched) < cache.ttl.as_secs() {
+            return Ok(entry.body.clone());
         }
+    }
 
-        let current_sv = parse_semver(current_version);
-        let latest_sv = parse_semver(&latest);
-        let update_type = match (current_sv.as_ref(), latest_sv.as_ref()) {
-            (Some(c), Some(l)) => classify_update(c, l).to_string(),
-            _ => "unknown".to_string(),
-        };
+    let mut request = agent.get(url);
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    if let Some(entry) = &cached {
+        if !entry.etag.is_empty() {
+            request = request.set("If-None-Match", &entry.etag);
+        }
+        if !entry.last_modified.is_empty() {
+            request = request.set("If-Modified-Since", &entry.last_modified);
+        }
+    }
+    let resp = request.call().map_err(|e| format!("registry request failed: {}", redact_url_credentials(&e.to_string())))?;
+    let fresh = match (resp.status(), cached) {
+        (304, Some(entry)) => MetadataEntry { fetched: unix_secs_now(), ..entry },
+        _ => MetadataEntry {
+            fetched: unix_secs_now(),
+            etag: resp.header("ETag").unwrap_or("").to_string(),
+            last_modified: resp.header("Last-Modified").unwrap_or("").to_string(),
+            body: resp.into_string().map_err(|e| format!("failed to read registry response: {}", e))?,
+        },
+    };
+    if let (Some(cache), Some(path)) = (cache, &path) {
+        cache.write(path, &fresh);
+    }
+    Ok(fresh.body)
+}
 
-        if update_type == "current" { return None; }
+#[deprecated(note = "use ProjectContext::check_outdated")]
+pub fn check_outdated(project_root: &Path, lockfile: &Path) -> Result<OutdatedReport, String> {
+    ProjectContext::new(project_root).with_lockfile(lockfile).check_outdated()
+}
 
-        Some(OutdatedEntry {
-            name: name.clone(),
-            current: current_version.clone(),
-            latest,
-            update_type,
-        })
-    }).collect();
+impl ProjectContext {
+    /// Registry latest versions for every package in the lockfile.
+    pub fn check_outdated(&self) -> Result<OutdatedReport, String> {
+        self.outdated_report(false)
+    }
 
-    let mut packages: Vec<OutdatedEntry> = results.into_iter().flatten().collect();
-    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    /// The project's declared dependencies against the newest published
+    /// version their package.json range allows (npm's "wanted": `latest` when
+    /// it satisfies the range, prereleases left out) as well as `latest`. A
+    /// package is outdated when its wanted version is newer than the installed one.
+    pub fn check_outdated_wanted(&self) -> Result<OutdatedReport, String> {
+        self.outdated_report(true)
+    }
+
+    fn outdated_report(&self, wanted: bool) -> Result<OutdatedReport, String> {
+        let lockfile = &self.lockfile;
+        use rayon::prelude::*;
+
+        // Get packages from lockfile
+        let resolve_result = resolve_from_lockfile(lockfile)?;
+
+        // Deduplicate by name (only check each package once); with `wanted`,
+        // only declared dependencies, at their hoisted version
+        let mut unique: HashMap<String, (String, Option<String>)> = HashMap::new();
+        if wanted {
+            let pkg_json = fs::read_to_string(self.root.join("package.json")).unwrap_or_default();
+            let declared: BTreeMap<String, String> = ["dependencies", "devDependencies", "optionalDependencies"]
+                .iter()
+                .flat_map(|section| dep_section(&pkg_json, section))
+                .collect();
+            for pkg in &resolve_result.packages {
+                let Some(range) = declared.get(&pkg.name) else { continue };
+                if pkg.rel_path == format!("node_modules/{}", pkg.name) || !unique.contains_key(&pkg.name) {
+                    unique.insert(pkg.name.clone(), (pkg.version.clone(), Some(range.clone())));
+                }
+            }
+        } else {
+            for pkg in &resolve_result.packages {
+                unique.entry(pkg.name.clone()).or_insert_with(|| (pkg.version.clone(), None));
+            }
+        }
+        let pkg_list: Vec<(String, String, Option<String>)> = unique.into_iter().map(|(name, (version, range))| (name, version, range)).collect();
+
+        let npmrc = self.npmrc();
+        let agent = build_http_agent(Some(&npmrc));
+
+        // Fetch latest versions in parallel. Ok(None) means up to date,
+        // Err means the package could not be checked.
+        let results: Vec<(String, Result<Option<OutdatedEntry>, String>)> = pkg_list.par_iter().map(|(name, current_version, range)| {
+            let (registry, token) = registry_for_package(&npmrc, name);
+            let url = format!("{}/{}", registry.trim_end_matches('/'), name.replace('/', "%2F"));
+            let check = || -> Result<Option<OutdatedEntry>, String> {
+                let body = fetch_registry_metadata(&agent, &url, token, self.metadata_cache.as_ref())?;
+
+                // Extract dist-tags.latest
+                let dist_section = extract_json_object_raw(&body, "dist-tags")
+                    .ok_or_else(|| "malformed metadata: missing dist-tags".to_string())?;
+                let latest = extract_json_field(&dist_section, "latest")
+                    .ok_or_else(|| "malformed metadata: missing dist-tags.latest".to_string())?;
+                let wanted = match range {
+                    Some(range) => Some(wanted_version(&body, range, &latest)?),
+                    None => None,
+                };
+                let target = wanted.as_deref().unwrap_or(&latest);
+
+                if target == current_version {
+                    return Ok(None);
+                }
+
+                let current_sv = parse_semver(current_version);
+                let target_sv = parse_semver(target);
+                let update_type = match (current_sv.as_ref(), target_sv.as_ref()) {
+                    (Some(c), Some(t)) => classify_update(c, t).to_string(),
+                    _ => "unknown".to_string(),
+                };
+
+                if update_type == "current" { return Ok(None); }
 
-    let total_checked = pkg_list.len() as u64;
-    let outdated = packages.len() as u64;
-    let major = packages.iter().filter(|p| p.update_type == "major").count() as u64;
-    let minor = packages.iter().filter(|p| p.update_type == "minor").count() as u64;
-    let patch = packages.iter().filter(|p| p.update_type == "patch").count() as u64;
+                Ok(Some(OutdatedEntry {
+                    name: name.clone(),
+                    current: current_version.clone(),
+                    latest,
+                    wanted,
+                    update_type,
+                }))
+            };
+            (name.clone(), check())
+        }).collect();
+
+        let mut packages: Vec<OutdatedEntry> = Vec::new();
+        let mut errors: Vec<(String, String)> = Vec::new();
+        for (name, result) in results {
+            match result {
+                Ok(Some(entry)) => packages.push(entry),
+                Ok(None) => {}
+                Err(reason) => errors.push((name, reason)),
+            }
+        }
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        errors.sort();
+
+        let total_checked = pkg_list.len() as u64;
+        let outdated = packages.len() as u64;
+        let major = packages.iter().filter(|p| p.update_type == "major").count() as u64;
+        let minor = packages.iter().filter(|p| p.update_type == "minor").count() as u64;
+        let patch = packages.iter().filter(|p| p.update_type == "patch").count() as u64;
 
-    Ok(OutdatedReport { packages, total_checked, outdated, major, minor, patch })
+        Ok(OutdatedReport { packages, total_checked, outdated, major, minor, patch, errors })
+    }
 }
 
 // --- B.6: Doctor ---
@@ -3538,6 +7570,59 @@ pub struct DoctorReport {
     pub findings: Vec<DoctorFinding>,
 }
 
+/// When `doctor` should exit non-zero, independent of the reported score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoctorFailOn {
+    /// Fail when the score drops below the threshold (default)
+    Score,
+    /// Fail when any `error` or `critical` finding is present
+    Error,
+    /// Fail only when a `critical` finding is present
+    Critical,
+    /// Report only, never fail
+    Never,
+}
+
+impl DoctorFailOn {
+    pub fn from_arg(value: &str) -> Option<Self> {
+        match value {
+            "score" => Some(Self::Score),
+            "error" => Some(Self::Error),
+            "critical" => Some(Self::Critical),
+            "never" => Some(Self::Never),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Score => "score",
+            Self::Error => "error",
+            Self::Critical => "critical",
+            Self::Never => "never",
+        }
+    }
+}
+
+impl DoctorReport {
+    /// Number of findings with the given severity.
+    pub fn severity_count(&self, severity: &str) -> u64 {
+        self.findings.iter().filter(|f| f.severity == severity).count() as u64
+    }
+
+    pub fn should_fail(&self, fail_on: DoctorFailOn) -> bool {
+        match fail_on {
+            DoctorFailOn::Score => self.score < self.threshold,
+            DoctorFailOn::Error => self.severity_count("error") + self.severity_count("critical") > 0,
+            DoctorFailOn::Critical => self.severity_count("critical") > 0,
+            DoctorFailOn::Never => false,
+        }
+    }
+}
+
+/// Most points dependency cycles take off the doctor score together.
+const DOCTOR_CYCLE_MAX_DEDUCTION: i32 = 5;
+
 pub fn run_doctor(project_root: &Path, threshold: i32) -> Result<DoctorReport, String> {
     let mut findings = Vec::new();
     let mut deductions = 0i32;
@@ -3545,7 +7630,7 @@ pub fn run_doctor(project_root: &Path, threshold: i32) -> Result<DoctorReport, S
     // Check 1: Duplicates
     let node_modules = project_root.join("node_modules");
     if node_modules.exists() {
-        if let Ok(report) = analyze(project_root, false) {
+        if let Ok(report) = analyze(project_root, true, None) {
             for d in &report.duplicates {
                 deductions += 2;
                 findings.push(DoctorFinding {
@@ -3564,8 +7649,21 @@ pub fn run_doctor(project_root: &Path, threshold: i32) -> Result<DoctorReport, S
                     id: "deep-nesting".to_string(),
                     title: format!("Deep nesting detected (max depth: {})", report.depth.max_depth),
                     severity: "warning".to_string(),
-                    impact: -3,
-                    recommendation: "Consider flattening dependencies".to_string(),
+                    impact: -3,
+                    recommendation: "Consider flattening dependencies".to_string(),
+                });
+            }
+
+            // Check dependency cycles: a point each, at most DOCTOR_CYCLE_MAX_DEDUCTION
+            for (i, cycle) in report.cycles.iter().enumerate() {
+                let impact = if (i as i32) < DOCTOR_CYCLE_MAX_DEDUCTION { 1 } else { 0 };
+                deductions += impact;
+                findings.push(DoctorFinding {
+                    id: format!("cycle-{}", cycle.first().map(String::as_str).unwrap_or_default()),
+                    title: format!("Dependency cycle: {}", cycle.join(" <-> ")),
+                    severity: "warning".to_string(),
+                    impact: -impact,
+                    recommendation: "Circular dependencies can break bundlers and module initialization order; consider breaking the cycle".to_string(),
                 });
             }
         }
@@ -3582,7 +7680,8 @@ pub fn run_doctor(project_root: &Path, threshold: i32) -> Result<DoctorReport, S
 
     // Check 2: Lockfile freshness
     let pkg_json = project_root.join("package.json");
-    let lockfile = project_root.join("package-lock.json");
+    let lockfile = default_lockfile(project_root);
+    let lockfile_name = lockfile.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
     if lockfile.exists() && pkg_json.exists() {
         let lock_mtime = fs::metadata(&lockfile).and_then(|m| m.modified()).ok();
         let pkg_mtime = fs::metadata(&pkg_json).and_then(|m| m.modified()).ok();
@@ -3591,7 +7690,7 @@ pub fn run_doctor(project_root: &Path, threshold: i32) -> Result<DoctorReport, S
                 deductions += 10;
                 findings.push(DoctorFinding {
                     id: "stale-lockfile".to_string(),
-                    title: "package-lock.json is older than package.json".to_string(),
+                    title: format!("{} is older than package.json", lockfile_name),
                     severity: "error".to_string(),
                     impact: -10,
                     recommendation: "Run `npm install` to update lockfile".to_string(),
@@ -3759,8 +7858,571 @@ fn gc_walk(dir: &Path, cutoff: &std::time::SystemTime, dry_run: bool, removed: &
     }
 }
 
+// --- Prune ---
+
+/// A package `prune_node_modules` removed (or would remove).
+#[derive(Debug, Clone)]
+pub struct PrunedPackage {
+    pub name: String,
+    pub rel_path: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub struct PruneReport {
+    pub removed: Vec<PrunedPackage>,
+    pub removed_count: u64,
+    pub freed_bytes: u64,
+}
+
+/// Remove packages in `project_root/node_modules` that `lockfile` does not
+/// list, with the `.bin` links pointing into them. Packages nested in a removed
+/// one go with it and are not reported separately; nothing under a symlinked
+/// package (a workspace link) is touched, nor anything a package bundles, which
+/// yarn and pnpm lockfiles do not list. Scope directories are removed only
+/// once empty. Every removal goes through `plan`.
+pub fn prune_node_modules(project_root: &Path, lockfile: &Path, plan: &mut FsPlan) -> Result<PruneReport, String> {
+    let content = fs::read_to_string(lockfile).map_err(|e| format!("Failed to read {}: {}", lockfile.display(), e))?;
+    // Every key counts, including linked and bundled entries resolving skips
+    let expected: HashSet<String> = match lockfile_packages(&content) {
+        Some(entries) => entries.into_iter().map(|(key, _)| key).collect(),
+        None => resolve_from_lockfile(lockfile)?.packages.into_iter().map(|p| p.rel_path).collect(),
+    };
+
+    let node_modules = project_root.join("node_modules");
+    let mut stale: Vec<(PathBuf, String)> = Vec::new();
+    let mut bundled: Vec<String> = Vec::new();
+    for path in list_packages_in_node_modules(&node_modules)? {
+        let Ok(rel) = path.strip_prefix(project_root) else { continue };
+        let rel_path = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        if expected.contains(&rel_path) || bundled.iter().any(|b| rel_path.starts_with(&format!("{}/", b))) {
+            continue;
+        }
+        if let Some(i) = rel_path.rfind("/node_modules/") {
+            let name = &rel_path[i + "/node_modules/".len()..];
+            if read_bundled_dependencies(&project_root.join(&rel_path[..i])).iter().any(|b| b == name) {
+                bundled.push(rel_path);
+                continue;
+            }
+        }
+        let inside_stale = stale.iter().any(|(_, parent)| rel_path.starts_with(&format!("{}/", parent)));
+        let inside_link = rel_path
+            .match_indices("/node_modules/")
+            .any(|(i, _)| fs::symlink_metadata(project_root.join(&rel_path[..i])).map(|md| md.file_type().is_symlink()).unwrap_or(false));
+        // A stale package an expected one is nested in has to stay
+        let holds_expected = expected.iter().any(|key| key.starts_with(&format!("{}/", rel_path)));
+        if !inside_stale && !inside_link && !holds_expected {
+            stale.push((path, rel_path));
+        }
+    }
+
+    let mut removed = Vec::new();
+    let mut scopes: Vec<PathBuf> = Vec::new();
+    let stale_paths: HashSet<PathBuf> = stale.iter().map(|(path, _)| path.clone()).collect();
+    for (path, rel_path) in stale {
+        let is_link = fs::symlink_metadata(&path).map(|md| md.file_type().is_symlink()).unwrap_or(false);
+        let bytes = if is_link { 0 } else { dir_stats_recursive(&path).1 };
+        remove_bin_links_into(&path, plan);
+        plan.record("remove", &path);
+        if !plan.dry_run {
+            remove_path_if_exists(&path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        }
+        if let Some(scope) = path.parent().filter(|p| is_scope_dir(p) && !scopes.iter().any(|s| s == p)) {
+            scopes.push(scope.to_path_buf());
+        }
+        removed.push(PrunedPackage { name: package_name_from_path(&rel_path), rel_path, bytes });
+    }
+    for scope in scopes {
+        // A scope goes only once nothing else is left in it
+        let emptied = fs::read_dir(&scope)
+            .map(|entries| entries.flatten().all(|e| stale_paths.contains(&e.path())))
+            .unwrap_or(false);
+        if emptied {
+            plan.record("remove", &scope);
+            if !plan.dry_run {
+                let _ = fs::remove_dir(&scope);
+            }
+        }
+    }
+
+    Ok(PruneReport {
+        removed_count: removed.len() as u64,
+        freed_bytes: removed.iter().map(|p| p.bytes).sum(),
+        removed,
+    })
+}
+
+/// Remove the symlinks in the `.bin` beside `package_dir` that point into it.
+fn remove_bin_links_into(package_dir: &Path, plan: &mut FsPlan) {
+    let mut node_modules = package_dir.parent();
+    if node_modules.is_some_and(is_scope_dir) {
+        node_modules = node_modules.and_then(Path::parent);
+    }
+    let Some(bin_dir) = node_modules.map(|nm| nm.join(".bin")) else { return };
+    let (Ok(package_dir), Ok(entries)) = (fs::canonicalize(package_dir), fs::read_dir(&bin_dir)) else { return };
+    for entry in entries.flatten() {
+        let Ok(target) = fs::read_link(entry.path()) else { continue };
+        if fs::canonicalize(bin_dir.join(target)).is_ok_and(|t| t.starts_with(&package_dir)) {
+            let _ = plan.remove_file(&entry.path());
+        }
+    }
+}
+
+/// One stored tarball re-hashed by `cache verify`.
+#[derive(Debug, Clone)]
+pub struct TarballCheck {
+    /// Set when the check came from the URL index
+    pub url: Option<String>,
+    pub algo: String,
+    pub hex: String,
+    pub path: PathBuf,
+    /// ok, missing, mismatch, unsupported (sha1 and other non-SHA-2 digests) or not-indexed
+    pub status: &'static str,
+}
+
+fn hash_file_with(path: &Path, algo: &str) -> Option<std::io::Result<String>> {
+    use sha2::{Digest, Sha256, Sha384, Sha512};
+    fn digest<D: Digest + Write>(path: &Path) -> std::io::Result<String> {
+        let mut hasher = D::new();
+        std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+    match algo {
+        "sha256" => Some(digest::<Sha256>(path)),
+        "sha384" => Some(digest::<Sha384>(path)),
+        "sha512" => Some(digest::<Sha512>(path)),
+        _ => None,
+    }
+}
+
+fn check_tarball(layout: &CasLayout, url: Option<String>, algo: &str, hex: &str) -> TarballCheck {
+    let path = tarball_path(layout, algo, hex);
+    let status = if !path.exists() {
+        "missing"
+    } else {
+        match hash_file_with(&path, algo) {
+            None => "unsupported",
+            Some(Ok(actual)) if actual == hex => "ok",
+            Some(_) => "mismatch",
+        }
+    };
+    TarballCheck { url, algo: algo.to_string(), hex: hex.to_string(), path, status }
+}
+
+/// Re-hash stored tarballs against the key they are stored under. `by_url`
+/// checks URL-index entries instead: the given URLs, or every indexed one when
+/// the list is empty.
+pub fn verify_cached_tarballs(cache_root: &Path, by_url: Option<&[String]>) -> Vec<TarballCheck> {
+    let layout = CasLayout::new(cache_root);
+    if let Some(urls) = by_url {
+        let index = read_url_index(&layout);
+        if urls.is_empty() {
+            return index.iter().map(|(url, (algo, hex))| check_tarball(&layout, Some(url.clone()), algo, hex)).collect();
+        }
+        return urls
+            .iter()
+            .map(|url| match index.get(url) {
+                Some((algo, hex)) => check_tarball(&layout, Some(url.clone()), algo, hex),
+                None => TarballCheck {
+                    url: Some(url.clone()),
+                    algo: String::new(),
+                    hex: String::new(),
+                    path: PathBuf::new(),
+                    status: "not-indexed",
+                },
+            })
+            .collect();
+    }
+
+    // tarballs/<algo>/<aa>/<bb>/<hex>.tgz
+    let mut checks = Vec::new();
+    for algo_dir in stable_list_dir(&layout.tarballs_dir).unwrap_or_default() {
+        let algo = algo_dir.file_name().to_string_lossy().to_string();
+        let mut stack = vec![algo_dir.path()];
+        while let Some(dir) = stack.pop() {
+            for ent in stable_list_dir(&dir).unwrap_or_default() {
+                let path = ent.path();
+                if path.is_dir() {
+                    stack.push(path);
+                } else if let Some(hex) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".tgz")) {
+                    checks.push(check_tarball(&layout, None, &algo, hex));
+                }
+            }
+        }
+    }
+    checks.sort_by(|a, b| a.path.cmp(&b.path));
+    checks
+}
+
+// --- Cache export/import ---
+
+/// First entry of an export archive; lists every tarball it carries.
+const CACHE_EXPORT_MANIFEST: &str = "better-cache.json";
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheExportReport {
+    pub tarballs: u64,
+    pub blobs: u64,
+    pub packages: u64,
+    /// Size of the written archive
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheImportReport {
+    pub tarballs_imported: u64,
+    pub blobs_imported: u64,
+    pub packages_imported: u64,
+    /// Already present locally, left untouched
+    pub skipped: u64,
+    /// Entries whose bytes did not match their hash (or could not be checked)
+    pub rejected: Vec<String>,
+}
+
+fn is_hex(s: &str) -> bool {
+    s.len() >= 4 && s.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+fn is_algo(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+}
+
+/// Files under `dir` as (absolute, `/`-separated relative) paths, sorted.
+fn walk_files(dir: &Path) -> Vec<(PathBuf, String)> {
+    let mut out = Vec::new();
+    let mut stack = vec![(dir.to_path_buf(), String::new())];
+    while let Some((d, rel)) = stack.pop() {
+        for ent in stable_list_dir(&d).unwrap_or_default() {
+            let name = ent.file_name().to_string_lossy().to_string();
+            let child_rel = if rel.is_empty() { name } else { format!("{}/{}", rel, name) };
+            match ent.file_type() {
+                Ok(ft) if ft.is_dir() => stack.push((ent.path(), child_rel)),
+                Ok(ft) if ft.is_file() => out.push((ent.path(), child_rel)),
+                _ => {}
+            }
+        }
+    }
+    out.sort_by(|a, b| a.1.cmp(&b.1));
+    out
+}
+
+/// Pack the verified tarballs, the file store and the URL index into one
+/// gzipped tar at `archive`. Unpacked trees are left out: import re-extracts
+/// them from the tarballs, which it can verify.
+pub fn cache_export(cache_root: &Path, store_root: &Path, archive: &Path) -> Result<CacheExportReport, String> {
+    let layout = CasLayout::new(cache_root);
+    let mut report = CacheExportReport::default();
+
+    // tarballs/<algo>/<aa>/<bb>/<hex>.tgz, only those fetch verified
+    let mut tarballs = Vec::new();
+    for (path, rel) in walk_files(&layout.tarballs_dir) {
+        let parts: Vec<&str> = rel.split('/').collect();
+        let [algo, _, _, file] = parts[..] else { continue };
+        let Some(hex) = file.strip_suffix(".tgz") else { continue };
+        if is_algo(algo) && is_hex(hex) && path.with_extension("tgz.verified").exists() {
+            tarballs.push((algo.to_string(), hex.to_string(), path));
+        }
+    }
+    let blobs: Vec<(PathBuf, String)> = walk_files(&store_root.join("files"))
+        .into_iter()
+        .filter(|(_, rel)| rel.rsplit('/').next().is_some_and(is_hex))
+        .collect();
+    let manifests: Vec<(PathBuf, String)> = walk_files(&store_root.join("packages"))
+        .into_iter()
+        .filter(|(_, rel)| rel.ends_with("/manifest.json"))
+        .collect();
+
+    let mut manifest = JsonWriter::compact();
+    manifest.begin_object();
+    manifest.key("kind");
+    manifest.value_string("better.cache.export");
+    manifest.key("schemaVersion");
+    manifest.value_u64(1);
+    manifest.key("tarballs");
+    manifest.begin_array();
+    for (algo, hex, _) in &tarballs {
+        manifest.begin_object();
+        manifest.key("algorithm");
+        manifest.value_string(algo);
+        manifest.key("hex");
+        manifest.value_string(hex);
+        manifest.end_object();
+    }
+    manifest.end_array();
+    manifest.key("blobs");
+    manifest.value_u64(blobs.len() as u64);
+    manifest.key("packages");
+    manifest.value_u64(manifests.len() as u64);
+    manifest.end_object();
+    let manifest = manifest.finish();
+
+    if let Some(parent) = archive.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let tmp = TmpFileGuard::beside(archive);
+    let file = fs::File::create(tmp.path()).map_err(|e| format!("Failed to create {}: {}", archive.display(), e))?;
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(file, flate2::Compression::default()));
+    let write_err = |e: std::io::Error| format!("Failed to write {}: {}", archive.display(), e);
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, CACHE_EXPORT_MANIFEST, manifest.as_bytes()).map_err(write_err)?;
+
+    for (algo, hex, path) in &tarballs {
+        builder.append_path_with_name(path, format!("tarballs/{}/{}.tgz", algo, hex)).map_err(write_err)?;
+    }
+    // Blobs before the manifests that reference them
+    for (path, rel) in &blobs {
+        builder.append_path_with_name(path, format!("file-store/files/{}", rel)).map_err(write_err)?;
+    }
+    for (path, rel) in &manifests {
+        builder.append_path_with_name(path, format!("file-store/packages/{}", rel)).map_err(write_err)?;
+    }
+    if layout.url_index.is_file() {
+        builder.append_path_with_name(&layout.url_index, "url-index").map_err(write_err)?;
+    }
+    builder.into_inner().and_then(|gz| gz.finish()).map_err(write_err)?;
+    fs::rename(tmp.path(), archive).map_err(write_err)?;
+    tmp.keep();
+
+    report.tarballs = tarballs.len() as u64;
+    report.blobs = blobs.len() as u64;
+    report.packages = manifests.len() as u64;
+    report.bytes = fs::metadata(archive).map(|m| m.len()).unwrap_or(0);
+    Ok(report)
+}
+
+/// Merge an archive written by [`cache_export`] into the local cache. Every
+/// tarball and blob is re-hashed before it is moved into place, entries
+/// already present are skipped, and imported tarballs are extracted so the
+/// cache is ready for an offline install.
+pub fn cache_import(cache_root: &Path, store_root: &Path, archive: &Path) -> Result<CacheImportReport, String> {
+    let layout = CasLayout::new(cache_root);
+    let mut report = CacheImportReport::default();
+    fs::create_dir_all(&layout.tmp_dir).map_err(|e| format!("Failed to create {}: {}", layout.tmp_dir.display(), e))?;
+
+    let file = fs::File::open(archive).map_err(|e| format!("Failed to open {}: {}", archive.display(), e))?;
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    let read_err = |e: std::io::Error| format!("Failed to read {}: {}", archive.display(), e);
+
+    let mut listed: BTreeSet<(String, String)> = BTreeSet::new();
+    let mut seen_manifest = false;
+    let mut package_manifests: Vec<(String, String, Vec<u8>)> = Vec::new();
+    let mut index_lines = String::new();
+
+    for entry in tar.entries().map_err(read_err)? {
+        let mut entry = entry.map_err(read_err)?;
+        let name = entry.path().map_err(read_err)?.to_string_lossy().to_string();
+
+        if !seen_manifest {
+            if name != CACHE_EXPORT_MANIFEST {
+                return Err(format!("{} is not a better cache export (no {})", archive.display(), CACHE_EXPORT_MANIFEST));
+            }
+            let mut content = String::new();
+            entry.read_to_string(&mut content).map_err(read_err)?;
+            if extract_json_field(&content, "kind").as_deref() != Some("better.cache.export") {
+                return Err(format!("{} is not a better cache export", archive.display()));
+            }
+            let tarballs = json_object_entries(&content).into_iter().find(|(k, _)| k == "tarballs").map(|(_, v)| v);
+            for item in json_array_elements(&tarballs.unwrap_or_default()) {
+                if let (Some(algo), Some(hex)) = (extract_json_field(&item, "algorithm"), extract_json_field(&item, "hex")) {
+                    listed.insert((algo, hex));
+                }
+            }
+            seen_manifest = true;
+            continue;
+        }
+
+        let parts: Vec<&str> = name.split('/').collect();
+        match parts[..] {
+            ["tarballs", algo, file] => {
+                let Some(hex) = file.strip_suffix(".tgz").filter(|h| is_algo(algo) && is_hex(h)) else {
+                    report.rejected.push(name);
+                    continue;
+                };
+                listed.remove(&(algo.to_string(), hex.to_string()));
+                let dest = tarball_path(&layout, algo, hex);
+                let marker = dest.with_extension("tgz.verified");
+                if marker.exists() {
+                    report.skipped += 1;
+                    continue;
+                }
+                if !import_verified(&mut entry, &layout, &dest, algo, hex)? {
+                    report.rejected.push(name);
+                    continue;
+                }
+                fs::write(&marker, "").map_err(|e| format!("Failed to write verified marker: {}", e))?;
+                extract_to_unpacked(&dest, &unpacked_path(&layout, algo, hex))?;
+                report.tarballs_imported += 1;
+            }
+            ["file-store", "files", "sha256", _, _, hex] if is_hex(hex) => {
+                let dest = file_store_path(store_root, hex);
+                if dest.is_file() {
+                    report.skipped += 1;
+                } else if import_verified(&mut entry, &layout, &dest, "sha256", hex)? {
+                    report.blobs_imported += 1;
+                } else {
+                    report.rejected.push(name);
+                }
+            }
+            ["file-store", "packages", algo, _, _, hex, "manifest.json"] if is_algo(algo) && is_hex(hex) => {
+                let mut bytes = Vec::new();
+                entry.read_to_end(&mut bytes).map_err(read_err)?;
+                package_manifests.push((algo.to_string(), hex.to_string(), bytes));
+            }
+            ["url-index"] => {
+                entry.read_to_string(&mut index_lines).map_err(read_err)?;
+            }
+            _ => report.rejected.push(name),
+        }
+    }
+    if !seen_manifest {
+        return Err(format!("{} is empty", archive.display()));
+    }
+
+    // A manifest is only usable once every blob it names is in the store
+    for (algo, hex, bytes) in package_manifests {
+        let dest = package_manifest_path(store_root, &algo, &hex);
+        if dest.is_file() {
+            report.skipped += 1;
+            continue;
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let tmp_dir = file_store_tmp_dir(store_root);
+        fs::create_dir_all(&tmp_dir).map_err(|e| format!("Failed to create {}: {}", tmp_dir.display(), e))?;
+        let tmp = TmpFileGuard::beside(&tmp_dir.join(format!("{}.json", hex)));
+        fs::write(tmp.path(), &bytes).map_err(|e| format!("Failed to write {}: {}", dest.display(), e))?;
+        fs::rename(tmp.path(), &dest).map_err(|e| format!("Failed to write {}: {}", dest.display(), e))?;
+        tmp.keep();
+        let complete = read_file_cas_manifest(store_root, &algo, &hex)
+            .is_some_and(|(files, _)| absent_blobs(store_root, &files).is_empty());
+        if complete {
+            report.packages_imported += 1;
+        } else {
+            let _ = fs::remove_file(&dest);
+            report.rejected.push(format!("file-store/packages/{}/{}/manifest.json", algo, hex));
+        }
+    }
+
+    // Only URLs whose tarball is now actually stored
+    let entries = index_lines
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, '\t');
+            let (url, algo, hex) = (parts.next()?, parts.next()?, parts.next()?);
+            let stored = is_algo(algo) && is_hex(hex) && tarball_path(&layout, algo, hex).with_extension("tgz.verified").exists();
+            stored.then(|| (url.to_string(), algo.to_string(), hex.to_string()))
+        })
+        .collect();
+    update_url_index(&layout, entries)?;
+
+    if !listed.is_empty() {
+        return Err(format!("{} is truncated: {} tarball(s) listed in its manifest are missing", archive.display(), listed.len()));
+    }
+    report.rejected.sort();
+    Ok(report)
+}
+
+/// Copy `entry` to `dest` through a tmp file, keeping it only when it hashes
+/// to `hex` under `algo`. False (and nothing written) on a mismatch or an
+/// algorithm that cannot be checked.
+fn import_verified(entry: &mut impl std::io::Read, layout: &CasLayout, dest: &Path, algo: &str, hex: &str) -> Result<bool, String> {
+    let tmp = TmpFileGuard::beside(&layout.tmp_dir.join(hex));
+    let mut out = fs::File::create(tmp.path()).map_err(|e| format!("Failed to create tmp file: {}", e))?;
+    std::io::copy(entry, &mut out).map_err(|e| format!("Failed to write tmp file: {}", e))?;
+    drop(out);
+    match hash_file_with(tmp.path(), algo) {
+        Some(Ok(actual)) if actual == hex => {}
+        Some(Err(e)) => return Err(format!("Failed to hash tmp file: {}", e)),
+        _ => return Ok(false),
+    }
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    rename_across_devices(tmp.path(), dest).map_err(|e| format!("Failed to move {} into the cache: {}", dest.display(), e))?;
+    tmp.keep();
+    Ok(true)
+}
+
 // --- B.8: Security Audit ---
 
+/// OSV API base URL; `BETTER_OSV_URL` points audits at a mirror.
+fn osv_api_base() -> String {
+    std::env::var("BETTER_OSV_URL")
+        .map(|u| u.trim_end_matches('/').to_string())
+        .unwrap_or_else(|_| "https://api.osv.dev/v1".to_string())
+}
+
+/// The full OSV record for advisory `id`, or None when it cannot be fetched;
+/// querybatch only returns ids.
+fn fetch_osv_vuln(agent: &ureq::Agent, npmrc: &NpmrcConfig, id: &str) -> Option<String> {
+    let url = format!("{}/vulns/{}", osv_api_base(), id);
+    let mut request = agent.get(&url);
+    if let Some(token) = find_auth_token(npmrc, &url) {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    request.call().ok()?.into_string().ok()
+}
+
+/// Whether the OSV record `details` says `name@version` is affected, going by
+/// the npm entries' `versions` lists and SEMVER/ECOSYSTEM range events, and
+/// the lowest fixed version above it. None when the record says nothing
+/// usable about the package, in which case the advisory is kept as reported.
+fn osv_affected(details: &str, name: &str, version: &str) -> Option<(bool, Option<String>)> {
+    let doc = JsonValue::parse(details).ok()?;
+    let installed = parse_semver(version)?;
+    let mut known = false;
+    let mut affected = false;
+    let mut fixed: Option<(SemVer, String)> = None;
+    for entry in doc.get("affected")?.as_array()? {
+        let package = entry.get("package");
+        let field = |key: &str| package.and_then(|p| p.get(key)).and_then(JsonValue::as_str);
+        if field("ecosystem") != Some("npm") || field("name") != Some(name) {
+            continue;
+        }
+        if let Some(versions) = entry.get("versions").and_then(JsonValue::as_array) {
+            known = true;
+            affected |= versions.iter().any(|v| v.as_str() == Some(version));
+        }
+        for range in entry.get("ranges").and_then(JsonValue::as_array).unwrap_or_default() {
+            if !matches!(range.get("type").and_then(JsonValue::as_str), Some("SEMVER" | "ECOSYSTEM")) {
+                continue;
+            }
+            // Each event is a one-key object: {"introduced": "0"}, {"fixed": "1.2.3"}, ...
+            let mut events: Vec<(SemVer, &str, &str)> = range
+                .get("events")
+                .and_then(JsonValue::as_array)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|event| {
+                    let (kind, at) = event.as_object()?.iter().next()?;
+                    let at = at.as_str()?;
+                    let parsed = if at == "0" { parse_semver("0.0.0") } else { parse_semver(at) };
+                    Some((parsed?, kind.as_str(), at))
+                })
+                .collect();
+            events.sort_by(|a, b| a.0.cmp(&b.0));
+            known = true;
+            let mut inside = false;
+            for (at, kind, raw) in &events {
+                match *kind {
+                    "introduced" if installed >= *at => inside = true,
+                    "fixed" if installed >= *at => inside = false,
+                    "last_affected" if installed > *at => inside = false,
+                    "fixed" if fixed.as_ref().is_none_or(|(f, _)| at < f) => fixed = Some((at.clone(), raw.to_string())),
+                    _ => {}
+                }
+            }
+            affected |= inside;
+        }
+    }
+    known.then(|| (affected, fixed.map(|(_, raw)| raw)))
+}
+
 #[derive(Debug, Clone)]
 pub struct AuditVulnerability {
     pub id: String,
@@ -3771,9 +8433,121 @@ pub struct AuditVulnerability {
     pub fixed: String,
 }
 
+/// Advisories a project has triaged and accepts, one per line in the project
+/// root: `GHSA-xxxx` for every package, `GHSA-xxxx@name` for one, either
+/// followed by `until=YYYY-MM-DD` to accept it only through that day (UTC).
+/// `#` starts a comment.
+pub const AUDIT_IGNORE_FILE: &str = ".better-audit-ignore";
+
+struct AuditIgnore {
+    id: String,
+    package: Option<String>,
+}
+
+/// The [`AUDIT_IGNORE_FILE`] entries still in effect; none when the file is
+/// absent. A malformed line is an error rather than a silent ignore.
+fn read_audit_ignores(project_root: &Path) -> Result<Vec<AuditIgnore>, String> {
+    let path = project_root.join(AUDIT_IGNORE_FILE);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+    let today = (unix_secs_now() / 86400) as i64;
+    let mut ignores = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let Some(spec) = words.next() else { continue };
+        let mut active = true;
+        for word in words {
+            let until = word.strip_prefix("until=").and_then(days_from_iso_date);
+            match until {
+                Some(day) => active = today <= day,
+                None => return Err(format!("{}:{}: expected until=YYYY-MM-DD, got '{}'", path.display(), n + 1, word)),
+            }
+        }
+        let (id, package) = match spec.split_once('@') {
+            Some((id, package)) => (id, Some(package.to_string())),
+            None => (spec, None),
+        };
+        if id.is_empty() || package.as_deref() == Some("") {
+            return Err(format!("{}:{}: expected <id> or <id>@<package>, got '{}'", path.display(), n + 1, spec));
+        }
+        if active {
+            ignores.push(AuditIgnore { id: id.to_string(), package });
+        }
+    }
+    Ok(ignores)
+}
+
+/// Days since 1970-01-01 for a `YYYY-MM-DD` date in the proleptic Gregorian
+/// calendar.
+fn days_from_iso_date(date: &str) -> Option<i64> {
+    let mut parts = date.split('-');
+    let (y, m, d) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() || y.len() != 4 || m.len() != 2 || d.len() != 2 {
+        return None;
+    }
+    let (y, m, d): (i64, i64, i64) = (y.parse().ok()?, m.parse().ok()?, d.parse().ok()?);
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil, with years starting in March
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some(era * 146097 + doe - 719468)
+}
+
+/// Where `audit` looks up advisories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuditSource {
+    /// OSV.dev (default)
+    #[default]
+    Osv,
+    /// The GitHub Advisory Database, through the GraphQL API
+    GithubAdvisory,
+}
+
+impl AuditSource {
+    pub fn from_arg(value: &str) -> Option<Self> {
+        match value {
+            "osv" => Some(Self::Osv),
+            "github" | "ghsa" => Some(Self::GithubAdvisory),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Osv => "osv",
+            Self::GithubAdvisory => "github",
+        }
+    }
+}
+
+/// Rank of an advisory severity: critical 4, high 3, moderate or medium 2,
+/// low 1 and anything else 0, case-insensitively.
+pub fn audit_severity_rank(severity: &str) -> u8 {
+    match severity.to_lowercase().as_str() {
+        "critical" => 4,
+        "high" => 3,
+        "medium" | "moderate" => 2,
+        "low" => 1,
+        _ => 0,
+    }
+}
+
 #[derive(Debug)]
 pub struct AuditReport {
     pub scanned_packages: u64,
+    /// Lockfile packages skipped by the context's `omit` set
+    pub omitted: u64,
+    /// Findings left out by [`AUDIT_IGNORE_FILE`]
+    pub ignored_count: u64,
     pub vulnerabilities: Vec<AuditVulnerability>,
     pub total: u64,
     pub critical: u64,
@@ -3783,170 +8557,295 @@ pub struct AuditReport {
     pub risk_level: String,
 }
 
-pub fn run_audit(lockfile: &Path, _project_root: &Path, min_severity: &str) -> Result<AuditReport, String> {
-    let resolve_result = resolve_from_lockfile(lockfile)?;
+impl AuditReport {
+    /// Whether any reported advisory is at or above severity `level`, the
+    /// `audit --fail-on` check.
+    pub fn fails_at(&self, level: &str) -> bool {
+        let min = audit_severity_rank(level);
+        self.vulnerabilities.iter().any(|v| audit_severity_rank(&v.severity) >= min)
+    }
+}
 
-    // Build OSV batch query
-    let mut query = JsonWriter::new();
+/// Advisories OSV reports for each `name@version`, checked against their
+/// affected ranges.
+fn osv_advisories(agent: &ureq::Agent, npmrc: &NpmrcConfig, unique: &[(&str, &str)]) -> Result<Vec<AuditVulnerability>, CoreError> {
+    let mut query = JsonWriter::compact();
     query.begin_object();
     query.key("queries");
     query.begin_array();
-
-    // Deduplicate packages
-    let mut seen: HashSet<String> = HashSet::new();
-    let mut query_count = 0u64;
-    for pkg in &resolve_result.packages {
-        let key = format!("{}@{}", pkg.name, pkg.version);
-        if seen.insert(key) {
-            query.begin_object();
-            query.key("package");
-            query.begin_object();
-            query.key("name");
-            query.value_string(&pkg.name);
-            query.key("ecosystem");
-            query.value_string("npm");
-            query.end_object();
-            query.key("version");
-            query.value_string(&pkg.version);
-            query.end_object();
-            query_count += 1;
-        }
+    for (name, version) in unique {
+        query.begin_object();
+        query.key("package");
+        query.begin_object();
+        query.key("name");
+        query.value_string(name);
+        query.key("ecosystem");
+        query.value_string("npm");
+        query.end_object();
+        query.key("version");
+        query.value_string(version);
+        query.end_object();
     }
-
     query.end_array();
     query.end_object();
     let body = query.finish();
 
     // POST to OSV.dev
-    let agent = ureq::AgentBuilder::new()
-        .timeout(std::time::Duration::from_secs(30))
-        .build();
-
-    let resp = agent.post("https://api.osv.dev/v1/querybatch")
-        .set("Content-Type", "application/json")
+    let url = format!("{}/querybatch", osv_api_base());
+    let mut request = agent.post(&url).set("Content-Type", "application/json");
+    // Only a mirror configured with its own registry token gets one
+    if let Some(token) = find_auth_token(npmrc, &url) {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    let resp = request
         .send_string(&body)
-        .map_err(|e| format!("OSV API request failed: {}", e))?;
+        .map_err(|e| CoreError::network(format!("OSV API request failed: {}", redact_url_credentials(&e.to_string()))))?;
 
     let resp_body = resp.into_string()
-        .map_err(|e| format!("Failed to read OSV response: {}", e))?;
+        .map_err(|e| CoreError::network(format!("Failed to read OSV response: {}", e)))?;
 
-    // Parse response
-    let mut vulns: Vec<AuditVulnerability> = Vec::new();
+    // Response format: {"results":[{"vulns":[{"id":"...","summary":"..."}]},{},..]}
+    // Packages without advisories get an empty object, so match by index, not by "vulns"
+    let results = json_object_entries(&resp_body)
+        .into_iter()
+        .find(|(k, _)| k == "results")
+        .map(|(_, v)| json_array_elements(&v))
+        .ok_or_else(|| "OSV response has no results array".to_string())?;
+    if results.len() != unique.len() {
+        return Err(format!("OSV response has {} results for {} queries", results.len(), unique.len()).into());
+    }
 
-    // Simple parsing: find all "vulns" arrays in the results
-    // Response format: {"results":[{"vulns":[{"id":"...","summary":"..."}]},{"vulns":[]},..]}
-    let severity_rank = |s: &str| -> u8 {
-        match s.to_lowercase().as_str() {
-            "critical" => 4,
-            "high" => 3,
-            "medium" | "moderate" => 2,
-            "low" => 1,
-            _ => 0,
-        }
+    let batch_vulns: Vec<Vec<String>> = results
+        .iter()
+        .map(|result| {
+            json_object_entries(result)
+                .into_iter()
+                .find(|(k, _)| k == "vulns")
+                .map(|(_, raw)| json_array_elements(&raw))
+                .unwrap_or_default()
+        })
+        .collect();
+
+    // The batch only names advisories; their full records say which
+    // versions are affected and carry the summary and fix
+    let ids: BTreeSet<String> = batch_vulns.iter().flatten().filter_map(|v| extract_json_field(v, "id")).collect();
+    let details: HashMap<&str, String> = {
+        use rayon::prelude::*;
+        ids.par_iter().filter_map(|id| Some((id.as_str(), fetch_osv_vuln(agent, npmrc, id)?))).collect()
     };
-    let min_rank = severity_rank(min_severity);
 
-    // Walk through unique packages and match with results
-    let mut pkg_names: Vec<(String, String)> = Vec::new();
-    let mut seen2: HashSet<String> = HashSet::new();
-    for pkg in &resolve_result.packages {
-        let key = format!("{}@{}", pkg.name, pkg.version);
-        if seen2.insert(key) {
-            pkg_names.push((pkg.name.clone(), pkg.version.clone()));
+    let mut vulns: Vec<AuditVulnerability> = Vec::new();
+    for ((pkg_name, pkg_version), batch) in unique.iter().zip(&batch_vulns) {
+        for batch_json in batch {
+            let id = extract_json_field(batch_json, "id").unwrap_or_default();
+            let (vuln_json, fixed) = match details.get(id.as_str()) {
+                Some(full) => match osv_affected(full, pkg_name, pkg_version) {
+                    Some((false, _)) => continue,
+                    Some((true, fixed)) => (full, fixed),
+                    None => (full, None),
+                },
+                None => (batch_json, None),
+            };
+            let summary = extract_json_field(vuln_json, "summary")
+                .unwrap_or_else(|| "No description".to_string());
+
+            // Try to extract severity
+            let severity = extract_json_field(vuln_json, "severity")
+                .or_else(|| {
+                    if vuln_json.contains("CRITICAL") { Some("CRITICAL".to_string()) }
+                    else if vuln_json.contains("HIGH") { Some("HIGH".to_string()) }
+                    else if vuln_json.contains("MODERATE") || vuln_json.contains("MEDIUM") { Some("MEDIUM".to_string()) }
+                    else { Some("LOW".to_string()) }
+                })
+                .unwrap_or_else(|| "UNKNOWN".to_string());
+
+            vulns.push(AuditVulnerability {
+                id,
+                summary,
+                severity: normalize_severity(&severity),
+                package: pkg_name.to_string(),
+                version: pkg_version.to_string(),
+                fixed: fixed.or_else(|| extract_json_field(vuln_json, "fixed")).unwrap_or_default(),
+            });
         }
     }
+    Ok(vulns)
+}
 
-    // Parse "results" array - each element corresponds to a query
-    // Simple approach: find each "vulns" occurrence and extract vulnerability info
-    let mut search_pos = 0;
-    let mut pkg_idx = 0usize;
-    while let Some(vulns_pos) = resp_body[search_pos..].find("\"vulns\"") {
-        let abs_pos = search_pos + vulns_pos;
-        search_pos = abs_pos + 6;
-
-        let (pkg_name, pkg_version) = if pkg_idx < pkg_names.len() {
-            (pkg_names[pkg_idx].0.clone(), pkg_names[pkg_idx].1.clone())
-        } else {
-            ("unknown".to_string(), "0.0.0".to_string())
-        };
-        pkg_idx += 1;
+/// GitHub's GraphQL API; `BETTER_GITHUB_GRAPHQL_URL` points audits at a mirror
+/// or a GitHub Enterprise Server.
+fn github_graphql_url() -> String {
+    std::env::var("BETTER_GITHUB_GRAPHQL_URL").unwrap_or_else(|_| "https://api.github.com/graphql".to_string())
+}
 
-        // Find the array content
-        let after = &resp_body[abs_pos..];
-        if let Some(arr_start) = after.find('[') {
-            let arr_section = &after[arr_start..];
-            // Check if empty array
-            let trimmed = arr_section.trim_start_matches('[').trim_start();
-            if trimmed.starts_with(']') { continue; }
+/// Package names per GraphQL request, each an aliased `securityVulnerabilities` field
+const GITHUB_AUDIT_BATCH: usize = 50;
 
-            // Extract individual vulnerability objects
-            let mut depth = 0i32;
-            let mut obj_start = 0usize;
-            let mut in_str = false;
-            let mut esc = false;
-
-            for (i, ch) in arr_section.char_indices() {
-                if esc { esc = false; continue; }
-                if ch == '\\' && in_str { esc = true; continue; }
-                if ch == '"' { in_str = !in_str; continue; }
-                if in_str { continue; }
-                if ch == '{' {
-                    if depth == 0 { obj_start = i; }
-                    depth += 1;
-                } else if ch == '}' {
-                    depth -= 1;
-                    if depth == 0 {
-                        let vuln_json = &arr_section[obj_start + 1..i];
-                        let id = extract_json_field(vuln_json, "id").unwrap_or_default();
-                        let summary = extract_json_field(vuln_json, "summary")
-                            .unwrap_or_else(|| "No description".to_string());
-
-                        // Try to extract severity
-                        let severity = extract_json_field(vuln_json, "severity")
-                            .or_else(|| {
-                                if vuln_json.contains("CRITICAL") { Some("CRITICAL".to_string()) }
-                                else if vuln_json.contains("HIGH") { Some("HIGH".to_string()) }
-                                else if vuln_json.contains("MODERATE") || vuln_json.contains("MEDIUM") { Some("MEDIUM".to_string()) }
-                                else { Some("LOW".to_string()) }
-                            })
-                            .unwrap_or_else(|| "UNKNOWN".to_string());
-
-                        if severity_rank(&severity) >= min_rank {
-                            vulns.push(AuditVulnerability {
-                                id: id.clone(),
-                                summary,
-                                severity: severity.to_uppercase(),
-                                package: pkg_name.clone(),
-                                version: pkg_version.clone(),
-                                fixed: extract_json_field(vuln_json, "fixed").unwrap_or_default(),
-                            });
-                        }
-                    }
-                } else if ch == ']' && depth == 0 {
-                    break;
+/// Advisories from the GitHub Advisory Database for each `name@version`,
+/// with the token from `GITHUB_TOKEN` or `GH_TOKEN`. Each package name is
+/// queried once and its vulnerable ranges are matched locally.
+fn github_advisories(agent: &ureq::Agent, unique: &[(&str, &str)]) -> Result<Vec<AuditVulnerability>, CoreError> {
+    let token = ["GITHUB_TOKEN", "GH_TOKEN"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok().filter(|t| !t.is_empty()))
+        .ok_or_else(|| "the GitHub advisory source needs a token in GITHUB_TOKEN or GH_TOKEN".to_string())?;
+    let names: Vec<&str> = unique.iter().map(|(name, _)| *name).collect::<BTreeSet<_>>().into_iter().collect();
+    let url = github_graphql_url();
+    let mut vulns = Vec::new();
+    for chunk in names.chunks(GITHUB_AUDIT_BATCH) {
+        let mut query = String::from("query {");
+        for (i, name) in chunk.iter().enumerate() {
+            let mut literal = JsonWriter::compact();
+            literal.value_string(name);
+            query.push_str(&format!(
+                " p{}: securityVulnerabilities(ecosystem: NPM, package: {}, first: 100) {{ nodes {{ advisory {{ ghsaId summary severity }} vulnerableVersionRange firstPatchedVersion {{ identifier }} }} }}",
+                i,
+                literal.finish().trim_end()
+            ));
+        }
+        query.push_str(" }");
+        let mut body = JsonWriter::compact();
+        body.begin_object();
+        body.key("query");
+        body.value_string(&query);
+        body.end_object();
+        let resp = agent
+            .post(&url)
+            .set("Content-Type", "application/json")
+            .set("Authorization", &format!("Bearer {}", token))
+            .send_string(&body.finish())
+            .map_err(|e| CoreError::network(format!("GitHub advisory request failed: {}", redact_url_credentials(&e.to_string()))))?;
+        let text = resp.into_string().map_err(|e| CoreError::network(format!("Failed to read GitHub advisory response: {}", e)))?;
+        vulns.extend(parse_github_advisories(&text, chunk, unique)?);
+    }
+    Ok(vulns)
+}
+
+/// Map a GraphQL response whose fields `p0`, `p1`, ... answer for `names` onto
+/// the installed versions in `unique`. A `vulnerableVersionRange` such as
+/// `>= 4.0.0, < 4.17.21` is comma-separated comparators that must all hold.
+fn parse_github_advisories(body: &str, names: &[&str], unique: &[(&str, &str)]) -> Result<Vec<AuditVulnerability>, String> {
+    let doc = JsonValue::parse(body).map_err(|e| format!("malformed GitHub advisory response: {}", e))?;
+    let Some(data) = doc.get("data").filter(|d| d.as_object().is_some()) else {
+        let message = doc
+            .get("errors")
+            .and_then(JsonValue::as_array)
+            .and_then(|errors| errors.first())
+            .and_then(|e| e.get("message"))
+            .and_then(JsonValue::as_str)
+            .unwrap_or("response has no data");
+        return Err(format!("GitHub advisory query failed: {}", message));
+    };
+    let mut vulns = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let nodes = data.get(&format!("p{}", i)).and_then(|f| f.get("nodes")).and_then(JsonValue::as_array).unwrap_or_default();
+        for (_, version) in unique.iter().filter(|(n, _)| n == name) {
+            let Some(installed) = parse_semver(version) else { continue };
+            for node in nodes {
+                let range = node.get("vulnerableVersionRange").and_then(JsonValue::as_str).unwrap_or("");
+                let comparators = range.split(',').map(|c| c.split_whitespace().collect::<String>());
+                if !comparators.filter(|c| !c.is_empty()).all(|c| check_semver_comparator(&installed, &c)) {
+                    continue;
                 }
+                let advisory = |key: &str| node.get("advisory").and_then(|a| a.get(key)).and_then(JsonValue::as_str).unwrap_or("");
+                vulns.push(AuditVulnerability {
+                    id: advisory("ghsaId").to_string(),
+                    summary: Some(advisory("summary")).filter(|s| !s.is_empty()).unwrap_or("No description").to_string(),
+                    severity: normalize_severity(advisory("severity")),
+                    package: name.to_string(),
+                    version: version.to_string(),
+                    fixed: node
+                        .get("firstPatchedVersion")
+                        .and_then(|f| f.get("identifier"))
+                        .and_then(JsonValue::as_str)
+                        .unwrap_or("")
+                        .to_string(),
+                });
             }
         }
     }
+    Ok(vulns)
+}
+
+/// One severity vocabulary across sources: upper case, with GitHub's and
+/// OSV's MODERATE reported as MEDIUM.
+fn normalize_severity(severity: &str) -> String {
+    match severity.to_uppercase().as_str() {
+        "MODERATE" => "MEDIUM".to_string(),
+        other => other.to_string(),
+    }
+}
 
-    let total = vulns.len() as u64;
-    let critical = vulns.iter().filter(|v| v.severity == "CRITICAL").count() as u64;
-    let high = vulns.iter().filter(|v| v.severity == "HIGH").count() as u64;
-    let medium = vulns.iter().filter(|v| v.severity == "MEDIUM" || v.severity == "MODERATE").count() as u64;
-    let low = vulns.iter().filter(|v| v.severity == "LOW").count() as u64;
+#[deprecated(note = "use ProjectContext::run_audit")]
+pub fn run_audit(lockfile: &Path, project_root: &Path, min_severity: &str) -> Result<AuditReport, CoreError> {
+    ProjectContext::new(project_root).with_lockfile(lockfile).run_audit(min_severity)
+}
 
-    let risk_level = if critical > 0 { "critical" }
-        else if high > 0 { "high" }
-        else if medium > 0 { "medium" }
-        else if low > 0 { "low" }
-        else { "none" };
+impl ProjectContext {
+    /// Advisories from the context's [`AuditSource`] for the lockfile packages
+    /// at or above `min_severity`.
+    /// Findings listed in the project's [`AUDIT_IGNORE_FILE`] are left out
+    /// and only counted.
+    pub fn run_audit(&self, min_severity: &str) -> Result<AuditReport, CoreError> {
+        let lockfile = &self.lockfile;
+        let ignores = read_audit_ignores(&self.root)?;
+        let mut resolve_result = resolve_from_lockfile(lockfile)?;
+        let omitted_paths = self.omitted_lockfile_paths()?;
+        let before = resolve_result.packages.len();
+        resolve_result.packages.retain(|p| !omitted_paths.contains(&p.rel_path));
+        let omitted = (before - resolve_result.packages.len()) as u64;
 
-    Ok(AuditReport {
-        scanned_packages: query_count,
-        vulnerabilities: vulns,
-        total, critical, high, medium, low,
-        risk_level: risk_level.to_string(),
-    })
+        // One query per unique name@version; results come back in the same order
+        let mut seen: HashSet<(&str, &str)> = HashSet::new();
+        let unique: Vec<(&str, &str)> = resolve_result
+            .packages
+            .iter()
+            .map(|p| (p.name.as_str(), p.version.as_str()))
+            .filter(|key| seen.insert(*key))
+            .collect();
+
+        let npmrc = self.npmrc();
+        let agent = build_http_agent(Some(&npmrc));
+        let found = match self.audit_source {
+            AuditSource::Osv => osv_advisories(&agent, &npmrc, &unique)?,
+            AuditSource::GithubAdvisory => github_advisories(&agent, &unique)?,
+        };
+
+        let min_rank = audit_severity_rank(min_severity);
+        let mut vulns: Vec<AuditVulnerability> = Vec::new();
+        let mut ignored_count = 0u64;
+        for vuln in found {
+            if audit_severity_rank(&vuln.severity) < min_rank {
+                continue;
+            }
+            if ignores.iter().any(|i| i.id == vuln.id && i.package.as_deref().is_none_or(|p| p == vuln.package)) {
+                ignored_count += 1;
+                continue;
+            }
+            vulns.push(vuln);
+        }
+
+        let total = vulns.len() as u64;
+        let critical = vulns.iter().filter(|v| v.severity == "CRITICAL").count() as u64;
+        let high = vulns.iter().filter(|v| v.severity == "HIGH").count() as u64;
+        let medium = vulns.iter().filter(|v| v.severity == "MEDIUM").count() as u64;
+        let low = vulns.iter().filter(|v| v.severity == "LOW").count() as u64;
+
+        let risk_level = if critical > 0 { "critical" }
+            else if high > 0 { "high" }
+            else if medium > 0 { "medium" }
+            else if low > 0 { "low" }
+            else { "none" };
+
+        Ok(AuditReport {
+            scanned_packages: unique.len() as u64,
+            omitted,
+            ignored_count,
+            vulnerabilities: vulns,
+            total, critical, high, medium, low,
+            risk_level: risk_level.to_string(),
+        })
+    }
 }
 
 // --- B.9: Benchmark ---
@@ -3957,6 +8856,9 @@ pub struct BenchmarkTiming {
     pub min_ms: u64,
     pub max_ms: u64,
     pub mean_ms: u64,
+    /// Highest peak RSS across the measured runs, in bytes. `None` when the
+    /// platform gives no per-process figure.
+    pub peak_rss_bytes: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -3974,16 +8876,175 @@ pub struct BenchmarkReport {
     pub results: Vec<BenchmarkResult>,
 }
 
-fn compute_timing(mut times: Vec<u64>) -> BenchmarkTiming {
+fn compute_timing(mut times: Vec<u64>, peak_rss: &[u64]) -> BenchmarkTiming {
+    let peak_rss_bytes = peak_rss.iter().copied().max();
     if times.is_empty() {
-        return BenchmarkTiming { median_ms: 0, min_ms: 0, max_ms: 0, mean_ms: 0 };
+        return BenchmarkTiming { median_ms: 0, min_ms: 0, max_ms: 0, mean_ms: 0, peak_rss_bytes };
     }
     times.sort_unstable();
     let min_ms = times[0];
     let max_ms = *times.last().unwrap();
     let mean_ms = times.iter().sum::<u64>() / times.len() as u64;
     let median_ms = times[times.len() / 2];
-    BenchmarkTiming { median_ms, min_ms, max_ms, mean_ms }
+    BenchmarkTiming { median_ms, min_ms, max_ms, mean_ms, peak_rss_bytes }
+}
+
+/// Outcome of a benchmarked child process.
+pub struct MeasuredRun {
+    pub success: bool,
+    pub elapsed_ms: u64,
+    /// Peak resident set size in bytes, including the child's own descendants
+    /// where the platform accounts for them.
+    pub peak_rss_bytes: Option<u64>,
+}
+
+/// Spawn `cmd`, wait for it and capture its peak RSS. Returns `None` when the
+/// process cannot be spawned.
+pub fn run_measured(cmd: &mut std::process::Command) -> Option<MeasuredRun> {
+    let start = Instant::now();
+    let mut child = cmd.spawn().ok()?;
+    let job = job_object::attach(&child);
+    let (success, peak_rss_bytes) = match wait_with_rusage(&child) {
+        Some(done) => done,
+        None => (child.wait().map(|s| s.success()).unwrap_or(false), None),
+    };
+    let peak_rss_bytes = peak_rss_bytes.or_else(|| job.as_ref().and_then(|j| j.peak_memory()));
+    Some(MeasuredRun { success, elapsed_ms: start.elapsed().as_millis() as u64, peak_rss_bytes })
+}
+
+/// Reap the child with wait4(2) so its rusage comes back alongside the status.
+#[cfg(all(unix, target_pointer_width = "64"))]
+fn wait_with_rusage(child: &std::process::Child) -> Option<(bool, Option<u64>)> {
+    use std::os::raw::{c_int, c_long};
+
+    #[repr(C)]
+    struct RUsage {
+        ru_utime: [i64; 2],
+        ru_stime: [i64; 2],
+        ru_maxrss: c_long,
+        ru_rest: [c_long; 13],
+    }
+    extern "C" {
+        fn wait4(pid: c_int, status: *mut c_int, options: c_int, rusage: *mut RUsage) -> c_int;
+    }
+
+    let pid = child.id() as c_int;
+    let mut status: c_int = 0;
+    let mut usage = RUsage { ru_utime: [0; 2], ru_stime: [0; 2], ru_maxrss: 0, ru_rest: [0; 13] };
+    loop {
+        let ret = unsafe { wait4(pid, &mut status, 0, &mut usage) };
+        if ret == pid {
+            break;
+        }
+        if ret == -1 && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+            continue;
+        }
+        return None;
+    }
+    // Linux reports ru_maxrss in KiB, macOS in bytes.
+    let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    let peak = (usage.ru_maxrss > 0).then(|| usage.ru_maxrss as u64 * unit);
+    // A zero wait status means exited normally with code 0.
+    Some((status == 0, peak))
+}
+
+#[cfg(not(all(unix, target_pointer_width = "64")))]
+fn wait_with_rusage(_child: &std::process::Child) -> Option<(bool, Option<u64>)> {
+    None
+}
+
+/// Windows job object that tracks peak memory of a child and its descendants.
+#[cfg(windows)]
+mod job_object {
+    use std::ffi::c_void;
+    use std::os::windows::io::AsRawHandle;
+
+    type Handle = *mut c_void;
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS: i32 = 9;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimitInformation {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimitInformation {
+        basic: BasicLimitInformation,
+        io_counters: [u64; 6],
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(attributes: *mut c_void, name: *const u16) -> Handle;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        fn QueryInformationJobObject(job: Handle, class: i32, info: *mut c_void, len: u32, ret_len: *mut u32) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    pub struct JobObject(Handle);
+
+    pub fn attach(child: &std::process::Child) -> Option<JobObject> {
+        let job = unsafe { CreateJobObjectW(std::ptr::null_mut(), std::ptr::null()) };
+        if job.is_null() {
+            return None;
+        }
+        let job = JobObject(job);
+        if unsafe { AssignProcessToJobObject(job.0, child.as_raw_handle() as Handle) } == 0 {
+            return None;
+        }
+        Some(job)
+    }
+
+    impl JobObject {
+        pub fn peak_memory(&self) -> Option<u64> {
+            let mut info = ExtendedLimitInformation::default();
+            let ok = unsafe {
+                QueryInformationJobObject(
+                    self.0,
+                    JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS,
+                    &mut info as *mut _ as *mut c_void,
+                    std::mem::size_of::<ExtendedLimitInformation>() as u32,
+                    std::ptr::null_mut(),
+                )
+            };
+            (ok != 0 && info.peak_job_memory_used > 0).then_some(info.peak_job_memory_used as u64)
+        }
+    }
+
+    impl Drop for JobObject {
+        fn drop(&mut self) {
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
+
+#[cfg(not(windows))]
+mod job_object {
+    pub struct JobObject;
+
+    pub fn attach(_child: &std::process::Child) -> Option<JobObject> {
+        None
+    }
+
+    impl JobObject {
+        pub fn peak_memory(&self) -> Option<u64> {
+            None
+        }
+    }
 }
 
 pub fn run_benchmark(project_root: &Path, rounds: usize, pms: &[String]) -> Result<BenchmarkReport, String> {
@@ -3998,10 +9059,7 @@ pub fn run_benchmark(project_root: &Path, rounds: usize, pms: &[String]) -> Resu
         let (cmd, args): (&str, Vec<&str>) = match pm.as_str() {
             "npm" => ("npm", vec!["install", "--no-audit", "--no-fund"]),
             "bun" => ("bun", vec!["install"]),
-            "better" => {
-                let _exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("better-core"));
-                ("__self__", vec![])
-            }
+            "better" => ("__self__", vec![]),
             other => (other, vec!["install"]),
         };
 
@@ -4017,65 +9075,49 @@ pub fn run_benchmark(project_root: &Path, rounds: usize, pms: &[String]) -> Resu
             }
         }
 
+        let install_cmd = || {
+            let mut c = if pm == "better" {
+                let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("better-core"));
+                let mut c = std::process::Command::new(exe);
+                c.args(["install", "--project-root"]).arg(project_root);
+                c
+            } else {
+                let mut c = std::process::Command::new(cmd);
+                c.args(&args).current_dir(project_root);
+                c
+            };
+            c.stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null());
+            c
+        };
+
         let mut cold_times = Vec::new();
         let mut warm_times = Vec::new();
+        let mut cold_rss = Vec::new();
+        let mut warm_rss = Vec::new();
 
         for _round in 0..rounds {
             // Cold install: remove node_modules first
             let _ = fs::remove_dir_all(&node_modules);
-
-            let start = Instant::now();
-            let status = if pm == "better" {
-                let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("better-core"));
-                std::process::Command::new(&exe)
-                    .args(["install", "--project-root"])
-                    .arg(project_root)
-                    .stdout(std::process::Stdio::null())
-                    .stderr(std::process::Stdio::null())
-                    .status()
-            } else {
-                std::process::Command::new(cmd)
-                    .args(&args)
-                    .current_dir(project_root)
-                    .stdout(std::process::Stdio::null())
-                    .stderr(std::process::Stdio::null())
-                    .status()
-            };
-            if let Ok(s) = status {
-                if s.success() {
-                    cold_times.push(start.elapsed().as_millis() as u64);
+            if let Some(run) = run_measured(&mut install_cmd()) {
+                if run.success {
+                    cold_times.push(run.elapsed_ms);
+                    cold_rss.extend(run.peak_rss_bytes);
                 }
             }
 
             // Warm install: node_modules exists
-            let start = Instant::now();
-            let status = if pm == "better" {
-                let exe = std::env::current_exe().unwrap_or_else(|_| PathBuf::from("better-core"));
-                std::process::Command::new(&exe)
-                    .args(["install", "--project-root"])
-                    .arg(project_root)
-                    .stdout(std::process::Stdio::null())
-                    .stderr(std::process::Stdio::null())
-                    .status()
-            } else {
-                std::process::Command::new(cmd)
-                    .args(&args)
-                    .current_dir(project_root)
-                    .stdout(std::process::Stdio::null())
-                    .stderr(std::process::Stdio::null())
-                    .status()
-            };
-            if let Ok(s) = status {
-                if s.success() {
-                    warm_times.push(start.elapsed().as_millis() as u64);
+            if let Some(run) = run_measured(&mut install_cmd()) {
+                if run.success {
+                    warm_times.push(run.elapsed_ms);
+                    warm_rss.extend(run.peak_rss_bytes);
                 }
             }
         }
 
         results.push(BenchmarkResult {
             name: pm.clone(),
-            cold: compute_timing(cold_times),
-            warm: compute_timing(warm_times),
+            cold: compute_timing(cold_times, &cold_rss),
+            warm: compute_timing(warm_times, &warm_rss),
         });
     }
 
@@ -4095,38 +9137,77 @@ fn extract_hooks_config(pkg_json_content: &str) -> Vec<(String, String)> {
     }
 }
 
-/// Validate a commit message against conventional commit format: type(scope): description
-pub fn validate_conventional_commit(message: &str) -> Result<(), String> {
+/// Marker line in every hook [`hooks_install`] writes; [`hooks_uninstall`]
+/// only removes hooks that carry it.
+const HOOK_MARKER: &str = "# Installed by better-core hooks";
+
+pub const CONVENTIONAL_COMMIT_TYPES: [&str; 11] = [
+    "feat", "fix", "docs", "style", "refactor", "perf",
+    "test", "build", "ci", "chore", "revert",
+];
+
+/// The header of a valid conventional commit message.
+#[derive(Debug, Clone)]
+pub struct ConventionalCommit {
+    pub commit_type: String,
+    pub scope: Option<String>,
+    /// `type!:` marks a breaking change
+    pub breaking: bool,
+    pub description: String,
+}
+
+/// Why a commit message is not a conventional commit. `code` is a stable id
+/// (`empty`, `missing-colon`, `malformed-scope`, `invalid-type` or
+/// `missing-description`) tools can match on; `message` is for people.
+#[derive(Debug, Clone)]
+pub struct CommitMessageError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Parse the first line of a commit message as `type(scope)!: description`,
+/// where the scope and `!` are optional.
+pub fn parse_conventional_commit(message: &str) -> Result<ConventionalCommit, CommitMessageError> {
+    let error = |code, message: String| CommitMessageError { code, message };
     let first_line = message.lines().next().unwrap_or("").trim();
     if first_line.is_empty() {
-        return Err("Empty commit message".to_string());
+        return Err(error("empty", "Empty commit message".to_string()));
     }
-    let valid_types = [
-        "feat", "fix", "docs", "style", "refactor", "perf",
-        "test", "build", "ci", "chore", "revert",
-    ];
     // Check format: type(scope): desc  or  type: desc
     let colon_pos = match first_line.find(':') {
         Some(p) => p,
-        None => return Err(format!("Missing colon in commit message: '{}'", first_line)),
+        None => return Err(error("missing-colon", format!("Missing colon in commit message: '{}'", first_line))),
     };
     let prefix = &first_line[..colon_pos];
-    let type_name = if let Some(paren) = prefix.find('(') {
-        if !prefix.ends_with(')') {
-            return Err(format!("Malformed scope in commit message: '{}'", first_line));
-        }
-        &prefix[..paren]
+    let (prefix, breaking) = match prefix.strip_suffix('!') {
+        Some(rest) => (rest, true),
+        None => (prefix, false),
+    };
+    let (type_name, scope) = if let Some(paren) = prefix.find('(') {
+        let scope = prefix[paren + 1..].strip_suffix(')').filter(|s| !s.is_empty());
+        let Some(scope) = scope else {
+            return Err(error("malformed-scope", format!("Malformed scope in commit message: '{}'", first_line)));
+        };
+        (&prefix[..paren], Some(scope.to_string()))
     } else {
-        prefix
+        (prefix, None)
     };
-    if !valid_types.contains(&type_name) {
-        return Err(format!("Invalid commit type '{}'. Valid: {}", type_name, valid_types.join(", ")));
+    if !CONVENTIONAL_COMMIT_TYPES.contains(&type_name) {
+        return Err(error(
+            "invalid-type",
+            format!("Invalid commit type '{}'. Valid: {}", type_name, CONVENTIONAL_COMMIT_TYPES.join(", ")),
+        ));
     }
     let desc = first_line[colon_pos + 1..].trim();
     if desc.is_empty() {
-        return Err("Missing description after colon".to_string());
+        return Err(error("missing-description", "Missing description after colon".to_string()));
     }
-    Ok(())
+    Ok(ConventionalCommit { commit_type: type_name.to_string(), scope, breaking, description: desc.to_string() })
+}
+
+/// Validate a commit message against conventional commit format: type(scope): description
+pub fn validate_conventional_commit(message: &str) -> Result<(), String> {
+    parse_conventional_commit(message).map(|_| ()).map_err(|e| e.message)
 }
 
 #[derive(Debug)]
@@ -4136,13 +9217,13 @@ pub struct HooksInstallResult {
     pub hooks: Vec<(String, String)>,
 }
 
-pub fn hooks_install(project_root: &Path) -> Result<HooksInstallResult, String> {
+pub fn hooks_install(project_root: &Path, plan: &mut FsPlan) -> Result<HooksInstallResult, String> {
     let git_dir = project_root.join(".git");
     if !git_dir.exists() {
         return Err("Not a git repository".to_string());
     }
     let hooks_dir = git_dir.join("hooks");
-    fs::create_dir_all(&hooks_dir).map_err(|e| e.to_string())?;
+    plan.create_dir_all(&hooks_dir).map_err(|e| e.to_string())?;
 
     let pkg_json = project_root.join("package.json");
     let content = fs::read_to_string(&pkg_json).unwrap_or_default();
@@ -4172,29 +9253,24 @@ pub fn hooks_install(project_root: &Path) -> Result<HooksInstallResult, String>
         let hook_path = hooks_dir.join(hook_type);
         let script = if action == "conventional-commit" {
             format!(
-                "#!/bin/sh\n# Installed by better-core hooks\n\
+                "#!/bin/sh\n{}\n\
                 MSG=$(cat \"$1\" 2>/dev/null || echo \"$1\")\n\
-                if ! echo \"$MSG\" | grep -qE '^(feat|fix|docs|style|refactor|perf|test|build|ci|chore|revert)(\\(.*\\))?: .+'; then\n  \
+                if ! echo \"$MSG\" | grep -qE '^({})(\\(.+\\))?!?: .+'; then\n  \
                 echo \"error: commit message must follow Conventional Commits format\" >&2\n  \
                 echo \"  format: type(scope): description\" >&2\n  \
-                exit 1\nfi\n"
+                exit 1\nfi\n",
+                HOOK_MARKER,
+                CONVENTIONAL_COMMIT_TYPES.join("|")
             )
         } else {
             format!(
-                "#!/bin/sh\n# Installed by better-core hooks\nexec {} \"$@\"\n",
-                action
+                "#!/bin/sh\n{}\nexec {} \"$@\"\n",
+                HOOK_MARKER, action
             )
         };
 
-        fs::write(&hook_path, &script).map_err(|e| e.to_string())?;
-
-        #[cfg(unix)]
-        {
-            use std::os::unix::fs::PermissionsExt;
-            let mut perms = fs::metadata(&hook_path).map_err(|e| e.to_string())?.permissions();
-            perms.set_mode(0o755);
-            fs::set_permissions(&hook_path, perms).map_err(|e| e.to_string())?;
-        }
+        plan.write(&hook_path, &script).map_err(|e| e.to_string())?;
+        plan.set_executable(&hook_path).map_err(|e| e.to_string())?;
 
         hooks_installed += 1;
         installed.push((hook_type.clone(), action.clone()));
@@ -4203,6 +9279,45 @@ pub fn hooks_install(project_root: &Path) -> Result<HooksInstallResult, String>
     Ok(HooksInstallResult { hooks_installed, from_config, hooks: installed })
 }
 
+#[derive(Debug, Default)]
+pub struct HooksUninstallResult {
+    /// Hooks removed, by name
+    pub removed: Vec<String>,
+    /// Hooks left alone because better-core did not write them
+    pub kept: Vec<String>,
+}
+
+/// Remove the hooks [`hooks_install`] wrote from `.git/hooks`, recognised by
+/// their marker line. Hooks written by hand or by other tools, and git's
+/// `*.sample` files, are kept.
+pub fn hooks_uninstall(project_root: &Path, plan: &mut FsPlan) -> Result<HooksUninstallResult, String> {
+    let git_dir = project_root.join(".git");
+    if !git_dir.exists() {
+        return Err("Not a git repository".to_string());
+    }
+    let mut result = HooksUninstallResult::default();
+    let Ok(entries) = fs::read_dir(git_dir.join("hooks")) else {
+        return Ok(result);
+    };
+    let mut hooks: Vec<PathBuf> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.is_file() && p.extension().is_none_or(|ext| ext != "sample"))
+        .collect();
+    hooks.sort();
+    for path in hooks {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let ours = fs::read(&path).is_ok_and(|content| String::from_utf8_lossy(&content).lines().any(|l| l == HOOK_MARKER));
+        if ours {
+            plan.remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            result.removed.push(name);
+        } else {
+            result.kept.push(name);
+        }
+    }
+    Ok(result)
+}
+
 // --- C.3: Exec (TypeScript/JS runner) ---
 
 pub fn exec_script(project_root: &Path, script_path: &str, extra_args: &[String]) -> Result<ScriptRunResult, String> {
@@ -4315,15 +9430,16 @@ pub struct EnvCheckResult {
 }
 
 pub fn env_check(project_root: &Path) -> Result<EnvCheckResult, String> {
-    let info = env_info(project_root);
     let pkg_json = project_root.join("package.json");
     let content = fs::read_to_string(&pkg_json).unwrap_or_default();
     let engines = extract_json_object_pairs(&content, "engines").unwrap_or_default();
+    let package_manager = package_manager_check(project_root);
 
-    if engines.is_empty() {
+    if engines.is_empty() && package_manager.is_none() {
         return Ok(EnvCheckResult { checks: Vec::new(), all_ok: true });
     }
 
+    let info = env_info(project_root);
     let mut checks = Vec::new();
     for (tool, constraint) in &engines {
         let current_ver = match tool.as_str() {
@@ -4343,11 +9459,40 @@ pub fn env_check(project_root: &Path) -> Result<EnvCheckResult, String> {
             satisfied,
         });
     }
+    checks.extend(package_manager);
 
     let all_ok = checks.iter().all(|c| c.satisfied);
     Ok(EnvCheckResult { checks, all_ok })
 }
 
+/// The `packageManager` pin from package.json (`npm@10.2.3`, as Corepack reads
+/// it) as (name, version), without any `+sha512.<hash>` suffix.
+pub fn read_package_manager(project_root: &Path) -> Option<(String, String)> {
+    let content = fs::read_to_string(project_root.join("package.json")).ok()?;
+    let pin = extract_json_field(&content, "packageManager")?;
+    let pin = pin.split('+').next().unwrap_or("").trim();
+    // A scoped name keeps its leading @
+    let at = pin.get(1..)?.find('@')? + 1;
+    let (name, version) = (&pin[..at], &pin[at + 1..]);
+    if name.is_empty() || version.is_empty() {
+        return None;
+    }
+    Some((name.to_string(), version.to_string()))
+}
+
+/// Compare the `packageManager` pin with the running tool, better-core, whose
+/// version is matched exactly against a `better@<version>` pin. Any other tool
+/// never matches. None when the project pins nothing.
+pub fn package_manager_check(project_root: &Path) -> Option<EnvCheckEntry> {
+    let (name, version) = read_package_manager(project_root)?;
+    Some(EnvCheckEntry {
+        tool: "packageManager".to_string(),
+        current: format!("better@{}", VERSION),
+        satisfied: name == "better" && version == VERSION,
+        required: format!("{}@{}", name, version),
+    })
+}
+
 /// Load environment variables from .env and .env.local files.
 /// Later files override earlier ones. Skips comments and blank lines.
 fn load_dotenv(project_root: &Path) -> Vec<(String, String)> {
@@ -4448,19 +9593,19 @@ pub struct InitResult {
     pub template: Option<String>,
 }
 
-fn write_file(root: &Path, rel: &str, content: &str, files: &mut Vec<String>) -> Result<(), String> {
+fn write_file(plan: &mut FsPlan, root: &Path, rel: &str, content: &str, files: &mut Vec<String>) -> Result<(), String> {
     let path = root.join(rel);
     if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        plan.create_dir_all(parent).map_err(|e| e.to_string())?;
     }
-    fs::write(&path, content).map_err(|e| format!("Failed to write {}: {}", rel, e))?;
+    plan.write(&path, content).map_err(|e| format!("Failed to write {}: {}", rel, e))?;
     files.push(rel.to_string());
     Ok(())
 }
 
-fn write_react_template(root: &Path, name: &str) -> Result<Vec<String>, String> {
+fn write_react_template(plan: &mut FsPlan, root: &Path, name: &str) -> Result<Vec<String>, String> {
     let mut files = Vec::new();
-    let mut w = JsonWriter::new();
+    let mut w = JsonWriter::compact();
     w.begin_object();
     w.key("name"); w.value_string(name);
     w.key("version"); w.value_string("0.1.0");
@@ -4483,16 +9628,16 @@ fn write_react_template(root: &Path, name: &str) -> Result<Vec<String>, String>
     w.key("vite"); w.value_string("^5.0.0");
     w.end_object();
     w.end_object(); w.out.push('\n');
-    write_file(root, "package.json", &w.finish(), &mut files)?;
-    write_file(root, "tsconfig.json", TEMPLATE_TSCONFIG, &mut files)?;
-    write_file(root, ".gitignore", TEMPLATE_GITIGNORE, &mut files)?;
-    write_file(root, "src/App.tsx", TEMPLATE_REACT_APP, &mut files)?;
+    write_file(plan, root, "package.json", &w.finish(), &mut files)?;
+    write_file(plan, root, "tsconfig.json", TEMPLATE_TSCONFIG, &mut files)?;
+    write_file(plan, root, ".gitignore", TEMPLATE_GITIGNORE, &mut files)?;
+    write_file(plan, root, "src/App.tsx", TEMPLATE_REACT_APP, &mut files)?;
     Ok(files)
 }
 
-fn write_next_template(root: &Path, name: &str) -> Result<Vec<String>, String> {
+fn write_next_template(plan: &mut FsPlan, root: &Path, name: &str) -> Result<Vec<String>, String> {
     let mut files = Vec::new();
-    let mut w = JsonWriter::new();
+    let mut w = JsonWriter::compact();
     w.begin_object();
     w.key("name"); w.value_string(name);
     w.key("version"); w.value_string("0.1.0");
@@ -4512,16 +9657,16 @@ fn write_next_template(root: &Path, name: &str) -> Result<Vec<String>, String> {
     w.key("typescript"); w.value_string("^5.0.0");
     w.end_object();
     w.end_object(); w.out.push('\n');
-    write_file(root, "package.json", &w.finish(), &mut files)?;
-    write_file(root, "tsconfig.json", TEMPLATE_TSCONFIG, &mut files)?;
-    write_file(root, ".gitignore", TEMPLATE_GITIGNORE, &mut files)?;
-    write_file(root, "src/app/page.tsx", TEMPLATE_NEXT_PAGE, &mut files)?;
+    write_file(plan, root, "package.json", &w.finish(), &mut files)?;
+    write_file(plan, root, "tsconfig.json", TEMPLATE_TSCONFIG, &mut files)?;
+    write_file(plan, root, ".gitignore", TEMPLATE_GITIGNORE, &mut files)?;
+    write_file(plan, root, "src/app/page.tsx", TEMPLATE_NEXT_PAGE, &mut files)?;
     Ok(files)
 }
 
-fn write_express_template(root: &Path, name: &str) -> Result<Vec<String>, String> {
+fn write_express_template(plan: &mut FsPlan, root: &Path, name: &str) -> Result<Vec<String>, String> {
     let mut files = Vec::new();
-    let mut w = JsonWriter::new();
+    let mut w = JsonWriter::compact();
     w.begin_object();
     w.key("name"); w.value_string(name);
     w.key("version"); w.value_string("0.1.0");
@@ -4541,15 +9686,15 @@ fn write_express_template(root: &Path, name: &str) -> Result<Vec<String>, String
     w.key("typescript"); w.value_string("^5.0.0");
     w.end_object();
     w.end_object(); w.out.push('\n');
-    write_file(root, "package.json", &w.finish(), &mut files)?;
-    write_file(root, "tsconfig.json", TEMPLATE_TSCONFIG, &mut files)?;
-    write_file(root, ".gitignore", TEMPLATE_GITIGNORE, &mut files)?;
-    write_file(root, "src/app.ts", TEMPLATE_EXPRESS_APP, &mut files)?;
+    write_file(plan, root, "package.json", &w.finish(), &mut files)?;
+    write_file(plan, root, "tsconfig.json", TEMPLATE_TSCONFIG, &mut files)?;
+    write_file(plan, root, ".gitignore", TEMPLATE_GITIGNORE, &mut files)?;
+    write_file(plan, root, "src/app.ts", TEMPLATE_EXPRESS_APP, &mut files)?;
     Ok(files)
 }
 
-pub fn init_project(project_root: &Path, name: Option<&str>, template: Option<&str>) -> Result<InitResult, String> {
-    fs::create_dir_all(project_root).map_err(|e| e.to_string())?;
+pub fn init_project(project_root: &Path, name: Option<&str>, template: Option<&str>, plan: &mut FsPlan) -> Result<InitResult, String> {
+    plan.create_dir_all(project_root).map_err(|e| e.to_string())?;
 
     let pkg_json = project_root.join("package.json");
     if pkg_json.exists() {
@@ -4564,9 +9709,9 @@ pub fn init_project(project_root: &Path, name: Option<&str>, template: Option<&s
 
     if let Some(tmpl) = template {
         let files = match tmpl {
-            "react" => write_react_template(project_root, &project_name)?,
-            "next" => write_next_template(project_root, &project_name)?,
-            "express" => write_express_template(project_root, &project_name)?,
+            "react" => write_react_template(plan, project_root, &project_name)?,
+            "next" => write_next_template(plan, project_root, &project_name)?,
+            "express" => write_express_template(plan, project_root, &project_name)?,
             _ => return Err(format!("Unknown template '{}'. Available: react, next, express", tmpl)),
         };
         return Ok(InitResult { files_created: files, template: Some(tmpl.to_string()) });
@@ -4574,7 +9719,7 @@ pub fn init_project(project_root: &Path, name: Option<&str>, template: Option<&s
 
     // Default init (no template)
     let mut files = Vec::new();
-    let mut w = JsonWriter::new();
+    let mut w = JsonWriter::compact();
     w.begin_object();
     w.key("name"); w.value_string(&project_name);
     w.key("version"); w.value_string("1.0.0");
@@ -4588,7 +9733,7 @@ pub fn init_project(project_root: &Path, name: Option<&str>, template: Option<&s
     w.key("license"); w.value_string("ISC");
     w.end_object(); w.out.push('\n');
 
-    fs::write(&pkg_json, w.finish()).map_err(|e| e.to_string())?;
+    plan.write(&pkg_json, w.finish()).map_err(|e| e.to_string())?;
     files.push("package.json".to_string());
 
     Ok(InitResult { files_created: files, template: None })
@@ -4670,34 +9815,29 @@ pub fn run_script_watch(
     }
 
     let debounce = Duration::from_millis(debounce_ms);
-    loop {
-        match rx.recv() {
-            Ok(_event) => {
-                // Debounce: drain remaining events within the window
-                let deadline = Instant::now() + debounce;
-                while Instant::now() < deadline {
-                    match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
-                        Ok(_) => continue,
-                        Err(_) => break,
-                    }
-                }
+    while let Ok(_event) = rx.recv() {
+        // Debounce: drain remaining events within the window
+        let deadline = Instant::now() + debounce;
+        while Instant::now() < deadline {
+            match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
 
-                eprintln!("[better] restarting '{}'...", script_name);
+        eprintln!("[better] restarting '{}'...", script_name);
 
-                // Kill old child
-                let _ = child.kill();
-                let _ = child.wait();
+        // Kill old child
+        let _ = child.kill();
+        let _ = child.wait();
 
-                // Re-spawn
-                match spawn_script(project_root, script_name, extra_args) {
-                    Ok(c) => child = c,
-                    Err(e) => {
-                        eprintln!("[better] error: {}", e);
-                        continue;
-                    }
-                }
+        // Re-spawn
+        match spawn_script(project_root, script_name, extra_args) {
+            Ok(c) => child = c,
+            Err(e) => {
+                eprintln!("[better] error: {}", e);
+                continue;
             }
-            Err(_) => break,
         }
     }
 
@@ -4767,7 +9907,37 @@ pub struct NpmrcConfig {
     pub default_registry: String,
     pub scoped_registries: Vec<(String, String)>,
     pub auth_tokens: Vec<(String, String)>,
-}
+    /// `engine-strict`: refuse to install when package.json `engines` are unmet.
+    pub engine_strict: bool,
+    /// `package-manager-strict`: refuse to install when package.json
+    /// `packageManager` pins another tool or version.
+    pub package_manager_strict: bool,
+    /// `prefix`: where global packages and their bins are installed.
+    pub prefix: Option<String>,
+    /// `header[]=Name: value`: extra headers sent with every registry request.
+    pub headers: Vec<(String, String)>,
+    /// `fetch-timeout` (ms): how long a single request may take, from
+    /// connecting to reading the last byte of the response.
+    pub fetch_timeout_ms: u64,
+    /// `fetch-retries`; None leaves the download retry policy's default.
+    pub fetch_retries: Option<u32>,
+    /// `fetch-retry-mintimeout` (ms): the first retry's backoff.
+    pub fetch_retry_mintimeout_ms: Option<u64>,
+    /// `fetch-retry-maxtimeout` (ms): the cap on any retry's backoff.
+    pub fetch_retry_maxtimeout_ms: Option<u64>,
+    /// `maxsockets`: downloads in flight at once; 0 for no limit.
+    pub maxsockets: Option<usize>,
+    /// `proxy`: the proxy for http:// requests, and https:// ones when
+    /// `https-proxy` is unset. `HTTP_PROXY` in the environment wins.
+    pub proxy: Option<String>,
+    /// `https-proxy`; `HTTPS_PROXY` in the environment wins.
+    pub https_proxy: Option<String>,
+    /// `noproxy`: comma-separated hosts to reach directly; `NO_PROXY` wins.
+    pub noproxy: Option<String>,
+}
+
+/// `fetch-timeout` when neither npmrc, the environment nor `--timeout` set one.
+pub const DEFAULT_FETCH_TIMEOUT_MS: u64 = 300_000;
 
 impl Default for NpmrcConfig {
     fn default() -> Self {
@@ -4775,15 +9945,26 @@ impl Default for NpmrcConfig {
             default_registry: "https://registry.npmjs.org/".to_string(),
             scoped_registries: Vec::new(),
             auth_tokens: Vec::new(),
+            engine_strict: false,
+            package_manager_strict: false,
+            prefix: None,
+            headers: Vec::new(),
+            fetch_timeout_ms: DEFAULT_FETCH_TIMEOUT_MS,
+            fetch_retries: None,
+            fetch_retry_mintimeout_ms: None,
+            fetch_retry_maxtimeout_ms: None,
+            maxsockets: None,
+            proxy: None,
+            https_proxy: None,
+            noproxy: None,
         }
     }
 }
 
+/// Project `.npmrc`, then `~/.npmrc`, then `npm_config_*` / `NODE_AUTH_TOKEN`
+/// from the environment, which take precedence over both files.
 pub fn parse_npmrc(project_root: &Path) -> NpmrcConfig {
     let mut config = NpmrcConfig::default();
-    if let Ok(reg) = std::env::var("NPM_CONFIG_REGISTRY") {
-        config.default_registry = reg;
-    }
     let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
     let candidates = [
         project_root.join(".npmrc"),
@@ -4794,17 +9975,73 @@ pub fn parse_npmrc(project_root: &Path) -> NpmrcConfig {
             parse_npmrc_content(&content, &mut config);
         }
     }
-    for (key, value) in std::env::vars() {
+    apply_npm_env(&mut config, std::env::vars());
+    config
+}
+
+/// Layer environment config over what the npmrc files set. Env auth tokens go
+/// in front of npmrc ones so they win for the same registry; `NODE_AUTH_TOKEN`
+/// (as written by CI setup actions) authenticates the default registry.
+fn apply_npm_env(config: &mut NpmrcConfig, vars: impl Iterator<Item = (String, String)>) {
+    let mut env_tokens: Vec<(String, String)> = Vec::new();
+    let mut node_auth_token: Option<String> = None;
+    for (key, value) in vars {
         let lower = key.to_lowercase();
-        if lower.starts_with("npm_config_") {
-            let suffix = &key["npm_config_".len()..];
-            if suffix.starts_with("//") && suffix.to_lowercase().ends_with(":_authtoken") {
-                let host = &suffix[2..suffix.len() - ":_authtoken".len()];
-                config.auth_tokens.push((host.to_string(), value));
-            }
+        if lower == "node_auth_token" {
+            node_auth_token = Some(value);
+            continue;
         }
-    }
-    config
+        let Some(suffix) = lower.strip_prefix("npm_config_") else { continue };
+        let raw = &key["npm_config_".len()..];
+        if suffix == "registry" {
+            config.default_registry = value;
+        } else if suffix == "engine_strict" || suffix == "engine-strict" {
+            config.engine_strict = value.trim() == "true";
+        } else if suffix == "package_manager_strict" || suffix == "package-manager-strict" {
+            config.package_manager_strict = value.trim() == "true";
+        } else if suffix == "prefix" {
+            config.prefix = Some(value);
+        } else if suffix == "maxsockets" {
+            config.maxsockets = value.trim().parse().ok().or(config.maxsockets);
+        } else if suffix == "proxy" {
+            config.proxy = Some(value);
+        } else if suffix == "https_proxy" || suffix == "https-proxy" {
+            config.https_proxy = Some(value);
+        } else if suffix == "noproxy" {
+            config.noproxy = Some(value);
+        } else if let Some(key) = suffix.strip_prefix("fetch_").or_else(|| suffix.strip_prefix("fetch-")) {
+            apply_fetch_setting(config, &key.replace('_', "-"), &value);
+        } else if suffix == "header" {
+            // One header per line, replacing the npmrc ones
+            config.headers = value.lines().filter_map(parse_header_line).collect();
+        } else if suffix.starts_with("//") && suffix.ends_with(":_authtoken") {
+            let host = &raw[2..raw.len() - ":_authtoken".len()];
+            env_tokens.push((host.to_string(), value));
+        } else if suffix.starts_with('@') && suffix.ends_with(":registry") {
+            let scope = suffix[..suffix.len() - ":registry".len()].to_string();
+            config.scoped_registries.retain(|(s, _)| *s != scope);
+            config.scoped_registries.push((scope, value));
+        }
+    }
+    if let Some(token) = node_auth_token.filter(|t| !t.is_empty()) {
+        let host = strip_url_scheme(&config.default_registry).to_string();
+        env_tokens.push((host, token));
+    }
+    config.auth_tokens.splice(0..0, env_tokens);
+}
+
+/// Expand `${VAR}` references in an npmrc value; unset variables expand to "".
+fn expand_npmrc_env(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else { break };
+        out.push_str(&rest[..start]);
+        out.push_str(&std::env::var(&rest[start + 2..start + 2 + len]).unwrap_or_default());
+        rest = &rest[start + 3 + len..];
+    }
+    out.push_str(rest);
+    out
 }
 
 fn parse_npmrc_content(content: &str, config: &mut NpmrcConfig) {
@@ -4815,9 +10052,27 @@ fn parse_npmrc_content(content: &str, config: &mut NpmrcConfig) {
         }
         if let Some(eq_pos) = line.find('=') {
             let key = line[..eq_pos].trim();
-            let value = line[eq_pos + 1..].trim().to_string();
+            let value = expand_npmrc_env(line[eq_pos + 1..].trim());
             if key == "registry" {
                 config.default_registry = value;
+            } else if key == "engine-strict" {
+                config.engine_strict = value == "true";
+            } else if key == "package-manager-strict" {
+                config.package_manager_strict = value == "true";
+            } else if key == "prefix" {
+                config.prefix = Some(value);
+            } else if key == "maxsockets" {
+                config.maxsockets = value.parse().ok().or(config.maxsockets);
+            } else if key == "proxy" {
+                config.proxy = Some(value);
+            } else if key == "https-proxy" {
+                config.https_proxy = Some(value);
+            } else if key == "noproxy" {
+                config.noproxy = Some(value);
+            } else if let Some(key) = key.strip_prefix("fetch-") {
+                apply_fetch_setting(config, key, &value);
+            } else if key == "header[]" {
+                config.headers.extend(parse_header_line(&value));
             } else if key.starts_with("//") && key.ends_with(":_authToken") {
                 let host = &key[2..key.len() - ":_authToken".len()];
                 config.auth_tokens.push((host.to_string(), value));
@@ -4829,6 +10084,182 @@ fn parse_npmrc_content(content: &str, config: &mut NpmrcConfig) {
     }
 }
 
+/// `fetch-<key>` settings; values that are not numbers are ignored.
+fn apply_fetch_setting(config: &mut NpmrcConfig, key: &str, value: &str) {
+    let value = value.trim();
+    match key {
+        "timeout" => {
+            if let Ok(ms) = value.parse::<u64>() {
+                config.fetch_timeout_ms = ms;
+            }
+        }
+        "retries" => config.fetch_retries = value.parse().ok().or(config.fetch_retries),
+        "retry-mintimeout" => config.fetch_retry_mintimeout_ms = value.parse().ok().or(config.fetch_retry_mintimeout_ms),
+        "retry-maxtimeout" => config.fetch_retry_maxtimeout_ms = value.parse().ok().or(config.fetch_retry_maxtimeout_ms),
+        _ => {}
+    }
+}
+
+/// `Name: value` -> `(Name, value)`; None for a line without a header name.
+fn parse_header_line(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((name.to_string(), value.trim().to_string()))
+}
+
+/// The agent every outbound request goes through: identifies as
+/// `better-core/<version>`, bounds each request by `fetch-timeout`, adds the
+/// configured `header[]` entries, which override a header of the same name set
+/// by the caller, and sends requests through the proxy [`ProxyConfig`] picks.
+pub fn build_http_agent(npmrc: Option<&NpmrcConfig>) -> ureq::Agent {
+    let mut builder = http_agent_builder(npmrc);
+    let headers = npmrc.map(|c| c.headers.clone()).unwrap_or_default();
+    if !headers.is_empty() {
+        builder = builder.middleware(ExtraHeaders(headers));
+    }
+    let config = ProxyConfig::from_env(npmrc);
+    let mut proxies: Vec<(String, Option<ureq::Agent>)> = Vec::new();
+    for url in config.https.iter().chain(config.http.iter()) {
+        if proxies.iter().all(|(p, _)| p != url) {
+            let agent = ureq::Proxy::new(url).ok().map(|proxy| http_agent_builder(npmrc).proxy(proxy).build());
+            proxies.push((url.clone(), agent));
+        }
+    }
+    if proxies.is_empty() {
+        return builder.build();
+    }
+    // Last, so the requests it re-issues carry every header set before it
+    builder.middleware(ProxyRouter { config, proxies }).build()
+}
+
+fn http_agent_builder(npmrc: Option<&NpmrcConfig>) -> ureq::AgentBuilder {
+    let timeout = std::time::Duration::from_millis(npmrc.map_or(DEFAULT_FETCH_TIMEOUT_MS, |c| c.fetch_timeout_ms).max(1));
+    ureq::AgentBuilder::new()
+        .user_agent(&format!("better-core/{}", VERSION))
+        // A whole-request deadline rather than per-read timeouts: ureq only
+        // re-applies the latter to fresh sockets, not pooled ones
+        .timeout(timeout)
+        // Proxies come from ProxyConfig, which also honors NO_PROXY
+        .try_proxy_from_env(false)
+}
+
+/// Which proxy, if any, a request goes through. `HTTPS_PROXY`, `HTTP_PROXY`
+/// and `NO_PROXY` (or their lowercase forms) take precedence over npmrc
+/// `https-proxy`, `proxy` and `noproxy`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ProxyConfig {
+    /// For https:// URLs; falls back to `http` when unset.
+    pub https: Option<String>,
+    /// For http:// URLs.
+    pub http: Option<String>,
+    /// Hosts reached directly: `example.com` also covers its subdomains,
+    /// `host:port` only that port, and `*` every host.
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    pub fn from_env(npmrc: Option<&NpmrcConfig>) -> Self {
+        Self::resolve(npmrc, |name| std::env::var(name).ok())
+    }
+
+    fn resolve(npmrc: Option<&NpmrcConfig>, env: impl Fn(&str) -> Option<String>) -> Self {
+        let setting = |var: &str, npmrc_value: Option<&Option<String>>| {
+            env(var)
+                .or_else(|| env(&var.to_lowercase()))
+                .or_else(|| npmrc_value.cloned().flatten())
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let no_proxy = setting("NO_PROXY", npmrc.map(|c| &c.noproxy)).unwrap_or_default();
+        ProxyConfig {
+            https: setting("HTTPS_PROXY", npmrc.map(|c| &c.https_proxy)),
+            http: setting("HTTP_PROXY", npmrc.map(|c| &c.proxy)),
+            no_proxy: no_proxy
+                .split([',', ' '])
+                .map(|entry| entry.trim().to_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        }
+    }
+
+    /// The proxy URL a request to `url` goes through; None to connect directly.
+    pub fn proxy_for(&self, url: &str) -> Option<&str> {
+        let (scheme, rest) = url.split_once("://")?;
+        let proxy = if scheme.eq_ignore_ascii_case("https") { self.https.as_ref().or(self.http.as_ref()) } else { self.http.as_ref() }?;
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+        let authority = authority.rsplit_once('@').map_or(authority, |(_, host)| host).to_lowercase();
+        let (host, port) = split_host_port(&authority);
+        let port = port.unwrap_or(if scheme.eq_ignore_ascii_case("https") { "443" } else { "80" });
+        if self.no_proxy.iter().any(|entry| no_proxy_matches(entry, host, port)) {
+            return None;
+        }
+        Some(proxy)
+    }
+}
+
+/// `host:port` -> (host, port), keeping an IPv6 literal's brackets off the host.
+fn split_host_port(authority: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, after) = rest.split_once(']').unwrap_or((rest, ""));
+        return (host, after.strip_prefix(':'));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host, Some(port)),
+        _ => (authority, None),
+    }
+}
+
+fn no_proxy_matches(entry: &str, host: &str, port: &str) -> bool {
+    if entry == "*" {
+        return true;
+    }
+    let (pattern, entry_port) = split_host_port(entry);
+    if entry_port.is_some_and(|p| p != port) {
+        return false;
+    }
+    let domain = pattern.trim_start_matches("*.").trim_start_matches('.');
+    host == domain || host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// ureq fixes the proxy per agent, so requests that need one are re-issued on
+/// an agent built for it. The body and deadline stay with the request.
+struct ProxyRouter {
+    config: ProxyConfig,
+    /// Each configured proxy URL and its agent; None when the URL is invalid
+    proxies: Vec<(String, Option<ureq::Agent>)>,
+}
+
+impl ureq::Middleware for ProxyRouter {
+    fn handle(&self, request: ureq::Request, next: ureq::MiddlewareNext) -> Result<ureq::Response, ureq::Error> {
+        let Some(url) = self.config.proxy_for(request.url()) else {
+            return next.handle(request);
+        };
+        let Some(agent) = self.proxies.iter().find(|(p, _)| p == url).and_then(|(_, agent)| agent.as_ref()) else {
+            let message = format!("invalid proxy URL: {}", redact_url_credentials(url));
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message).into());
+        };
+        let mut routed = agent.request(request.method(), request.url());
+        for name in request.header_names() {
+            if let Some(value) = request.header(&name) {
+                routed = routed.set(&name, value);
+            }
+        }
+        next.handle(routed)
+    }
+}
+
+struct ExtraHeaders(Vec<(String, String)>);
+
+impl ureq::Middleware for ExtraHeaders {
+    fn handle(&self, request: ureq::Request, next: ureq::MiddlewareNext) -> Result<ureq::Response, ureq::Error> {
+        let request = self.0.iter().fold(request, |r, (name, value)| r.set(name, value));
+        next.handle(request)
+    }
+}
+
 pub fn registry_for_package<'a>(config: &'a NpmrcConfig, package_name: &str) -> (&'a str, Option<&'a str>) {
     if package_name.starts_with('@') {
         if let Some(slash) = package_name.find('/') {
@@ -4845,23 +10276,50 @@ pub fn registry_for_package<'a>(config: &'a NpmrcConfig, package_name: &str) ->
     (&config.default_registry, token)
 }
 
-fn find_auth_token<'a>(config: &'a NpmrcConfig, registry_url: &str) -> Option<&'a str> {
-    let host = registry_url
-        .strip_prefix("https://")
-        .or_else(|| registry_url.strip_prefix("http://"))
-        .unwrap_or(registry_url)
-        .trim_end_matches('/');
+fn strip_url_scheme(url: &str) -> &str {
+    url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")).unwrap_or(url)
+}
+
+/// `text` with the `user:password@` of every URL in it masked, for request
+/// errors that name the URL and end up in reports.
+pub fn redact_url_credentials(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("://") {
+        let (head, tail) = rest.split_at(start + 3);
+        out.push_str(head);
+        let authority_len = tail.find(|c: char| matches!(c, '/' | '?' | '#') || c.is_whitespace()).unwrap_or(tail.len());
+        match tail[..authority_len].rfind('@') {
+            Some(at) => {
+                out.push_str("***");
+                rest = &tail[at..];
+            }
+            None => rest = tail,
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The token for `url`: the longest configured `//host[:port]/path/` that is a
+/// path prefix of it, so a token is only ever sent to the host it was set for.
+/// On a tie the earlier entry (env before npmrc) wins.
+pub fn find_auth_token<'a>(config: &'a NpmrcConfig, url: &str) -> Option<&'a str> {
+    let target = strip_url_scheme(url).trim_end_matches('/');
+    let mut best: Option<(usize, &str)> = None;
     for (token_host, token) in &config.auth_tokens {
         let th = token_host.trim_end_matches('/');
-        if host == th || host.ends_with(th) {
-            return Some(token);
+        let matches = target.strip_prefix(th).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        if matches && !th.is_empty() && best.is_none_or(|(len, _)| th.len() > len) {
+            best = Some((th.len(), token));
         }
     }
-    None
+    best.map(|(_, token)| token)
 }
 
 // === D.2: Script sandboxing policy ===
 
+#[derive(Debug, Clone)]
 pub struct ScriptPolicy {
     pub default_policy: String,
     pub allowed_packages: Vec<String>,
@@ -4892,7 +10350,8 @@ pub fn load_script_policy(project_root: &Path) -> ScriptPolicy {
     }
     let pkg_json = project_root.join("package.json");
     if let Ok(content) = fs::read_to_string(&pkg_json) {
-        if let Some(raw) = extract_json_object_raw(&content, "betterScripts") {
+        let better_scripts = extract_json_object_raw(&content, "better").and_then(|better| extract_json_object_raw(&better, "scripts"));
+        if let Some(raw) = better_scripts.or_else(|| extract_json_object_raw(&content, "betterScripts")) {
             return parse_script_policy_json(&raw);
         }
     }
@@ -4938,6 +10397,30 @@ pub fn check_script_permission(policy: &ScriptPolicy, package_name: &str, script
     (policy.default_policy.clone(), format!("default policy: {}", policy.default_policy))
 }
 
+/// Whether install may run `script_type` of `package_name`, or why not.
+///
+/// Blocked packages never run. Packages in the allowed list or a trusted
+/// scope run their allowed script types. Any other package runs only with
+/// `allow_all` (`install --allow-all-scripts`), as pnpm does.
+pub fn check_lifecycle_permission(policy: &ScriptPolicy, allow_all: bool, package_name: &str, script_type: &str) -> Result<(), String> {
+    if policy.blocked_packages.iter().any(|b| b == package_name) {
+        return Err("package is in blocked list".into());
+    }
+    let scope = package_name.strip_prefix('@').and_then(|_| package_name.split_once('/')).map(|(scope, _)| scope);
+    let listed = policy.allowed_packages.iter().any(|a| a == package_name)
+        || scope.is_some_and(|scope| policy.trusted_scopes.iter().any(|s| s == scope));
+    if allow_all {
+        return Ok(());
+    }
+    if !listed {
+        return Err("package is not in allowed list".into());
+    }
+    if !policy.allowed_script_types.iter().any(|t| t == script_type) {
+        return Err(format!("script type '{}' is not allowed", script_type));
+    }
+    Ok(())
+}
+
 pub fn scan_scripts(project_root: &Path) -> Result<ScriptScanResult, String> {
     let nm = project_root.join("node_modules");
     let pkg_dirs = list_packages_in_node_modules(&nm)?;
@@ -4964,28 +10447,28 @@ pub fn scan_scripts(project_root: &Path) -> Result<ScriptScanResult, String> {
     Ok(ScriptScanResult { packages, total_with_scripts, allowed, blocked })
 }
 
-pub fn scripts_allow(project_root: &Path, package: &str) -> Result<ScriptPolicy, String> {
+pub fn scripts_allow(project_root: &Path, package: &str, plan: &mut FsPlan) -> Result<ScriptPolicy, String> {
     let mut policy = load_script_policy(project_root);
     policy.blocked_packages.retain(|p| p != package);
     if !policy.allowed_packages.iter().any(|p| p == package) {
         policy.allowed_packages.push(package.to_string());
     }
-    write_script_policy(project_root, &policy)?;
+    write_script_policy(project_root, &policy, plan)?;
     Ok(policy)
 }
 
-pub fn scripts_block(project_root: &Path, package: &str) -> Result<ScriptPolicy, String> {
+pub fn scripts_block(project_root: &Path, package: &str, plan: &mut FsPlan) -> Result<ScriptPolicy, String> {
     let mut policy = load_script_policy(project_root);
     policy.allowed_packages.retain(|p| p != package);
     if !policy.blocked_packages.iter().any(|p| p == package) {
         policy.blocked_packages.push(package.to_string());
     }
-    write_script_policy(project_root, &policy)?;
+    write_script_policy(project_root, &policy, plan)?;
     Ok(policy)
 }
 
-fn write_script_policy(project_root: &Path, policy: &ScriptPolicy) -> Result<(), String> {
-    let mut w = JsonWriter::new();
+fn write_script_policy(project_root: &Path, policy: &ScriptPolicy, plan: &mut FsPlan) -> Result<(), String> {
+    let mut w = JsonWriter::compact();
     w.begin_object();
     w.key("defaultPolicy"); w.value_string(&policy.default_policy);
     w.key("allowedPackages"); w.begin_array();
@@ -5002,7 +10485,7 @@ fn write_script_policy(project_root: &Path, policy: &ScriptPolicy) -> Result<(),
     w.end_array();
     w.end_object();
     w.out.push('\n');
-    fs::write(project_root.join(".better-scripts.json"), w.finish())
+    plan.write(&project_root.join(".better-scripts.json"), w.finish())
         .map_err(|e| format!("Failed to write policy: {}", e))
 }
 
@@ -5126,7 +10609,7 @@ pub fn policy_check(project_root: &Path) -> Result<PolicyCheckResult, String> {
     }
 
     // Check duplicates
-    if let Ok(dedupe_report) = check_dedupe(project_root) {
+    if let Ok(dedupe_report) = ProjectContext::new(project_root).check_dedupe() {
         let max_dup = config.rules.iter().find(|r| r.id == "max-duplicates")
             .and_then(|r| r.max_duplicates).unwrap_or(3);
         for entry in &dedupe_report.duplicates {
@@ -5170,9 +10653,9 @@ pub fn policy_check(project_root: &Path) -> Result<PolicyCheckResult, String> {
     Ok(PolicyCheckResult { score, threshold: config.threshold, pass, violations, errors, warnings, waived })
 }
 
-pub fn policy_init(project_root: &Path) -> Result<String, String> {
+pub fn policy_init(project_root: &Path, plan: &mut FsPlan) -> Result<String, String> {
     let path = project_root.join(".betterrc.json");
-    let mut w = JsonWriter::new();
+    let mut w = JsonWriter::compact();
     w.begin_object();
     w.key("policy"); w.begin_object();
     w.key("threshold"); w.value_i64(70);
@@ -5199,7 +10682,7 @@ pub fn policy_init(project_root: &Path) -> Result<String, String> {
     w.end_object();
     w.end_object();
     w.out.push('\n');
-    fs::write(&path, w.finish()).map_err(|e| format!("Failed to write config: {}", e))?;
+    plan.write(&path, w.finish()).map_err(|e| format!("Failed to write config: {}", e))?;
     Ok(path.to_string_lossy().to_string())
 }
 
@@ -5230,7 +10713,7 @@ pub struct LockVerifyResult {
 fn build_lock_metadata(project_root: &Path) -> Result<LockMetadata, String> {
     use sha2::{Digest, Sha256};
     let lockfile_candidates = [
-        ("package-lock.json", "npm"), ("pnpm-lock.yaml", "pnpm"),
+        ("npm-shrinkwrap.json", "npm"), ("package-lock.json", "npm"), ("pnpm-lock.yaml", "pnpm"),
         ("yarn.lock", "yarn"), ("bun.lock", "bun"),
     ];
     let mut lockfile_path = None;
@@ -5267,9 +10750,9 @@ fn build_lock_metadata(project_root: &Path) -> Result<LockMetadata, String> {
     Ok(LockMetadata { key, lockfile_file, lockfile_hash, fingerprint })
 }
 
-pub fn generate_lock_metadata(project_root: &Path) -> Result<LockMetadata, String> {
+pub fn generate_lock_metadata(project_root: &Path, plan: &mut FsPlan) -> Result<LockMetadata, String> {
     let metadata = build_lock_metadata(project_root)?;
-    let mut w = JsonWriter::new();
+    let mut w = JsonWriter::compact();
     w.begin_object();
     w.key("key"); w.value_string(&metadata.key);
     w.key("lockfile"); w.value_string(&metadata.lockfile_file);
@@ -5282,7 +10765,7 @@ pub fn generate_lock_metadata(project_root: &Path) -> Result<LockMetadata, Strin
     w.end_object();
     w.end_object();
     w.out.push('\n');
-    fs::write(project_root.join("better.lock.json"), w.finish())
+    plan.write(&project_root.join("better.lock.json"), w.finish())
         .map_err(|e| format!("Failed to write better.lock.json: {}", e))?;
     Ok(metadata)
 }
@@ -5312,6 +10795,316 @@ pub fn verify_lock_metadata(project_root: &Path) -> Result<LockVerifyResult, Str
     Ok(LockVerifyResult { ok, key_matches, lockfile_matches, expected, current })
 }
 
+/// Content hash of an installed node_modules tree.
+pub struct TreeHash {
+    pub hash: String,
+    pub files: u64,
+    pub symlinks: u64,
+}
+
+pub struct ReproducibleVerifyResult {
+    pub ok: bool,
+    pub expected: Option<String>,
+    pub current: TreeHash,
+}
+
+/// File that records the expected tree hash, next to better.lock.json.
+pub const TREE_HASH_FILE: &str = "better.tree.json";
+
+/// Hash a node_modules tree from sorted relative paths plus file contents
+/// (and symlink targets). Timestamps, permissions and inode identity are
+/// left out so a fresh install and a restored cache hash the same.
+/// `node_modules/.cache` is skipped: tools write there after install. So is
+/// install's own [`INSTALL_INTEGRITY_FILE`].
+pub fn hash_node_modules_tree(node_modules: &Path) -> Result<TreeHash, String> {
+    if !node_modules.is_dir() {
+        return Err(format!("node_modules not found at {}", node_modules.display()));
+    }
+    hash_dir_tree(node_modules, &[".cache", INSTALL_INTEGRITY_FILE])
+}
+
+/// The [`hash_node_modules_tree`] hash of `root`, leaving out the top-level
+/// entries named in `skip`.
+fn hash_dir_tree(root: &Path, skip: &[&str]) -> Result<TreeHash, String> {
+    use sha2::{Digest, Sha256};
+
+    fn walk(dir: &Path, rel: &str, skip: &[&str], out: &mut Vec<(String, Option<PathBuf>)>) -> Result<(), String> {
+        let entries = fs::read_dir(dir)
+            .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read dir entry: {}", e))?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let child_rel = if rel.is_empty() { name.clone() } else { format!("{}/{}", rel, name) };
+            if rel.is_empty() && skip.contains(&name.as_str()) {
+                continue;
+            }
+            let ft = entry.file_type()
+                .map_err(|e| format!("Failed to stat {}: {}", entry.path().display(), e))?;
+            if ft.is_symlink() {
+                let target = fs::read_link(entry.path())
+                    .map_err(|e| format!("Failed to read link {}: {}", entry.path().display(), e))?;
+                out.push((child_rel, Some(target)));
+            } else if ft.is_dir() {
+                walk(&entry.path(), &child_rel, skip, out)?;
+            } else if ft.is_file() {
+                out.push((child_rel, None));
+            }
+        }
+        Ok(())
+    }
+
+    let mut entries = Vec::new();
+    walk(root, "", skip, &mut entries)?;
+    entries.sort();
+
+    let mut hasher = Sha256::new();
+    let mut files = 0u64;
+    let mut symlinks = 0u64;
+    for (rel, link_target) in &entries {
+        match link_target {
+            Some(target) => {
+                let target = target.to_string_lossy().replace('\\', "/");
+                hasher.update(format!("l {}\0{}\n", rel, target).as_bytes());
+                symlinks += 1;
+            }
+            None => {
+                let content_hash = hash_file(&root.join(rel))?;
+                hasher.update(format!("f {}\0{}\n", rel, content_hash).as_bytes());
+                files += 1;
+            }
+        }
+    }
+    Ok(TreeHash { hash: format!("sha256-{:x}", hasher.finalize()), files, symlinks })
+}
+
+/// Record the current node_modules hash in better.tree.json.
+pub fn record_tree_hash(project_root: &Path, plan: &mut FsPlan) -> Result<TreeHash, String> {
+    let current = hash_node_modules_tree(&project_root.join("node_modules"))?;
+    let mut w = JsonWriter::compact();
+    w.begin_object();
+    w.key("treeHash"); w.value_string(&current.hash);
+    w.key("files"); w.value_u64(current.files);
+    w.key("symlinks"); w.value_u64(current.symlinks);
+    w.end_object();
+    w.out.push('\n');
+    plan.write(&project_root.join(TREE_HASH_FILE), w.finish())
+        .map_err(|e| format!("Failed to write {}: {}", TREE_HASH_FILE, e))?;
+    Ok(current)
+}
+
+/// Compare the installed tree against `expected`, or against the hash
+/// stored in better.tree.json when no explicit value is given.
+pub fn verify_reproducible(project_root: &Path, expected: Option<&str>) -> Result<ReproducibleVerifyResult, String> {
+    let expected = match expected {
+        Some(hash) => Some(hash.to_string()),
+        None => {
+            let stored = project_root.join(TREE_HASH_FILE);
+            if stored.exists() {
+                let content = fs::read_to_string(&stored)
+                    .map_err(|e| format!("Failed to read {}: {}", TREE_HASH_FILE, e))?;
+                extract_json_field(&content, "treeHash")
+            } else {
+                None
+            }
+        }
+    };
+    let current = hash_node_modules_tree(&project_root.join("node_modules"))?;
+    let ok = expected.as_deref() == Some(current.hash.as_str());
+    Ok(ReproducibleVerifyResult { ok, expected, current })
+}
+
+/// Written into node_modules by install: the content hash of every package
+/// it installed, so `verify --integrity` can tell when installed files
+/// changed afterwards. Unlike `--verify-store`, which checks store blobs,
+/// this covers the tree as installed, lifecycle script output included.
+pub const INSTALL_INTEGRITY_FILE: &str = ".better-integrity.json";
+
+/// An installed package whose files no longer hash to what install recorded.
+pub struct IntegrityMismatch {
+    pub rel_path: String,
+    pub expected: String,
+    /// None when the package directory is gone.
+    pub actual: Option<String>,
+}
+
+pub struct IntegrityVerifyResult {
+    pub checked: u64,
+    pub mismatches: Vec<IntegrityMismatch>,
+}
+
+/// Content hash of one installed package directory. Its own `node_modules`
+/// holds other packages, which are hashed separately.
+pub fn hash_installed_package(dir: &Path) -> Result<String, String> {
+    hash_dir_tree(dir, &["node_modules"]).map(|tree| tree.hash)
+}
+
+/// Hash every installed package of `packages` and record the hashes in
+/// node_modules/[`INSTALL_INTEGRITY_FILE`]. Packages with no directory (an
+/// optional dependency skipped on this platform, say) are left out. Returns
+/// how many were recorded.
+pub fn record_installed_integrity(project_root: &Path, packages: &[ResolvedPackage]) -> Result<u64, String> {
+    use rayon::prelude::*;
+
+    let hashes: Vec<(String, String)> = packages
+        .par_iter()
+        .filter(|pkg| project_root.join(&pkg.rel_path).is_dir())
+        .map(|pkg| Ok((pkg.rel_path.clone(), hash_installed_package(&project_root.join(&pkg.rel_path))?)))
+        .collect::<Result<_, String>>()?;
+    let hashes: BTreeMap<String, String> = hashes.into_iter().collect();
+
+    let mut w = JsonWriter::compact();
+    w.begin_object();
+    w.key("version"); w.value_u64(1);
+    w.key("packages"); w.begin_object();
+    for (rel_path, hash) in &hashes {
+        w.key(rel_path); w.value_string(hash);
+    }
+    w.end_object();
+    w.end_object();
+    w.out.push('\n');
+    fs::write(project_root.join("node_modules").join(INSTALL_INTEGRITY_FILE), w.finish())
+        .map_err(|e| format!("Failed to write {}: {}", INSTALL_INTEGRITY_FILE, e))?;
+    Ok(hashes.len() as u64)
+}
+
+/// Re-hash the packages install recorded and report each one that changed
+/// or disappeared, sorted by path.
+pub fn verify_installed_integrity(project_root: &Path) -> Result<IntegrityVerifyResult, String> {
+    use rayon::prelude::*;
+
+    let record = project_root.join("node_modules").join(INSTALL_INTEGRITY_FILE);
+    let content = fs::read_to_string(&record)
+        .map_err(|e| format!("Failed to read {}: {}; run install first", record.display(), e))?;
+    let parsed = JsonValue::parse(&content).map_err(|e| format!("Malformed {}: {}", INSTALL_INTEGRITY_FILE, e))?;
+    let expected: Vec<(&String, &str)> = parsed
+        .get("packages")
+        .and_then(JsonValue::as_object)
+        .ok_or_else(|| format!("Malformed {}: missing packages", INSTALL_INTEGRITY_FILE))?
+        .iter()
+        .filter_map(|(rel_path, hash)| Some((rel_path, hash.as_str()?)))
+        .collect();
+
+    let mut mismatches: Vec<IntegrityMismatch> = expected
+        .par_iter()
+        .map(|(rel_path, expected)| {
+            let dir = project_root.join(rel_path);
+            let actual = if dir.is_dir() { Some(hash_installed_package(&dir)?) } else { None };
+            Ok((actual.as_deref() != Some(*expected)).then(|| IntegrityMismatch {
+                rel_path: rel_path.to_string(),
+                expected: expected.to_string(),
+                actual,
+            }))
+        })
+        .collect::<Result<Vec<_>, String>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+    mismatches.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    Ok(IntegrityVerifyResult { checked: expected.len() as u64, mismatches })
+}
+
+/// Installed files compared with the file CAS manifests they came from.
+/// Paths are relative to the project root, sorted.
+#[derive(Debug, Default)]
+pub struct VerifyInstalledReport {
+    pub packages_checked: u64,
+    /// Installed packages the store has no manifest for, so nothing to compare
+    pub packages_unverified: u64,
+    pub files_checked: u64,
+    pub mismatched: Vec<String>,
+    pub missing: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+/// Re-hash every file of each package the lockfile installed and compare it
+/// with the package's manifest in the file CAS at `store_root`. Unlike
+/// [`verify_installed_integrity`], which compares with what install saw
+/// after lifecycle scripts, this compares with the published tarball, so
+/// files a script wrote show up as extra.
+pub fn verify_installed(project_root: &Path, store_root: &Path) -> Result<VerifyInstalledReport, String> {
+    use rayon::prelude::*;
+
+    let store_root = &long_path(store_root);
+    let packages = resolve_from_lockfile(&install_lockfile(project_root))?.packages;
+    let results: Vec<Option<VerifyInstalledReport>> = packages
+        .par_iter()
+        .filter(|pkg| project_root.join(&pkg.rel_path).is_dir())
+        .map(|pkg| {
+            let manifest = cas_key_from_integrity(&pkg.integrity)
+                .and_then(|(algorithm, hex)| read_file_cas_manifest(store_root, &algorithm, &hex));
+            let Some((files, symlinks)) = manifest else { return Ok(None) };
+            let dir = project_root.join(&pkg.rel_path);
+            let display = |rel: &str| format!("{}/{}", pkg.rel_path, rel);
+            let mut report = VerifyInstalledReport { packages_checked: 1, ..Default::default() };
+            for (rel, hash) in &files {
+                let path = dir.join(rel);
+                if !path.is_file() {
+                    report.missing.push(display(rel));
+                    continue;
+                }
+                report.files_checked += 1;
+                if hash_file(&path)? != *hash {
+                    report.mismatched.push(display(rel));
+                }
+            }
+            for (rel, target) in &symlinks {
+                match fs::read_link(dir.join(rel)) {
+                    Ok(actual) if actual.to_string_lossy() == target.as_str() => {}
+                    Ok(_) => report.mismatched.push(display(rel)),
+                    Err(_) => report.missing.push(display(rel)),
+                }
+            }
+            let known: HashSet<&str> = files.iter().chain(&symlinks).map(|(rel, _)| rel.as_str()).collect();
+            report.extra = installed_package_files(&dir)
+                .into_iter()
+                .filter(|rel| !known.contains(rel.as_str()))
+                .map(|rel| display(&rel))
+                .collect();
+            Ok(Some(report))
+        })
+        .collect::<Result<_, String>>()?;
+
+    let mut total = VerifyInstalledReport::default();
+    for result in results {
+        let Some(report) = result else {
+            total.packages_unverified += 1;
+            continue;
+        };
+        total.packages_checked += report.packages_checked;
+        total.files_checked += report.files_checked;
+        total.mismatched.extend(report.mismatched);
+        total.missing.extend(report.missing);
+        total.extra.extend(report.extra);
+    }
+    total.mismatched.sort();
+    total.missing.sort();
+    total.extra.sort();
+    Ok(total)
+}
+
+/// `/`-separated paths of the files and symlinks in an installed package,
+/// leaving out its own `node_modules`.
+fn installed_package_files(dir: &Path) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut stack = vec![(dir.to_path_buf(), String::new())];
+    while let Some((current, prefix)) = stack.pop() {
+        let Ok(entries) = fs::read_dir(&current) else { continue };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if prefix.is_empty() && name == "node_modules" {
+                continue;
+            }
+            let rel = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+            match entry.file_type() {
+                Ok(ft) if ft.is_dir() => stack.push((entry.path(), rel)),
+                Ok(_) => out.push(rel),
+                Err(_) => {}
+            }
+        }
+    }
+    out
+}
+
 // === D.5: Workspace support ===
 
 pub struct WorkspacePackage {
@@ -5349,6 +11142,9 @@ pub struct WorkspaceRunResult {
     pub results: Vec<(String, i32, u64)>,
 }
 
+/// (dir, name, version, relative_dir, scripts)
+type WorkspacePackageData = (PathBuf, String, String, String, Vec<(String, String)>);
+
 pub fn detect_workspaces(project_root: &Path) -> Result<WorkspaceInfo, String> {
     let pkg_json = project_root.join("package.json");
     let content = fs::read_to_string(&pkg_json)
@@ -5369,10 +11165,10 @@ pub fn detect_workspaces(project_root: &Path) -> Result<WorkspaceInfo, String> {
                     for entry in entries.flatten() {
                         if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
                             let candidate = entry.path();
-                            if suffix.is_empty() || candidate.to_string_lossy().ends_with(suffix) {
-                                if candidate.join("package.json").exists() {
-                                    workspace_dirs.push(candidate);
-                                }
+                            if (suffix.is_empty() || candidate.to_string_lossy().ends_with(suffix))
+                                && candidate.join("package.json").exists()
+                            {
+                                workspace_dirs.push(candidate);
                             }
                         }
                     }
@@ -5387,7 +11183,7 @@ pub fn detect_workspaces(project_root: &Path) -> Result<WorkspaceInfo, String> {
     }
     workspace_dirs.sort();
     let mut all_names: HashSet<String> = HashSet::new();
-    let mut package_data: Vec<(PathBuf, String, String, String, Vec<(String, String)>)> = Vec::new();
+    let mut package_data: Vec<WorkspacePackageData> = Vec::new();
     for dir in &workspace_dirs {
         let pj = dir.join("package.json");
         let c = match fs::read_to_string(&pj) { Ok(c) => c, Err(_) => continue };
@@ -5538,6 +11334,186 @@ pub fn workspace_run(
     })
 }
 
+/// Where one lockfile package ends up under [`compute_hoist_plan`]. `from` is
+/// the lockfile key, i.e. the package's `ResolvedPackage::rel_path`.
+pub struct HoistPlacement {
+    pub name: String,
+    pub version: String,
+    pub from: String,
+    /// Install paths relative to the monorepo root; a package nested per
+    /// workspace gets one per workspace that uses it
+    pub targets: Vec<String>,
+    /// Placed once at the root `node_modules`
+    pub hoisted: bool,
+}
+
+pub struct HoistPlan {
+    pub placements: Vec<HoistPlacement>,
+    /// Names locked at more than one version across the monorepo
+    pub conflicts: Vec<String>,
+    /// `better.nohoist` patterns from the root package.json
+    pub nohoist: Vec<String>,
+}
+
+/// Plan which packages can live once at the monorepo root `node_modules` and
+/// which have to stay nested per workspace.
+///
+/// A package locked at a single version across the lockfile is hoisted to the
+/// root. A name locked at several versions is placed under each workspace that
+/// reaches it (the root project keeps the root copy), and packages nested
+/// inside another package move along with their parent. `better.nohoist`
+/// patterns in the root package.json are globs over `<workspace>/<package>`
+/// (`**/react`, `web/*`) or a bare package name, and keep matching packages
+/// nested in that workspace whatever their versions.
+pub fn compute_hoist_plan(project_root: &Path, workspaces: &WorkspaceInfo, lockfile: &Path) -> Result<HoistPlan, String> {
+    let content = fs::read_to_string(lockfile).map_err(|e| format!("Failed to read lockfile: {}", e))?;
+    let graph = parse_lockfile_graph(&content)?;
+    let root_json = fs::read_to_string(project_root.join("package.json")).unwrap_or_default();
+    let nohoist = extract_json_object_raw(&root_json, "better")
+        .map(|better| extract_json_array_strings(&better, "nohoist"))
+        .unwrap_or_default();
+
+    let workspace_names: HashSet<&str> = workspaces.packages.iter().map(|p| p.name.as_str()).collect();
+    let is_package = |key: &str| key.contains("node_modules/") && graph.get(key).is_some_and(|n| !workspace_names.contains(n.name.as_str()));
+
+    // Importers: the root project ("") and every workspace, with the names
+    // they declare, dev dependencies included
+    let root_entry = lockfile_packages(&content)
+        .and_then(|packages| packages.into_iter().find(|(key, _)| key.is_empty()))
+        .map(|(_, raw)| raw)
+        .unwrap_or_else(|| root_json.clone());
+    let declared = |raw: &str| -> Vec<String> {
+        ["dependencies", "devDependencies", "optionalDependencies"]
+            .iter()
+            .flat_map(|section| dep_section(raw, section).into_keys())
+            .collect()
+    };
+    let mut importers: Vec<(String, String, Vec<String>)> = vec![(String::new(), String::new(), declared(&root_entry))];
+    for ws in &workspaces.packages {
+        let raw = fs::read_to_string(ws.dir.join("package.json")).unwrap_or_default();
+        importers.push((ws.relative_dir.replace('\\', "/"), ws.name.clone(), declared(&raw)));
+    }
+
+    let resolve = |from: &str, name: &str| resolve_lockfile_key(from, name, is_package);
+
+    // Which importers reach each package
+    let mut reached_by: HashMap<String, BTreeSet<usize>> = HashMap::new();
+    for (index, (dir, _, names)) in importers.iter().enumerate() {
+        let mut queue: VecDeque<String> = names.iter().filter_map(|name| resolve(dir, name)).collect();
+        while let Some(key) = queue.pop_front() {
+            if !reached_by.entry(key.clone()).or_default().insert(index) {
+                continue;
+            }
+            if let Some(node) = graph.get(&key) {
+                queue.extend(node.deps.iter().filter_map(|(name, _)| resolve(&key, name)));
+            }
+        }
+    }
+
+    let mut versions: HashMap<&str, BTreeSet<&str>> = HashMap::new();
+    for (key, node) in &graph {
+        if is_package(key) {
+            versions.entry(node.name.as_str()).or_default().insert(node.version.as_str());
+        }
+    }
+    let mut conflicts: Vec<String> = versions.iter().filter(|(_, v)| v.len() > 1).map(|(name, _)| name.to_string()).collect();
+    conflicts.sort();
+    let nohoisted = |workspace: &str, name: &str| {
+        !workspace.is_empty()
+            && nohoist.iter().any(|p| glob_match(p, name) || glob_match(p, &format!("{}/{}", workspace, name)))
+    };
+
+    // Parents sort before their nested packages, so their targets are known
+    let mut keys: Vec<&String> = graph.keys().filter(|k| is_package(k)).collect();
+    keys.sort();
+    let mut targets_of: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut placements = Vec::new();
+    for key in keys {
+        let node = &graph[key.as_str()];
+        let root_target = format!("node_modules/{}", node.name);
+        let conflict = conflicts.binary_search(&node.name).is_ok();
+        let users: Vec<&(String, String, Vec<String>)> = reached_by
+            .get(key.as_str())
+            .map(|set| set.iter().map(|&i| &importers[i]).collect())
+            .unwrap_or_default();
+        let held_back = users.iter().any(|(_, ws_name, _)| nohoisted(ws_name, &node.name));
+        let parent = key.rfind("node_modules/").map(|i| key[..i].trim_end_matches('/')).unwrap_or("");
+
+        let mut targets: Vec<String> = if !conflict && !held_back {
+            vec![root_target.clone()]
+        } else if let Some(parent_targets) = targets_of.get(parent) {
+            parent_targets.iter().map(|t| format!("{}/{}", t, root_target)).collect()
+        } else if parent.is_empty() {
+            // A root-level copy: each workspace that uses it gets its own
+            users
+                .iter()
+                .map(|(dir, ws_name, _)| {
+                    if dir.is_empty() || (!conflict && !nohoisted(ws_name, &node.name)) {
+                        root_target.clone()
+                    } else {
+                        format!("{}/{}", dir, root_target)
+                    }
+                })
+                .collect()
+        } else {
+            vec![key.clone()]
+        };
+        if targets.is_empty() {
+            targets.push(key.clone());
+        }
+        targets.sort();
+        targets.dedup();
+        let hoisted = targets == [root_target];
+        targets_of.insert(key.as_str(), targets.clone());
+        placements.push(HoistPlacement { name: node.name.clone(), version: node.version.clone(), from: key.clone(), targets, hoisted });
+    }
+    Ok(HoistPlan { placements, conflicts, nohoist })
+}
+
+// --- UTC timestamps ---
+
+/// `YYYY-MM-DDThh:mm:ss.mmmZ` for a time since the Unix epoch, in the
+/// proleptic Gregorian calendar (days to civil date after Howard Hinnant's
+/// `civil_from_days`).
+pub fn format_utc_timestamp(since_epoch: std::time::Duration) -> String {
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86_400) as i64;
+    let time_of_day = secs % 86_400;
+
+    // Shift the epoch to 0000-03-01 so leap days fall at the end of a year
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time_of_day / 3600,
+        (time_of_day % 3600) / 60,
+        time_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// The current UTC time, or `SOURCE_DATE_EPOCH` (seconds) when set so that
+/// generated documents can be reproduced byte for byte.
+pub fn utc_timestamp_now() -> String {
+    let since_epoch = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(std::time::Duration::from_secs)
+        .unwrap_or_else(|| std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default());
+    format_utc_timestamp(since_epoch)
+}
+
 // === D.6: SBOM export (CycloneDX + SPDX) ===
 
 pub struct SbomComponent {
@@ -5553,37 +11529,48 @@ pub struct SbomReport {
     pub components: Vec<SbomComponent>,
     pub project_name: String,
     pub project_version: String,
+    /// When the document was generated, see [`utc_timestamp_now`]
+    pub created: String,
 }
 
+#[deprecated(note = "use ProjectContext::generate_sbom")]
 pub fn generate_sbom(project_root: &Path, lockfile: &Path, format: &str) -> Result<SbomReport, String> {
-    let resolve_result = resolve_from_lockfile(lockfile)?;
-    let nm = project_root.join("node_modules");
-    let license_report = scan_licenses(&nm, &[], &[])?;
-    let license_map: HashMap<String, String> = license_report.packages.iter()
-        .map(|p| (p.name.clone(), p.license.clone())).collect();
-    let mut components = Vec::new();
-    for pkg in &resolve_result.packages {
-        let license = license_map.get(&pkg.name).cloned().unwrap_or_else(|| "NOASSERTION".into());
-        let purl = format!("pkg:npm/{}@{}", pkg.name, pkg.version);
-        components.push(SbomComponent {
-            name: pkg.name.clone(), version: pkg.version.clone(),
-            license, purl, integrity: pkg.integrity.clone(),
-        });
+    ProjectContext::new(project_root).with_lockfile(lockfile).generate_sbom(format)
+}
+
+impl ProjectContext {
+    /// SBOM components for the lockfile packages, with licenses from node_modules.
+    pub fn generate_sbom(&self, format: &str) -> Result<SbomReport, String> {
+        let lockfile = &self.lockfile;
+        let resolve_result = resolve_from_lockfile(lockfile)?;
+        let license_report = self.scan_licenses(&[], &[])?;
+        let license_map: HashMap<String, String> = license_report.packages.iter()
+            .map(|p| (p.name.clone(), p.license.clone())).collect();
+        let mut components = Vec::new();
+        for pkg in &resolve_result.packages {
+            let license = license_map.get(&pkg.name).cloned().unwrap_or_else(|| "NOASSERTION".into());
+            let purl = format!("pkg:npm/{}@{}", pkg.name, pkg.version);
+            components.push(SbomComponent {
+                name: pkg.name.clone(), version: pkg.version.clone(),
+                license, purl, integrity: pkg.integrity.clone(),
+            });
+        }
+        let pj = self.root.join("package.json");
+        let c = fs::read_to_string(&pj).unwrap_or_default();
+        let project_name = extract_json_field(&c, "name").unwrap_or_else(|| "unknown".into());
+        let project_version = extract_json_field(&c, "version").unwrap_or_else(|| "0.0.0".into());
+        Ok(SbomReport { format: format.into(), components, project_name, project_version, created: utc_timestamp_now() })
     }
-    let pj = project_root.join("package.json");
-    let c = fs::read_to_string(&pj).unwrap_or_default();
-    let project_name = extract_json_field(&c, "name").unwrap_or_else(|| "unknown".into());
-    let project_version = extract_json_field(&c, "version").unwrap_or_else(|| "0.0.0".into());
-    Ok(SbomReport { format: format.into(), components, project_name, project_version })
 }
 
-pub fn write_cyclonedx_json(report: &SbomReport) -> String {
-    let mut w = JsonWriter::new();
+pub fn write_cyclonedx_json(report: &SbomReport, indent: usize) -> String {
+    let mut w = JsonWriter::with_indent(indent);
     w.begin_object();
     w.key("bomFormat"); w.value_string("CycloneDX");
     w.key("specVersion"); w.value_string("1.5");
     w.key("version"); w.value_i64(1);
     w.key("metadata"); w.begin_object();
+    w.key("timestamp"); w.value_string(&report.created);
     w.key("component"); w.begin_object();
     w.key("type"); w.value_string("application");
     w.key("name"); w.value_string(&report.project_name);
@@ -5612,8 +11599,8 @@ pub fn write_cyclonedx_json(report: &SbomReport) -> String {
     w.finish()
 }
 
-pub fn write_spdx_json(report: &SbomReport) -> String {
-    let mut w = JsonWriter::new();
+pub fn write_spdx_json(report: &SbomReport, indent: usize) -> String {
+    let mut w = JsonWriter::with_indent(indent);
     w.begin_object();
     w.key("spdxVersion"); w.value_string("SPDX-2.3");
     w.key("dataLicense"); w.value_string("CC0-1.0");
@@ -5622,6 +11609,17 @@ pub fn write_spdx_json(report: &SbomReport) -> String {
     w.key("documentNamespace"); w.value_string(
         &format!("https://spdx.org/spdxdocs/{}-{}", report.project_name, report.project_version)
     );
+    w.key("creationInfo"); w.begin_object();
+    // SPDX 2.3 wants YYYY-MM-DDThh:mm:ssZ: no fractional seconds
+    let created = match report.created.split_once('.') {
+        Some((seconds, _)) => format!("{}Z", seconds),
+        None => report.created.clone(),
+    };
+    w.key("created"); w.value_string(&created);
+    w.key("creators"); w.begin_array();
+    w.value_string(&format!("Tool: better-core-{}", VERSION));
+    w.end_array();
+    w.end_object();
     w.key("packages"); w.begin_array();
     for (i, comp) in report.components.iter().enumerate() {
         w.begin_object();
@@ -5644,3 +11642,976 @@ pub fn write_spdx_json(report: &SbomReport) -> String {
     w.out.push('\n');
     w.finish()
 }
+
+// === D.7: Pack (publish file set) ===
+
+/// Paths npm never publishes, in `.gitignore` syntax.
+const PACK_ALWAYS_EXCLUDED: &[&str] = &[
+    ".git", ".svn", ".hg", "CVS", "node_modules",
+    ".npmignore", ".gitignore", ".npmrc", "npm-debug.log", ".DS_Store", "._*", ".*.swp", "*.orig",
+    "/.lock-wscript", "/.wafpickle-*", "/build/config.gypi", "/archived-packages",
+    "/package-lock.json", "/yarn.lock", "/pnpm-lock.yaml",
+];
+
+/// npm's fixed tarball mtime (1985-10-26T08:15:00Z), keeps packs reproducible.
+const PACK_MTIME: u64 = 499_162_500;
+
+/// One `.gitignore`-style rule, relative to the directory its file lives in.
+#[derive(Clone)]
+struct IgnoreRule {
+    base: String,
+    pattern: String,
+    negate: bool,
+    dir_only: bool,
+}
+
+impl IgnoreRule {
+    fn parse(base: &str, line: &str) -> Option<Self> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').unwrap_or(line)),
+        };
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        // A slash anywhere but the end anchors the pattern to `base`
+        let pattern = match line.strip_prefix('/') {
+            Some(anchored) => anchored.to_string(),
+            None if line.contains('/') => line.to_string(),
+            None => format!("**/{}", line),
+        };
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(Self { base: base.to_string(), pattern, negate, dir_only })
+    }
+
+    fn matches(&self, rel: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let local = if self.base.is_empty() {
+            rel
+        } else {
+            match rel.strip_prefix(&self.base).and_then(|r| r.strip_prefix('/')) {
+                Some(r) => r,
+                None => return false,
+            }
+        };
+        glob_match(&self.pattern, local)
+    }
+}
+
+/// Last matching rule wins; `None` when no rule mentions the path.
+fn ignore_verdict(rules: &[IgnoreRule], rel: &str, is_dir: bool) -> Option<bool> {
+    rules.iter().rev().find(|r| r.matches(rel, is_dir)).map(|r| !r.negate)
+}
+
+/// Match a `/`-separated path against a glob with `*`, `?` and `**` segments.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let pat: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    let segs: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    glob_match_segments(&pat, &segs)
+}
+
+fn glob_match_segments(pat: &[&str], segs: &[&str]) -> bool {
+    match pat.split_first() {
+        None => segs.is_empty(),
+        Some((&"**", rest)) => (0..=segs.len()).any(|skip| glob_match_segments(rest, &segs[skip..])),
+        Some((first, rest)) => match segs.split_first() {
+            Some((seg, seg_rest)) => glob_match_segment(first.as_bytes(), seg.as_bytes()) && glob_match_segments(rest, seg_rest),
+            None => false,
+        },
+    }
+}
+
+fn glob_match_segment(pat: &[u8], name: &[u8]) -> bool {
+    match pat.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_match_segment(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && glob_match_segment(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_match_segment(rest, &name[1..]),
+    }
+}
+
+/// Rules from the ignore file in `dir`: `.npmignore`, or `.gitignore` when
+/// there is no `.npmignore`.
+fn read_ignore_rules(dir: &Path, base: &str) -> Vec<IgnoreRule> {
+    let content = fs::read_to_string(dir.join(".npmignore"))
+        .or_else(|_| fs::read_to_string(dir.join(".gitignore")))
+        .unwrap_or_default();
+    content.lines().filter_map(|l| IgnoreRule::parse(base, l)).collect()
+}
+
+/// Files npm would publish from the package in `dir`, relative to `dir`, sorted.
+///
+/// Precedence follows npm's documented rules:
+/// - `package.json`, `README*`, `LICENSE*`/`LICENCE*`, `main` and `bin` files are
+///   always included;
+/// - `.git`, `node_modules`, lockfiles, `.npmrc` and the like are always excluded;
+/// - with a `files` field only matching paths are kept and the root ignore file
+///   is not consulted; nested `.npmignore`/`.gitignore` files still exclude;
+/// - without `files`, every ignore file from the root down applies;
+/// - `bundleDependencies` are included from `node_modules` in full.
+pub fn compute_publish_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let pkg_json = fs::read_to_string(dir.join("package.json"))
+        .map_err(|e| format!("Failed to read package.json: {}", e))?;
+    let fields = json_object_entries(&pkg_json);
+    let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+    let normalize = |p: &str| p.trim_start_matches("./").trim_matches('/').replace('\\', "/");
+
+    let mut always_included: HashSet<String> = HashSet::new();
+    always_included.insert("package.json".to_string());
+    if let Some(main) = field("main").and_then(|m| m.strip_prefix('"')).and_then(|m| m.strip_suffix('"')) {
+        always_included.insert(normalize(main));
+    }
+    let pkg_name = field("name").and_then(|n| n.strip_prefix('"')).and_then(|n| n.strip_suffix('"')).unwrap_or("");
+    for (_, target) in package_bins(dir, &pkg_json, pkg_name) {
+        always_included.insert(normalize(&target));
+    }
+
+    let bundled = read_bundled_dependencies(dir);
+    let excluded: Vec<IgnoreRule> = PACK_ALWAYS_EXCLUDED.iter().filter_map(|p| IgnoreRule::parse("", p)).collect();
+    let files_rules: Option<Vec<IgnoreRule>> = field("files").map(|raw| {
+        json_array_elements(raw)
+            .iter()
+            .filter_map(|e| e.strip_prefix('"').and_then(|e| e.strip_suffix('"')))
+            .filter_map(|e| {
+                let (negate, e) = match e.strip_prefix('!') {
+                    Some(rest) => ("!", rest),
+                    None => ("", e),
+                };
+                IgnoreRule::parse("", &format!("{}/{}", negate, normalize(e)))
+            })
+            .collect()
+    });
+
+    let mut out: Vec<PathBuf> = Vec::new();
+    // (directory, its rel path, ignore rules in effect for its children)
+    let mut stack: Vec<(PathBuf, String, Vec<IgnoreRule>)> = Vec::new();
+    let root_rules = if files_rules.is_some() { Vec::new() } else { read_ignore_rules(dir, "") };
+    stack.push((dir.to_path_buf(), String::new(), root_rules));
+
+    while let Some((abs, rel_dir, rules)) = stack.pop() {
+        let entries = fs::read_dir(&abs).map_err(|e| format!("Failed to read {}: {}", abs.display(), e))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| format!("Failed to read {}: {}", abs.display(), e))?;
+            let ft = entry.file_type().map_err(|e| format!("Failed to stat {}: {}", entry.path().display(), e))?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let rel = if rel_dir.is_empty() { name.clone() } else { format!("{}/{}", rel_dir, name) };
+            // Bundled deps ship whole, whatever the package's own rules say
+            let in_bundle = rel.split('/').next() == Some("node_modules") && !bundled.is_empty() && bundled_entry_allowed(&rel, &bundled);
+
+            if ft.is_dir() && in_bundle {
+                stack.push((entry.path(), rel, Vec::new()));
+                continue;
+            }
+            if ft.is_dir() {
+                if ignore_verdict(&excluded, &rel, true) == Some(true) || ignore_verdict(&rules, &rel, true) == Some(true) {
+                    continue;
+                }
+                let mut child_rules = rules.clone();
+                child_rules.extend(read_ignore_rules(&entry.path(), &rel));
+                stack.push((entry.path(), rel, child_rules));
+                continue;
+            }
+            if !ft.is_file() {
+                continue;
+            }
+
+            let upper = name.to_ascii_uppercase();
+            let root_doc = rel_dir.is_empty()
+                && ["README", "LICENSE", "LICENCE"].iter().any(|d| upper == *d || upper.starts_with(&format!("{}.", d)));
+            let included = if in_bundle || root_doc || always_included.contains(&rel) {
+                true
+            } else if ignore_verdict(&excluded, &rel, false) == Some(true) || ignore_verdict(&rules, &rel, false) == Some(true) {
+                false
+            } else if let Some(files_rules) = &files_rules {
+                // An entry naming a directory includes everything under it
+                let mut verdict = None;
+                let mut prefix = String::new();
+                for seg in rel.split('/') {
+                    if !prefix.is_empty() {
+                        prefix.push('/');
+                    }
+                    prefix.push_str(seg);
+                    if let Some(v) = ignore_verdict(files_rules, &prefix, prefix != rel) {
+                        verdict = Some(v);
+                    }
+                }
+                verdict == Some(true)
+            } else {
+                true
+            };
+            if included {
+                out.push(PathBuf::from(rel));
+            }
+        }
+    }
+
+    out.sort();
+    Ok(out)
+}
+
+pub struct PackResult {
+    pub name: String,
+    pub version: String,
+    pub files: Vec<PathBuf>,
+    /// Sum of the packed files' sizes
+    pub unpacked_size: u64,
+    /// Written tarball; `None` for a dry run
+    pub tarball: Option<PathBuf>,
+    /// `sha512-<base64>` of the tarball; `None` for a dry run
+    pub integrity: Option<String>,
+}
+
+/// Pack `project_root` into `<name>-<version>.tgz` in `dest_dir`, with every file
+/// under `package/` like `npm pack`. `dry_run` only computes the file set.
+pub fn pack_project(project_root: &Path, dest_dir: &Path, dry_run: bool) -> Result<PackResult, String> {
+    use sha2::{Digest, Sha512};
+
+    let pkg_json = fs::read_to_string(project_root.join("package.json"))
+        .map_err(|e| format!("Failed to read package.json: {}", e))?;
+    let name = extract_json_field(&pkg_json, "name").ok_or("package.json has no name")?;
+    let version = extract_json_field(&pkg_json, "version").ok_or("package.json has no version")?;
+    let files = compute_publish_files(project_root)?;
+
+    let mut unpacked_size = 0u64;
+    for rel in &files {
+        let md = fs::metadata(project_root.join(rel)).map_err(|e| format!("Failed to stat {}: {}", rel.display(), e))?;
+        unpacked_size += md.len();
+    }
+    if dry_run {
+        return Ok(PackResult { name, version, files, unpacked_size, tarball: None, integrity: None });
+    }
+
+    fs::create_dir_all(dest_dir).map_err(|e| format!("Failed to create {}: {}", dest_dir.display(), e))?;
+    let tarball = dest_dir.join(format!("{}-{}.tgz", name.trim_start_matches('@').replace('/', "-"), version));
+    let tmp = TmpFileGuard::beside(&tarball);
+    let file = fs::File::create(tmp.path()).map_err(|e| format!("Failed to create tarball: {}", e))?;
+    let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(file, flate2::Compression::default()));
+    for rel in &files {
+        let abs = project_root.join(rel);
+        let md = fs::metadata(&abs).map_err(|e| format!("Failed to stat {}: {}", rel.display(), e))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(md.len());
+        header.set_mode(if get_file_mode(&md) & 0o111 != 0 { 0o755 } else { 0o644 });
+        header.set_mtime(PACK_MTIME);
+        header.set_entry_type(tar::EntryType::Regular);
+        let src = fs::File::open(&abs).map_err(|e| format!("Failed to open {}: {}", rel.display(), e))?;
+        let archive_path = format!("package/{}", rel.to_string_lossy().replace('\\', "/"));
+        builder.append_data(&mut header, &archive_path, src)
+            .map_err(|e| format!("Failed to add {} to tarball: {}", rel.display(), e))?;
+    }
+    builder.into_inner()
+        .and_then(|gz| gz.finish())
+        .map_err(|e| format!("Failed to write tarball: {}", e))?;
+
+    let bytes = fs::read(tmp.path()).map_err(|e| format!("Failed to read tarball: {}", e))?;
+    let digest = Sha512::digest(&bytes);
+    let integrity = format!("sha512-{}", base64::Engine::encode(&base64::engine::general_purpose::STANDARD, digest));
+    fs::rename(tmp.path(), &tarball).map_err(|e| format!("Failed to move tarball into place: {}", e))?;
+    tmp.keep();
+
+    Ok(PackResult { name, version, files, unpacked_size, tarball: Some(tarball), integrity: Some(integrity) })
+}
+
+// === D.8: Registry resolution + global installs ===
+
+/// Unescape a raw JSON string literal such as `"a\"b"`.
+fn json_string_value(raw: &str) -> Option<String> {
+    let inner = raw.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            't' => out.push('\t'),
+            'u' => {
+                let code: String = chars.by_ref().take(4).collect();
+                out.push(u32::from_str_radix(&code, 16).ok().and_then(char::from_u32)?);
+            }
+            other => out.push(other),
+        }
+    }
+    Some(out)
+}
+
+/// Split `name@range` (the name may be scoped). A bare name means `latest`.
+pub fn parse_package_spec(spec: &str) -> (String, String) {
+    let at = if let Some(rest) = spec.strip_prefix('@') {
+        rest.find('@').map(|i| i + 1)
+    } else {
+        spec.find('@')
+    };
+    match at {
+        Some(i) => (spec[..i].to_string(), spec[i + 1..].to_string()),
+        None => (spec.to_string(), "latest".to_string()),
+    }
+}
+
+fn fetch_packument(agent: &ureq::Agent, npmrc: &NpmrcConfig, name: &str) -> Result<String, CoreError> {
+    let (registry, token) = registry_for_package(npmrc, name);
+    let url = format!("{}/{}", registry.trim_end_matches('/'), name.replace('/', "%2F"));
+    let mut request = agent.get(&url).set("Accept", "application/json");
+    if let Some(token) = token {
+        request = request.set("Authorization", &format!("Bearer {}", token));
+    }
+    let resp = request
+        .call()
+        .map_err(|e| CoreError::network(format!("registry request for {} failed: {}", name, redact_url_credentials(&e.to_string()))))?;
+    resp.into_string().map_err(|e| CoreError::network(format!("failed to read registry response for {}: {}", name, e)))
+}
+
+/// A version picked from registry metadata.
+struct RegistryVersion {
+    version: String,
+    tarball: String,
+    integrity: String,
+    dependencies: Vec<(String, String)>,
+    bins: Vec<String>,
+}
+
+/// Pick the version of `name` that `range` selects from its packument:
+/// a dist-tag by name, else `latest` when it satisfies the range, else the
+/// highest satisfying release.
+fn pick_registry_version(packument: &str, name: &str, range: &str) -> Result<RegistryVersion, String> {
+    let top = json_object_entries(packument);
+    let field = |key: &str| top.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+    let tags: Vec<(String, String)> = field("dist-tags")
+        .map(json_object_entries)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(k, v)| json_string_value(&v).map(|v| (k, v)))
+        .collect();
+    let versions = field("versions").map(json_object_entries).unwrap_or_default();
+    let range = range.trim();
+
+    let tagged = tags.iter().find(|(tag, _)| tag == range).map(|(_, v)| v.clone());
+    let chosen = match tagged {
+        Some(v) => Some(v),
+        None => {
+            let latest = tags.iter().find(|(tag, _)| tag == "latest").map(|(_, v)| v.as_str());
+            let satisfies = |v: &str| parse_semver(v).map(|sv| check_semver_range(&sv, range)).unwrap_or(false);
+            match latest {
+                Some(l) if satisfies(l) => Some(l.to_string()),
+                _ => versions
+                    .iter()
+                    .filter(|(v, _)| satisfies(v))
+                    .filter_map(|(v, _)| parse_semver(v).map(|sv| (sv, v.clone())))
+                    .max()
+                    .map(|(_, v)| v),
+            }
+        }
+    };
+    let version = chosen.ok_or_else(|| format!("No version of {} satisfies {}", name, range))?;
+    let manifest = versions
+        .iter()
+        .find(|(v, _)| *v == version)
+        .map(|(_, raw)| raw.as_str())
+        .ok_or_else(|| format!("Registry metadata for {} has no version {}", name, version))?;
+
+    let fields = json_object_entries(manifest);
+    let dist = fields.iter().find(|(k, _)| k == "dist").map(|(_, v)| json_object_entries(v)).unwrap_or_default();
+    let dist_field = |key: &str| dist.iter().find(|(k, _)| k == key).and_then(|(_, v)| json_string_value(v));
+    let tarball = dist_field("tarball").ok_or_else(|| format!("{}@{} has no dist.tarball", name, version))?;
+    let integrity = dist_field("integrity").ok_or_else(|| format!("{}@{} has no dist.integrity", name, version))?;
+    let mut dependencies: Vec<(String, String)> = fields
+        .iter()
+        .find(|(k, _)| k == "dependencies")
+        .map(|(_, v)| json_object_entries(v))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(k, v)| json_string_value(&v).map(|v| (k, v)))
+        .collect();
+    dependencies.sort();
+
+    let bins = parse_bin_field(manifest, name).into_iter().map(|(bin, _)| bin).collect();
+
+    Ok(RegistryVersion { version, tarball, integrity, dependencies, bins })
+}
+
+/// Resolve `deps` and everything they depend on against the registry.
+///
+/// Placement follows npm: a dependency reuses the nearest copy up the tree
+/// when it satisfies the range, goes to the top-level `node_modules` when
+/// that slot is free, and otherwise nests under the package that needs it.
+/// The kind of each of `deps` decides the packages' [`DepFlags`].
+pub fn resolve_from_registry(npmrc: &NpmrcConfig, deps: &[(String, String, DepKind)]) -> Result<ResolveResult, CoreError> {
+    let agent = build_http_agent(Some(npmrc));
+    let mut packuments: HashMap<String, String> = HashMap::new();
+    let mut placed: BTreeMap<String, ResolvedPackage> = BTreeMap::new();
+    let mut root_edges: Vec<(DepKind, String)> = Vec::new();
+    let mut edges: HashMap<String, Vec<String>> = HashMap::new();
+    let mut queue: VecDeque<(String, String, String, DepKind)> = deps
+        .iter()
+        .map(|(name, range, kind)| (String::new(), name.clone(), range.clone(), *kind))
+        .collect();
+
+    while let Some((parent, name, range, kind)) = queue.pop_front() {
+        let slot = |level: &str| {
+            if level.is_empty() { format!("node_modules/{}", name) } else { format!("{}/node_modules/{}", level, name) }
+        };
+
+        // Walk from the dependent up to the root
+        let mut level = parent.clone();
+        let mut reuse = false;
+        let mut conflict = false;
+        loop {
+            if let Some(existing) = placed.get(&slot(&level)) {
+                reuse = parse_semver(&existing.version).map(|v| check_semver_range(&v, &range)).unwrap_or(false)
+                    || existing.version == range;
+                conflict = !reuse;
+                if reuse {
+                    level = slot(&level);
+                }
+                break;
+            }
+            if level.is_empty() {
+                break;
+            }
+            level = match level.rfind("/node_modules/") {
+                Some(i) => level[..i].to_string(),
+                None => String::new(),
+            };
+        }
+        let target = if reuse {
+            level
+        } else if conflict {
+            slot(&parent)
+        } else {
+            slot("")
+        };
+        if parent.is_empty() {
+            root_edges.push((kind, target.clone()));
+        } else {
+            edges.entry(parent.clone()).or_default().push(target.clone());
+        }
+        if reuse {
+            continue;
+        }
+        if placed.contains_key(&target) {
+            return Err(format!("Cannot place {}@{} under {}: a conflicting version is already there", name, range, parent).into());
+        }
+
+        if !packuments.contains_key(&name) {
+            let body = fetch_packument(&agent, npmrc, &name)?;
+            packuments.insert(name.clone(), body);
+        }
+        let picked = pick_registry_version(&packuments[&name], &name, &range)?;
+        for (dep, dep_range) in &picked.dependencies {
+            queue.push_back((target.clone(), dep.clone(), dep_range.clone(), DepKind::Prod));
+        }
+        placed.insert(target.clone(), ResolvedPackage {
+            name: name.clone(),
+            version: picked.version,
+            rel_path: target,
+            resolved_url: picked.tarball,
+            integrity: picked.integrity,
+            dependencies: picked.dependencies,
+            bins: picked.bins,
+            flags: DepFlags::default(),
+        });
+    }
+
+    // Only the root's edges have kinds, so a package's flags come down to
+    // which kinds of root dependency reach it
+    let reachable = |kinds: &[DepKind]| {
+        let mut seen: HashSet<&str> = HashSet::new();
+        let mut stack: Vec<&str> =
+            root_edges.iter().filter(|(kind, _)| kinds.contains(kind)).map(|(_, slot)| slot.as_str()).collect();
+        while let Some(slot) = stack.pop() {
+            if seen.insert(slot) {
+                stack.extend(edges.get(slot).into_iter().flatten().map(String::as_str));
+            }
+        }
+        seen
+    };
+    let prod = reachable(&[DepKind::Prod]);
+    let without_dev = reachable(&[DepKind::Prod, DepKind::Optional]);
+    let without_optional = reachable(&[DepKind::Prod, DepKind::Dev]);
+    let mut packages: Vec<ResolvedPackage> = placed.into_values().collect();
+    for pkg in &mut packages {
+        let dev = !without_dev.contains(pkg.rel_path.as_str());
+        let optional = !without_optional.contains(pkg.rel_path.as_str());
+        pkg.flags = DepFlags { dev, optional, dev_optional: !dev && !optional && !prod.contains(pkg.rel_path.as_str()) };
+    }
+
+    Ok(ResolveResult { packages, lockfile_version: 0 })
+}
+
+/// Resolve package.json `dependencies`, `devDependencies` and
+/// `optionalDependencies` against `registry`, for a project without a lockfile; see [`resolve_from_registry`]
+/// for how packages are placed. Scoped registries and auth tokens still come
+/// from the project's npmrc. Dependencies that are not registry ranges
+/// (`file:`, git, `workspace:`) need a lockfile.
+pub fn resolve_from_manifest(project_root: &Path, registry: &str) -> Result<ResolveResult, CoreError> {
+    let path = project_root.join("package.json");
+    let content = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let manifest = JsonValue::parse(&content).map_err(|e| format!("{} is not valid JSON: {}", path.display(), e))?;
+    let mut deps: Vec<(String, String, DepKind)> = Vec::new();
+    // Like npm, an optional dependency overrides the same name elsewhere and a
+    // production one overrides a dev one
+    let sections = [("optionalDependencies", DepKind::Optional), ("dependencies", DepKind::Prod), ("devDependencies", DepKind::Dev)];
+    for (section, kind) in sections {
+        for (name, range) in manifest.get(section).and_then(JsonValue::as_object).into_iter().flatten() {
+            let Some(range) = range.as_str() else { continue };
+            if range.contains(':') || range.contains('/') {
+                return Err(format!("{}@{} is not a registry dependency; install it from a lockfile", name, range).into());
+            }
+            if !deps.iter().any(|(n, _, _)| n == name) {
+                deps.push((name.clone(), range.to_string(), kind));
+            }
+        }
+    }
+    let mut npmrc = parse_npmrc(project_root);
+    npmrc.default_registry = registry.to_string();
+    resolve_from_registry(&npmrc, &deps)
+}
+
+/// Write `result` as a lockfileVersion 3 package-lock.json at `path`. The root
+/// `""` entry carries package.json's name, version and dependency ranges;
+/// packages follow sorted by path, so resolving the same tree always writes
+/// the same bytes. The file is replaced atomically.
+pub fn write_npm_lockfile(path: &Path, result: &ResolveResult, project_root: &Path) -> Result<(), String> {
+    let manifest_path = project_root.join("package.json");
+    let content = fs::read_to_string(&manifest_path).map_err(|e| format!("Failed to read {}: {}", manifest_path.display(), e))?;
+    let manifest = JsonValue::parse(&content).map_err(|e| format!("{} is not valid JSON: {}", manifest_path.display(), e))?;
+    let manifest_str = |key: &str| manifest.get(key).and_then(JsonValue::as_str);
+    let write_ranges = |w: &mut JsonWriter, key: &str, ranges: &[(&str, &str)]| {
+        if !ranges.is_empty() {
+            w.key(key);
+            w.begin_object();
+            for (name, range) in ranges {
+                w.key(name);
+                w.value_string(range);
+            }
+            w.end_object();
+        }
+    };
+
+    let mut w = JsonWriter::with_indent(2);
+    w.begin_object();
+    if let Some(name) = manifest_str("name") {
+        w.key("name"); w.value_string(name);
+    }
+    if let Some(version) = manifest_str("version") {
+        w.key("version"); w.value_string(version);
+    }
+    w.key("lockfileVersion"); w.value_u64(3);
+    w.key("requires"); w.value_bool(true);
+    w.key("packages");
+    w.begin_object();
+    w.key("");
+    w.begin_object();
+    if let Some(name) = manifest_str("name") {
+        w.key("name"); w.value_string(name);
+    }
+    if let Some(version) = manifest_str("version") {
+        w.key("version"); w.value_string(version);
+    }
+    for section in ["dependencies", "devDependencies", "optionalDependencies"] {
+        let ranges: Vec<(&str, &str)> = manifest
+            .get(section)
+            .and_then(JsonValue::as_object)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, range)| Some((name.as_str(), range.as_str()?)))
+            .collect();
+        write_ranges(&mut w, section, &ranges);
+    }
+    w.end_object();
+
+    let mut packages: Vec<&ResolvedPackage> = result.packages.iter().collect();
+    packages.sort_by(|a, b| a.rel_path.cmp(&b.rel_path));
+    for pkg in packages {
+        w.key(&pkg.rel_path);
+        w.begin_object();
+        if package_name_from_path(&pkg.rel_path) != pkg.name {
+            w.key("name"); w.value_string(&pkg.name);
+        }
+        w.key("version"); w.value_string(&pkg.version);
+        w.key("resolved"); w.value_string(&pkg.resolved_url);
+        w.key("integrity"); w.value_string(&pkg.integrity);
+        if pkg.flags.dev {
+            w.key("dev"); w.value_bool(true);
+        }
+        if pkg.flags.optional {
+            w.key("optional"); w.value_bool(true);
+        }
+        if pkg.flags.dev_optional {
+            w.key("devOptional"); w.value_bool(true);
+        }
+        let ranges: Vec<(&str, &str)> = pkg.dependencies.iter().map(|(n, r)| (n.as_str(), r.as_str())).collect();
+        write_ranges(&mut w, "dependencies", &ranges);
+        w.end_object();
+    }
+    w.end_object();
+    w.end_object();
+    w.out.push('\n');
+
+    let tmp = TmpFileGuard::beside(path);
+    fs::write(tmp.path(), w.finish()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    fs::rename(tmp.path(), path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    tmp.keep();
+    Ok(())
+}
+
+/// Where global packages and their bins live under a prefix. Matches npm's
+/// layout: `lib/node_modules` and `bin` on Unix, `node_modules` and the
+/// prefix itself on Windows.
+pub struct GlobalLayout {
+    pub prefix: PathBuf,
+    pub node_modules: PathBuf,
+    pub bin_dir: PathBuf,
+}
+
+impl GlobalLayout {
+    pub fn new(prefix: &Path) -> Self {
+        if cfg!(windows) {
+            Self { prefix: prefix.to_path_buf(), node_modules: prefix.join("node_modules"), bin_dir: prefix.to_path_buf() }
+        } else {
+            Self {
+                prefix: prefix.to_path_buf(),
+                node_modules: prefix.join("lib").join("node_modules"),
+                bin_dir: prefix.join("bin"),
+            }
+        }
+    }
+}
+
+/// The global prefix: `BETTER_PREFIX`, then `prefix` from .npmrc or
+/// `npm_config_prefix`, then `~/.better/global` (`%APPDATA%\better\global`
+/// on Windows).
+pub fn default_global_prefix(npmrc: &NpmrcConfig) -> PathBuf {
+    if let Ok(p) = std::env::var("BETTER_PREFIX") {
+        if !p.is_empty() {
+            return PathBuf::from(p);
+        }
+    }
+    if let Some(p) = &npmrc.prefix {
+        return PathBuf::from(p);
+    }
+    let (var, dir) = if cfg!(windows) { ("APPDATA", "better") } else { ("HOME", ".better") };
+    let base = std::env::var(var).map(PathBuf::from).unwrap_or_else(|_| std::env::temp_dir());
+    base.join(dir).join("global")
+}
+
+#[derive(Debug, Clone)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    pub bins: Vec<String>,
+}
+
+/// Top-level packages in `node_modules` (scoped ones included), sorted by name.
+pub fn list_top_level_packages(node_modules: &Path) -> Result<Vec<InstalledPackage>, String> {
+    let mut dirs = Vec::new();
+    let entries = match stable_list_dir(node_modules) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", node_modules.display(), e)),
+    };
+    for ent in entries {
+        let name = ent.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = node_modules.join(&name);
+        if name.starts_with('@') {
+            for sc in stable_list_dir(&path).unwrap_or_default() {
+                dirs.push(path.join(sc.file_name()));
+            }
+        } else {
+            dirs.push(path);
+        }
+    }
+
+    let mut packages = Vec::new();
+    for dir in dirs {
+        let Some((name, version)) = read_package_identity(&dir) else { continue };
+        let pkg_json = fs::read_to_string(dir.join("package.json")).unwrap_or_default();
+        let bins = package_bins(&dir, &pkg_json, &name).into_iter().map(|(b, _)| b).collect();
+        packages.push(InstalledPackage { name, version, bins });
+    }
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+/// Top-level packages as the lockfile places them, for listing before anything
+/// is installed. Sorted by name; workspace links without a version are skipped.
+pub fn list_locked_packages(lockfile: &Path) -> Result<Vec<InstalledPackage>, String> {
+    let content = fs::read_to_string(lockfile)
+        .map_err(|e| format!("Failed to read {}: {}", lockfile.display(), e))?;
+    let entries = lockfile_packages(&content)
+        .ok_or_else(|| format!("{} has no packages section; regenerate it with npm 7 or later", lockfile.display()))?;
+
+    let mut packages = Vec::new();
+    for (rel_path, entry) in entries {
+        let Some(rest) = rel_path.strip_prefix("node_modules/") else { continue };
+        if rest.contains("/node_modules/") {
+            continue;
+        }
+        let Some(version) = extract_json_field(&entry, "version") else { continue };
+        let name = extract_json_field(&entry, "name").unwrap_or_else(|| package_name_from_path(&rel_path));
+        let bins = parse_bin_field(&entry, &name).into_iter().map(|(b, _)| b).collect();
+        packages.push(InstalledPackage { name, version, bins });
+    }
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+#[derive(Clone, Default)]
+pub struct GlobalAddResult {
+    /// The requested packages as installed
+    pub packages: Vec<InstalledPackage>,
+    /// Requested packages plus their dependencies
+    pub total_packages: u64,
+    pub fetch: FetchResult,
+}
+
+/// Install `specs` (`name[@range]`) into the global prefix: each package goes
+/// to its own directory with its dependencies nested inside it, and its bins
+/// are linked into the prefix's bin directory. Every change goes through
+/// `plan`; a dry run still resolves against the registry but fetches nothing,
+/// and plans the bins the registry manifest declares.
+pub fn global_add(
+    layout: &GlobalLayout,
+    npmrc: &NpmrcConfig,
+    specs: &[String],
+    cache_root: &Path,
+    link_strategy: LinkStrategy,
+    plan: &mut FsPlan,
+) -> Result<GlobalAddResult, CoreError> {
+    let mut result = GlobalAddResult::default();
+    plan.create_dir_all(&layout.node_modules).map_err(|e| format!("Failed to create {}: {}", layout.node_modules.display(), e))?;
+    plan.create_dir_all(&layout.bin_dir).map_err(|e| format!("Failed to create {}: {}", layout.bin_dir.display(), e))?;
+    let cas = CasLayout::new(cache_root);
+
+    for spec in specs {
+        let (name, range) = parse_package_spec(spec);
+        let resolved = resolve_from_registry(npmrc, &[(name.clone(), range, DepKind::Prod)])?;
+
+        // Everything but the package itself belongs in its own node_modules
+        let own = format!("node_modules/{}", name);
+        let packages: Vec<ResolvedPackage> = resolved
+            .packages
+            .into_iter()
+            .map(|mut pkg| {
+                if pkg.rel_path != own {
+                    pkg.rel_path = format!("{}/{}", own, pkg.rel_path);
+                }
+                pkg
+            })
+            .collect();
+
+        for pkg in &packages {
+            if let Some((algo, hex)) = cas_key_from_integrity(&pkg.integrity) {
+                let tarball = tarball_path(&cas, &algo, &hex);
+                if !tarball.with_extension("tgz.verified").exists() {
+                    plan.record("fetch", &tarball);
+                }
+            }
+        }
+        if !plan.dry_run {
+            let fetch = fetch_packages(&packages, cache_root, Some(npmrc))?;
+            result.fetch.packages_fetched += fetch.packages_fetched;
+            result.fetch.packages_cached += fetch.packages_cached;
+            result.fetch.bytes_downloaded += fetch.bytes_downloaded;
+        }
+
+        let pkg_dir = layout.node_modules.join(&name);
+        if pkg_dir.exists() {
+            global_remove(layout, std::slice::from_ref(&name), plan)?;
+        }
+        for pkg in &packages {
+            let (algo, hex) = cas_key_from_integrity(&pkg.integrity)
+                .ok_or_else(|| format!("Invalid integrity format: {}", pkg.integrity))?;
+            let src = unpacked_path(&cas, &algo, &hex).join("package");
+            let dest = layout.node_modules.join(&pkg.rel_path["node_modules/".len()..]);
+            plan.record("materialize", &dest);
+            if !plan.dry_run {
+                materialize_tree(&src, &dest, link_strategy, 4, MaterializeProfile::Auto, MaterializeOptions::default())
+                    .map_err(|e| format!("Failed to materialize {}: {}", pkg.name, e))?;
+            }
+        }
+
+        let own_pkg = packages.iter().find(|p| p.rel_path == own);
+        let bins = if plan.dry_run {
+            own_pkg.map(|p| p.bins.clone()).unwrap_or_default()
+        } else {
+            let mut links = BinLinkResult::default();
+            let bins = link_package_bins(&pkg_dir, &name, &name, &layout.bin_dir, &mut HashMap::new(), &mut links);
+            if links.links_failed > 0 {
+                return Err(format!("Failed to link {} bin(s) for {}", links.links_failed, name).into());
+            }
+            bins
+        };
+        for bin in &bins {
+            plan.record("link", &layout.bin_dir.join(bin));
+        }
+        let version = own_pkg.map(|p| p.version.clone()).unwrap_or_default();
+        result.total_packages += packages.len() as u64;
+        result.packages.push(InstalledPackage { name, version, bins });
+    }
+
+    Ok(result)
+}
+
+/// Remove global packages and the bins they linked, through `plan`. Errors on
+/// a package that is not installed.
+pub fn global_remove(layout: &GlobalLayout, names: &[String], plan: &mut FsPlan) -> Result<Vec<InstalledPackage>, String> {
+    let mut removed = Vec::new();
+    for name in names {
+        let pkg_dir = layout.node_modules.join(name);
+        let Some((_, version)) = read_package_identity(&pkg_dir) else {
+            return Err(format!("{} is not installed globally", name));
+        };
+        let pkg_json = fs::read_to_string(pkg_dir.join("package.json")).unwrap_or_default();
+        let bins: Vec<String> = package_bins(&pkg_dir, &pkg_json, name).into_iter().map(|(b, _)| b).collect();
+        let owned_dir = fs::canonicalize(&pkg_dir).unwrap_or_else(|_| pkg_dir.clone());
+        for bin in &bins {
+            if cfg!(windows) {
+                for shim in [format!("{}.cmd", bin), format!("{}.ps1", bin), bin.clone()] {
+                    let shim = layout.bin_dir.join(shim);
+                    if shim.exists() {
+                        let _ = plan.remove_file(&shim);
+                    }
+                }
+                continue;
+            }
+            // Leave bins that another package has since taken over
+            let link = layout.bin_dir.join(bin);
+            let owned = fs::canonicalize(&link).map(|t| t.starts_with(&owned_dir)).unwrap_or(true);
+            if owned && fs::symlink_metadata(&link).is_ok() {
+                let _ = plan.remove_file(&link);
+            }
+        }
+        plan.record("remove", &pkg_dir);
+        if !plan.dry_run {
+            remove_path_if_exists(&pkg_dir).map_err(|e| format!("Failed to remove {}: {}", pkg_dir.display(), e))?;
+            if let Some(scope_dir) = pkg_dir.parent().filter(|p| *p != layout.node_modules) {
+                let _ = fs::remove_dir(scope_dir);
+            }
+        }
+        removed.push(InstalledPackage { name: name.clone(), version, bins });
+    }
+    Ok(removed)
+}
+
+// === D.9: Filesystem diagnostics ===
+
+/// What one filesystem (the cache's or the project's) supports.
+#[derive(Debug, Clone, Default)]
+pub struct FsCapabilities {
+    pub path: PathBuf,
+    /// The probe directory could be created; every other field is false otherwise
+    pub writable: bool,
+    pub hardlink: bool,
+    pub reflink: bool,
+    pub symlink: bool,
+    pub case_sensitive: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiagnosticsReport {
+    pub cache: FsCapabilities,
+    pub project: FsCapabilities,
+    /// Cache and project sit on different devices, so store files cannot be hardlinked into node_modules
+    pub cross_device: bool,
+    pub hardlink_cache_to_project: bool,
+    pub reflink_cache_to_project: bool,
+    /// How `install` (auto strategy) will place files: clonefile, hardlink or copy
+    pub install_strategy: &'static str,
+    /// The proxies the environment and the project's npmrc configure
+    pub proxy: ProxyConfig,
+    /// The project's default registry
+    pub registry: String,
+    /// The proxy requests to `registry` go through; None when direct
+    pub registry_proxy: Option<String>,
+}
+
+/// Scratch directory removed on drop, for probes that must touch a real filesystem.
+struct ProbeDir(PathBuf);
+
+impl ProbeDir {
+    fn create(parent: &Path) -> Option<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let dir = parent.join(format!(".better-probe-{}-{}", std::process::id(), n));
+        fs::create_dir_all(&dir).ok()?;
+        Some(Self(dir))
+    }
+}
+
+impl Drop for ProbeDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn probe_filesystem(root: &Path) -> (FsCapabilities, Option<ProbeDir>) {
+    let mut caps = FsCapabilities { path: root.to_path_buf(), ..Default::default() };
+    let Some(dir) = ProbeDir::create(root) else { return (caps, None) };
+    caps.writable = true;
+    let src = dir.0.join("Probe");
+    if fs::write(&src, b"better").is_err() {
+        return (caps, Some(dir));
+    }
+    caps.hardlink = fs::hard_link(&src, dir.0.join("hardlink")).is_ok();
+    caps.reflink = try_reflink_file(&src, &dir.0.join("reflink"));
+    caps.symlink = create_symlink(Path::new("Probe"), &dir.0.join("symlink"), &src).is_ok();
+    caps.case_sensitive = fs::symlink_metadata(dir.0.join("probe")).is_err();
+    (caps, Some(dir))
+}
+
+/// Probe hardlink, reflink, symlink and case-sensitivity support on the cache and
+/// project filesystems, and whether store files can be linked across them.
+pub fn run_diagnostics(cache_root: &Path, project_root: &Path) -> DiagnosticsReport {
+    let _ = fs::create_dir_all(cache_root);
+    let (cache, cache_dir) = probe_filesystem(cache_root);
+    let (project, project_dir) = probe_filesystem(project_root);
+
+    let device = |p: &Path| fs::metadata(p).ok().map(|md| identity_key(&md)).filter(|k| k.2).map(|k| k.0);
+    let cross_device = match (device(cache_root), device(project_root)) {
+        (Some(a), Some(b)) => a != b,
+        _ => false,
+    };
+
+    let (mut hardlink_cache_to_project, mut reflink_cache_to_project) = (false, false);
+    if let (Some(from), Some(to)) = (&cache_dir, &project_dir) {
+        let src = from.0.join("Probe");
+        hardlink_cache_to_project = fs::hard_link(&src, to.0.join("linked")).is_ok();
+        reflink_cache_to_project = try_reflink_file(&src, &to.0.join("cloned"));
+    }
+
+    let install_strategy = if cfg!(target_os = "macos") && reflink_cache_to_project {
+        "clonefile"
+    } else if hardlink_cache_to_project {
+        "hardlink"
+    } else {
+        "copy"
+    };
+    let npmrc = parse_npmrc(project_root);
+    let proxy = ProxyConfig::from_env(Some(&npmrc));
+    let registry_proxy = proxy.proxy_for(&npmrc.default_registry).map(str::to_string);
+    DiagnosticsReport {
+        cache,
+        project,
+        cross_device,
+        hardlink_cache_to_project,
+        reflink_cache_to_project,
+        install_strategy,
+        proxy,
+        registry: npmrc.default_registry,
+        registry_proxy,
+    }
+}
@@ -1,37 +1,74 @@
 use std::collections::HashSet;
+use std::io::IsTerminal;
 use std::path::PathBuf;
 use std::time::Instant;
 
 use better_core::{
-    analyze, cas_key_from_integrity, create_bin_links, detect_lifecycle_scripts, fetch_packages,
-    ingest_to_file_cas, materialize_from_file_cas, materialize_tree, resolve_from_lockfile,
-    run_lifecycle_scripts, scan_tree, try_clonefile_dir, unpacked_path, write_analyze_json,
-    write_materialize_json, write_scan_json, CasLayout, JsonWriter, LifecycleRunResult,
-    LinkStrategy, MaterializeProfile, MaterializeStats, PhaseDurations, ScanAgg, VERSION,
+    analyze, diff_analyze, read_analyze_baseline, cas_key_from_integrity, create_bin_links, detect_lifecycle_scripts, fetch_packages_with_progress, FetchOptions,
+    ingest_to_file_cas, lockfile_sync_problems, materialize_from_file_cas, remove_file_cas_manifest, materialize_tree, resolve_from_lockfile_cached,
+    run_lifecycle_scripts, run_lifecycle_scripts_npm_rebuild, LifecycleRunOptions, LifecycleScriptOutcome, script_timeout, scan_tree, scan_tree_cached, ExcludeMatcher, WalkOptions, try_clonefile_dir, unpacked_path, write_analyze_json,
+    write_materialize_json, write_scan_json, write_warnings, ResolvedPackage, Warning, CasLayout, JsonWriter, LifecycleRunResult,
+    LinkStrategy, MaterializeOptions, MaterializeProfile, SpecialFilePolicy, MaterializeStats, PhaseDurations, ScanAgg, VERSION,
+    remove_path_if_exists, staging_dir_for, swap_staged_node_modules,
     // Phase B
-    run_script, run_scripts_parallel,
-    scan_licenses, check_dedupe, trace_dependency, check_outdated,
-    run_doctor, cache_stats, cache_gc, run_audit, run_benchmark,
+    run_script, run_scripts_parallel, run_scripts_sequential,
+    ProjectContext, OmitSet, SortKey, MetadataCache, METADATA_CACHE_DEFAULT_TTL, WHY_DEFAULT_MAX_PATHS, dedupe_from_analyze, write_dedupe_fields, render_table,
+    run_doctor, DoctorFailOn, audit_severity_rank, AuditSource, cache_stats, cache_gc, verify_cached_tarballs, cache_export, cache_import, run_benchmark,
     // Phase C
-    hooks_install, exec_script, env_info, env_check, init_project, run_script_watch,
+    hooks_install, hooks_uninstall, exec_script, env_info, env_check, package_manager_check, init_project, run_script_watch,
     // Phase D
-    parse_npmrc, scan_scripts, scripts_allow, scripts_block,
+    parse_npmrc, load_script_policy, scan_scripts, scripts_allow, scripts_block,
     policy_check, policy_init,
     generate_lock_metadata, verify_lock_metadata,
-    detect_workspaces, workspace_graph, workspace_changed, workspace_run,
-    generate_sbom, write_cyclonedx_json, write_spdx_json,
+    record_tree_hash, verify_reproducible, ReproducibleVerifyResult, prune_node_modules,
+    record_installed_integrity, verify_installed_integrity, verify_installed, INSTALL_INTEGRITY_FILE,
+    detect_workspaces, workspace_graph, workspace_changed, workspace_run, compute_hoist_plan,
+    write_cyclonedx_json, write_spdx_json,
+    pack_project, FsPlan, write_plan_json, plan_install, resolve_from_lockfile, resolve_from_manifest, write_npm_lockfile, default_lockfile, install_lockfile,
+    check_lockfile, lockfile_duplicate_keys, validate_resolved_packages, LockfileProblem,
+    default_global_prefix, global_add, global_remove, list_top_level_packages, list_locked_packages, GlobalLayout, InstalledPackage,
+    run_diagnostics, FsCapabilities, CoreError, ErrorKind, redact_url_credentials,
+    sweep_stale_tmp_files, file_store_tmp_dir, STALE_TMP_MAX_AGE_SECS,
 };
 
+// Exit codes, so scripts can branch on the kind of failure without parsing
+// the report. Scripts run by `run` and `exec` exit with their own code.
+/// Any failure not covered below.
+const EXIT_FAILURE: i32 = 1;
+/// Bad arguments; the help text is printed.
+const EXIT_USAGE: i32 = 2;
+/// A check ran and found problems: audit findings, license, policy, engine
+/// or doctor violations, a lockfile out of sync.
+const EXIT_VIOLATION: i32 = 3;
+/// The registry or an advisory source could not be reached, or refused the
+/// request.
+const EXIT_NETWORK: i32 = 4;
+/// Content does not match its recorded hash.
+const EXIT_INTEGRITY: i32 = 5;
+
+/// The exit code for a failure of `kind` reported by better-core.
+fn failure_exit_code(kind: ErrorKind) -> i32 {
+    match kind {
+        ErrorKind::Network => EXIT_NETWORK,
+        ErrorKind::Integrity => EXIT_INTEGRITY,
+        ErrorKind::Other => EXIT_FAILURE,
+    }
+}
+
 #[derive(Debug)]
 enum Command {
-    Analyze { root: PathBuf, graph: bool },
-    Scan { root: PathBuf },
+    Analyze { root: PathBuf, graph: bool, dedupe_report: bool, sort: Option<SortKey>, baseline: Option<PathBuf>, max_depth: Option<u64> },
+    AnalyzeDiff { root: PathBuf, baseline: PathBuf },
+    Scan { root: PathBuf, cache: Option<PathBuf>, exclude: Vec<String>, follow_symlinks: bool, max_depth: Option<u64> },
     Materialize {
         src: PathBuf,
         dest: PathBuf,
         link_strategy: LinkStrategy,
         jobs: usize,
         profile: MaterializeProfile,
+        special_files: SpecialFilePolicy,
+        force: bool,
+        dry_run: bool,
     },
     Install {
         lockfile: PathBuf,
@@ -39,52 +76,120 @@ enum Command {
         cache_root: PathBuf,
         store_root: Option<PathBuf>,
         link_strategy: LinkStrategy,
+        #[allow(dead_code)]
         jobs: usize,
         scripts: bool,
+        /// Run lifecycle scripts through `npm rebuild` instead of natively
+        npm_rebuild: bool,
+        /// Run lifecycle scripts of packages outside the script policy's allowed list
+        allow_all_scripts: bool,
+        /// Keep lifecycle script output for the JSON report instead of passing it through
+        capture_script_output: bool,
+        /// `--script-jobs`: packages whose lifecycle scripts may run at once
+        script_jobs: Option<usize>,
         dedup: bool,
+        staged: bool,
+        dry_run: bool,
+        verify_store: bool,
+        frozen: bool,
+        /// Materialize every package and report all failures instead of stopping at the first
+        keep_going: bool,
+        /// `ci`: require a lockfile in sync with package.json, then replace node_modules from it
+        ci: bool,
+        engine_strict: bool,
+        /// Fail, instead of warning, when package.json `packageManager` pins another tool or version
+        package_manager_strict: bool,
+        /// Install only from the cache; never download
+        offline: bool,
+        /// `--timeout` (ms), over npmrc `fetch-timeout`
+        timeout: Option<u64>,
     },
     Run {
         project_root: PathBuf,
         script_names: Vec<String>,
         extra_args: Vec<String>,
         watch: bool,
+        /// Several scripts run at once with `--parallel`, else one by one
+        parallel: bool,
     },
     License {
-        root: PathBuf,
+        project_root: PathBuf,
+        /// `--root` keeps pointing at node_modules itself for compatibility
+        node_modules: Option<PathBuf>,
         allow: Vec<String>,
         deny: Vec<String>,
+        omit: OmitSet,
+        sort: Option<SortKey>,
+        ndjson: bool,
+        human: bool,
     },
-    Dedupe { root: PathBuf },
+    Dedupe { root: PathBuf, node_modules: Option<PathBuf>, human: bool },
     Why {
         project_root: PathBuf,
         lockfile: PathBuf,
         package: String,
+        max_paths: usize,
+    },
+    Prune {
+        project_root: PathBuf,
+        lockfile: PathBuf,
+        dry_run: bool,
     },
     Outdated {
         project_root: PathBuf,
         lockfile: PathBuf,
+        wanted: bool,
+        metadata_cache: Option<MetadataCache>,
+        sort: Option<SortKey>,
+        timeout: Option<u64>,
+        ndjson: bool,
+        human: bool,
     },
     Doctor {
         project_root: PathBuf,
         threshold: i32,
+        fail_on: DoctorFailOn,
+        human: bool,
     },
     CacheStats { cache_root: PathBuf },
+    Diagnostics { project_root: PathBuf, cache_root: PathBuf },
     CacheGc {
         cache_root: PathBuf,
         max_age: u64,
         dry_run: bool,
     },
+    CacheVerify {
+        cache_root: PathBuf,
+        by_url: Option<Vec<String>>,
+    },
+    CacheExport {
+        cache_root: PathBuf,
+        store_root: PathBuf,
+        archive: PathBuf,
+    },
+    CacheImport {
+        cache_root: PathBuf,
+        store_root: PathBuf,
+        archive: PathBuf,
+    },
     Audit {
         project_root: PathBuf,
         lockfile: PathBuf,
         min_severity: String,
+        /// Lowest severity that makes the audit exit non-zero
+        fail_on: String,
+        source: AuditSource,
+        omit: OmitSet,
+        timeout: Option<u64>,
+        ndjson: bool,
     },
     Benchmark {
         project_root: PathBuf,
         rounds: usize,
         pms: Vec<String>,
     },
-    HooksInstall { project_root: PathBuf },
+    HooksInstall { project_root: PathBuf, dry_run: bool },
+    HooksUninstall { project_root: PathBuf, dry_run: bool },
     Exec {
         project_root: PathBuf,
         script: String,
@@ -95,27 +200,74 @@ enum Command {
         project_root: PathBuf,
         name: Option<String>,
         template: Option<String>,
+        dry_run: bool,
     },
     // Phase D
     Scripts {
         project_root: PathBuf,
         subcommand: String,
         package: Option<String>,
+        dry_run: bool,
+        ndjson: bool,
     },
     Policy {
         project_root: PathBuf,
         subcommand: String,
+        dry_run: bool,
     },
     Lock {
         project_root: PathBuf,
         subcommand: String,
+        dry_run: bool,
+    },
+    Verify {
+        project_root: PathBuf,
+        expected: Option<String>,
+        record: bool,
+        dry_run: bool,
+    },
+    VerifyIntegrity {
+        project_root: PathBuf,
+    },
+    VerifyStore {
+        project_root: PathBuf,
+        store_root: PathBuf,
     },
     Workspace {
         project_root: PathBuf,
+        lockfile: PathBuf,
         subcommand: String,
         since: Option<String>,
         command_arg: Option<String>,
     },
+    Pack {
+        project_root: PathBuf,
+        dest: PathBuf,
+        dry_run: bool,
+    },
+    Resolve {
+        lockfile: PathBuf,
+        check: bool,
+    },
+    Add {
+        prefix: Option<PathBuf>,
+        cache_root: PathBuf,
+        link_strategy: LinkStrategy,
+        specs: Vec<String>,
+        timeout: Option<u64>,
+        dry_run: bool,
+    },
+    Remove {
+        prefix: Option<PathBuf>,
+        names: Vec<String>,
+        dry_run: bool,
+    },
+    List {
+        global: bool,
+        prefix: Option<PathBuf>,
+        project_root: PathBuf,
+        lockfile: PathBuf,
+    },
     Sbom {
         project_root: PathBuf,
         lockfile: PathBuf,
@@ -162,6 +314,7 @@ fn parse_args() -> Command {
     let sub = args[0].as_str();
     let mut root: Option<PathBuf> = None;
     let mut graph = false;
+    let mut dedupe_report = false;
     let mut src: Option<PathBuf> = None;
     let mut dest: Option<PathBuf> = None;
     let mut link_strategy = LinkStrategy::Auto;
@@ -170,17 +323,33 @@ fn parse_args() -> Command {
         .unwrap_or(8);
     jobs = jobs.clamp(1, 64);
     let mut profile = MaterializeProfile::Auto;
+    let mut special_files = SpecialFilePolicy::Skip;
     let mut lockfile: Option<PathBuf> = None;
     let mut project_root: Option<PathBuf> = None;
     let mut cache_root: Option<PathBuf> = None;
     let mut store_root: Option<PathBuf> = None;
     let mut scripts_flag = true;
+    let mut npm_rebuild = false;
+    let mut allow_all_scripts = false;
+    let mut capture_script_output = false;
+    let mut script_jobs: Option<usize> = None;
     let mut dedup = false;
+    let mut staged = false;
+    let mut verify_store = false;
+    let mut frozen = false;
+    let mut keep_going = false;
+    let mut force = false;
+    let mut engine_strict = false;
+    let mut package_manager_strict = false;
+    let mut offline = false;
     let mut allow: Vec<String> = Vec::new();
     let mut deny: Vec<String> = Vec::new();
     let mut threshold = 70i32;
+    // doctor takes a DoctorFailOn, audit a severity
+    let mut fail_on: Option<String> = None;
     let mut max_age = 30u64;
     let mut dry_run = false;
+    let mut by_url = false;
     let mut min_severity = "low".to_string();
     let mut rounds = 3usize;
     let mut pms: Vec<String> = Vec::new();
@@ -190,8 +359,32 @@ fn parse_args() -> Command {
     let mut name_opt: Option<String> = None;
     let mut template_opt: Option<String> = None;
     let mut watch = false;
+    let mut parallel: Option<bool> = None;
     let mut format_opt = "cyclonedx".to_string();
     let mut since_opt: Option<String> = None;
+    let mut reproducible = false;
+    let mut integrity = false;
+    let mut verify_store_files = false;
+    let mut expected_opt: Option<String> = None;
+    let mut record = false;
+    let mut omit = OmitSet::default();
+    let mut sort: Option<SortKey> = None;
+    let mut global = false;
+    let mut check = false;
+    let mut prefix: Option<PathBuf> = None;
+    let mut timeout: Option<u64> = None;
+    let mut baseline: Option<PathBuf> = None;
+    let mut scan_cache: Option<PathBuf> = None;
+    let mut scan_exclude: Vec<String> = Vec::new();
+    let mut follow_symlinks = false;
+    let mut max_depth: Option<u64> = None;
+    let mut max_paths: Option<usize> = None;
+    let mut wanted = false;
+    let mut no_cache = false;
+    let mut audit_source = AuditSource::default();
+    let mut cache_ttl: Option<u64> = None;
+    let mut ndjson = false;
+    let mut human_flag: Option<bool> = None;
 
     let mut i = 1usize;
     while i < args.len() {
@@ -210,15 +403,71 @@ fn parse_args() -> Command {
                 root = Some(PathBuf::from(&args[i + 1]));
                 i += 2;
             }
+            "--ndjson" => { ndjson = true; i += 1; }
+            // Read by json_indent
+            "--json-pretty" | "--json-compact" => { i += 1; }
+            "--human" => { human_flag = Some(true); i += 1; }
+            "--json" => { human_flag = Some(false); i += 1; }
             "--graph" => { graph = true; i += 1; }
             "--no-graph" => { graph = false; i += 1; }
+            "--dedupe-report" => { dedupe_report = true; i += 1; }
+            "--baseline" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--baseline requires a value".into()) }; }
+                baseline = Some(PathBuf::from(&args[i + 1]));
+                i += 2;
+            }
+            "--cache" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--cache requires a value".into()) }; }
+                scan_cache = Some(PathBuf::from(&args[i + 1]));
+                i += 2;
+            }
+            "--follow-symlinks" => { follow_symlinks = true; i += 1; }
+            "--wanted" => { wanted = true; i += 1; }
+            "--no-cache" => { no_cache = true; i += 1; }
+            "--source" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--source requires a value".into()) }; }
+                match AuditSource::from_arg(&args[i + 1]) {
+                    Some(v) => audit_source = v,
+                    None => return Command::Help { error: Some(format!("invalid --source: {}", args[i + 1])) },
+                }
+                i += 2;
+            }
+            "--cache-ttl" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--cache-ttl requires a value".into()) }; }
+                match args[i + 1].parse::<u64>() {
+                    Ok(secs) => cache_ttl = Some(secs),
+                    Err(_) => return Command::Help { error: Some(format!("invalid --cache-ttl '{}'", args[i + 1])) },
+                }
+                i += 2;
+            }
+            "--max-paths" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--max-paths requires a value".into()) }; }
+                match args[i + 1].parse::<usize>() {
+                    Ok(n) if n > 0 => max_paths = Some(n),
+                    _ => return Command::Help { error: Some(format!("invalid --max-paths '{}'", args[i + 1])) },
+                }
+                i += 2;
+            }
+            "--max-depth" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--max-depth requires a value".into()) }; }
+                match args[i + 1].parse::<u64>() {
+                    Ok(n) => max_depth = Some(n),
+                    Err(_) => return Command::Help { error: Some(format!("invalid --max-depth '{}'", args[i + 1])) },
+                }
+                i += 2;
+            }
+            "--exclude" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--exclude requires a value".into()) }; }
+                scan_exclude.push(args[i + 1].clone());
+                i += 2;
+            }
             "--src" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--src requires a value".into()) }; }
                 src = Some(PathBuf::from(&args[i + 1]));
                 i += 2;
             }
-            "--dest" => {
-                if i + 1 >= args.len() { return Command::Help { error: Some("--dest requires a value".into()) }; }
+            "--dest" | "--pack-destination" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some(format!("{} requires a value", args[i])) }; }
                 dest = Some(PathBuf::from(&args[i + 1]));
                 i += 2;
             }
@@ -238,6 +487,14 @@ fn parse_args() -> Command {
                 }
                 i += 2;
             }
+            "--script-jobs" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--script-jobs requires a value".into()) }; }
+                match args[i + 1].parse::<usize>() {
+                    Ok(n) if n > 0 => script_jobs = Some(n.clamp(1, 256)),
+                    _ => return Command::Help { error: Some(format!("invalid --script-jobs '{}'", args[i + 1])) },
+                }
+                i += 2;
+            }
             "--profile" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--profile requires a value".into()) }; }
                 match MaterializeProfile::from_arg(&args[i + 1]) {
@@ -246,6 +503,14 @@ fn parse_args() -> Command {
                 }
                 i += 2;
             }
+            "--special-files" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--special-files requires a value".into()) }; }
+                match SpecialFilePolicy::from_arg(&args[i + 1]) {
+                    Some(p) => special_files = p,
+                    None => return Command::Help { error: Some(format!("unknown --special-files '{}'", args[i + 1])) },
+                }
+                i += 2;
+            }
             "--lockfile" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--lockfile requires a value".into()) }; }
                 lockfile = Some(PathBuf::from(&args[i + 1]));
@@ -268,7 +533,55 @@ fn parse_args() -> Command {
             }
             "--no-scripts" => { scripts_flag = false; i += 1; }
             "--scripts" => { scripts_flag = true; i += 1; }
+            "--use-npm-rebuild" => { npm_rebuild = true; i += 1; }
+            "--allow-all-scripts" => { allow_all_scripts = true; i += 1; }
+            "--capture-script-output" => { capture_script_output = true; i += 1; }
             "--dedup" => { dedup = true; i += 1; }
+            "--staged" => { staged = true; i += 1; }
+            "--verify-store" => { verify_store = true; i += 1; }
+            "--frozen" => { frozen = true; i += 1; }
+            "--keep-going" => { keep_going = true; i += 1; }
+            "--force" => { force = true; i += 1; }
+            "--engine-strict" => { engine_strict = true; i += 1; }
+            "--package-manager-strict" => { package_manager_strict = true; i += 1; }
+            "--offline" => { offline = true; i += 1; }
+            "--global" | "-g" => { global = true; i += 1; }
+            "--check" => { check = true; i += 1; }
+            "--prefix" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--prefix requires a value".into()) }; }
+                prefix = Some(PathBuf::from(&args[i + 1]));
+                i += 2;
+            }
+            "--reproducible" => { reproducible = true; i += 1; }
+            "--integrity" => { integrity = true; i += 1; }
+            "--store" => { verify_store_files = true; i += 1; }
+            "--record" => { record = true; i += 1; }
+            "--prod" | "--production" => { omit.dev = true; i += 1; }
+            "--omit" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--omit requires a value".into()) }; }
+                match OmitSet::parse(&args[i + 1]) {
+                    Ok(o) => {
+                        omit.dev |= o.dev;
+                        omit.optional |= o.optional;
+                        omit.peer |= o.peer;
+                    }
+                    Err(e) => return Command::Help { error: Some(e) },
+                }
+                i += 2;
+            }
+            "--sort" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--sort requires a value".into()) }; }
+                match SortKey::from_arg(&args[i + 1]) {
+                    Some(k) => sort = Some(k),
+                    None => return Command::Help { error: Some(format!("unknown --sort key '{}' (expected name, type or size)", args[i + 1])) },
+                }
+                i += 2;
+            }
+            "--expected" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--expected requires a value".into()) }; }
+                expected_opt = Some(args[i + 1].clone());
+                i += 2;
+            }
             "--no-dedup" => { dedup = false; i += 1; }
             "--allow" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--allow requires a value".into()) }; }
@@ -280,17 +593,31 @@ fn parse_args() -> Command {
                 deny = args[i + 1].split(',').map(|s| s.trim().to_string()).collect();
                 i += 2;
             }
+            "--timeout" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some("--timeout requires a value".into()) }; }
+                match args[i + 1].parse::<u64>() {
+                    Ok(ms) if ms > 0 => timeout = Some(ms),
+                    _ => return Command::Help { error: Some(format!("invalid --timeout: {} (milliseconds)", args[i + 1])) },
+                }
+                i += 2;
+            }
             "--threshold" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--threshold requires a value".into()) }; }
                 threshold = args[i + 1].parse().unwrap_or(70);
                 i += 2;
             }
+            "--fail-on" | "--audit-level" => {
+                if i + 1 >= args.len() { return Command::Help { error: Some(format!("{} requires a value", args[i])) }; }
+                fail_on = Some(args[i + 1].clone());
+                i += 2;
+            }
             "--max-age" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--max-age requires a value".into()) }; }
                 max_age = args[i + 1].parse().unwrap_or(30);
                 i += 2;
             }
             "--dry-run" => { dry_run = true; i += 1; }
+            "--by-url" => { by_url = true; i += 1; }
             "--min-severity" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--min-severity requires a value".into()) }; }
                 min_severity = args[i + 1].clone();
@@ -317,6 +644,14 @@ fn parse_args() -> Command {
                 i += 2;
             }
             "--watch" | "-w" => { watch = true; i += 1; }
+            "--parallel" | "--sequential" => {
+                let wanted = args[i] == "--parallel";
+                if parallel.is_some_and(|p| p != wanted) {
+                    return Command::Help { error: Some("--parallel and --sequential cannot be combined".into()) };
+                }
+                parallel = Some(wanted);
+                i += 1;
+            }
             "--format" => {
                 if i + 1 >= args.len() { return Command::Help { error: Some("--format requires a value".into()) }; }
                 format_opt = args[i + 1].clone();
@@ -337,92 +672,162 @@ fn parse_args() -> Command {
         }
     }
 
+    // Each list supports the keys that mean something for it
+    let sort_supported = match (sub, sort) {
+        (_, None) | (_, Some(SortKey::Name)) => true,
+        ("analyze", Some(k)) => k == SortKey::Size,
+        ("license" | "outdated", Some(k)) => k == SortKey::Type,
+        _ => false,
+    };
+    if !sort_supported {
+        if let Some(k) = sort {
+            return Command::Help { error: Some(format!("--sort {} is not supported for {}", k.as_str(), sub)) };
+        }
+    }
+    // Tables for a person at a terminal, JSON for pipes unless asked otherwise
+    let human = human_flag.unwrap_or_else(|| !ndjson && std::io::stdout().is_terminal());
+
     match sub {
-        "analyze" => match root {
-            Some(r) => Command::Analyze { root: r, graph },
-            None => Command::Help { error: Some("analyze requires --root".into()) },
+        "analyze" => match (root, positional.first().map(String::as_str)) {
+            (None, _) => Command::Help { error: Some("analyze requires --root".into()) },
+            (Some(r), None) => Command::Analyze { root: r, graph, dedupe_report, sort, baseline, max_depth },
+            (Some(r), Some("diff")) => match positional.get(1) {
+                Some(path) => Command::AnalyzeDiff { root: r, baseline: PathBuf::from(path) },
+                None => Command::Help { error: Some("analyze diff requires a baseline file".into()) },
+            },
+            (Some(_), Some(other)) => Command::Help { error: Some(format!("unknown analyze subcommand: {other}")) },
         },
         "scan" => match root {
-            Some(r) => Command::Scan { root: r },
+            Some(_) if scan_cache.is_some() && !scan_exclude.is_empty() => {
+                Command::Help { error: Some("scan --exclude cannot be combined with --cache".into()) }
+            }
+            Some(_) if scan_cache.is_some() && (follow_symlinks || max_depth.is_some()) => {
+                Command::Help { error: Some("scan --follow-symlinks and --max-depth cannot be combined with --cache".into()) }
+            }
+            Some(r) => Command::Scan { root: r, cache: scan_cache, exclude: scan_exclude, follow_symlinks, max_depth },
             None => Command::Help { error: Some("scan requires --root".into()) },
         },
         "materialize" => match (src, dest) {
-            (Some(s), Some(d)) => Command::Materialize { src: s, dest: d, link_strategy, jobs, profile },
+            (Some(s), Some(d)) => Command::Materialize { src: s, dest: d, link_strategy, jobs, profile, special_files, force, dry_run },
             _ => Command::Help { error: Some("materialize requires --src and --dest".into()) },
         },
         "install" | "i" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            let lf = lockfile.unwrap_or_else(|| pr.join("package-lock.json"));
+            let lf = lockfile.unwrap_or_else(|| install_lockfile(&pr));
             let cr = cache_root.unwrap_or_else(default_cache_root);
-            Command::Install { lockfile: lf, project_root: pr, cache_root: cr, store_root, link_strategy, jobs, scripts: scripts_flag, dedup }
+            Command::Install { lockfile: lf, project_root: pr, cache_root: cr, store_root, link_strategy, jobs, scripts: scripts_flag, npm_rebuild, allow_all_scripts, capture_script_output, script_jobs, dedup, staged, dry_run, verify_store, frozen, keep_going, engine_strict, package_manager_strict, offline, ci: false, timeout }
+        },
+        "ci" => {
+            // A clean, lockfile-only install: the staged swap drops whatever
+            // node_modules held before, and store blobs are never re-ingested.
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
+            let cr = cache_root.unwrap_or_else(default_cache_root);
+            Command::Install { lockfile: lf, project_root: pr, cache_root: cr, store_root, link_strategy, jobs, scripts: scripts_flag, npm_rebuild, allow_all_scripts, capture_script_output, script_jobs, dedup, staged: true, dry_run, verify_store, frozen: true, keep_going, engine_strict, package_manager_strict, offline, ci: true, timeout }
         },
         "run" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
             if positional.is_empty() {
                 return Command::Help { error: Some("run requires a script name".into()) };
             }
-            Command::Run { project_root: pr, script_names: positional, extra_args, watch }
+            Command::Run { project_root: pr, script_names: positional, extra_args, watch, parallel: parallel.unwrap_or(false) }
         },
         "test" | "t" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::Run { project_root: pr, script_names: vec!["test".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch }
+            Command::Run { project_root: pr, script_names: vec!["test".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch, parallel: false }
         },
         "lint" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::Run { project_root: pr, script_names: vec!["lint".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch }
+            Command::Run { project_root: pr, script_names: vec!["lint".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch, parallel: false }
         },
         "dev" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::Run { project_root: pr, script_names: vec!["dev".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch: true }
+            Command::Run { project_root: pr, script_names: vec!["dev".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch: true, parallel: false }
         },
         "build" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::Run { project_root: pr, script_names: vec!["build".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch }
+            Command::Run { project_root: pr, script_names: vec!["build".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch, parallel: false }
         },
         "start" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::Run { project_root: pr, script_names: vec!["start".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch }
+            Command::Run { project_root: pr, script_names: vec!["start".into()], extra_args: positional.into_iter().chain(extra_args).collect(), watch, parallel: false }
         },
         "license" => {
-            let r = root.unwrap_or_else(|| {
-                let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-                pr.join("node_modules")
-            });
-            Command::License { root: r, allow, deny }
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            Command::License { project_root: pr, node_modules: root, allow, deny, omit, sort, ndjson, human }
         },
-        "dedupe" | "dedup" => {
-            let r = root.unwrap_or_else(|| project_root.unwrap_or_else(|| PathBuf::from(".")));
-            Command::Dedupe { root: r }
+        "dedupe" | "dedup" => match project_root {
+            // As for license, --root then names the node_modules to read
+            Some(pr) => Command::Dedupe { root: pr, node_modules: root, human },
+            None => Command::Dedupe { root: root.unwrap_or_else(|| PathBuf::from(".")), node_modules: None, human },
         },
         "why" => {
             if positional.is_empty() {
                 return Command::Help { error: Some("why requires a package name".into()) };
             }
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            let lf = lockfile.unwrap_or_else(|| pr.join("package-lock.json"));
-            Command::Why { project_root: pr, lockfile: lf, package: positional[0].clone() }
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
+            Command::Why { project_root: pr, lockfile: lf, package: positional[0].clone(), max_paths: max_paths.unwrap_or(WHY_DEFAULT_MAX_PATHS) }
+        },
+        "prune" => {
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            let lf = lockfile.unwrap_or_else(|| install_lockfile(&pr));
+            Command::Prune { project_root: pr, lockfile: lf, dry_run }
         },
         "outdated" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            let lf = lockfile.unwrap_or_else(|| pr.join("package-lock.json"));
-            Command::Outdated { project_root: pr, lockfile: lf }
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
+            let metadata_cache = (!no_cache).then(|| {
+                let ttl = cache_ttl.map_or(METADATA_CACHE_DEFAULT_TTL, std::time::Duration::from_secs);
+                MetadataCache::new(&cache_root.unwrap_or_else(default_cache_root), ttl)
+            });
+            Command::Outdated { project_root: pr, lockfile: lf, wanted, metadata_cache, sort, timeout, ndjson, human }
         },
         "doctor" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::Doctor { project_root: pr, threshold }
+            let fail_on = match fail_on.as_deref().map(|v| (v, DoctorFailOn::from_arg(v))) {
+                None => DoctorFailOn::Score,
+                Some((_, Some(v))) => v,
+                Some((v, None)) => return Command::Help { error: Some(format!("invalid --fail-on: {}", v)) },
+            };
+            Command::Doctor { project_root: pr, threshold, fail_on, human }
         },
         "cache" => {
             let cr = cache_root.unwrap_or_else(default_cache_root);
             if positional.first().map(|s| s.as_str()) == Some("gc") {
                 Command::CacheGc { cache_root: cr, max_age, dry_run }
+            } else if positional.first().map(|s| s.as_str()) == Some("verify") {
+                let urls: Vec<String> = positional[1..].to_vec();
+                if !by_url && !urls.is_empty() {
+                    return Command::Help { error: Some("cache verify takes URLs only with --by-url".into()) };
+                }
+                Command::CacheVerify { cache_root: cr, by_url: by_url.then_some(urls) }
+            } else if let Some(sub @ ("export" | "import")) = positional.first().map(|s| s.as_str()) {
+                let Some(archive) = positional.get(1).map(PathBuf::from) else {
+                    return Command::Help { error: Some(format!("cache {sub} requires an archive path")) };
+                };
+                let sr = store_root.unwrap_or_else(|| cr.join("file-store"));
+                if sub == "export" {
+                    Command::CacheExport { cache_root: cr, store_root: sr, archive }
+                } else {
+                    Command::CacheImport { cache_root: cr, store_root: sr, archive }
+                }
             } else {
                 Command::CacheStats { cache_root: cr }
             }
         },
+        "diagnostics" => {
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            Command::Diagnostics { project_root: pr, cache_root: cache_root.unwrap_or_else(default_cache_root) }
+        },
         "audit" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            let lf = lockfile.unwrap_or_else(|| pr.join("package-lock.json"));
-            Command::Audit { project_root: pr, lockfile: lf, min_severity }
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
+            let fail_on = fail_on.unwrap_or_else(|| "low".to_string());
+            if audit_severity_rank(&fail_on) == 0 {
+                return Command::Help { error: Some(format!("invalid --fail-on: {}", fail_on)) };
+            }
+            Command::Audit { project_root: pr, lockfile: lf, min_severity, fail_on, source: audit_source, omit, timeout, ndjson }
         },
         "benchmark" | "bench" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
@@ -431,7 +836,11 @@ fn parse_args() -> Command {
         },
         "hooks" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::HooksInstall { project_root: pr }
+            if positional.first().map(|s| s.as_str()) == Some("uninstall") {
+                Command::HooksUninstall { project_root: pr, dry_run }
+            } else {
+                Command::HooksInstall { project_root: pr, dry_run }
+            }
         },
         "exec" | "x" => {
             if positional.is_empty() {
@@ -447,35 +856,85 @@ fn parse_args() -> Command {
         },
         "init" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            Command::Init { project_root: pr, name: name_opt.or_else(|| positional.first().cloned()), template: template_opt }
+            Command::Init { project_root: pr, name: name_opt.or_else(|| positional.first().cloned()), template: template_opt, dry_run }
         },
         "scripts" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
             let subcmd = positional.first().cloned().unwrap_or_else(|| "list".into());
             let pkg = positional.get(1).cloned();
-            Command::Scripts { project_root: pr, subcommand: subcmd, package: pkg }
+            Command::Scripts { project_root: pr, subcommand: subcmd, package: pkg, dry_run, ndjson }
         },
         "policy" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
             let subcmd = positional.first().cloned().unwrap_or_else(|| "check".into());
-            Command::Policy { project_root: pr, subcommand: subcmd }
+            Command::Policy { project_root: pr, subcommand: subcmd, dry_run }
         },
         "lock" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
             let subcmd = positional.first().cloned().unwrap_or_else(|| "generate".into());
-            Command::Lock { project_root: pr, subcommand: subcmd }
+            Command::Lock { project_root: pr, subcommand: subcmd, dry_run }
+        },
+        "verify" => {
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            match (reproducible, integrity, verify_store_files) {
+                (true, false, false) => Command::Verify { project_root: pr, expected: expected_opt, record, dry_run },
+                (false, true, false) => Command::VerifyIntegrity { project_root: pr },
+                (false, false, true) => {
+                    let sr = store_root.unwrap_or_else(|| cache_root.unwrap_or_else(default_cache_root).join("file-store"));
+                    Command::VerifyStore { project_root: pr, store_root: sr }
+                }
+                (false, false, false) => Command::Help { error: Some("verify requires --reproducible, --integrity or --store".into()) },
+                _ => Command::Help { error: Some("verify takes one of --reproducible, --integrity and --store".into()) },
+            }
         },
         "workspace" | "ws" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
             let subcmd = positional.first().cloned().unwrap_or_else(|| "list".into());
             let cmd_arg = if subcmd == "run" { positional.get(1).cloned() } else { None };
-            Command::Workspace { project_root: pr, subcommand: subcmd, since: since_opt, command_arg: cmd_arg }
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
+            Command::Workspace { project_root: pr, lockfile: lf, subcommand: subcmd, since: since_opt, command_arg: cmd_arg }
         },
         "sbom" => {
             let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
-            let lf = lockfile.unwrap_or_else(|| pr.join("package-lock.json"));
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
             Command::Sbom { project_root: pr, lockfile: lf, format: format_opt }
         },
+        "pack" => {
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            let d = dest.unwrap_or_else(|| pr.clone());
+            Command::Pack { project_root: pr, dest: d, dry_run }
+        },
+        "resolve" => {
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
+            Command::Resolve { lockfile: lf, check }
+        },
+        // Project-local add/remove edit package.json, which belongs to the JS CLI;
+        // the core only manages the global prefix.
+        "add" => {
+            if !global {
+                return Command::Help { error: Some("add requires --global".into()) };
+            }
+            if positional.is_empty() {
+                return Command::Help { error: Some("add requires at least one package".into()) };
+            }
+            let cr = cache_root.unwrap_or_else(default_cache_root);
+            Command::Add { prefix, cache_root: cr, link_strategy, specs: positional, timeout, dry_run }
+        },
+        "remove" | "rm" | "uninstall" => {
+            if !global {
+                return Command::Help { error: Some("remove requires --global".into()) };
+            }
+            if positional.is_empty() {
+                return Command::Help { error: Some("remove requires at least one package".into()) };
+            }
+            Command::Remove { prefix, names: positional, dry_run }
+        },
+        "list" | "ls" => {
+            let pr = project_root.unwrap_or_else(|| PathBuf::from("."));
+            let lf = lockfile.unwrap_or_else(|| default_lockfile(&pr));
+            Command::List { global, prefix, project_root: pr, lockfile: lf }
+        },
         _ => Command::Help { error: Some(format!("unknown command: {sub}")) },
     }
 }
@@ -488,68 +947,362 @@ fn print_help(error: Option<String>) {
         "better-core {VERSION}
 
 Usage:
-  better-core install [--lockfile <path>] [--project-root <path>] [--cache-root <path>] [--dedup]
+  better-core install [--lockfile <path>] [--project-root <path>] [--cache-root <path>] [--dedup] [--staged] [--verify-store] [--frozen] [--keep-going] [--engine-strict] [--package-manager-strict] [--offline] [--dry-run] [--timeout <ms>] [--no-scripts] [--allow-all-scripts] [--capture-script-output] [--script-jobs <n>] [--use-npm-rebuild]
+  better-core ci [--project-root <path>] [--cache-root <path>] [--keep-going] [--engine-strict] [--package-manager-strict] [--offline] [--dry-run] [--timeout <ms>]
   better-core run <script> [--watch] [-- extra args...]
+  better-core run <script> <script>... [--sequential|--parallel]
   better-core test|lint|build|start [--watch] [args...]
   better-core dev [args...]  (watch mode by default)
-  better-core license [--root <path>] [--allow MIT,ISC] [--deny GPL-3.0]
-  better-core dedupe [--root <path>]
-  better-core why <package> [--project-root <path>] [--lockfile <path>]
-  better-core outdated [--project-root <path>] [--lockfile <path>]
-  better-core doctor [--project-root <path>] [--threshold 70]
+  better-core license [--root <path>] [--allow MIT,ISC] [--deny GPL-3.0] [--omit dev,optional,peer] [--prod] [--sort name|type] [--ndjson]
+  better-core dedupe [--root <path>] [--project-root <path>]
+  better-core why <package> [--project-root <path>] [--lockfile <path>] [--max-paths <n>]
+  better-core prune [--project-root <path>] [--lockfile <path>] [--dry-run]
+  better-core outdated [--project-root <path>] [--lockfile <path>] [--wanted] [--no-cache] [--cache-ttl <secs>] [--cache-root <path>] [--sort name|type] [--timeout <ms>] [--ndjson]
+  better-core doctor [--project-root <path>] [--threshold 70] [--fail-on score|error|critical|never]
   better-core cache stats [--cache-root <path>]
   better-core cache gc [--cache-root <path>] [--max-age 30] [--dry-run]
-  better-core audit [--project-root <path>] [--lockfile <path>] [--min-severity medium]
+  better-core cache verify [--cache-root <path>] [--by-url [<url>...]]
+  better-core cache export|import <archive> [--cache-root <path>] [--store-root <path>]
+  better-core diagnostics [--project-root <path>] [--cache-root <path>]
+  better-core audit [--project-root <path>] [--lockfile <path>] [--min-severity medium] [--fail-on low|moderate|high|critical] [--source osv|github] [--omit dev,optional,peer] [--prod|--production] [--timeout <ms>] [--ndjson]
   better-core benchmark [--project-root <path>] [--rounds 3] [--pm npm,bun]
-  better-core hooks install [--project-root <path>]
+  better-core hooks install|uninstall [--project-root <path>] [--dry-run]
   better-core exec <script.ts> [-- args...]
   better-core env [check] [--project-root <path>]
-  better-core init [--name <name>] [--template react|next|express]
-  better-core scripts [list|scan|allow|block] [package] [--project-root <path>]
-  better-core policy [check|init] [--project-root <path>]
-  better-core lock [generate|verify] [--project-root <path>]
-  better-core workspace [list|graph|changed|run] [--project-root <path>] [--since <ref>]
+  better-core init [--name <name>] [--template react|next|express] [--dry-run]
+  better-core scripts [list|scan|allow|block] [package] [--project-root <path>] [--dry-run] [--ndjson]
+  better-core policy [check|init] [--project-root <path>] [--dry-run]
+  better-core lock [generate|verify] [--project-root <path>] [--dry-run]
+  better-core verify --reproducible [--project-root <path>] [--expected <hash>] [--record] [--dry-run]
+  better-core verify --integrity [--project-root <path>]
+  better-core verify --store [--project-root <path>] [--cache-root <path>] [--store-root <path>]
+  better-core workspace [list|graph|changed|run|hoist] [--project-root <path>] [--since <ref>] [--lockfile <path>]
   better-core sbom [--project-root <path>] [--lockfile <path>] [--format cyclonedx|spdx]
-  better-core analyze --root <path> [--graph]
-  better-core scan --root <path>
+  better-core pack [--project-root <path>] [--pack-destination <dir>] [--dry-run]
+  better-core resolve [--project-root <path>] [--lockfile <path>] [--check]
+  better-core add --global <package>[@<range>]... [--prefix <dir>] [--cache-root <path>] [--timeout <ms>] [--dry-run]
+  better-core remove --global <package>... [--prefix <dir>] [--dry-run]
+  better-core list [--global] [--prefix <dir>] [--project-root <path>] [--lockfile <path>]
+  better-core analyze --root <path> [--graph] [--dedupe-report] [--sort name|size] [--baseline <file>] [--max-depth <n>]
+  better-core analyze diff <baseline.json> --root <path>
+  better-core scan --root <path> [--cache <file> | --exclude <glob>... --follow-symlinks --max-depth <n>]
+  better-core materialize --src <path> --dest <path> [--link-strategy auto|hardlink|copy|reflink] [--special-files skip|error] [--force] [--dry-run]
   better-core version
+
+why, outdated, audit and sbom read only the lockfile, and list falls back to it
+when node_modules is missing, so they work before install. license, dedupe,
+analyze, scan and scripts scan need an installed node_modules. prune removes
+packages in node_modules that the lockfile does not list.
+
+why lists the shortest paths from the project to a package, each hop the
+name@version installed where Node would load it from, so two versions of one
+package reached through different parents show up as separate paths. It
+reports at most 10 paths unless --max-paths says otherwise. hoistBlockers
+lists the requests for the package that no single installed version satisfies
+together, which is why it could not be hoisted to one copy.
+
+outdated compares every package in the lockfile with its registry latest.
+outdated --wanted checks only the dependencies package.json declares, against
+the newest release their range allows (latest when it satisfies the range),
+and reports both wanted and latest, like npm outdated. Registry metadata is
+kept under <cache-root>/registry for --cache-ttl seconds (default 3600) and
+used without a request until then; after that it is revalidated with ETag and
+Last-Modified. --no-cache fetches everything afresh and leaves the cache alone.
+
+audit asks OSV (BETTER_OSV_URL for a mirror) which advisories name each
+package, then checks the installed version against every advisory's affected
+ranges and drops those it falls outside. fixed is the first fixed version above
+the installed one. Every finding at or above --min-severity is reported, but
+audit exits 3 only for one at or above --fail-on (default low; --audit-level
+is the npm spelling). Advisories listed in .better-audit-ignore are left out
+and counted in ignored: one GHSA-xxxx (any package) or GHSA-xxxx@name per
+line, optionally followed by until=YYYY-MM-DD after which the advisory is
+reported again. # starts a comment. --source github asks the GitHub Advisory
+Database instead of OSV, with the token in GITHUB_TOKEN or GH_TOKEN
+(BETTER_GITHUB_GRAPHQL_URL for GitHub Enterprise Server); MODERATE is
+reported as MEDIUM from either source.
+
+env check and install compare package.json packageManager with better-core:
+only better@<this version> matches. install warns on a mismatch, or fails with
+--package-manager-strict (npmrc package-manager-strict=true).
+
+install reads package-lock.json (or npm-shrinkwrap.json), falling back to a
+Yarn classic yarn.lock and then pnpm-lock.yaml when the project has no npm
+lockfile. With no lockfile at all, install resolves package.json dependencies
+and devDependencies against the registry (not with --frozen or ci) and writes
+the result to package-lock.json.
+
+--timeout (default: npmrc fetch-timeout, else 300000) bounds how long a single
+registry request may take, including reading the response. Tarball downloads
+that fail with a connection error or HTTP 429, 500, 502, 503 or 504 are
+retried (npmrc fetch-retries, default 3), backing off from
+fetch-retry-mintimeout (200 ms). npmrc maxsockets caps the downloads in flight
+(default: twice the CPU count, at most 16; 0 for no limit).
+
+install --offline never downloads: it fails on the first package whose tarball
+is not in the cache, and packagesCached still counts the ones that are.
+
+install runs each package's preinstall, install and postinstall scripts itself
+(node-gyp rebuild when a binding.gyp has no install script), in the package
+directory with node_modules/.bin on PATH, after the scripts of the packages it
+depends on. Script output goes to stderr; with --capture-script-output it is
+kept instead, and the last 64 KiB of a failed script's stdout and stderr are
+reported under scripts.failures. A script still running after package.json
+better.scriptTimeoutMs (default 300000; 0 for none) is killed along with
+everything it started and reported as timed-out. --script-jobs (default: the
+CPU count) caps how many packages run scripts at once. Only packages in the scripts
+policy's allowed list (or a trusted scope) run their allowed script types;
+blocked packages never run, and others only with --allow-all-scripts. The
+policy is .better-scripts.json, else package.json better.scripts.
+--use-npm-rebuild hands every script to npm rebuild instead; --no-scripts
+skips them.
+
+Registry, tarball and OSV requests go through HTTPS_PROXY / HTTP_PROXY (or
+npmrc https-proxy / proxy when those are unset), except for hosts listed in
+NO_PROXY (or npmrc noproxy). diagnostics reports the proxies in effect and the
+one, if any, the registry is reached through.
+
+dedupe reads <root>/node_modules. With --project-root, --root instead names
+the node_modules directory, wherever it lives, as it does for license; the
+lockfile still comes from the project root.
+
+analyze --dedupe-report derives the savings from the same walk of
+node_modules as the report.
+
+outdated, license, dedupe and doctor print plain text tables instead of JSON
+when stdout is a terminal, or always with --human; --json keeps JSON. Errors
+and exit codes are the same either way.
+
+--json-pretty indents JSON output two spaces per level; --json-compact (the
+default) keeps it on one line. Files better-core writes for itself stay compact,
+and package-lock.json is always indented like npm's.
+
+--ndjson makes license, outdated, audit and scripts scan print one JSON line per
+record (kind better.<command>.package, or .vulnerability and .error), then a
+last line with summary: true carrying the report's other fields.
+
+run with several scripts runs them one at a time and stops at the first
+failure; --parallel starts them all at once. Either way the report lists the
+scripts in the order given.
+
+analyze --baseline also writes the report to a file; analyze diff compares the
+tree against it and reports size, file and package count deltas, added and
+removed packages, and names that became duplicated since.
+
+scan --cache keeps per-directory totals in a file and, next time, reuses them
+for directories whose mtime has not changed. A file rewritten in place does
+not change its directory's mtime, and hardlinks across cached directories
+cannot be deduplicated, so a scan that used the cache reports
+physicalBytesApprox: true.
+
+scan --exclude skips paths matching a gitignore-style glob (*, ? and **); it
+can be repeated. A pattern without a slash matches a name at any depth
+(--exclude .cache), one with a slash is anchored at --root
+(--exclude '**/test/fixtures'). It cannot be combined with --cache.
+
+scan does not follow symlinks: a symlinked directory counts as one small file
+and what it points to is not walked. --follow-symlinks enters them, walking
+each real directory once however many links lead to it, so symlink cycles
+end and nothing is counted twice. analyze always follows them, since
+workspace and pnpm-style node_modules are built from symlinks.
+
+scan and analyze --max-depth <n> do not enter node_modules nested more than n
+deep (1 keeps only the top-level packages) and mark the totals approximate
+(physicalBytesApprox: true) when anything was left out. scan cannot combine
+--follow-symlinks or --max-depth with --cache.
+
+materialize --link-strategy reflink clones files copy-on-write (FICLONE on
+Linux btrfs/XFS, clonefile on macOS APFS) and copies each file the filesystem
+cannot clone; stats.reflinkFallbackCopies counts those. materialize --dry-run
+only scans: stats count the files, directories and symlinks it would create,
+and --dest is left untouched.
+
+workspace hoist reads the lockfile and plans where each package goes: once in
+the root node_modules when the monorepo locks a single version of it, else
+under each workspace that uses it. Root package.json better.nohoist globs over
+<workspace>/<package> keep matching packages nested.
+
+sbom stamps documents with the current UTC time (CycloneDX metadata.timestamp,
+SPDX creationInfo.created); set SOURCE_DATE_EPOCH for a reproducible stamp.
+
+verify --integrity compares installed packages with the hashes install
+recorded; verify --store re-hashes every installed file against the file store
+manifest it was materialized from and lists mismatched, missing and extra
+files. Extra files (lifecycle script output, say) are reported but do not fail.
+
+Exit codes: 0 success, 1 any other failure, 2 bad arguments, 3 a check found
+problems (audit, license, policy, doctor, env check, engine-strict,
+package-manager-strict, lock verify, resolve --check, ci out of sync), 4 the
+registry or OSV could not be reached or refused a request, 5 an integrity
+mismatch (verify, cache verify, a downloaded tarball). run and exec exit with the script's own code.
 "
     );
 }
 
+/// Output of a list-style report: one JSON document, or with `--ndjson` one
+/// line per record (`"kind": "<kind>.<record>"`) and a final line holding the
+/// remaining fields, `"summary": true` and the summary object's fields inline.
+struct ReportEmitter {
+    kind: &'static str,
+    ndjson: bool,
+    records: String,
+    w: JsonWriter,
+}
+
+impl ReportEmitter {
+    fn new(kind: &'static str, ok: bool, ndjson: bool, indent: usize) -> Self {
+        let mut w = if ndjson { JsonWriter::compact() } else { JsonWriter::with_indent(indent) };
+        w.begin_object();
+        w.key("ok"); w.value_bool(ok);
+        w.key("kind"); w.value_string(kind);
+        if ndjson {
+            w.key("summary"); w.value_bool(true);
+        }
+        Self { kind, ndjson, records: String::new(), w }
+    }
+
+    /// Top-level fields other than records and summary.
+    fn fields(&mut self, write: impl FnOnce(&mut JsonWriter)) {
+        write(&mut self.w);
+    }
+
+    /// The `key` array, or one `<kind>.<record>` line per item; `write` emits
+    /// an item's fields.
+    fn records<T>(&mut self, key: &str, record: &str, items: &[T], mut write: impl FnMut(&mut JsonWriter, &T)) {
+        if !self.ndjson {
+            self.w.key(key); self.w.begin_array();
+            for item in items {
+                self.w.begin_object();
+                write(&mut self.w, item);
+                self.w.end_object();
+            }
+            self.w.end_array();
+            return;
+        }
+        for item in items {
+            let mut line = JsonWriter::compact();
+            line.begin_object();
+            line.key("kind"); line.value_string(&format!("{}.{}", self.kind, record));
+            write(&mut line, item);
+            line.end_object();
+            self.records.push_str(&line.finish());
+            self.records.push('\n');
+        }
+    }
+
+    fn summary(&mut self, write: impl FnOnce(&mut JsonWriter)) {
+        if self.ndjson {
+            write(&mut self.w);
+        } else {
+            self.w.key("summary"); self.w.begin_object();
+            write(&mut self.w);
+            self.w.end_object();
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.w.end_object(); self.w.out.push('\n');
+        self.records + &self.w.finish()
+    }
+}
+
+fn write_installed_packages(w: &mut JsonWriter, packages: &[InstalledPackage]) {
+    w.key("packages"); w.begin_array();
+    for pkg in packages {
+        w.begin_object();
+        w.key("name"); w.value_string(&pkg.name);
+        w.key("version"); w.value_string(&pkg.version);
+        w.key("bins"); w.begin_array();
+        for bin in &pkg.bins {
+            w.value_string(bin);
+        }
+        w.end_array();
+        w.end_object();
+    }
+    w.end_array();
+}
+
+fn write_lockfile_problems(w: &mut JsonWriter, problems: &[LockfileProblem]) {
+    w.key("problems"); w.begin_array();
+    for problem in problems {
+        w.begin_object();
+        w.key("path"); w.value_string(&problem.rel_path);
+        w.key("reason"); w.value_string(&problem.reason);
+        w.end_object();
+    }
+    w.end_array();
+}
+
+fn global_layout(prefix: Option<PathBuf>) -> GlobalLayout {
+    let prefix = prefix.unwrap_or_else(|| default_global_prefix(&parse_npmrc(std::path::Path::new("."))));
+    GlobalLayout::new(&prefix)
+}
+
+/// Spaces per level for JSON on stdout: two after `--json-pretty`, none
+/// after `--json-compact` (the default), the last one winning. Arguments
+/// after `--` belong to scripts.
+fn json_indent() -> usize {
+    let mut indent = 0;
+    for arg in std::env::args().skip(1).take_while(|a| a != "--") {
+        match arg.as_str() {
+            "--json-pretty" => indent = 2,
+            "--json-compact" => indent = 0,
+            _ => {}
+        }
+    }
+    indent
+}
+
 fn main() {
+    let indent = json_indent();
     match parse_args() {
         Command::Version => {
             println!("{VERSION}");
         }
         Command::Help { error } => {
             print_help(error);
-            std::process::exit(2);
+            std::process::exit(EXIT_USAGE);
         }
-        Command::Scan { root } => {
+        Command::Scan { root, cache, exclude, follow_symlinks, max_depth } => {
             let mut seen: HashSet<(u64, u64)> = HashSet::new();
-            match scan_tree(&root, &HashSet::new(), Some(&mut seen)) {
+            let scanned = match &cache {
+                Some(cache) => scan_tree_cached(&root, cache),
+                None => ExcludeMatcher::default().with_patterns(&exclude).and_then(|exclude| {
+                    scan_tree(&root, &WalkOptions { exclude, follow_symlinks, max_depth }, Some(&mut seen))
+                }),
+            };
+            match scanned {
                 Ok(agg) => {
-                    print!("{}", write_scan_json(&root, &agg, true, None));
+                    print!("{}", write_scan_json(&root, &agg, true, None, indent));
                 }
                 Err(e) => {
                     let agg = ScanAgg::default();
-                    print!("{}", write_scan_json(&root, &agg, false, Some(e)));
-                    std::process::exit(1);
+                    print!("{}", write_scan_json(&root, &agg, false, Some(e), indent));
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
-        Command::Materialize { src, dest, link_strategy, jobs, profile } => {
+        Command::Materialize { src, dest, link_strategy, jobs, profile, special_files, force, dry_run } => {
             let started = Instant::now();
-            match materialize_tree(&src, &dest, link_strategy, jobs, profile) {
+            // Reuse whatever is already correct at the destination unless --force
+            // asks for a clean rebuild.
+            let cleared = if force && !dry_run { remove_path_if_exists(&dest) } else { Ok(()) };
+            let options = MaterializeOptions { special_files, incremental: !force, dry_run, ..MaterializeOptions::default() };
+            match cleared.and_then(|()| materialize_tree(&src, &dest, link_strategy, jobs, profile, options)) {
                 Ok(report) => {
+                    for path in &report.skipped_special {
+                        eprintln!("[better] warning: skipped special file {}", path.display());
+                    }
+                    for path in &report.broken_symlinks {
+                        eprintln!("[better] warning: dangling symlink {}", path.display());
+                    }
                     let duration_ms = started.elapsed().as_millis() as u64;
                     let effective_jobs = match profile {
                         MaterializeProfile::Auto => jobs,
                         MaterializeProfile::IoHeavy => (jobs * 2).max(4),
                         MaterializeProfile::SmallFiles => (jobs * 3).max(8),
                     };
-                    print!("{}", write_materialize_json(&src, &dest, link_strategy, jobs, profile, effective_jobs, true, None, duration_ms, &report.stats, &report.phases));
+                    print!("{}", write_materialize_json(&src, &dest, link_strategy, Some((report.strategy, report.strategy_reason)), jobs, profile, effective_jobs, dry_run, true, None, duration_ms, &report.stats, &report.phases, &report.warnings, indent));
                 }
                 Err(reason) => {
                     let duration_ms = started.elapsed().as_millis() as u64;
@@ -558,17 +1311,32 @@ fn main() {
                         MaterializeProfile::IoHeavy => (jobs * 2).max(4),
                         MaterializeProfile::SmallFiles => (jobs * 3).max(8),
                     };
-                    print!("{}", write_materialize_json(&src, &dest, link_strategy, jobs, profile, effective_jobs, false, Some(reason), duration_ms, &MaterializeStats::default(), &PhaseDurations::default()));
-                    std::process::exit(1);
+                    print!("{}", write_materialize_json(&src, &dest, link_strategy, None, jobs, profile, effective_jobs, dry_run, false, Some(reason), duration_ms, &MaterializeStats::default(), &PhaseDurations::default(), &[], indent));
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
-        Command::Analyze { root, graph } => match analyze(&root, graph) {
-            Ok(report) => {
-                print!("{}", write_analyze_json(&root, &report.totals, &report.node_modules_dir, &report.packages, &report.duplicates, &report.depth, graph));
+        Command::Analyze { root, graph, dedupe_report, sort, baseline, max_depth } => match analyze(&root, graph, max_depth) {
+            Ok(mut report) => {
+                if let Some(key) = sort { report.sort_packages(key); }
+                let dedupe = if dedupe_report { Some(dedupe_from_analyze(&report, Some(&default_lockfile(&root)))) } else { None };
+                let json = write_analyze_json(&root, &report.totals, &report.node_modules_dir, &report.packages, &report.duplicates, &report.depth, graph.then_some(report.edges.as_slice()), dedupe.as_ref(), &report.warnings, indent);
+                if let Some(path) = baseline {
+                    if let Err(e) = std::fs::write(&path, &json) {
+                        let mut w = JsonWriter::with_indent(indent);
+                        w.begin_object();
+                        w.key("ok"); w.value_bool(false);
+                        w.key("kind"); w.value_string("better.analyze.report");
+                        w.key("reason"); w.value_string(&format!("failed to write baseline {}: {}", path.display(), e));
+                        w.end_object(); w.out.push('\n');
+                        print!("{}", w.finish());
+                        std::process::exit(EXIT_FAILURE);
+                    }
+                }
+                print!("{}", json);
             }
             Err(reason) => {
-                let mut w = JsonWriter::new();
+                let mut w = JsonWriter::with_indent(indent);
                 w.begin_object();
                 w.key("ok"); w.value_bool(false);
                 w.key("kind"); w.value_string("better.analyze.report");
@@ -576,43 +1344,244 @@ fn main() {
                 w.end_object();
                 w.out.push('\n');
                 print!("{}", w.finish());
-                std::process::exit(1);
+                std::process::exit(EXIT_FAILURE);
             }
         },
-        Command::Install { lockfile, project_root, cache_root, store_root, link_strategy, jobs: _, scripts, dedup } => {
+        Command::AnalyzeDiff { root, baseline } => {
+            let diff = read_analyze_baseline(&baseline)
+                .and_then(|before| analyze(&root, false, None).map(|now| diff_analyze(&before, &now)));
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match diff {
+                Ok(diff) => {
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.analyze.diff");
+                    w.key("projectRoot"); w.value_string(&root.to_string_lossy());
+                    w.key("baseline"); w.value_string(&baseline.to_string_lossy());
+                    w.key("nodeModules"); w.begin_object();
+                    let totals = [
+                        ("logicalBytes", diff.baseline.logical, diff.current.logical),
+                        ("physicalBytes", diff.baseline.physical, diff.current.physical),
+                        ("fileCount", diff.baseline.file_count, diff.current.file_count),
+                        ("packageCount", diff.baseline.package_count, diff.current.package_count),
+                    ];
+                    for (key, before, now) in totals {
+                        w.key(key); w.begin_object();
+                        w.key("baseline"); w.value_u64(before);
+                        w.key("current"); w.value_u64(now);
+                        w.key("delta"); w.value_i64(now as i64 - before as i64);
+                        w.end_object();
+                    }
+                    w.end_object();
+                    for (key, packages) in [("addedPackages", &diff.added), ("removedPackages", &diff.removed)] {
+                        w.key(key); w.begin_array();
+                        for p in packages {
+                            w.begin_object();
+                            w.key("key"); w.value_string(&p.key);
+                            w.key("name"); w.value_string(&p.name);
+                            w.key("version"); w.value_string(&p.version);
+                            w.key("logicalBytes"); w.value_u64(p.logical);
+                            w.key("fileCount"); w.value_u64(p.file_count);
+                            w.end_object();
+                        }
+                        w.end_array();
+                    }
+                    w.key("newDuplicates"); w.begin_array();
+                    for d in &diff.new_duplicates {
+                        w.begin_object();
+                        w.key("name"); w.value_string(&d.name);
+                        w.key("versions"); w.begin_array();
+                        for v in &d.versions { w.value_string(v); }
+                        w.end_array();
+                        w.key("count"); w.value_u64(d.count);
+                        w.end_object();
+                    }
+                    w.end_array();
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.analyze.diff");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+        Command::Install { lockfile, project_root, cache_root, store_root, link_strategy, jobs: _, scripts, npm_rebuild, allow_all_scripts, capture_script_output, script_jobs, dedup, staged, dry_run, verify_store, frozen, keep_going, engine_strict, package_manager_strict, offline, ci, timeout } => {
             let started = Instant::now();
-            let npmrc = parse_npmrc(&project_root);
+            let mut npmrc = parse_npmrc(&project_root);
+            if let Some(ms) = timeout {
+                npmrc.fetch_timeout_ms = ms;
+            }
+            let kind = if ci { "better.ci.report" } else { "better.install.report" };
+
+            // Engine check runs before any network or disk work so an unsupported
+            // runtime fails fast instead of midway through lifecycle scripts.
+            if engine_strict || npmrc.engine_strict {
+                let unmet: Vec<_> = match env_check(&project_root) {
+                    Ok(result) => result.checks.into_iter().filter(|c| !c.satisfied).collect(),
+                    Err(_) => Vec::new(),
+                };
+                if !unmet.is_empty() {
+                    let details: Vec<String> = unmet
+                        .iter()
+                        .map(|c| format!("{} {} (wanted {})", c.tool, c.current, c.required))
+                        .collect();
+                    let mut w = JsonWriter::with_indent(indent);
+                    w.begin_object();
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string(kind);
+                    w.key("reason"); w.value_string(&format!("Unsupported engine: {}", details.join(", ")));
+                    w.key("engines"); w.begin_array();
+                    for entry in &unmet {
+                        w.begin_object();
+                        w.key("tool"); w.value_string(&entry.tool);
+                        w.key("current"); w.value_string(&entry.current);
+                        w.key("required"); w.value_string(&entry.required);
+                        w.key("satisfied"); w.value_bool(false);
+                        w.end_object();
+                    }
+                    w.end_array();
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_VIOLATION);
+                }
+            }
+
+            // A mismatched packageManager pin only warns unless strict
+            let package_manager = package_manager_check(&project_root).filter(|entry| !entry.satisfied);
+            if let Some(entry) = package_manager.as_ref().filter(|_| package_manager_strict || npmrc.package_manager_strict) {
+                let mut w = JsonWriter::with_indent(indent);
+                w.begin_object();
+                w.key("ok"); w.value_bool(false);
+                w.key("kind"); w.value_string(kind);
+                w.key("reason"); w.value_string(&format!("packageManager pins {}, but this is {}", entry.required, entry.current));
+                w.end_object(); w.out.push('\n');
+                print!("{}", w.finish());
+                std::process::exit(EXIT_VIOLATION);
+            }
+
+            // ci never writes the lockfile, so it must already match package.json
+            if ci {
+                let checked = if lockfile.exists() {
+                    lockfile_sync_problems(&project_root, &lockfile)
+                } else {
+                    Err(format!("ci requires an existing lockfile: {} not found", lockfile.display()))
+                };
+                let out_of_sync = matches!(&checked, Ok(problems) if !problems.is_empty());
+                let (reason, problems) = match checked {
+                    Ok(problems) if problems.is_empty() => (None, problems),
+                    Ok(problems) => (Some(format!("{} is out of sync with package.json", lockfile.display())), problems),
+                    Err(reason) => (Some(reason), Vec::new()),
+                };
+                if let Some(reason) = reason {
+                    let mut w = JsonWriter::with_indent(indent);
+                    w.begin_object();
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string(kind);
+                    w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
+                    w.key("reason"); w.value_string(&reason);
+                    w.key("problems"); w.begin_array();
+                    for problem in &problems { w.value_string(problem); }
+                    w.end_array();
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(if out_of_sync { EXIT_VIOLATION } else { EXIT_FAILURE });
+                }
+            }
+
+            // Without a lockfile, package.json is resolved against the registry;
+            // --frozen (and so ci) still insists on the lockfile
+            let from_manifest = !lockfile.exists() && !frozen;
+            let resolved_from = if from_manifest { "package.json" } else { "lockfile" };
+
+            if dry_run {
+                // Plain resolve: a dry run must not populate the resolve cache either
+                let mut w = JsonWriter::with_indent(indent);
+                w.begin_object();
+                let resolved = if from_manifest {
+                    resolve_from_manifest(&project_root, &npmrc.default_registry)
+                } else {
+                    resolve_from_lockfile(&lockfile).map_err(CoreError::from)
+                };
+                match resolved {
+                    Ok(resolved) => {
+                        let mut plan = FsPlan::new(true);
+                        plan_install(&resolved.packages, &cache_root, &project_root, staged, &mut plan);
+                        w.key("ok"); w.value_bool(true);
+                        w.key("kind"); w.value_string(kind);
+                        w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
+                        w.key("resolvedFrom"); w.value_string(resolved_from);
+                        w.key("packages"); w.value_u64(resolved.packages.len() as u64);
+                        write_plan_json(&mut w, &plan);
+                        w.end_object(); w.out.push('\n');
+                        print!("{}", w.finish());
+                    }
+                    Err(reason) => {
+                        w.key("ok"); w.value_bool(false);
+                        w.key("kind"); w.value_string(kind);
+                        w.key("reason"); w.value_string(&reason.message);
+                        w.end_object(); w.out.push('\n');
+                        print!("{}", w.finish());
+                        std::process::exit(failure_exit_code(reason.kind));
+                    }
+                }
+                return;
+            }
 
             // Step 1: Resolve
             let t_resolve = Instant::now();
-            let resolve_result = match resolve_from_lockfile(&lockfile) {
+            let resolved = if from_manifest {
+                resolve_from_manifest(&project_root, &npmrc.default_registry).map(|r| (r, false))
+            } else {
+                resolve_from_lockfile_cached(&lockfile, &cache_root).map_err(CoreError::from)
+            };
+            let (resolve_result, resolve_cache_hit) = match resolved {
                 Ok(r) => r,
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
-                    w.key("kind"); w.value_string("better.install.report");
-                    w.key("reason"); w.value_string(&reason);
+                    w.key("kind"); w.value_string(kind);
+                    w.key("reason"); w.value_string(&reason.message);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(failure_exit_code(reason.kind));
                 }
             };
             let phase_resolve_ms = t_resolve.elapsed().as_millis() as u64;
 
+            // Report every malformed entry up front rather than the first one fetch hits
+            let problems = validate_resolved_packages(&resolve_result.packages);
+            if !problems.is_empty() {
+                let mut w = JsonWriter::with_indent(indent);
+                w.begin_object();
+                w.key("ok"); w.value_bool(false);
+                w.key("kind"); w.value_string(kind);
+                w.key("reason"); w.value_string(&format!("{} lockfile entries are invalid", problems.len()));
+                write_lockfile_problems(&mut w, &problems);
+                w.end_object(); w.out.push('\n');
+                print!("{}", w.finish());
+                std::process::exit(EXIT_FAILURE);
+            }
+
             // Step 2: Fetch
             let t_fetch = Instant::now();
-            let fetch_result = match fetch_packages(&resolve_result.packages, &cache_root, Some(&npmrc)) {
+            let fetch_options = FetchOptions { offline, ..FetchOptions::from_npmrc(Some(&npmrc)) };
+            let fetch_result = match fetch_packages_with_progress(&resolve_result.packages, &cache_root, Some(&npmrc), &fetch_options, |_| {}) {
                 Ok(r) => r,
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
-                    w.key("kind"); w.value_string("better.install.report");
-                    w.key("reason"); w.value_string(&reason);
+                    w.key("kind"); w.value_string(kind);
+                    w.key("reason"); w.value_string(&reason.message);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(failure_exit_code(reason.kind));
                 }
             };
             let phase_fetch_ms = t_fetch.elapsed().as_millis() as u64;
@@ -621,7 +1590,17 @@ fn main() {
             let t_mat = Instant::now();
             let layout = CasLayout::new(&cache_root);
             let file_cas_root = store_root.unwrap_or_else(|| cache_root.join("file-store"));
-            let node_modules = project_root.join("node_modules");
+            sweep_stale_tmp_files(&file_store_tmp_dir(&file_cas_root), std::time::Duration::from_secs(STALE_TMP_MAX_AGE_SECS));
+            // With --staged, everything up to and including bin links is built in
+            // node_modules.better-tmp and swapped into place only once it all succeeded.
+            let final_node_modules = project_root.join("node_modules");
+            let node_modules = if staged {
+                let staging = staging_dir_for(&final_node_modules);
+                let _ = remove_path_if_exists(&staging);
+                staging
+            } else {
+                final_node_modules.clone()
+            };
             let _ = std::fs::create_dir_all(&node_modules);
 
             let total_files = std::sync::atomic::AtomicU64::new(0);
@@ -630,7 +1609,9 @@ fn main() {
             let cloned = std::sync::atomic::AtomicU64::new(0);
             let cas_linked = std::sync::atomic::AtomicU64::new(0);
             let cas_copied = std::sync::atomic::AtomicU64::new(0);
+            let cas_reingested = std::sync::atomic::AtomicU64::new(0);
             let fallback_materialized = std::sync::atomic::AtomicU64::new(0);
+            let materialize_warnings: std::sync::Mutex<Vec<Warning>> = std::sync::Mutex::new(Vec::new());
 
             for pkg in &resolve_result.packages {
                 let dest_path = if pkg.rel_path.starts_with("node_modules/") {
@@ -644,10 +1625,60 @@ fn main() {
             }
 
             use rayon::prelude::*;
-            let materialize_error: std::sync::Mutex<Option<String>> = std::sync::Mutex::new(None);
+            // (name, relPath, reason) per failed package. Without --keep-going the
+            // first failure stops packages that have not started yet.
+            let failures: std::sync::Mutex<Vec<(String, String, String)>> = std::sync::Mutex::new(Vec::new());
+            let fail = |pkg: &ResolvedPackage, reason: String| {
+                if let Ok(mut guard) = failures.lock() {
+                    guard.push((pkg.name.clone(), pkg.rel_path.clone(), reason));
+                }
+            };
+            let pkg_failed = |pkg: &ResolvedPackage| {
+                failures.lock().map(|g| g.iter().any(|(_, rel, _)| *rel == pkg.rel_path)).unwrap_or(false)
+            };
+
+            // With --verify-store a blob that fails re-hashing fails the package;
+            // other ingest errors fall through to a plain materialize.
+            let ingest = |pkg: &ResolvedPackage, algo: &str, hex: &str, src_dir: &std::path::Path| -> bool {
+                match ingest_to_file_cas(&file_cas_root, algo, hex, src_dir, verify_store) {
+                    Err(reason) if verify_store => {
+                        fail(pkg, reason);
+                        false
+                    }
+                    _ => true,
+                }
+            };
+
+            // Blobs missing from the store fail a --frozen install; otherwise the
+            // package is re-ingested from its unpacked tree and materialized again.
+            let materialize_cas = |pkg: &ResolvedPackage, algo: &str, hex: &str, src_dir: &std::path::Path, dest: &std::path::Path| {
+                let result = materialize_from_file_cas(&file_cas_root, algo, hex, dest, link_strategy).ok()?;
+                if result.missing_blobs.is_empty() {
+                    return Some(result);
+                }
+                if frozen {
+                    let shown: Vec<&str> = result.missing_blobs.iter().take(5).map(String::as_str).collect();
+                    let more = result.missing_blobs.len() - shown.len();
+                    let reason = format!(
+                        "Store is missing {} blob(s) for {}: {}{}",
+                        result.missing_blobs.len(),
+                        pkg.name,
+                        shown.join(", "),
+                        if more > 0 { format!(" (+{} more)", more) } else { String::new() }
+                    );
+                    fail(pkg, reason);
+                    return None;
+                }
+                cas_reingested.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                remove_file_cas_manifest(&file_cas_root, algo, hex).ok()?;
+                if !ingest(pkg, algo, hex, src_dir) { return None; }
+                materialize_from_file_cas(&file_cas_root, algo, hex, dest, link_strategy).ok()
+            };
+
+            let stop = || !keep_going && failures.lock().map(|g| !g.is_empty()).unwrap_or(false);
 
             resolve_result.packages.par_iter().for_each(|pkg| {
-                if materialize_error.lock().ok().and_then(|g| g.as_ref().cloned()).is_some() { return; }
+                if stop() { return; }
                 let (algo, hex) = match cas_key_from_integrity(&pkg.integrity) { Some(k) => k, None => return };
                 let unpacked = unpacked_path(&layout, &algo, &hex);
                 let src_dir = unpacked.join("package");
@@ -659,8 +1690,8 @@ fn main() {
                 };
 
                 if dedup {
-                    let _ = ingest_to_file_cas(&file_cas_root, &algo, &hex, &src_dir);
-                    if let Ok(result) = materialize_from_file_cas(&file_cas_root, &algo, &hex, &dest_path, link_strategy) {
+                    if !ingest(pkg, &algo, &hex, &src_dir) { return; }
+                    if let Some(result) = materialize_cas(pkg, &algo, &hex, &src_dir, &dest_path) {
                         if result.ok && result.files > 0 {
                             total_files.fetch_add(result.files, std::sync::atomic::Ordering::Relaxed);
                             cas_linked.fetch_add(result.linked, std::sync::atomic::Ordering::Relaxed);
@@ -669,6 +1700,7 @@ fn main() {
                             return;
                         }
                     }
+                    if pkg_failed(pkg) { return; }
                     if try_clonefile_dir(&src_dir, &dest_path) {
                         cloned.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                         return;
@@ -676,11 +1708,11 @@ fn main() {
                 } else {
                     if try_clonefile_dir(&src_dir, &dest_path) {
                         cloned.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                        let _ = ingest_to_file_cas(&file_cas_root, &algo, &hex, &src_dir);
+                        ingest(pkg, &algo, &hex, &src_dir);
                         return;
                     }
-                    let _ = ingest_to_file_cas(&file_cas_root, &algo, &hex, &src_dir);
-                    if let Ok(result) = materialize_from_file_cas(&file_cas_root, &algo, &hex, &dest_path, link_strategy) {
+                    if !ingest(pkg, &algo, &hex, &src_dir) { return; }
+                    if let Some(result) = materialize_cas(pkg, &algo, &hex, &src_dir, &dest_path) {
                         if result.ok && result.files > 0 {
                             total_files.fetch_add(result.files, std::sync::atomic::Ordering::Relaxed);
                             cas_linked.fetch_add(result.linked, std::sync::atomic::Ordering::Relaxed);
@@ -689,32 +1721,51 @@ fn main() {
                             return;
                         }
                     }
+                    if pkg_failed(pkg) { return; }
                 }
 
-                match materialize_tree(&src_dir, &dest_path, link_strategy, 4, MaterializeProfile::Auto) {
+                match materialize_tree(&src_dir, &dest_path, link_strategy, 4, MaterializeProfile::Auto, MaterializeOptions::default()) {
                     Ok(report) => {
                         total_files.fetch_add(report.stats.files, std::sync::atomic::Ordering::Relaxed);
                         total_dirs.fetch_add(report.stats.directories, std::sync::atomic::Ordering::Relaxed);
                         total_symlinks.fetch_add(report.stats.symlinks, std::sync::atomic::Ordering::Relaxed);
                         fallback_materialized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-                    }
-                    Err(reason) => {
-                        if let Ok(mut guard) = materialize_error.lock() {
-                            if guard.is_none() { *guard = Some(format!("Failed to materialize {}: {}", pkg.name, reason)); }
+                        if !report.warnings.is_empty() {
+                            if let Ok(mut guard) = materialize_warnings.lock() { guard.extend(report.warnings); }
                         }
                     }
+                    Err(reason) => fail(pkg, format!("Failed to materialize {}: {}", pkg.name, reason)),
                 }
             });
 
-            if let Some(reason) = materialize_error.lock().ok().and_then(|g| g.clone()) {
-                let mut w = JsonWriter::new();
+            let mut failures = failures.into_inner().unwrap_or_default();
+            if !failures.is_empty() {
+                if staged {
+                    let _ = remove_path_if_exists(&node_modules);
+                }
+                failures.sort_by(|a, b| a.1.cmp(&b.1));
+                let reason = if failures.len() == 1 {
+                    failures[0].2.clone()
+                } else {
+                    format!("{} packages failed to materialize", failures.len())
+                };
+                let mut w = JsonWriter::with_indent(indent);
                 w.begin_object();
                 w.key("ok"); w.value_bool(false);
-                w.key("kind"); w.value_string("better.install.report");
+                w.key("kind"); w.value_string(kind);
                 w.key("reason"); w.value_string(&reason);
+                w.key("failures"); w.begin_array();
+                for (name, rel_path, reason) in &failures {
+                    w.begin_object();
+                    w.key("name"); w.value_string(name);
+                    w.key("path"); w.value_string(rel_path);
+                    w.key("reason"); w.value_string(reason);
+                    w.end_object();
+                }
+                w.end_array();
                 w.end_object(); w.out.push('\n');
                 print!("{}", w.finish());
-                std::process::exit(1);
+                std::process::exit(EXIT_FAILURE);
             }
             let phase_materialize_ms = t_mat.elapsed().as_millis() as u64;
 
@@ -723,16 +1774,56 @@ fn main() {
             let bin_result = create_bin_links(&node_modules, &resolve_result.packages).unwrap_or_default();
             let phase_binlinks_ms = t_bins.elapsed().as_millis() as u64;
 
+            let swap_result = if staged {
+                match swap_staged_node_modules(&node_modules, &final_node_modules) {
+                    Ok(r) => Some(r),
+                    Err(reason) => {
+                        let _ = remove_path_if_exists(&node_modules);
+                        let mut w = JsonWriter::with_indent(indent);
+                        w.begin_object();
+                        w.key("ok"); w.value_bool(false);
+                        w.key("kind"); w.value_string(kind);
+                        w.key("reason"); w.value_string(&reason);
+                        w.end_object(); w.out.push('\n');
+                        print!("{}", w.finish());
+                        std::process::exit(EXIT_FAILURE);
+                    }
+                }
+            } else {
+                None
+            };
+            let node_modules = final_node_modules;
+
             // Step 5: Lifecycle scripts
             let t_scripts = Instant::now();
             let scripts_result = if scripts {
                 let detection = detect_lifecycle_scripts(&node_modules, &resolve_result.packages);
-                run_lifecycle_scripts(&project_root, &detection)
+                if npm_rebuild {
+                    run_lifecycle_scripts_npm_rebuild(&project_root, &detection)
+                } else {
+                    let defaults = LifecycleRunOptions::default();
+                    let options = LifecycleRunOptions {
+                        jobs: script_jobs.unwrap_or(defaults.jobs),
+                        policy: Some(load_script_policy(&project_root)),
+                        allow_all: allow_all_scripts,
+                        capture_output: capture_script_output,
+                        timeout: script_timeout(&project_root),
+                    };
+                    run_lifecycle_scripts(&project_root, &detection, &options)
+                }
             } else {
                 LifecycleRunResult { skipped_reason: Some("disabled".into()), ..Default::default() }
             };
             let phase_scripts_ms = t_scripts.elapsed().as_millis() as u64;
 
+            // Step 6: Record what was installed, for verify --integrity
+            let t_integrity = Instant::now();
+            let integrity = record_installed_integrity(&project_root, &resolve_result.packages);
+            let phase_integrity_ms = t_integrity.elapsed().as_millis() as u64;
+
+            // A first install from package.json leaves a lockfile behind
+            let lockfile_written = from_manifest.then(|| write_npm_lockfile(&lockfile, &resolve_result, &project_root));
+
             let duration_ms = started.elapsed().as_millis() as u64;
             let total_files = total_files.load(std::sync::atomic::Ordering::Relaxed);
             let total_dirs = total_dirs.load(std::sync::atomic::Ordering::Relaxed);
@@ -740,19 +1831,56 @@ fn main() {
             let cloned = cloned.load(std::sync::atomic::Ordering::Relaxed);
             let cas_linked = cas_linked.load(std::sync::atomic::Ordering::Relaxed);
             let cas_copied = cas_copied.load(std::sync::atomic::Ordering::Relaxed);
+            let cas_reingested = cas_reingested.load(std::sync::atomic::Ordering::Relaxed);
             let fallback_materialized = fallback_materialized.load(std::sync::atomic::Ordering::Relaxed);
+            let mut warnings = lockfile_duplicate_keys(&lockfile);
+            if let Some(entry) = &package_manager {
+                let message = format!("packageManager pins {}, but this is {}", entry.required, entry.current);
+                warnings.push(Warning::new("package-manager-mismatch", message, Some(project_root.join("package.json").to_string_lossy().to_string())));
+            }
+            if let Some(Err(reason)) = &lockfile_written {
+                warnings.push(Warning::new("lockfile-write-failed", reason.clone(), Some(lockfile.to_string_lossy().to_string())));
+            }
+            warnings.extend(materialize_warnings.into_inner().unwrap_or_default());
+            if bin_result.links_failed > 0 {
+                warnings.push(Warning::new("bin-link-failed", format!("{} bin link(s) could not be created", bin_result.links_failed), None));
+            }
+            let unlisted = scripts_result.blocked.iter().filter(|b| b.reason == "package is not in allowed list").count();
+            if unlisted > 0 {
+                let message = format!("{} lifecycle script(s) skipped: their packages are not in the scripts allowed list (better scripts allow <package>, or install --allow-all-scripts)", unlisted);
+                warnings.push(Warning::new("lifecycle-scripts-not-allowed", message, None));
+            }
+            for failure in &scripts_result.failures {
+                let status = match (failure.outcome, failure.exit_code) {
+                    (LifecycleScriptOutcome::TimedOut, _) => "timed out and was killed".to_string(),
+                    (_, Some(code)) => format!("exited with {}", code),
+                    (_, None) => "did not finish".to_string(),
+                };
+                let message = format!("{} script of {} {}", failure.script_name, failure.package_name, status);
+                warnings.push(Warning::new("lifecycle-script-failed", message, None));
+            }
+            for conflict in &bin_result.conflicts {
+                let message = format!("bin {} is declared by {} and {}; linked {}", conflict.name, conflict.winner, conflict.loser, conflict.winner);
+                warnings.push(Warning::new("bin-conflict", message, None));
+            }
+            if let Err(reason) = &integrity {
+                warnings.push(Warning::new("integrity-record-failed", reason.clone(), None));
+            }
 
-            let mut w = JsonWriter::new();
+            let mut w = JsonWriter::with_indent(indent);
             w.begin_object();
             w.key("ok"); w.value_bool(true);
-            w.key("kind"); w.value_string("better.install.report");
+            w.key("kind"); w.value_string(kind);
             w.key("schemaVersion"); w.value_u64(2);
             w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
+            w.key("resolvedFrom"); w.value_string(resolved_from);
+            w.key("lockfileWritten"); w.value_bool(matches!(lockfile_written, Some(Ok(()))));
             w.key("projectRoot"); w.value_string(&project_root.to_string_lossy());
             w.key("cacheRoot"); w.value_string(&cache_root.to_string_lossy());
             w.key("durationMs"); w.value_u64(duration_ms);
             w.key("stats"); w.begin_object();
             w.key("packagesResolved"); w.value_u64(resolve_result.packages.len() as u64);
+            w.key("resolveCacheHit"); w.value_bool(resolve_cache_hit);
             w.key("packagesFetched"); w.value_u64(fetch_result.packages_fetched);
             w.key("packagesCached"); w.value_u64(fetch_result.packages_cached);
             w.key("bytesDownloaded"); w.value_u64(fetch_result.bytes_downloaded);
@@ -762,52 +1890,102 @@ fn main() {
             w.key("cloned"); w.value_u64(cloned);
             w.key("casLinked"); w.value_u64(cas_linked);
             w.key("casCopied"); w.value_u64(cas_copied);
+            w.key("casReingested"); w.value_u64(cas_reingested);
             w.key("fallbackMaterialized"); w.value_u64(fallback_materialized);
             w.end_object();
+            if let Some(swap) = &swap_result {
+                w.key("staged"); w.begin_object();
+                w.key("atomic"); w.value_bool(swap.atomic);
+                w.key("replacedExisting"); w.value_bool(swap.replaced_existing);
+                w.end_object();
+            }
             w.key("binLinks"); w.begin_object();
             w.key("created"); w.value_u64(bin_result.links_created);
             w.key("failed"); w.value_u64(bin_result.links_failed);
+            w.key("skipped"); w.value_u64(bin_result.links_skipped);
+            w.key("conflicts"); w.begin_array();
+            for conflict in &bin_result.conflicts {
+                w.begin_object();
+                w.key("name"); w.value_string(&conflict.name);
+                w.key("winner"); w.value_string(&conflict.winner);
+                w.key("loser"); w.value_string(&conflict.loser);
+                w.end_object();
+            }
+            w.end_array();
             w.end_object();
             w.key("scripts"); w.begin_object();
             w.key("run"); w.value_u64(scripts_result.scripts_run);
             w.key("succeeded"); w.value_u64(scripts_result.scripts_succeeded);
             w.key("failed"); w.value_u64(scripts_result.scripts_failed);
+            w.key("timedOut"); w.value_u64(scripts_result.scripts_timed_out);
             if let Some(reason) = &scripts_result.skipped_reason { w.key("skippedReason"); w.value_string(reason); }
             if let Some(code) = scripts_result.rebuild_exit_code { w.key("rebuildExitCode"); w.value_i64(code as i64); }
+            w.key("allowed"); w.value_u64(scripts_result.allowed_scripts);
+            w.key("blocked"); w.value_u64(scripts_result.blocked_scripts);
+            w.key("blockedScripts"); w.begin_array();
+            for block in &scripts_result.blocked {
+                w.begin_object();
+                w.key("package"); w.value_string(&block.package_name);
+                w.key("script"); w.value_string(&block.script_name);
+                w.key("reason"); w.value_string(&block.reason);
+                w.end_object();
+            }
+            w.end_array();
+            w.key("failures"); w.begin_array();
+            for failure in &scripts_result.failures {
+                w.begin_object();
+                w.key("package"); w.value_string(&failure.package_name);
+                w.key("script"); w.value_string(&failure.script_name);
+                w.key("outcome"); w.value_string(failure.outcome.as_str());
+                w.key("exitCode");
+                match failure.exit_code { Some(code) => w.value_i64(code as i64), None => w.value_null() }
+                if let Some(stdout) = &failure.stdout { w.key("stdout"); w.value_string(stdout); }
+                if let Some(stderr) = &failure.stderr { w.key("stderr"); w.value_string(stderr); }
+                w.end_object();
+            }
+            w.end_array();
             w.end_object();
+            if let Ok(recorded) = integrity {
+                w.key("integrity"); w.begin_object();
+                w.key("file"); w.value_string(&node_modules.join(INSTALL_INTEGRITY_FILE).to_string_lossy());
+                w.key("packages"); w.value_u64(recorded);
+                w.end_object();
+            }
             w.key("timing"); w.begin_object();
             w.key("resolveMs"); w.value_u64(phase_resolve_ms);
             w.key("fetchMs"); w.value_u64(phase_fetch_ms);
             w.key("materializeMs"); w.value_u64(phase_materialize_ms);
             w.key("binLinksMs"); w.value_u64(phase_binlinks_ms);
             w.key("scriptsMs"); w.value_u64(phase_scripts_ms);
+            w.key("integrityMs"); w.value_u64(phase_integrity_ms);
             w.key("totalMs"); w.value_u64(duration_ms);
             w.end_object();
+            write_warnings(&mut w, &warnings);
             w.end_object(); w.out.push('\n');
             print!("{}", w.finish());
         }
 
         // === Phase B Commands ===
 
-        Command::Run { project_root, script_names, extra_args, watch } => {
+        Command::Run { project_root, script_names, extra_args, watch, parallel } => {
             if watch && script_names.len() == 1 {
                 match run_script_watch(&project_root, &script_names[0], &extra_args, 300) {
                     Ok(()) => {}
                     Err(reason) => {
-                        let mut w = JsonWriter::new();
+                        let mut w = JsonWriter::with_indent(indent);
                         w.begin_object();
                         w.key("ok"); w.value_bool(false);
                         w.key("kind"); w.value_string("better.run.report");
                         w.key("reason"); w.value_string(&reason);
                         w.end_object(); w.out.push('\n');
                         eprint!("{}", w.finish());
-                        std::process::exit(1);
+                        std::process::exit(EXIT_FAILURE);
                     }
                 }
             } else if script_names.len() == 1 {
                 match run_script(&project_root, &script_names[0], &extra_args) {
                     Ok(result) => {
-                        let mut w = JsonWriter::new();
+                        let mut w = JsonWriter::with_indent(indent);
                         w.begin_object();
                         w.key("ok"); w.value_bool(result.exit_code == 0);
                         w.key("kind"); w.value_string("better.run.report");
@@ -820,37 +1998,44 @@ fn main() {
                         std::process::exit(result.exit_code);
                     }
                     Err(reason) => {
-                        let mut w = JsonWriter::new();
+                        let mut w = JsonWriter::with_indent(indent);
                         w.begin_object();
                         w.key("ok"); w.value_bool(false);
                         w.key("kind"); w.value_string("better.run.report");
                         w.key("reason"); w.value_string(&reason);
                         w.end_object(); w.out.push('\n');
                         eprint!("{}", w.finish());
-                        std::process::exit(1);
+                        std::process::exit(EXIT_FAILURE);
                     }
                 }
             } else {
-                // Parallel execution
-                let results = run_scripts_parallel(&project_root, &script_names);
-                let mut w = JsonWriter::new();
+                let results = if parallel {
+                    run_scripts_parallel(&project_root, &script_names)
+                } else {
+                    run_scripts_sequential(&project_root, &script_names)
+                };
+                // Sequential runs stop at the first failure, leaving later scripts without a result
+                let any_failed = results.len() < script_names.len()
+                    || results.iter().any(|r| r.as_ref().map_or(true, |s| s.exit_code != 0));
+                let mut w = JsonWriter::with_indent(indent);
                 w.begin_object();
-                w.key("ok"); w.value_bool(results.iter().all(|r| r.as_ref().map(|s| s.exit_code == 0).unwrap_or(false)));
-                w.key("kind"); w.value_string("better.run.parallel");
+                w.key("ok"); w.value_bool(!any_failed);
+                w.key("kind"); w.value_string(if parallel { "better.run.parallel" } else { "better.run.sequential" });
+                // One entry per requested script, in the order given
                 w.key("results"); w.begin_array();
-                let mut any_failed = false;
-                for result in &results {
+                for (i, name) in script_names.iter().enumerate() {
                     w.begin_object();
-                    match result {
-                        Ok(r) => {
-                            w.key("script"); w.value_string(&r.script_name);
+                    w.key("script"); w.value_string(name);
+                    match results.get(i) {
+                        Some(Ok(r)) => {
                             w.key("exitCode"); w.value_i64(r.exit_code as i64);
                             w.key("durationMs"); w.value_u64(r.duration_ms);
-                            if r.exit_code != 0 { any_failed = true; }
                         }
-                        Err(reason) => {
+                        Some(Err(reason)) => {
                             w.key("error"); w.value_string(reason);
-                            any_failed = true;
+                        }
+                        None => {
+                            w.key("skipped"); w.value_bool(true);
                         }
                     }
                     w.end_object();
@@ -858,100 +2043,154 @@ fn main() {
                 w.end_array();
                 w.end_object(); w.out.push('\n');
                 eprint!("{}", w.finish());
-                if any_failed { std::process::exit(1); }
+                if any_failed { std::process::exit(EXIT_FAILURE); }
             }
         }
 
-        Command::License { root, allow, deny } => {
-            match scan_licenses(&root, &allow, &deny) {
-                Ok(report) => {
-                    let mut w = JsonWriter::new();
-                    w.begin_object();
-                    w.key("ok"); w.value_bool(report.violations.is_empty());
-                    w.key("kind"); w.value_string("better.license");
-                    w.key("packages"); w.begin_array();
-                    for pkg in &report.packages {
-                        w.begin_object();
+        Command::License { project_root, node_modules, allow, deny, omit, sort, ndjson, human } => {
+            let mut ctx = ProjectContext::new(&project_root).with_omit(omit);
+            if let Some(node_modules) = node_modules {
+                ctx = ctx.with_node_modules(node_modules);
+            }
+            match ctx.scan_licenses(&allow, &deny) {
+                Ok(mut report) => {
+                    if let Some(key) = sort { report.sort_packages(key); }
+                    if human {
+                        let rows: Vec<Vec<String>> = report.packages.iter()
+                            .map(|p| vec![p.name.clone(), p.version.clone(), p.license.clone()])
+                            .collect();
+                        print!("{}", render_table(&["Package", "Version", "License"], &rows));
+                        println!("\n{} packages, {} license violations", report.total_packages, report.violations.len());
+                        for v in &report.violations {
+                            println!("  {}@{}: {}", v.name, v.version, v.license);
+                        }
+                        if !report.violations.is_empty() { std::process::exit(EXIT_VIOLATION); }
+                        return;
+                    }
+                    let mut out = ReportEmitter::new("better.license", report.violations.is_empty(), ndjson, indent);
+                    out.fields(|w| {
+                        w.key("omit"); w.begin_array();
+                        for section in omit.sections() { w.value_string(section); }
+                        w.end_array();
+                    });
+                    out.records("packages", "package", &report.packages, |w, pkg| {
                         w.key("name"); w.value_string(&pkg.name);
                         w.key("version"); w.value_string(&pkg.version);
                         w.key("license"); w.value_string(&pkg.license);
+                    });
+                    out.summary(|w| {
+                        w.key("totalPackages"); w.value_u64(report.total_packages);
+                        w.key("omitted"); w.value_u64(report.omitted);
+                        w.key("byLicense"); w.begin_object();
+                        for (lic, count) in &report.by_license {
+                            w.key(lic); w.value_u64(*count);
+                        }
                         w.end_object();
-                    }
-                    w.end_array();
-                    w.key("summary"); w.begin_object();
-                    w.key("totalPackages"); w.value_u64(report.total_packages);
-                    w.key("byLicense"); w.begin_object();
-                    for (lic, count) in &report.by_license {
-                        w.key(lic); w.value_u64(*count);
-                    }
-                    w.end_object();
-                    w.key("violations"); w.value_u64(report.violations.len() as u64);
-                    w.end_object();
-                    w.end_object(); w.out.push('\n');
-                    print!("{}", w.finish());
-                    if !report.violations.is_empty() { std::process::exit(1); }
+                        w.key("violations"); w.value_u64(report.violations.len() as u64);
+                    });
+                    print!("{}", out.finish());
+                    if !report.violations.is_empty() { std::process::exit(EXIT_VIOLATION); }
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.license");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
-        Command::Dedupe { root } => {
-            match check_dedupe(&root) {
+        Command::Dedupe { root, node_modules, human } => {
+            let mut ctx = ProjectContext::new(&root);
+            if let Some(node_modules) = node_modules {
+                ctx = ctx.with_node_modules(node_modules);
+            }
+            match ctx.check_dedupe() {
+                Ok(report) if human => {
+                    let rows: Vec<Vec<String>> = report.duplicates.iter()
+                        .map(|d| vec![
+                            d.name.clone(),
+                            d.versions.join(", "),
+                            d.instances.to_string(),
+                            d.target_version.clone().unwrap_or_else(|| "-".into()),
+                            d.saved_bytes.to_string(),
+                        ])
+                        .collect();
+                    print!("{}", render_table(&["Package", "Versions", "Instances", "Target", "Saved bytes"], &rows));
+                    println!(
+                        "\n{} duplicated, {} can be deduped, saving {} packages ({} bytes)",
+                        report.total_duplicates, report.deduplicatable, report.estimated_saved, report.estimated_saved_bytes
+                    );
+                }
                 Ok(report) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.dedupe");
-                    w.key("duplicates"); w.begin_array();
-                    for d in &report.duplicates {
+                    write_dedupe_fields(&mut w, &report);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    let mut w = JsonWriter::with_indent(indent);
+                    w.begin_object();
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.dedupe");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::Prune { project_root, lockfile, dry_run } => {
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            let mut plan = FsPlan::new(dry_run);
+            match prune_node_modules(&project_root, &lockfile, &mut plan) {
+                Ok(report) => {
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.prune");
+                    w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
+                    w.key("removedCount"); w.value_u64(report.removed_count);
+                    w.key("freedBytes"); w.value_u64(report.freed_bytes);
+                    w.key("removed"); w.begin_array();
+                    for pkg in &report.removed {
                         w.begin_object();
-                        w.key("name"); w.value_string(&d.name);
-                        w.key("versions"); w.begin_array();
-                        for v in &d.versions { w.value_string(v); }
-                        w.end_array();
-                        w.key("instances"); w.value_u64(d.instances);
-                        w.key("canDedupe"); w.value_bool(d.can_dedupe);
-                        w.key("savedInstances"); w.value_u64(d.saved_instances);
+                        w.key("name"); w.value_string(&pkg.name);
+                        w.key("path"); w.value_string(&pkg.rel_path);
+                        w.key("bytes"); w.value_u64(pkg.bytes);
                         w.end_object();
                     }
                     w.end_array();
-                    w.key("summary"); w.begin_object();
-                    w.key("totalDuplicates"); w.value_u64(report.total_duplicates);
-                    w.key("deduplicatable"); w.value_u64(report.deduplicatable);
-                    w.key("estimatedSavedPackages"); w.value_u64(report.estimated_saved);
-                    w.end_object();
+                    write_plan_json(&mut w, &plan);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
-                    w.begin_object();
                     w.key("ok"); w.value_bool(false);
-                    w.key("kind"); w.value_string("better.dedupe");
+                    w.key("kind"); w.value_string("better.prune");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
-        Command::Why { project_root, lockfile, package } => {
-            match trace_dependency(&project_root, &lockfile, &package) {
+        Command::Why { project_root, lockfile, package, max_paths } => {
+            match ProjectContext::new(&project_root).with_lockfile(&lockfile).trace_dependency_limited(&package, max_paths) {
                 Ok(report) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.why");
+                    w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
                     w.key("package"); w.value_string(&report.package);
                     w.key("version"); match &report.version { Some(v) => w.value_string(v), None => w.value_null() }
                     w.key("isDirect"); w.value_bool(report.is_direct);
@@ -962,6 +2201,13 @@ fn main() {
                         w.end_array();
                     }
                     w.end_array();
+                    w.key("dependencyPathKinds"); w.begin_array();
+                    for kinds in &report.dependency_path_kinds {
+                        w.begin_array();
+                        for k in kinds { w.value_string(k.as_str()); }
+                        w.end_array();
+                    }
+                    w.end_array();
                     w.key("dependedOnBy"); w.begin_array();
                     for (name, ver) in &report.depended_on_by {
                         w.begin_object();
@@ -971,73 +2217,116 @@ fn main() {
                     }
                     w.end_array();
                     w.key("totalPaths"); w.value_u64(report.total_paths);
+                    w.key("hoistBlockers"); w.begin_array();
+                    for blocker in &report.hoist_blockers { w.value_string(blocker); }
+                    w.end_array();
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.why");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
-        Command::Outdated { project_root, lockfile } => {
-            match check_outdated(&project_root, &lockfile) {
-                Ok(report) => {
-                    let mut w = JsonWriter::new();
-                    w.begin_object();
-                    w.key("ok"); w.value_bool(true);
-                    w.key("kind"); w.value_string("better.outdated");
-                    w.key("packages"); w.begin_array();
-                    for pkg in &report.packages {
-                        w.begin_object();
+        Command::Outdated { project_root, lockfile, wanted, metadata_cache, sort, timeout, ndjson, human } => {
+            let ctx = ProjectContext::new(&project_root)
+                .with_lockfile(&lockfile)
+                .with_fetch_timeout(timeout)
+                .with_metadata_cache(metadata_cache);
+            match if wanted { ctx.check_outdated_wanted() } else { ctx.check_outdated() } {
+                Ok(mut report) => {
+                    if let Some(key) = sort { report.sort_packages(key); }
+                    if human {
+                        let rows: Vec<Vec<String>> = report.packages.iter()
+                            .map(|p| match &p.wanted {
+                                Some(w) => vec![p.name.clone(), p.current.clone(), w.clone(), p.latest.clone(), p.update_type.clone()],
+                                None => vec![p.name.clone(), p.current.clone(), p.latest.clone(), p.update_type.clone()],
+                            })
+                            .collect();
+                        let headers: &[&str] = if wanted { &["Package", "Current", "Wanted", "Latest", "Type"] } else { &["Package", "Current", "Latest", "Type"] };
+                        print!("{}", render_table(headers, &rows));
+                        println!(
+                            "\n{} of {} packages outdated (major {}, minor {}, patch {})",
+                            report.outdated, report.total_checked, report.major, report.minor, report.patch
+                        );
+                        for (name, reason) in &report.errors {
+                            println!("  error: {}: {}", name, reason);
+                        }
+                        return;
+                    }
+                    let mut out = ReportEmitter::new("better.outdated", true, ndjson, indent);
+                    out.fields(|w| {
+                        w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
+                    });
+                    out.records("packages", "package", &report.packages, |w, pkg| {
                         w.key("name"); w.value_string(&pkg.name);
                         w.key("current"); w.value_string(&pkg.current);
+                        w.key("wanted"); match &pkg.wanted { Some(v) => w.value_string(v), None => w.value_null() }
                         w.key("latest"); w.value_string(&pkg.latest);
                         w.key("updateType"); w.value_string(&pkg.update_type);
-                        w.end_object();
-                    }
-                    w.end_array();
-                    w.key("summary"); w.begin_object();
-                    w.key("totalChecked"); w.value_u64(report.total_checked);
-                    w.key("outdated"); w.value_u64(report.outdated);
-                    w.key("major"); w.value_u64(report.major);
-                    w.key("minor"); w.value_u64(report.minor);
-                    w.key("patch"); w.value_u64(report.patch);
-                    w.end_object();
-                    w.end_object(); w.out.push('\n');
-                    print!("{}", w.finish());
+                    });
+                    out.records("errors", "error", &report.errors, |w, (name, reason)| {
+                        w.key("name"); w.value_string(name);
+                        w.key("reason"); w.value_string(reason);
+                    });
+                    out.summary(|w| {
+                        w.key("totalChecked"); w.value_u64(report.total_checked);
+                        w.key("outdated"); w.value_u64(report.outdated);
+                        w.key("errors"); w.value_u64(report.errors.len() as u64);
+                        w.key("major"); w.value_u64(report.major);
+                        w.key("minor"); w.value_u64(report.minor);
+                        w.key("patch"); w.value_u64(report.patch);
+                    });
+                    print!("{}", out.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.outdated");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
-        Command::Doctor { project_root, threshold } => {
+        Command::Doctor { project_root, threshold, fail_on, human } => {
             match run_doctor(&project_root, threshold) {
                 Ok(report) => {
-                    let mut w = JsonWriter::new();
+                    let failed = report.should_fail(fail_on);
+                    if human {
+                        println!("Health score: {} (threshold {})\n", report.score, report.threshold);
+                        let rows: Vec<Vec<String>> = report.findings.iter()
+                            .map(|f| vec![f.severity.clone(), f.id.clone(), f.impact.to_string(), f.title.clone(), f.recommendation.clone()])
+                            .collect();
+                        print!("{}", render_table(&["Severity", "Id", "Impact", "Finding", "Recommendation"], &rows));
+                        if failed { std::process::exit(EXIT_VIOLATION); }
+                        return;
+                    }
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
-                    w.key("ok"); w.value_bool(report.score >= report.threshold);
+                    w.key("ok"); w.value_bool(!failed);
                     w.key("kind"); w.value_string("better.doctor");
+                    w.key("failOn"); w.value_string(fail_on.as_str());
                     w.key("healthScore"); w.begin_object();
                     w.key("score"); w.value_i64(report.score as i64);
                     w.key("threshold"); w.value_i64(report.threshold as i64);
                     w.end_object();
+                    w.key("severityCounts"); w.begin_object();
+                    for sev in ["critical", "error", "warning", "info"] {
+                        w.key(sev); w.value_u64(report.severity_count(sev));
+                    }
+                    w.end_object();
                     w.key("findings"); w.begin_array();
                     for f in &report.findings {
                         w.begin_object();
@@ -1051,17 +2340,17 @@ fn main() {
                     w.end_array();
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    if report.score < report.threshold { std::process::exit(1); }
+                    if failed { std::process::exit(EXIT_VIOLATION); }
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.doctor");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
@@ -1069,7 +2358,7 @@ fn main() {
         Command::CacheStats { cache_root } => {
             match cache_stats(&cache_root) {
                 Ok(report) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.cache.stats");
@@ -1092,14 +2381,14 @@ fn main() {
                     print!("{}", w.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.cache.stats");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
@@ -1107,7 +2396,7 @@ fn main() {
         Command::CacheGc { cache_root, max_age, dry_run } => {
             match cache_gc(&cache_root, max_age, dry_run) {
                 Ok(report) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.cache.gc");
@@ -1118,59 +2407,200 @@ fn main() {
                     print!("{}", w.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.cache.gc");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
-        Command::Audit { project_root, lockfile, min_severity } => {
-            match run_audit(&lockfile, &project_root, &min_severity) {
+        Command::CacheVerify { cache_root, by_url } => {
+            let checks = verify_cached_tarballs(&cache_root, by_url.as_deref());
+            let failed = checks.iter().filter(|c| c.status != "ok").count();
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            w.key("ok"); w.value_bool(failed == 0);
+            w.key("kind"); w.value_string("better.cache.verify");
+            w.key("checked"); w.value_u64(checks.len() as u64);
+            w.key("failed"); w.value_u64(failed as u64);
+            w.key("entries"); w.begin_array();
+            for c in &checks {
+                w.begin_object();
+                if let Some(url) = &c.url {
+                    w.key("url"); w.value_string(url);
+                }
+                w.key("algorithm"); w.value_string(&c.algo);
+                w.key("hex"); w.value_string(&c.hex);
+                w.key("path"); w.value_string(&c.path.to_string_lossy());
+                w.key("status"); w.value_string(c.status);
+                w.end_object();
+            }
+            w.end_array();
+            w.end_object(); w.out.push('\n');
+            print!("{}", w.finish());
+            if failed > 0 {
+                std::process::exit(EXIT_INTEGRITY);
+            }
+        }
+
+        Command::CacheExport { cache_root, store_root, archive } => {
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match cache_export(&cache_root, &store_root, &archive) {
                 Ok(report) => {
-                    let mut w = JsonWriter::new();
-                    w.begin_object();
-                    w.key("ok"); w.value_bool(report.total == 0);
-                    w.key("kind"); w.value_string("better.audit");
-                    w.key("scannedPackages"); w.value_u64(report.scanned_packages);
-                    w.key("vulnerabilities"); w.begin_array();
-                    for v in &report.vulnerabilities {
-                        w.begin_object();
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.cache.export");
+                    w.key("archive"); w.value_string(&archive.to_string_lossy());
+                    w.key("tarballs"); w.value_u64(report.tarballs);
+                    w.key("blobs"); w.value_u64(report.blobs);
+                    w.key("packages"); w.value_u64(report.packages);
+                    w.key("bytes"); w.value_u64(report.bytes);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.cache.export");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::CacheImport { cache_root, store_root, archive } => {
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match cache_import(&cache_root, &store_root, &archive) {
+                Ok(report) => {
+                    w.key("ok"); w.value_bool(report.rejected.is_empty());
+                    w.key("kind"); w.value_string("better.cache.import");
+                    w.key("archive"); w.value_string(&archive.to_string_lossy());
+                    w.key("tarballsImported"); w.value_u64(report.tarballs_imported);
+                    w.key("blobsImported"); w.value_u64(report.blobs_imported);
+                    w.key("packagesImported"); w.value_u64(report.packages_imported);
+                    w.key("skipped"); w.value_u64(report.skipped);
+                    w.key("rejected"); w.begin_array();
+                    for name in &report.rejected {
+                        w.value_string(name);
+                    }
+                    w.end_array();
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    if !report.rejected.is_empty() {
+                        std::process::exit(EXIT_FAILURE);
+                    }
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.cache.import");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::Diagnostics { project_root, cache_root } => {
+            let report = run_diagnostics(&cache_root, &project_root);
+            let write_caps = |w: &mut JsonWriter, caps: &FsCapabilities| {
+                w.begin_object();
+                w.key("path"); w.value_string(&caps.path.to_string_lossy());
+                w.key("writable"); w.value_bool(caps.writable);
+                w.key("hardlink"); w.value_bool(caps.hardlink);
+                w.key("reflink"); w.value_bool(caps.reflink);
+                w.key("symlink"); w.value_bool(caps.symlink);
+                w.key("caseSensitive"); w.value_bool(caps.case_sensitive);
+                w.end_object();
+            };
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            w.key("ok"); w.value_bool(report.cache.writable && report.project.writable);
+            w.key("kind"); w.value_string("better.diagnostics");
+            w.key("cache"); write_caps(&mut w, &report.cache);
+            w.key("project"); write_caps(&mut w, &report.project);
+            w.key("crossDevice"); w.value_bool(report.cross_device);
+            w.key("cacheToProject"); w.begin_object();
+            w.key("hardlink"); w.value_bool(report.hardlink_cache_to_project);
+            w.key("reflink"); w.value_bool(report.reflink_cache_to_project);
+            w.end_object();
+            w.key("installStrategy"); w.value_string(report.install_strategy);
+            let proxy_url = |w: &mut JsonWriter, url: Option<&String>| match url {
+                Some(url) => w.value_string(&redact_url_credentials(url)),
+                None => w.value_null(),
+            };
+            w.key("proxy"); w.begin_object();
+            w.key("https"); proxy_url(&mut w, report.proxy.https.as_ref());
+            w.key("http"); proxy_url(&mut w, report.proxy.http.as_ref());
+            w.key("noProxy"); w.begin_array();
+            for entry in &report.proxy.no_proxy { w.value_string(entry); }
+            w.end_array();
+            w.key("registry"); w.value_string(&report.registry);
+            w.key("registryProxy"); proxy_url(&mut w, report.registry_proxy.as_ref());
+            w.end_object();
+            w.end_object(); w.out.push('\n');
+            print!("{}", w.finish());
+            if !(report.cache.writable && report.project.writable) {
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+
+        Command::Audit { project_root, lockfile, min_severity, fail_on, source, omit, timeout, ndjson } => {
+            let ctx = ProjectContext::new(&project_root)
+                .with_lockfile(&lockfile)
+                .with_omit(omit)
+                .with_fetch_timeout(timeout)
+                .with_audit_source(source);
+            match ctx.run_audit(&min_severity) {
+                Ok(report) => {
+                    let failed = report.fails_at(&fail_on);
+                    let mut out = ReportEmitter::new("better.audit", !failed, ndjson, indent);
+                    out.fields(|w| {
+                        w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
+                        w.key("source"); w.value_string(source.as_str());
+                        w.key("failOn"); w.value_string(&fail_on.to_lowercase());
+                        w.key("scannedPackages"); w.value_u64(report.scanned_packages);
+                        w.key("omit"); w.begin_array();
+                        for section in omit.sections() { w.value_string(section); }
+                        w.end_array();
+                        w.key("omitted"); w.value_u64(report.omitted);
+                        w.key("ignored"); w.value_u64(report.ignored_count);
+                    });
+                    out.records("vulnerabilities", "vulnerability", &report.vulnerabilities, |w, v| {
                         w.key("id"); w.value_string(&v.id);
                         w.key("summary"); w.value_string(&v.summary);
                         w.key("severity"); w.value_string(&v.severity);
                         w.key("package"); w.value_string(&v.package);
                         w.key("version"); w.value_string(&v.version);
                         w.key("fixed"); w.value_string(&v.fixed);
-                        w.end_object();
-                    }
-                    w.end_array();
-                    w.key("summary"); w.begin_object();
-                    w.key("total"); w.value_u64(report.total);
-                    w.key("critical"); w.value_u64(report.critical);
-                    w.key("high"); w.value_u64(report.high);
-                    w.key("medium"); w.value_u64(report.medium);
-                    w.key("low"); w.value_u64(report.low);
-                    w.key("riskLevel"); w.value_string(&report.risk_level);
-                    w.end_object();
-                    w.end_object(); w.out.push('\n');
-                    print!("{}", w.finish());
-                    if report.total > 0 { std::process::exit(1); }
+                    });
+                    out.summary(|w| {
+                        w.key("total"); w.value_u64(report.total);
+                        w.key("critical"); w.value_u64(report.critical);
+                        w.key("high"); w.value_u64(report.high);
+                        w.key("medium"); w.value_u64(report.medium);
+                        w.key("low"); w.value_u64(report.low);
+                        w.key("riskLevel"); w.value_string(&report.risk_level);
+                    });
+                    print!("{}", out.finish());
+                    if failed { std::process::exit(EXIT_VIOLATION); }
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.audit");
-                    w.key("reason"); w.value_string(&reason);
+                    w.key("reason"); w.value_string(&reason.message);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(failure_exit_code(reason.kind));
                 }
             }
         }
@@ -1178,7 +2608,7 @@ fn main() {
         Command::Benchmark { project_root, rounds, pms } => {
             match run_benchmark(&project_root, rounds, &pms) {
                 Ok(report) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.benchmark");
@@ -1195,12 +2625,22 @@ fn main() {
                         w.key("minMs"); w.value_u64(r.cold.min_ms);
                         w.key("maxMs"); w.value_u64(r.cold.max_ms);
                         w.key("meanMs"); w.value_u64(r.cold.mean_ms);
+                        w.key("peakRssBytes");
+                        match r.cold.peak_rss_bytes {
+                            Some(bytes) => w.value_u64(bytes),
+                            None => w.value_null(),
+                        }
                         w.end_object();
                         w.key("warm"); w.begin_object();
                         w.key("medianMs"); w.value_u64(r.warm.median_ms);
                         w.key("minMs"); w.value_u64(r.warm.min_ms);
                         w.key("maxMs"); w.value_u64(r.warm.max_ms);
                         w.key("meanMs"); w.value_u64(r.warm.mean_ms);
+                        w.key("peakRssBytes");
+                        match r.warm.peak_rss_bytes {
+                            Some(bytes) => w.value_u64(bytes),
+                            None => w.value_null(),
+                        }
                         w.end_object();
                         w.end_object();
                     }
@@ -1209,24 +2649,25 @@ fn main() {
                     print!("{}", w.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.benchmark");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
         // === Phase C Commands ===
 
-        Command::HooksInstall { project_root } => {
-            match hooks_install(&project_root) {
+        Command::HooksInstall { project_root, dry_run } => {
+            let mut plan = FsPlan::new(dry_run);
+            match hooks_install(&project_root, &mut plan) {
                 Ok(result) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.hooks.install");
@@ -1240,18 +2681,48 @@ fn main() {
                         w.end_object();
                     }
                     w.end_array();
+                    write_plan_json(&mut w, &plan);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.hooks.install");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::HooksUninstall { project_root, dry_run } => {
+            let mut plan = FsPlan::new(dry_run);
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match hooks_uninstall(&project_root, &mut plan) {
+                Ok(result) => {
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.hooks.uninstall");
+                    w.key("removed"); w.begin_array();
+                    for name in &result.removed { w.value_string(name); }
+                    w.end_array();
+                    w.key("kept"); w.begin_array();
+                    for name in &result.kept { w.value_string(name); }
+                    w.end_array();
+                    write_plan_json(&mut w, &plan);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.hooks.uninstall");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
@@ -1259,7 +2730,7 @@ fn main() {
         Command::Exec { project_root, script, extra_args } => {
             match exec_script(&project_root, &script, &extra_args) {
                 Ok(result) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(result.exit_code == 0);
                     w.key("kind"); w.value_string("better.exec");
@@ -1272,14 +2743,14 @@ fn main() {
                     std::process::exit(result.exit_code);
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.exec");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
@@ -1288,7 +2759,7 @@ fn main() {
             if check {
                 match env_check(&project_root) {
                     Ok(result) => {
-                        let mut w = JsonWriter::new();
+                        let mut w = JsonWriter::with_indent(indent);
                         w.begin_object();
                         w.key("ok"); w.value_bool(result.all_ok);
                         w.key("kind"); w.value_string("better.env.check");
@@ -1304,22 +2775,22 @@ fn main() {
                         w.end_array();
                         w.end_object(); w.out.push('\n');
                         print!("{}", w.finish());
-                        if !result.all_ok { std::process::exit(1); }
+                        if !result.all_ok { std::process::exit(EXIT_VIOLATION); }
                     }
                     Err(reason) => {
-                        let mut w = JsonWriter::new();
+                        let mut w = JsonWriter::with_indent(indent);
                         w.begin_object();
                         w.key("ok"); w.value_bool(false);
                         w.key("kind"); w.value_string("better.env.check");
                         w.key("reason"); w.value_string(&reason);
                         w.end_object(); w.out.push('\n');
                         print!("{}", w.finish());
-                        std::process::exit(1);
+                        std::process::exit(EXIT_FAILURE);
                     }
                 }
             } else {
                 let info = env_info(&project_root);
-                let mut w = JsonWriter::new();
+                let mut w = JsonWriter::with_indent(indent);
                 w.begin_object();
                 w.key("ok"); w.value_bool(true);
                 w.key("kind"); w.value_string("better.env");
@@ -1328,17 +2799,18 @@ fn main() {
                 w.key("betterVersion"); w.value_string(&info.better_version);
                 w.key("platform"); w.value_string(&info.platform);
                 w.key("arch"); w.value_string(&info.arch);
-                match &info.project_name { Some(n) => { w.key("projectName"); w.value_string(n); } None => {} }
-                match &info.project_version { Some(v) => { w.key("projectVersion"); w.value_string(v); } None => {} }
+                if let Some(n) = &info.project_name { w.key("projectName"); w.value_string(n); }
+                if let Some(v) = &info.project_version { w.key("projectVersion"); w.value_string(v); }
                 w.end_object(); w.out.push('\n');
                 print!("{}", w.finish());
             }
         }
 
-        Command::Init { project_root, name, template } => {
-            match init_project(&project_root, name.as_deref(), template.as_deref()) {
+        Command::Init { project_root, name, template, dry_run } => {
+            let mut plan = FsPlan::new(dry_run);
+            match init_project(&project_root, name.as_deref(), template.as_deref(), &mut plan) {
                 Ok(result) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.init");
@@ -1347,36 +2819,33 @@ fn main() {
                     w.key("filesCreated"); w.begin_array();
                     for f in &result.files_created { w.value_string(f); }
                     w.end_array();
+                    write_plan_json(&mut w, &plan);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.init");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
         // === Phase D Commands ===
 
-        Command::Scripts { project_root, subcommand, package } => {
+        Command::Scripts { project_root, subcommand, package, dry_run, ndjson } => {
+            let mut plan = FsPlan::new(dry_run);
             match subcommand.as_str() {
                 "scan" | "list" => {
                     match scan_scripts(&project_root) {
                         Ok(result) => {
-                            let mut w = JsonWriter::new();
-                            w.begin_object();
-                            w.key("ok"); w.value_bool(true);
-                            w.key("kind"); w.value_string("better.scripts.scan");
-                            w.key("packages"); w.begin_array();
-                            for entry in &result.packages {
-                                w.begin_object();
+                            let mut out = ReportEmitter::new("better.scripts.scan", true, ndjson, indent);
+                            out.records("packages", "package", &result.packages, |w, entry| {
                                 w.key("name"); w.value_string(&entry.name);
                                 w.key("version"); w.value_string(&entry.version);
                                 w.key("scripts"); w.begin_array();
@@ -1389,26 +2858,23 @@ fn main() {
                                 w.end_array();
                                 w.key("policy"); w.value_string(&entry.policy);
                                 w.key("reason"); w.value_string(&entry.reason);
-                                w.end_object();
-                            }
-                            w.end_array();
-                            w.key("summary"); w.begin_object();
-                            w.key("totalWithScripts"); w.value_u64(result.total_with_scripts);
-                            w.key("allowed"); w.value_u64(result.allowed);
-                            w.key("blocked"); w.value_u64(result.blocked);
-                            w.end_object();
-                            w.end_object(); w.out.push('\n');
-                            print!("{}", w.finish());
+                            });
+                            out.summary(|w| {
+                                w.key("totalWithScripts"); w.value_u64(result.total_with_scripts);
+                                w.key("allowed"); w.value_u64(result.allowed);
+                                w.key("blocked"); w.value_u64(result.blocked);
+                            });
+                            print!("{}", out.finish());
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.scripts.scan");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
@@ -1416,11 +2882,11 @@ fn main() {
                     let pkg = package.unwrap_or_default();
                     if pkg.is_empty() {
                         eprintln!("error: scripts allow requires a package name");
-                        std::process::exit(2);
+                        std::process::exit(EXIT_USAGE);
                     }
-                    match scripts_allow(&project_root, &pkg) {
+                    match scripts_allow(&project_root, &pkg, &mut plan) {
                         Ok(policy) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(true);
                             w.key("kind"); w.value_string("better.scripts.allow");
@@ -1428,18 +2894,19 @@ fn main() {
                             w.key("allowedPackages"); w.begin_array();
                             for p in &policy.allowed_packages { w.value_string(p); }
                             w.end_array();
+                            write_plan_json(&mut w, &plan);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.scripts.allow");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
@@ -1447,11 +2914,11 @@ fn main() {
                     let pkg = package.unwrap_or_default();
                     if pkg.is_empty() {
                         eprintln!("error: scripts block requires a package name");
-                        std::process::exit(2);
+                        std::process::exit(EXIT_USAGE);
                     }
-                    match scripts_block(&project_root, &pkg) {
+                    match scripts_block(&project_root, &pkg, &mut plan) {
                         Ok(policy) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(true);
                             w.key("kind"); w.value_string("better.scripts.block");
@@ -1459,34 +2926,35 @@ fn main() {
                             w.key("blockedPackages"); w.begin_array();
                             for p in &policy.blocked_packages { w.value_string(p); }
                             w.end_array();
+                            write_plan_json(&mut w, &plan);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.scripts.block");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
                 other => {
                     eprintln!("error: unknown scripts subcommand: {other}");
-                    std::process::exit(2);
+                    std::process::exit(EXIT_USAGE);
                 }
             }
         }
 
-        Command::Policy { project_root, subcommand } => {
+        Command::Policy { project_root, subcommand, dry_run } => {
             match subcommand.as_str() {
                 "check" => {
                     match policy_check(&project_root) {
                         Ok(result) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(result.pass);
                             w.key("kind"); w.value_string("better.policy.check");
@@ -1510,56 +2978,59 @@ fn main() {
                             w.end_object();
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            if !result.pass { std::process::exit(1); }
+                            if !result.pass { std::process::exit(EXIT_VIOLATION); }
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.policy.check");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
                 "init" => {
-                    match policy_init(&project_root) {
+                    let mut plan = FsPlan::new(dry_run);
+                    match policy_init(&project_root, &mut plan) {
                         Ok(path) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(true);
                             w.key("kind"); w.value_string("better.policy.init");
                             w.key("path"); w.value_string(&path);
+                            write_plan_json(&mut w, &plan);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.policy.init");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
                 other => {
                     eprintln!("error: unknown policy subcommand: {other}");
-                    std::process::exit(2);
+                    std::process::exit(EXIT_USAGE);
                 }
             }
         }
 
-        Command::Lock { project_root, subcommand } => {
+        Command::Lock { project_root, subcommand, dry_run } => {
             match subcommand.as_str() {
                 "generate" => {
-                    match generate_lock_metadata(&project_root) {
+                    let mut plan = FsPlan::new(dry_run);
+                    match generate_lock_metadata(&project_root, &mut plan) {
                         Ok(metadata) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(true);
                             w.key("kind"); w.value_string("better.lock.generate");
@@ -1572,25 +3043,26 @@ fn main() {
                             w.key("nodeMajor"); w.value_u64(metadata.fingerprint.node_major);
                             w.key("pm"); w.value_string(&metadata.fingerprint.pm);
                             w.end_object();
+                            write_plan_json(&mut w, &plan);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.lock.generate");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
                 "verify" => {
                     match verify_lock_metadata(&project_root) {
                         Ok(result) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(result.ok);
                             w.key("kind"); w.value_string("better.lock.verify");
@@ -1610,44 +3082,162 @@ fn main() {
                             }
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            if !result.ok { std::process::exit(1); }
+                            if !result.ok { std::process::exit(EXIT_VIOLATION); }
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.lock.verify");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
                 other => {
                     eprintln!("error: unknown lock subcommand: {other}");
-                    std::process::exit(2);
+                    std::process::exit(EXIT_USAGE);
+                }
+            }
+        }
+
+        Command::Verify { project_root, expected, record, dry_run } => {
+            let mut plan = FsPlan::new(dry_run);
+            let result = if record {
+                record_tree_hash(&project_root, &mut plan).map(|current| ReproducibleVerifyResult {
+                    ok: true,
+                    expected: Some(current.hash.clone()),
+                    current,
+                })
+            } else {
+                verify_reproducible(&project_root, expected.as_deref())
+            };
+            match result {
+                Ok(result) => {
+                    let mut w = JsonWriter::with_indent(indent);
+                    w.begin_object();
+                    w.key("ok"); w.value_bool(result.ok);
+                    w.key("kind"); w.value_string("better.verify.reproducible");
+                    w.key("recorded"); w.value_bool(record);
+                    w.key("treeHash"); w.value_string(&result.current.hash);
+                    w.key("expected");
+                    match &result.expected {
+                        Some(hash) => w.value_string(hash),
+                        None => w.value_null(),
+                    }
+                    w.key("files"); w.value_u64(result.current.files);
+                    w.key("symlinks"); w.value_u64(result.current.symlinks);
+                    write_plan_json(&mut w, &plan);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    if !result.ok { std::process::exit(EXIT_INTEGRITY); }
+                }
+                Err(reason) => {
+                    let mut w = JsonWriter::with_indent(indent);
+                    w.begin_object();
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.verify.reproducible");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::VerifyIntegrity { project_root } => {
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match verify_installed_integrity(&project_root) {
+                Ok(result) => {
+                    w.key("ok"); w.value_bool(result.mismatches.is_empty());
+                    w.key("kind"); w.value_string("better.verify.integrity");
+                    w.key("packagesChecked"); w.value_u64(result.checked);
+                    w.key("mismatches"); w.begin_array();
+                    for m in &result.mismatches {
+                        w.begin_object();
+                        w.key("path"); w.value_string(&m.rel_path);
+                        w.key("reason"); w.value_string(if m.actual.is_some() { "modified" } else { "missing" });
+                        w.key("expected"); w.value_string(&m.expected);
+                        w.key("actual");
+                        match &m.actual {
+                            Some(hash) => w.value_string(hash),
+                            None => w.value_null(),
+                        }
+                        w.end_object();
+                    }
+                    w.end_array();
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    if !result.mismatches.is_empty() { std::process::exit(EXIT_INTEGRITY); }
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.verify.integrity");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::VerifyStore { project_root, store_root } => {
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match verify_installed(&project_root, &store_root) {
+                Ok(report) => {
+                    let ok = report.mismatched.is_empty() && report.missing.is_empty();
+                    w.key("ok"); w.value_bool(ok);
+                    w.key("kind"); w.value_string("better.verify.store");
+                    w.key("storeRoot"); w.value_string(&store_root.to_string_lossy());
+                    w.key("packagesChecked"); w.value_u64(report.packages_checked);
+                    w.key("packagesUnverified"); w.value_u64(report.packages_unverified);
+                    w.key("filesChecked"); w.value_u64(report.files_checked);
+                    w.key("counts"); w.begin_object();
+                    w.key("mismatched"); w.value_u64(report.mismatched.len() as u64);
+                    w.key("missing"); w.value_u64(report.missing.len() as u64);
+                    w.key("extra"); w.value_u64(report.extra.len() as u64);
+                    w.end_object();
+                    for (key, paths) in [("mismatched", &report.mismatched), ("missing", &report.missing), ("extra", &report.extra)] {
+                        w.key(key); w.begin_array();
+                        for path in paths { w.value_string(path); }
+                        w.end_array();
+                    }
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    if !ok { std::process::exit(EXIT_INTEGRITY); }
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.verify.store");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
 
-        Command::Workspace { project_root, subcommand, since, command_arg } => {
+        Command::Workspace { project_root, lockfile, subcommand, since, command_arg } => {
             let ws_info = match detect_workspaces(&project_root) {
                 Ok(info) => info,
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.workspace");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
                 }
             };
             match subcommand.as_str() {
                 "list" => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.workspace.list");
@@ -1670,7 +3260,7 @@ fn main() {
                 }
                 "graph" => {
                     let graph = workspace_graph(&ws_info);
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(true);
                     w.key("kind"); w.value_string("better.workspace.graph");
@@ -1698,7 +3288,7 @@ fn main() {
                     let since_ref = since.unwrap_or_else(|| "HEAD~1".into());
                     match workspace_changed(&project_root, &ws_info, &since_ref) {
                         Ok(result) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(true);
                             w.key("kind"); w.value_string("better.workspace.changed");
@@ -1714,26 +3304,67 @@ fn main() {
                             print!("{}", w.finish());
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.workspace.changed");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
+                "hoist" => match compute_hoist_plan(&project_root, &ws_info, &lockfile) {
+                    Ok(plan) => {
+                        let mut w = JsonWriter::with_indent(indent);
+                        w.begin_object();
+                        w.key("ok"); w.value_bool(true);
+                        w.key("kind"); w.value_string("better.workspace.hoist");
+                        w.key("hoisted"); w.value_u64(plan.placements.iter().filter(|p| p.hoisted).count() as u64);
+                        w.key("nested"); w.value_u64(plan.placements.iter().filter(|p| !p.hoisted).count() as u64);
+                        w.key("conflicts"); w.begin_array();
+                        for name in &plan.conflicts { w.value_string(name); }
+                        w.end_array();
+                        w.key("nohoist"); w.begin_array();
+                        for pattern in &plan.nohoist { w.value_string(pattern); }
+                        w.end_array();
+                        w.key("placements"); w.begin_array();
+                        for p in &plan.placements {
+                            w.begin_object();
+                            w.key("name"); w.value_string(&p.name);
+                            w.key("version"); w.value_string(&p.version);
+                            w.key("from"); w.value_string(&p.from);
+                            w.key("targets"); w.begin_array();
+                            for t in &p.targets { w.value_string(t); }
+                            w.end_array();
+                            w.key("hoisted"); w.value_bool(p.hoisted);
+                            w.end_object();
+                        }
+                        w.end_array();
+                        w.end_object(); w.out.push('\n');
+                        print!("{}", w.finish());
+                    }
+                    Err(reason) => {
+                        let mut w = JsonWriter::with_indent(indent);
+                        w.begin_object();
+                        w.key("ok"); w.value_bool(false);
+                        w.key("kind"); w.value_string("better.workspace.hoist");
+                        w.key("reason"); w.value_string(&reason);
+                        w.end_object(); w.out.push('\n');
+                        print!("{}", w.finish());
+                        std::process::exit(EXIT_FAILURE);
+                    }
+                },
                 "run" => {
                     let cmd = command_arg.unwrap_or_default();
                     if cmd.is_empty() {
                         eprintln!("error: workspace run requires a command");
-                        std::process::exit(2);
+                        std::process::exit(EXIT_USAGE);
                     }
                     match workspace_run(&project_root, &ws_info, &cmd) {
                         Ok(result) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(result.failure == 0);
                             w.key("kind"); w.value_string("better.workspace.run");
@@ -1752,45 +3383,228 @@ fn main() {
                             w.end_array();
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            if result.failure > 0 { std::process::exit(1); }
+                            if result.failure > 0 { std::process::exit(EXIT_FAILURE); }
                         }
                         Err(reason) => {
-                            let mut w = JsonWriter::new();
+                            let mut w = JsonWriter::with_indent(indent);
                             w.begin_object();
                             w.key("ok"); w.value_bool(false);
                             w.key("kind"); w.value_string("better.workspace.run");
                             w.key("reason"); w.value_string(&reason);
                             w.end_object(); w.out.push('\n');
                             print!("{}", w.finish());
-                            std::process::exit(1);
+                            std::process::exit(EXIT_FAILURE);
                         }
                     }
                 }
                 other => {
                     eprintln!("error: unknown workspace subcommand: {other}");
-                    std::process::exit(2);
+                    std::process::exit(EXIT_USAGE);
                 }
             }
         }
 
         Command::Sbom { project_root, lockfile, format } => {
-            match generate_sbom(&project_root, &lockfile, &format) {
+            match ProjectContext::new(&project_root).with_lockfile(&lockfile).generate_sbom(&format) {
                 Ok(report) => {
                     let output = match format.as_str() {
-                        "spdx" => write_spdx_json(&report),
-                        _ => write_cyclonedx_json(&report),
+                        "spdx" => write_spdx_json(&report, indent),
+                        _ => write_cyclonedx_json(&report, indent),
                     };
                     print!("{}", output);
                 }
                 Err(reason) => {
-                    let mut w = JsonWriter::new();
+                    let mut w = JsonWriter::with_indent(indent);
                     w.begin_object();
                     w.key("ok"); w.value_bool(false);
                     w.key("kind"); w.value_string("better.sbom");
                     w.key("reason"); w.value_string(&reason);
                     w.end_object(); w.out.push('\n');
                     print!("{}", w.finish());
-                    std::process::exit(1);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::Pack { project_root, dest, dry_run } => {
+            match pack_project(&project_root, &dest, dry_run) {
+                Ok(result) => {
+                    let mut w = JsonWriter::with_indent(indent);
+                    w.begin_object();
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.pack");
+                    w.key("name"); w.value_string(&result.name);
+                    w.key("version"); w.value_string(&result.version);
+                    w.key("dryRun"); w.value_bool(dry_run);
+                    w.key("filename");
+                    match &result.tarball {
+                        Some(p) => w.value_string(&p.to_string_lossy()),
+                        None => w.value_null(),
+                    }
+                    w.key("integrity");
+                    match &result.integrity {
+                        Some(i) => w.value_string(i),
+                        None => w.value_null(),
+                    }
+                    w.key("files"); w.begin_array();
+                    for f in &result.files {
+                        w.value_string(&f.to_string_lossy().replace('\\', "/"));
+                    }
+                    w.end_array();
+                    w.key("entryCount"); w.value_u64(result.files.len() as u64);
+                    w.key("unpackedSize"); w.value_u64(result.unpacked_size);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    let mut w = JsonWriter::with_indent(indent);
+                    w.begin_object();
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.pack");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::Resolve { lockfile, check } => {
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match check_lockfile(&lockfile) {
+                Ok((result, problems)) => {
+                    let ok = !check || problems.is_empty();
+                    w.key("ok"); w.value_bool(ok);
+                    w.key("kind"); w.value_string("better.resolve");
+                    w.key("lockfile"); w.value_string(&lockfile.to_string_lossy());
+                    w.key("packages"); w.begin_array();
+                    for pkg in &result.packages {
+                        w.begin_object();
+                        w.key("path"); w.value_string(&pkg.rel_path);
+                        w.key("name"); w.value_string(&pkg.name);
+                        w.key("version"); w.value_string(&pkg.version);
+                        w.end_object();
+                    }
+                    w.end_array();
+                    if check {
+                        write_lockfile_problems(&mut w, &problems);
+                    }
+                    write_warnings(&mut w, &lockfile_duplicate_keys(&lockfile));
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    if !ok { std::process::exit(EXIT_VIOLATION); }
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.resolve");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::Add { prefix, cache_root, link_strategy, specs, timeout, dry_run } => {
+            let layout = global_layout(prefix);
+            let mut npmrc = parse_npmrc(std::path::Path::new("."));
+            if let Some(ms) = timeout {
+                npmrc.fetch_timeout_ms = ms;
+            }
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            let mut plan = FsPlan::new(dry_run);
+            match global_add(&layout, &npmrc, &specs, &cache_root, link_strategy, &mut plan) {
+                Ok(result) => {
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.add");
+                    w.key("global"); w.value_bool(true);
+                    w.key("prefix"); w.value_string(&layout.prefix.to_string_lossy());
+                    w.key("binDir"); w.value_string(&layout.bin_dir.to_string_lossy());
+                    write_installed_packages(&mut w, &result.packages);
+                    w.key("totalPackages"); w.value_u64(result.total_packages);
+                    w.key("packagesFetched"); w.value_u64(result.fetch.packages_fetched);
+                    w.key("packagesCached"); w.value_u64(result.fetch.packages_cached);
+                    write_plan_json(&mut w, &plan);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.add");
+                    w.key("reason"); w.value_string(&reason.message);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(failure_exit_code(reason.kind));
+                }
+            }
+        }
+
+        Command::Remove { prefix, names, dry_run } => {
+            let layout = global_layout(prefix);
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            let mut plan = FsPlan::new(dry_run);
+            match global_remove(&layout, &names, &mut plan) {
+                Ok(removed) => {
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.remove");
+                    w.key("global"); w.value_bool(true);
+                    w.key("prefix"); w.value_string(&layout.prefix.to_string_lossy());
+                    write_installed_packages(&mut w, &removed);
+                    write_plan_json(&mut w, &plan);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.remove");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+        }
+
+        Command::List { global, prefix, project_root, lockfile } => {
+            let (node_modules, layout) = if global {
+                let layout = global_layout(prefix);
+                (layout.node_modules.clone(), Some(layout))
+            } else {
+                (project_root.join("node_modules"), None)
+            };
+            // Before an install there is nothing to read but the lockfile
+            let from_lockfile = !global && !node_modules.exists() && lockfile.exists();
+            let listed = if from_lockfile {
+                list_locked_packages(&lockfile)
+            } else {
+                list_top_level_packages(&node_modules)
+            };
+            let mut w = JsonWriter::with_indent(indent);
+            w.begin_object();
+            match listed {
+                Ok(packages) => {
+                    w.key("ok"); w.value_bool(true);
+                    w.key("kind"); w.value_string("better.list");
+                    w.key("global"); w.value_bool(global);
+                    w.key("source"); w.value_string(if from_lockfile { "lockfile" } else { "node_modules" });
+                    if let Some(layout) = &layout {
+                        w.key("prefix"); w.value_string(&layout.prefix.to_string_lossy());
+                        w.key("binDir"); w.value_string(&layout.bin_dir.to_string_lossy());
+                    }
+                    write_installed_packages(&mut w, &packages);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                }
+                Err(reason) => {
+                    w.key("ok"); w.value_bool(false);
+                    w.key("kind"); w.value_string("better.list");
+                    w.key("reason"); w.value_string(&reason);
+                    w.end_object(); w.out.push('\n');
+                    print!("{}", w.finish());
+                    std::process::exit(EXIT_FAILURE);
                 }
             }
         }
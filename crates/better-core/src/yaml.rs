@@ -0,0 +1,180 @@
+//! A reader for the block-style YAML subset lockfiles such as pnpm-lock.yaml
+//! are written in: mappings nested by indentation, `- item` lists, one-line
+//! `{a: b}` and `[a, b]` flow collections, and plain or quoted scalars. The
+//! result is a [`JsonValue`] tree; scalars stay strings, except `true`,
+//! `false` and `null`.
+
+use crate::json::JsonValue;
+use std::collections::BTreeMap;
+
+/// A non-blank, non-comment line: its 1-based number, indentation and text.
+struct Line<'a> {
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+pub fn parse(input: &str) -> Result<JsonValue, String> {
+    let lines: Vec<Line> = input
+        .lines()
+        .enumerate()
+        .filter_map(|(i, raw)| {
+            let raw = raw.trim_end();
+            let text = raw.trim_start();
+            if text.is_empty() || text.starts_with('#') || text == "---" {
+                return None;
+            }
+            Some(Line { number: i + 1, indent: raw.len() - text.len(), text })
+        })
+        .collect();
+    if lines.is_empty() {
+        return Ok(JsonValue::Object(BTreeMap::new()));
+    }
+    let mut pos = 0;
+    let value = block(&lines, &mut pos, lines[0].indent)?;
+    match lines.get(pos) {
+        Some(line) => Err(format!("line {}: unexpected indentation", line.number)),
+        None => Ok(value),
+    }
+}
+
+fn block(lines: &[Line], pos: &mut usize, indent: usize) -> Result<JsonValue, String> {
+    if is_list_item(lines[*pos].text) {
+        let mut items = Vec::new();
+        while let Some(line) = lines.get(*pos).filter(|l| l.indent == indent && is_list_item(l.text)) {
+            items.push(inline(line.text[1..].trim(), line.number)?);
+            *pos += 1;
+        }
+        return Ok(JsonValue::Array(items));
+    }
+    let mut map = BTreeMap::new();
+    while let Some(line) = lines.get(*pos).filter(|l| l.indent >= indent) {
+        if line.indent > indent {
+            return Err(format!("line {}: unexpected indentation", line.number));
+        }
+        let (key, rest) = split_key(line.text).ok_or_else(|| format!("line {}: expected `key: value`", line.number))?;
+        *pos += 1;
+        let value = if !rest.is_empty() {
+            inline(rest, line.number)?
+        } else {
+            match lines.get(*pos) {
+                Some(next) if next.indent > indent => block(lines, pos, next.indent)?,
+                // A list may sit at its key's own indentation
+                Some(next) if next.indent == indent && is_list_item(next.text) => block(lines, pos, indent)?,
+                _ => JsonValue::Null,
+            }
+        };
+        map.insert(key, value);
+    }
+    Ok(JsonValue::Object(map))
+}
+
+fn is_list_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// `key: rest` with the key unquoted; `rest` is empty for a `key:` line.
+fn split_key(text: &str) -> Option<(String, &str)> {
+    let (key, after) = if text.starts_with(['\'', '"']) {
+        let end = quoted_end(text)?;
+        (scalar_string(&text[..end]), &text[end..])
+    } else {
+        let colon = text.find(": ").or_else(|| text.strip_suffix(':').map(|t| t.len()))?;
+        (text[..colon].to_string(), &text[colon..])
+    };
+    let rest = after.strip_prefix(':')?;
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some((key, rest.trim()))
+}
+
+/// The byte just past the quoted scalar `text` starts with.
+fn quoted_end(text: &str) -> Option<usize> {
+    let quote = text.as_bytes()[0];
+    let bytes = text.as_bytes();
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if quote == b'"' => i += 2,
+            // '' is an escaped quote inside a single-quoted scalar
+            b'\'' if quote == b'\'' && bytes.get(i + 1) == Some(&b'\'') => i += 2,
+            b if b == quote => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+/// A value written on the same line as its key or list marker.
+fn inline(text: &str, number: usize) -> Result<JsonValue, String> {
+    if let Some(body) = text.strip_prefix('{') {
+        let body = body.strip_suffix('}').ok_or_else(|| format!("line {}: unterminated {{", number))?;
+        let mut map = BTreeMap::new();
+        for part in split_flow(body) {
+            let (key, value) = split_key(part).ok_or_else(|| format!("line {}: expected `key: value`", number))?;
+            map.insert(key, scalar(value));
+        }
+        return Ok(JsonValue::Object(map));
+    }
+    if let Some(body) = text.strip_prefix('[') {
+        let body = body.strip_suffix(']').ok_or_else(|| format!("line {}: unterminated [", number))?;
+        return Ok(JsonValue::Array(split_flow(body).into_iter().map(scalar).collect()));
+    }
+    Ok(scalar(text))
+}
+
+/// The comma-separated items of a flow collection's body, outside quotes.
+fn split_flow(body: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < body.len() {
+        let rest = &body[i..];
+        if rest.starts_with(['\'', '"']) {
+            i += quoted_end(rest).unwrap_or(rest.len());
+            continue;
+        }
+        if rest.starts_with(',') {
+            items.push(body[start..i].trim());
+            start = i + 1;
+        }
+        i += rest.chars().next().map_or(1, char::len_utf8);
+    }
+    items.push(body[start..].trim());
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+fn scalar(text: &str) -> JsonValue {
+    match text {
+        "true" => JsonValue::Bool(true),
+        "false" => JsonValue::Bool(false),
+        "null" | "~" => JsonValue::Null,
+        _ => JsonValue::String(scalar_string(text)),
+    }
+}
+
+fn scalar_string(text: &str) -> String {
+    if let Some(inner) = text.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+        return inner.replace("''", "'");
+    }
+    if let Some(inner) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        let mut out = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(other) => out.push(other),
+                None => {}
+            }
+        }
+        return out;
+    }
+    text.to_string()
+}
@@ -1,12 +1,14 @@
 use std::collections::HashSet;
 use std::path::Path;
+use std::sync::Mutex;
 
 use napi_derive::napi;
 use rayon::prelude::*;
 
 use better_core::{
-    analyze, materialize_tree, scan_tree, resolve_from_lockfile, fetch_packages,
-    LinkStrategy, MaterializeProfile,
+    analyze, materialize_tree, scan_tree_excluding_names, resolve_from_lockfile, fetch_packages_with_progress, parse_conventional_commit,
+    FetchEvent, FetchOptions,
+    FsPlan, LinkStrategy, MaterializeOptions, MaterializeProfile, SpecialFilePolicy, Warning,
 };
 
 // --- Scan ---
@@ -33,7 +35,7 @@ pub struct NapiScanResult {
 pub fn scan(root: String) -> NapiScanResult {
     let root_path = Path::new(&root);
     let mut seen: HashSet<(u64, u64)> = HashSet::new();
-    match scan_tree(root_path, &HashSet::new(), Some(&mut seen)) {
+    match scan_tree_excluding_names(root_path, &HashSet::new(), Some(&mut seen)) {
         Ok(agg) => NapiScanResult {
             ok: true,
             reason: None,
@@ -121,6 +123,30 @@ pub struct NapiNodeModules {
     pub file_count: f64,
 }
 
+#[napi(object)]
+pub struct NapiEdge {
+    pub from: String,
+    pub to: String,
+}
+
+#[napi(object)]
+pub struct NapiWarning {
+    pub code: String,
+    pub message: String,
+    pub context: Option<String>,
+}
+
+fn napi_warnings(warnings: &[Warning]) -> Vec<NapiWarning> {
+    warnings
+        .iter()
+        .map(|w| NapiWarning {
+            code: w.code.to_string(),
+            message: w.message.clone(),
+            context: w.context.clone(),
+        })
+        .collect()
+}
+
 #[napi(object)]
 pub struct NapiAnalyzeResult {
     pub ok: bool,
@@ -132,12 +158,15 @@ pub struct NapiAnalyzeResult {
     pub packages: Vec<NapiPackage>,
     pub duplicates: Vec<NapiDuplicate>,
     pub depth: Option<NapiDepth>,
+    pub edges: Vec<NapiEdge>,
+    pub cycles: Vec<Vec<String>>,
+    pub warnings: Vec<NapiWarning>,
 }
 
 #[napi(js_name = "analyze")]
 pub fn napi_analyze(root: String, include_graph: bool) -> NapiAnalyzeResult {
     let root_path = Path::new(&root);
-    match analyze(root_path, include_graph) {
+    match analyze(root_path, include_graph, None) {
         Ok(report) => NapiAnalyzeResult {
             ok: true,
             reason: None,
@@ -184,6 +213,16 @@ pub fn napi_analyze(root: String, include_graph: bool) -> NapiAnalyzeResult {
                 max_depth: report.depth.max_depth as f64,
                 p95_depth: report.depth.p95_depth as f64,
             }),
+            edges: report
+                .edges
+                .iter()
+                .map(|e| NapiEdge {
+                    from: e.from.clone(),
+                    to: e.to.clone(),
+                })
+                .collect(),
+            cycles: report.cycles.clone(),
+            warnings: napi_warnings(&report.warnings),
         },
         Err(reason) => NapiAnalyzeResult {
             ok: false,
@@ -193,6 +232,9 @@ pub fn napi_analyze(root: String, include_graph: bool) -> NapiAnalyzeResult {
             packages: vec![],
             duplicates: vec![],
             depth: None,
+            edges: vec![],
+            cycles: vec![],
+            warnings: vec![],
         },
     }
 }
@@ -205,6 +247,14 @@ pub struct NapiMaterializeOpts {
     pub link_strategy: Option<String>,
     pub jobs: Option<f64>,
     pub profile: Option<String>,
+    /// "skip" (default) or "error" for FIFOs, sockets and device nodes
+    #[napi(js_name = "specialFiles")]
+    pub special_files: Option<String>,
+    /// Keep destination files that already match the source
+    pub incremental: Option<bool>,
+    /// Scan and count only, without touching the destination
+    #[napi(js_name = "dryRun")]
+    pub dry_run: Option<bool>,
 }
 
 #[napi(object)]
@@ -216,8 +266,24 @@ pub struct NapiMaterializeStats {
     pub files_copied: f64,
     #[napi(js_name = "linkFallbackCopies")]
     pub link_fallback_copies: f64,
+    #[napi(js_name = "filesReflinked")]
+    pub files_reflinked: f64,
+    #[napi(js_name = "reflinkFallbackCopies")]
+    pub reflink_fallback_copies: f64,
+    #[napi(js_name = "bytesLinked")]
+    pub bytes_linked: f64,
+    #[napi(js_name = "bytesCopied")]
+    pub bytes_copied: f64,
+    #[napi(js_name = "bytesReflinked")]
+    pub bytes_reflinked: f64,
     pub directories: f64,
     pub symlinks: f64,
+    #[napi(js_name = "skippedSpecial")]
+    pub skipped_special: f64,
+    #[napi(js_name = "brokenSymlinks")]
+    pub broken_symlinks: f64,
+    #[napi(js_name = "filesSkipped")]
+    pub files_skipped: f64,
 }
 
 #[napi(object)]
@@ -248,6 +314,12 @@ pub struct NapiMaterializeResult {
     pub phase_durations: Option<NapiPhaseDurations>,
     #[napi(js_name = "fallbackReasons")]
     pub fallback_reasons: Option<NapiFallbackReasons>,
+    /// "hardlink" or "copy": what `auto` settled on, or the requested strategy
+    #[napi(js_name = "effectiveStrategy")]
+    pub effective_strategy: Option<String>,
+    #[napi(js_name = "strategyReason")]
+    pub strategy_reason: Option<String>,
+    pub warnings: Vec<NapiWarning>,
 }
 
 #[napi]
@@ -281,7 +353,16 @@ pub fn materialize(
         .and_then(MaterializeProfile::from_arg)
         .unwrap_or(MaterializeProfile::Auto);
 
-    match materialize_tree(src_path, dest_path, strategy, jobs, profile) {
+    let special_files = opts
+        .as_ref()
+        .and_then(|o| o.special_files.as_deref())
+        .and_then(SpecialFilePolicy::from_arg)
+        .unwrap_or_default();
+
+    let incremental = opts.as_ref().and_then(|o| o.incremental).unwrap_or(false);
+    let dry_run = opts.as_ref().and_then(|o| o.dry_run).unwrap_or(false);
+
+    match materialize_tree(src_path, dest_path, strategy, jobs, profile, MaterializeOptions { special_files, incremental, dry_run, ..MaterializeOptions::default() }) {
         Ok(report) => NapiMaterializeResult {
             ok: true,
             reason: None,
@@ -290,8 +371,16 @@ pub fn materialize(
                 files_linked: report.stats.files_linked as f64,
                 files_copied: report.stats.files_copied as f64,
                 link_fallback_copies: report.stats.link_fallback_copies as f64,
+                files_reflinked: report.stats.files_reflinked as f64,
+                reflink_fallback_copies: report.stats.reflink_fallback_copies as f64,
+                bytes_linked: report.stats.bytes_linked as f64,
+                bytes_copied: report.stats.bytes_copied as f64,
+                bytes_reflinked: report.stats.bytes_reflinked as f64,
                 directories: report.stats.directories as f64,
                 symlinks: report.stats.symlinks as f64,
+                skipped_special: report.stats.skipped_special as f64,
+                broken_symlinks: report.stats.broken_symlinks as f64,
+                files_skipped: report.stats.files_skipped as f64,
             }),
             phase_durations: Some(NapiPhaseDurations {
                 scan_ms: report.phases.scan_ms as f64,
@@ -304,6 +393,9 @@ pub fn materialize(
                 exdev: report.stats.fallback_exdev as f64,
                 other: report.stats.fallback_other as f64,
             }),
+            effective_strategy: Some(report.strategy.as_str().to_string()),
+            strategy_reason: Some(report.strategy_reason.to_string()),
+            warnings: napi_warnings(&report.warnings),
         },
         Err(reason) => NapiMaterializeResult {
             ok: false,
@@ -311,6 +403,9 @@ pub fn materialize(
             stats: None,
             phase_durations: None,
             fallback_reasons: None,
+            effective_strategy: None,
+            strategy_reason: None,
+            warnings: vec![],
         },
     }
 }
@@ -370,7 +465,43 @@ pub fn resolve(lockfile_path: String) -> NapiResolveResult {
 
 #[napi(object)]
 pub struct NapiFetchOpts {
+    /// Downloads in flight at once (default: twice the CPU count, at most
+    /// 16); 0 for no limit.
     pub jobs: Option<f64>,
+    /// Collect every package's progress events into the result.
+    pub events: Option<bool>,
+    /// Download retries after the first attempt (default 3).
+    pub retries: Option<f64>,
+    /// Only use the cache; an uncached package fails the fetch.
+    pub offline: Option<bool>,
+}
+
+#[napi(object)]
+pub struct NapiFetchEvent {
+    /// started, downloaded, cached, verified or extracted
+    pub kind: String,
+    pub name: String,
+    pub version: Option<String>,
+    pub bytes: Option<f64>,
+    /// Cached and extracted end a package's events
+    pub terminal: bool,
+}
+
+impl From<FetchEvent> for NapiFetchEvent {
+    fn from(event: FetchEvent) -> Self {
+        let (version, bytes) = match &event {
+            FetchEvent::Started { version, .. } => (Some(version.clone()), None),
+            FetchEvent::Downloaded { bytes, .. } => (None, Some(*bytes as f64)),
+            _ => (None, None),
+        };
+        NapiFetchEvent {
+            kind: event.as_str().to_string(),
+            name: event.name().to_string(),
+            version,
+            bytes,
+            terminal: event.is_terminal(),
+        }
+    }
 }
 
 #[napi(object)]
@@ -383,14 +514,26 @@ pub struct NapiFetchResult {
     pub packages_cached: f64,
     #[napi(js_name = "bytesDownloaded")]
     pub bytes_downloaded: f64,
+    /// With `events: true`, every progress event in the order it happened;
+    /// events of different packages interleave.
+    pub events: Option<Vec<NapiFetchEvent>>,
 }
 
 #[napi]
 pub fn fetch_and_extract(
     lockfile_path: String,
     cache_dir: String,
-    _opts: Option<NapiFetchOpts>,
+    opts: Option<NapiFetchOpts>,
 ) -> NapiFetchResult {
+    let collect = opts.as_ref().and_then(|o| o.events).unwrap_or(false);
+    let mut options = FetchOptions::default();
+    if let Some(retries) = opts.as_ref().and_then(|o| o.retries) {
+        options.retries = retries.max(0.0) as u32;
+    }
+    options.offline = opts.as_ref().and_then(|o| o.offline).unwrap_or(false);
+    if let Some(jobs) = opts.as_ref().and_then(|o| o.jobs) {
+        options.max_concurrent_downloads = jobs.max(0.0) as usize;
+    }
     let lockfile = Path::new(&lockfile_path);
     let cache = Path::new(&cache_dir);
 
@@ -404,25 +547,37 @@ pub fn fetch_and_extract(
                 packages_fetched: 0.0,
                 packages_cached: 0.0,
                 bytes_downloaded: 0.0,
+                events: None,
             }
         }
     };
 
     // Fetch packages
-    match fetch_packages(&packages, cache) {
+    let events: Mutex<Vec<FetchEvent>> = Mutex::new(Vec::new());
+    let fetched = fetch_packages_with_progress(&packages, cache, None, &options, |event| {
+        if collect {
+            if let Ok(mut guard) = events.lock() {
+                guard.push(event);
+            }
+        }
+    });
+    let events = collect.then(|| events.into_inner().unwrap_or_default().into_iter().map(NapiFetchEvent::from).collect());
+    match fetched {
         Ok(fetch_result) => NapiFetchResult {
             ok: true,
             reason: None,
             packages_fetched: fetch_result.packages_fetched as f64,
             packages_cached: fetch_result.packages_cached as f64,
             bytes_downloaded: fetch_result.bytes_downloaded as f64,
+            events,
         },
         Err(reason) => NapiFetchResult {
             ok: false,
-            reason: Some(reason),
+            reason: Some(reason.message),
             packages_fetched: 0.0,
             packages_cached: 0.0,
             bytes_downloaded: 0.0,
+            events,
         },
     }
 }
@@ -510,7 +665,7 @@ pub fn materialize_batch(
             }
 
             // Fallback: traditional scan+mkdir+hardlink
-            (false, materialize_tree(src_path, dest_path, strategy, jobs_per_pkg, profile))
+            (false, materialize_tree(src_path, dest_path, strategy, jobs_per_pkg, profile, MaterializeOptions::default()))
         })
         .collect();
 
@@ -554,3 +709,102 @@ pub fn materialize_batch(
         failed: failed as f64,
     }
 }
+
+// --- Git Hooks ---
+
+#[napi(object)]
+pub struct NapiHook {
+    #[napi(js_name = "type")]
+    pub hook_type: String,
+    pub action: String,
+}
+
+#[napi(object)]
+pub struct NapiHooksInstallResult {
+    pub ok: bool,
+    pub reason: Option<String>,
+    #[napi(js_name = "hooksInstalled")]
+    pub hooks_installed: f64,
+    #[napi(js_name = "fromConfig")]
+    pub from_config: bool,
+    pub hooks: Vec<NapiHook>,
+}
+
+#[napi]
+pub fn hooks_install(project_root: String, dry_run: Option<bool>) -> NapiHooksInstallResult {
+    let mut plan = FsPlan::new(dry_run.unwrap_or(false));
+    match better_core::hooks_install(Path::new(&project_root), &mut plan) {
+        Ok(result) => NapiHooksInstallResult {
+            ok: true,
+            reason: None,
+            hooks_installed: result.hooks_installed as f64,
+            from_config: result.from_config,
+            hooks: result
+                .hooks
+                .into_iter()
+                .map(|(hook_type, action)| NapiHook { hook_type, action })
+                .collect(),
+        },
+        Err(reason) => NapiHooksInstallResult {
+            ok: false,
+            reason: Some(reason),
+            hooks_installed: 0.0,
+            from_config: false,
+            hooks: vec![],
+        },
+    }
+}
+
+#[napi(object)]
+pub struct NapiHooksUninstallResult {
+    pub ok: bool,
+    pub reason: Option<String>,
+    pub removed: Vec<String>,
+    pub kept: Vec<String>,
+}
+
+#[napi]
+pub fn hooks_uninstall(project_root: String, dry_run: Option<bool>) -> NapiHooksUninstallResult {
+    let mut plan = FsPlan::new(dry_run.unwrap_or(false));
+    match better_core::hooks_uninstall(Path::new(&project_root), &mut plan) {
+        Ok(result) => NapiHooksUninstallResult { ok: true, reason: None, removed: result.removed, kept: result.kept },
+        Err(reason) => NapiHooksUninstallResult { ok: false, reason: Some(reason), removed: vec![], kept: vec![] },
+    }
+}
+
+#[napi(object)]
+pub struct NapiCommitValidation {
+    pub ok: bool,
+    /// Stable error id, see `better_core::CommitMessageError`
+    pub code: Option<String>,
+    pub reason: Option<String>,
+    #[napi(js_name = "type")]
+    pub commit_type: Option<String>,
+    pub scope: Option<String>,
+    pub breaking: bool,
+    pub description: Option<String>,
+}
+
+#[napi]
+pub fn validate_conventional_commit(message: String) -> NapiCommitValidation {
+    match parse_conventional_commit(&message) {
+        Ok(commit) => NapiCommitValidation {
+            ok: true,
+            code: None,
+            reason: None,
+            commit_type: Some(commit.commit_type),
+            scope: commit.scope,
+            breaking: commit.breaking,
+            description: Some(commit.description),
+        },
+        Err(err) => NapiCommitValidation {
+            ok: false,
+            code: Some(err.code.to_string()),
+            reason: Some(err.message),
+            commit_type: None,
+            scope: None,
+            breaking: false,
+            description: None,
+        },
+    }
+}
@@ -768,6 +768,14 @@ Workspace options:
 
   // Load and report active overrides/resolutions
   const overridesResult = await loadOverrides(projectRoot);
+  if (overridesResult.errors?.length > 0) {
+    const err = new Error(
+      `Invalid overrides in package.json:\n${overridesResult.errors.map(e => e.message).join("\n")}`
+    );
+    err.overridesResult = overridesResult;
+    err.exitCode = 1;
+    throw err;
+  }
   let overridesValidation = null;
   if (overridesResult.count > 0) {
     progress(`overrides detected: ${overridesResult.count} override(s) in ${overridesResult.format} format`);
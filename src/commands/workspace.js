@@ -7,6 +7,7 @@ import { printJson, printText } from "../lib/output.js";
 import { getRuntimeConfig } from "../lib/config.js";
 import { runCommand } from "../lib/spawn.js";
 import { childLogger } from "../lib/log.js";
+import { findBetterCore, runBetterCoreWorkspaceHoist } from "../lib/core.js";
 
 /**
  * Format a table row with aligned columns.
@@ -197,6 +198,7 @@ export async function cmdWorkspace(argv) {
   better workspace graph [--json] [--project-root PATH]
   better workspace changed [--since <ref>] [--json] [--project-root PATH]
   better workspace run <command> [--concurrency N] [--json] [--project-root PATH]
+  better workspace hoist [--lockfile PATH] [--json] [--project-root PATH]
 `);
     return;
   }
@@ -207,7 +209,8 @@ export async function cmdWorkspace(argv) {
       json: { type: "boolean", default: runtime.json === true },
       "project-root": { type: "string" },
       since: { type: "string", default: "HEAD" },
-      concurrency: { type: "string", default: "1" }
+      concurrency: { type: "string", default: "1" },
+      lockfile: { type: "string" }
     },
     allowPositionals: true,
     strict: false
@@ -507,5 +510,33 @@ export async function cmdWorkspace(argv) {
     return;
   }
 
-  throw new Error(`Unknown workspace subcommand '${sub}'. Expected list|info|graph|changed|run.`);
+  if (sub === "hoist") {
+    const corePath = await findBetterCore();
+    if (!corePath) {
+      throw new Error("better workspace hoist needs better-core (set BETTER_CORE_PATH or build via `npm run core:build`)");
+    }
+    const lockfile = values.lockfile ? path.resolve(values.lockfile) : undefined;
+    const plan = await runBetterCoreWorkspaceHoist(corePath, projectRoot, { lockfile });
+    const out = { ...plan, schemaVersion: 1, projectRoot };
+
+    if (values.json) {
+      printJson(out);
+    } else if (!plan.ok) {
+      printText(`better workspace hoist: ${plan.reason}`);
+    } else {
+      const rows = plan.placements.map(p => [p.name, p.version, p.targets.join(", ")]);
+      const table = formatTable(rows, ["Name", "Version", "Path"]);
+      const lines = [
+        `better workspace hoist\n\n${table}\n`,
+        `- hoisted: ${plan.hoisted}`,
+        `- nested: ${plan.nested}`
+      ];
+      if (plan.conflicts.length > 0) lines.push(`- conflicts: ${plan.conflicts.join(", ")}`);
+      printText(lines.join("\n"));
+    }
+    if (!plan.ok) process.exitCode = 1;
+    return;
+  }
+
+  throw new Error(`Unknown workspace subcommand '${sub}'. Expected list|info|graph|changed|run|hoist.`);
 }
@@ -217,6 +217,29 @@ export async function runBetterCoreInstall(corePath, projectRoot, opts = {}) {
   return parsed;
 }
 
+export async function runBetterCoreWorkspaceHoist(corePath, projectRoot, opts = {}) {
+  const args = ["workspace", "hoist", "--project-root", projectRoot];
+  if (opts.lockfile) args.push("--lockfile", String(opts.lockfile));
+  const res = await runCommand(corePath, args, {
+    cwd: projectRoot,
+    passthroughStdio: false,
+    captureLimitBytes: 50 * 1024 * 1024
+  });
+  let parsed = null;
+  try {
+    parsed = JSON.parse(res.stdout);
+  } catch {
+    // ignore parse failure, surface process error below
+  }
+  // A plan that cannot be made still reports ok:false with a reason
+  if (!parsed || typeof parsed !== "object") {
+    const err = new Error(`better-core workspace hoist failed (exit ${res.exitCode})`);
+    err.core = { ...res, parsed };
+    throw err;
+  }
+  return parsed;
+}
+
 export async function runBetterCoreMaterialize(corePath, srcDir, destDir, opts = {}) {
   const args = ["materialize", "--src", srcDir, "--dest", destDir];
   if (opts.linkStrategy) args.push("--link-strategy", String(opts.linkStrategy));
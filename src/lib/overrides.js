@@ -4,9 +4,12 @@ import path from "node:path";
 /**
  * Read overrides/resolutions from package.json.
  * Supports npm overrides, yarn resolutions, and pnpm overrides.
+ * npm `$name` values are resolved to the root's own spec for `name`; a
+ * reference to something that is not a direct dependency makes `ok` false
+ * and is listed in `errors`.
  *
  * @param {string} projectRoot
- * @returns {{ ok: boolean, overrides: Object, format: string, count: number }}
+ * @returns {{ ok: boolean, overrides: Object, format: string, count: number, errors?: Object[] }}
  */
 export async function loadOverrides(projectRoot) {
   let pkg;
@@ -19,8 +22,23 @@ export async function loadOverrides(projectRoot) {
 
   // npm overrides (package.json "overrides" field)
   if (pkg.overrides && typeof pkg.overrides === "object") {
-    const flat = flattenOverrides(pkg.overrides);
-    return { ok: true, overrides: pkg.overrides, flat, format: "npm", count: flat.length };
+    const rootDeps = {
+      ...pkg.peerDependencies,
+      ...pkg.optionalDependencies,
+      ...pkg.devDependencies,
+      ...pkg.dependencies
+    };
+    const errors = [];
+    const flat = flattenOverrides(pkg.overrides, rootDeps, errors);
+    return {
+      ok: errors.length === 0,
+      ...(errors.length > 0 ? { reason: "invalid_override_reference" } : {}),
+      overrides: pkg.overrides,
+      flat,
+      format: "npm",
+      count: flat.length,
+      errors
+    };
   }
 
   // yarn resolutions (package.json "resolutions" field)
@@ -47,30 +65,53 @@ export async function loadOverrides(projectRoot) {
 }
 
 /**
- * Flatten npm-style nested overrides into a list of { pattern, version } entries.
+ * Flatten npm-style nested overrides into a list of { pattern, path, version } entries.
+ * `path` holds the keys from the outermost parent down to the overridden package.
+ * `$name` values are replaced by `rootDeps[name]`; unresolvable ones go to `errors`.
  */
-function flattenOverrides(overrides, parentPath = "") {
+function flattenOverrides(overrides, rootDeps = {}, errors = [], parentPath = []) {
   const result = [];
   for (const [key, value] of Object.entries(overrides)) {
-    const currentPath = parentPath ? `${parentPath} > ${key}` : key;
-    if (typeof value === "string") {
-      result.push({ pattern: currentPath, version: value, source: "overrides" });
-    } else if (typeof value === "object" && value !== null) {
-      // npm overrides can have a "." key for the direct version
-      if (typeof value["."] === "string") {
-        result.push({ pattern: currentPath, version: value["."], source: "overrides" });
-      }
-      // Recurse for nested overrides
-      const nested = flattenOverrides(value, currentPath);
-      result.push(...nested.filter(n => n.pattern !== currentPath));
+    if (key === ".") continue;
+    const currentPath = [...parentPath, key];
+    const pattern = currentPath.join(" > ");
+    // npm overrides can have a "." key for the direct version
+    const direct = typeof value === "string" ? value : value?.["."];
+    if (typeof direct === "string") {
+      const entry = resolveReference({ pattern, path: currentPath, version: direct, source: "overrides" }, rootDeps);
+      if (entry.issue) errors.push(entry);
+      else result.push(entry);
+    }
+    if (typeof value === "object" && value !== null) {
+      result.push(...flattenOverrides(value, rootDeps, errors, currentPath));
     }
   }
   return result;
 }
 
+/**
+ * Replace a `$name` version with the root package's spec for `name`.
+ */
+function resolveReference(entry, rootDeps) {
+  if (!entry.version.startsWith("$")) return entry;
+  const ref = entry.version.slice(1);
+  const spec = Object.hasOwn(rootDeps, ref) ? rootDeps[ref] : undefined;
+  if (typeof spec !== "string") {
+    return {
+      ...entry,
+      issue: "unresolved_reference",
+      message: `Override '${entry.pattern}' references $${ref}, which is not a direct dependency`
+    };
+  }
+  return { ...entry, version: spec, reference: entry.version };
+}
+
 /**
  * Validate overrides against actual lockfile packages.
- * Checks that override targets exist and versions match.
+ * Checks that override targets exist and versions match. A nested override
+ * only covers the copy its parent actually resolves to (walking up the
+ * node_modules chain from the parent, as Node does), so a pin under
+ * `express` says nothing about a top-level copy other packages use.
  *
  * @param {Object[]} flatOverrides - from loadOverrides().flat
  * @param {Object} lockPackages - packages from package-lock.json
@@ -81,9 +122,8 @@ export function validateOverrides(flatOverrides, lockPackages) {
   const warnings = [];
 
   for (const override of flatOverrides) {
-    // Extract the package name from the pattern
-    const pkgName = extractPackageName(override.pattern);
-    if (!pkgName) {
+    const chain = (override.path ?? override.pattern.split(">")).map(s => parseOverrideKey(s.trim()));
+    if (chain.some(c => !c)) {
       warnings.push({
         ...override,
         issue: "unparseable_pattern",
@@ -91,26 +131,25 @@ export function validateOverrides(flatOverrides, lockPackages) {
       });
       continue;
     }
+    const pkgName = chain[chain.length - 1].name;
 
-    // Check if the package exists in the lockfile
-    const lockKey = `node_modules/${pkgName}`;
-    const lockEntry = lockPackages?.[lockKey];
-
-    if (!lockEntry) {
+    const targets = resolveOverrideTargets(chain, lockPackages ?? {});
+    if (targets.length === 0) {
+      const scope = chain.length > 1 ? ` under '${chain.slice(0, -1).map(c => c.name).join(" > ")}'` : "";
       warnings.push({
         ...override,
         package: pkgName,
         issue: "package_not_in_lockfile",
-        message: `Override target '${pkgName}' not found in lockfile`
+        message: `Override target '${pkgName}'${scope} not found in lockfile`
       });
       continue;
     }
 
     // Check if the version matches the override
-    const actualVersion = lockEntry.version;
-    const overrideVersion = override.version.replace(/^\^|~|>=|<=|>|<|=/, "");
+    const mismatched = targets.filter(key => !versionMatches(lockPackages[key].version, override.version));
+    const actualVersion = lockPackages[(mismatched[0] ?? targets[0])].version;
 
-    if (actualVersion === overrideVersion) {
+    if (mismatched.length === 0) {
       valid.push({
         ...override,
         package: pkgName,
@@ -122,8 +161,9 @@ export function validateOverrides(flatOverrides, lockPackages) {
         ...override,
         package: pkgName,
         actualVersion,
+        lockPath: mismatched[0],
         issue: "version_mismatch",
-        message: `Override specifies '${override.version}' but lockfile has '${actualVersion}'`
+        message: `Override specifies '${override.version}' but lockfile has '${actualVersion}' at ${mismatched[0]}`
       });
     }
   }
@@ -131,21 +171,70 @@ export function validateOverrides(flatOverrides, lockPackages) {
   return { valid, warnings };
 }
 
+function versionMatches(actual, wanted) {
+  return actual === wanted.replace(/^\^|~|>=|<=|>|<|=/, "");
+}
+
 /**
- * Extract the base package name from an override pattern.
- * Handles patterns like "lodash", "@scope/pkg", "express > lodash"
+ * Lockfile keys of every package an override chain reaches. The first link
+ * matches any copy of the package; each further link is the copy its parent
+ * resolves when the parent declares it as a dependency.
  */
-function extractPackageName(pattern) {
-  // For nested patterns like "express > lodash", take the last segment
-  const parts = pattern.split(">").map(s => s.trim());
-  const last = parts[parts.length - 1];
-  if (!last) return null;
+function resolveOverrideTargets(chain, lockPackages) {
+  const nameOf = (key) => lockPackages[key]?.name ?? key.slice(key.lastIndexOf("node_modules/") + "node_modules/".length);
+  const matches = (key, link) => nameOf(key) === link.name && selectorMatches(lockPackages[key]?.version, link.selector);
 
+  let current = Object.keys(lockPackages).filter(key => key !== "" && matches(key, chain[0]));
+  for (const link of chain.slice(1)) {
+    const next = new Set();
+    for (const parent of current) {
+      const entry = lockPackages[parent];
+      const declared = [entry.dependencies, entry.optionalDependencies, entry.peerDependencies]
+        .some(deps => deps && Object.hasOwn(deps, link.name));
+      if (!declared) continue;
+      const resolved = resolveFrom(parent, link.name, lockPackages);
+      if (resolved && matches(resolved, link)) next.add(resolved);
+    }
+    current = [...next];
+  }
+  return current;
+}
+
+/**
+ * Node's lookup: `<from>/node_modules/<name>`, then each ancestor's node_modules.
+ */
+function resolveFrom(from, name, lockPackages) {
+  let base = from;
+  while (true) {
+    const key = base ? `${base}/node_modules/${name}` : `node_modules/${name}`;
+    if (lockPackages[key]) return key;
+    if (!base) return null;
+    const i = base.lastIndexOf("/node_modules/");
+    base = i === -1 ? "" : base.slice(0, i);
+  }
+}
+
+/**
+ * Split an override key into the package name and an optional version
+ * selector: "lodash", "@scope/pkg", "foo@1" or "@scope/pkg@2.1".
+ * Selectors match by version prefix ("1" matches 1.x.y).
+ */
+function parseOverrideKey(key) {
+  const at = key.indexOf("@", 1);
+  const name = at === -1 ? key : key.slice(0, at);
+  const selector = at === -1 ? null : key.slice(at + 1).replace(/^[\^~=v]+/, "");
+  if (!name) return null;
   // Handle scoped packages
-  if (last.startsWith("@") && last.includes("/")) return last;
+  if (name.startsWith("@") && !/^@[a-z0-9._-]+\/[a-z0-9._-]+$/i.test(name)) return null;
   // Handle simple package names
-  if (/^[a-z0-9@][a-z0-9._-]*$/i.test(last)) return last;
-  return null;
+  if (!name.startsWith("@") && !/^[a-z0-9][a-z0-9._-]*$/i.test(name)) return null;
+  return { name, selector };
+}
+
+function selectorMatches(version, selector) {
+  if (!selector) return true;
+  if (typeof version !== "string") return false;
+  return version === selector || version.startsWith(`${selector}.`);
 }
 
 /**
@@ -0,0 +1,142 @@
+import test from "node:test";
+import assert from "node:assert/strict";
+import path from "node:path";
+import { makeTempDir, rmrf } from "./helpers.js";
+import { ensureCoreBuilt, execFileAsync, exists, hasTar, makeTarball, runCore, startRegistry } from "./core-helpers.js";
+
+const skip = !(await hasTar()) || process.platform === "win32";
+
+async function startCliRegistry(dir) {
+  const manifests = {
+    "cli@1.0.0": { bin: { hello: "bin/hello.js" }, dependencies: { dep: "^1.0.0", shared: "^2.0.0" } },
+    "dep@1.0.0": { dependencies: { shared: "^1.0.0" } },
+    "dep@1.2.0": { dependencies: { shared: "^1.0.0" } }
+  };
+  const tarball = async (key, files) => {
+    const [name, version] = key.split("@");
+    return makeTarball(dir, { name, version, ...manifests[key] }, files);
+  };
+  const packages = {
+    "cli@1.0.0": await tarball("cli@1.0.0", {
+      "bin/hello.js": "#!/usr/bin/env node\nconsole.log(require('dep'), require('shared'));\n"
+    }),
+    "dep@1.0.0": await tarball("dep@1.0.0", { "index.js": "module.exports = 'dep@1.0.0+' + require('shared');\n" }),
+    "dep@1.2.0": await tarball("dep@1.2.0", { "index.js": "module.exports = 'dep@1.2.0+' + require('shared');\n" }),
+    "shared@1.0.0": await tarball("shared@1.0.0", { "index.js": "module.exports = 'shared@1.0.0';\n" }),
+    "shared@2.0.0": await tarball("shared@2.0.0", { "index.js": "module.exports = 'shared@2.0.0';\n" })
+  };
+  return startRegistry(packages, manifests);
+}
+
+test("add --global installs into the prefix and links bins into its bin dir", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-global-");
+  const registry = await startCliRegistry(dir);
+  try {
+    const prefix = path.join(dir, "prefix");
+    const options = { env: { ...process.env, HOME: dir, NPM_CONFIG_REGISTRY: registry.url } };
+    const common = ["--prefix", prefix, "--cache-root", path.join(dir, "cache")];
+
+    const { code, json } = await runCore(corePath, ["add", "--global", "cli@^1.0.0", ...common], options);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(json.kind, "better.add");
+    assert.equal(json.binDir, path.join(prefix, "bin"));
+    assert.deepEqual(json.packages, [{ name: "cli", version: "1.0.0", bins: ["hello"] }]);
+    assert.equal(json.totalPackages, 4);
+
+    // Dependencies live inside the package, nesting the conflicting `shared`
+    const pkgDir = path.join(prefix, "lib", "node_modules", "cli");
+    assert.equal(await exists(path.join(pkgDir, "node_modules", "dep", "node_modules", "shared")), true);
+    assert.equal(await exists(path.join(prefix, "lib", "node_modules", "dep")), false);
+    const { stdout } = await execFileAsync(path.join(prefix, "bin", "hello"));
+    assert.equal(stdout.trim(), "dep@1.2.0+shared@1.0.0 shared@2.0.0");
+
+    const listed = await runCore(corePath, ["list", "--global", "--prefix", prefix]);
+    assert.equal(listed.code, 0, JSON.stringify(listed.json));
+    assert.deepEqual(listed.json.packages, [{ name: "cli", version: "1.0.0", bins: ["hello"] }]);
+
+    const removed = await runCore(corePath, ["remove", "--global", "cli", "--prefix", prefix]);
+    assert.equal(removed.code, 0, JSON.stringify(removed.json));
+    assert.equal(await exists(path.join(prefix, "bin", "hello")), false);
+    assert.equal(await exists(pkgDir), false);
+    const empty = await runCore(corePath, ["list", "--global", "--prefix", prefix]);
+    assert.deepEqual(empty.json.packages, []);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("add --global and remove --global --dry-run plan their changes without making them", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-global-dry-run-");
+  const registry = await startCliRegistry(dir);
+  try {
+    const prefix = path.join(dir, "prefix");
+    const cacheRoot = path.join(dir, "cache");
+    const options = { env: { ...process.env, HOME: dir, NPM_CONFIG_REGISTRY: registry.url } };
+    const common = ["--prefix", prefix, "--cache-root", cacheRoot];
+    const nm = path.join(prefix, "lib", "node_modules");
+    const actions = (json) => json.plan.map((c) => `${c.action} ${path.relative(dir, c.path)}`);
+
+    const dry = await runCore(corePath, ["add", "--global", "cli@^1.0.0", ...common, "--dry-run"], options);
+    assert.equal(dry.code, 0, JSON.stringify(dry.json));
+    assert.equal(dry.json.dryRun, true);
+    // Bins come from the registry manifest, since nothing is unpacked
+    assert.deepEqual(dry.json.packages, [{ name: "cli", version: "1.0.0", bins: ["hello"] }]);
+    const planned = actions(dry.json);
+    assert.deepEqual(planned.filter((a) => !a.startsWith("fetch ")), [
+      "mkdir prefix/lib/node_modules",
+      "mkdir prefix/bin",
+      "materialize prefix/lib/node_modules/cli",
+      "materialize prefix/lib/node_modules/cli/node_modules/dep",
+      "materialize prefix/lib/node_modules/cli/node_modules/dep/node_modules/shared",
+      "materialize prefix/lib/node_modules/cli/node_modules/shared",
+      "link prefix/bin/hello"
+    ]);
+    assert.equal(planned.filter((a) => a.startsWith("fetch cache/")).length, 4);
+    assert.equal(await exists(prefix), false);
+    assert.equal(await exists(cacheRoot), false);
+
+    const added = await runCore(corePath, ["add", "--global", "cli@^1.0.0", ...common], options);
+    assert.equal(added.code, 0, JSON.stringify(added.json));
+    assert.equal(added.json.dryRun, false);
+    assert.ok(actions(added.json).includes("link prefix/bin/hello"));
+
+    const dryRemove = await runCore(corePath, ["remove", "--global", "cli", "--prefix", prefix, "--dry-run"]);
+    assert.equal(dryRemove.code, 0, JSON.stringify(dryRemove.json));
+    assert.deepEqual(actions(dryRemove.json), ["remove prefix/bin/hello", "remove prefix/lib/node_modules/cli"]);
+    assert.equal(await exists(path.join(prefix, "bin", "hello")), true);
+    assert.equal(await exists(path.join(nm, "cli")), true);
+
+    // Re-adding an installed package replaces it: its old bins and directory go first
+    const dryReadd = await runCore(corePath, ["add", "--global", "cli", ...common, "--dry-run"], options);
+    assert.deepEqual(actions(dryReadd.json).slice(0, 3), [
+      "remove prefix/bin/hello",
+      "remove prefix/lib/node_modules/cli",
+      "materialize prefix/lib/node_modules/cli"
+    ]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("add without --global is rejected and remove --global reports unknown packages", async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-global-errors-");
+  try {
+    await assert.rejects(execFileAsync(corePath, ["add", "cli"]), (err) => /add requires --global/.test(err.stderr));
+    const { code, json } = await runCore(corePath, ["remove", "--global", "missing", "--prefix", dir]);
+    assert.equal(code, 1);
+    assert.equal(json.reason, "missing is not installed globally");
+  } finally {
+    await rmrf(dir);
+  }
+});
@@ -0,0 +1,130 @@
+import test from "node:test";
+import { execFile } from "node:child_process";
+import { promisify } from "node:util";
+import path from "node:path";
+import fs from "node:fs/promises";
+import http from "node:http";
+import crypto from "node:crypto";
+import { writeFile, writeJson } from "./helpers.js";
+
+export const execFileAsync = promisify(execFile);
+
+export async function exists(p) {
+  try {
+    await fs.access(p);
+    return true;
+  } catch {
+    return false;
+  }
+}
+
+export async function ensureCoreBuilt() {
+  const corePath = path.resolve(process.cwd(), "crates", "target", "debug", process.platform === "win32" ? "better-core.exe" : "better-core");
+  if (await exists(corePath)) return corePath;
+  try {
+    await execFileAsync("cargo", ["build", "--manifest-path", "crates/Cargo.toml", "-p", "better-core"], {
+      cwd: process.cwd(),
+      timeout: 120_000
+    });
+  } catch (err) {
+    test.skip(`cargo build not available: ${err?.message ?? err}`);
+    return null;
+  }
+  if (await exists(corePath)) return corePath;
+  throw new Error("better-core build did not produce expected binary");
+}
+
+// Run better-core and parse its JSON stdout, also for non-zero exits.
+export async function runCore(corePath, args, options = {}) {
+  try {
+    const { stdout } = await execFileAsync(corePath, args, { timeout: 60_000, ...options });
+    return { code: 0, json: JSON.parse(stdout) };
+  } catch (err) {
+    if (typeof err.code !== "number") throw err;
+    return { code: err.code, json: JSON.parse(err.stdout) };
+  }
+}
+
+export async function startServer(handler) {
+  const server = http.createServer(handler);
+  await new Promise((resolve) => server.listen(0, "127.0.0.1", resolve));
+  const url = `http://127.0.0.1:${server.address().port}`;
+  return { url, close: () => new Promise((resolve) => server.close(resolve)) };
+}
+
+export function sha512Integrity(buf) {
+  return `sha512-${crypto.createHash("sha512").update(buf).digest("base64")}`;
+}
+
+export async function hasTar() {
+  try {
+    await execFileAsync("tar", ["--version"]);
+    return true;
+  } catch {
+    return false;
+  }
+}
+
+// Build an npm-style tarball (files under package/) and return its bytes.
+export async function makeTarball(workDir, pkg, files = {}) {
+  const root = path.join(workDir, `tarball-${pkg.name.replace("/", "+")}-${pkg.version}`);
+  await writeJson(path.join(root, "package", "package.json"), pkg);
+  for (const [rel, content] of Object.entries(files)) {
+    await writeFile(path.join(root, "package", rel), content);
+  }
+  const tgz = `${root}.tgz`;
+  await execFileAsync("tar", ["-czf", tgz, "-C", root, "package"]);
+  return await fs.readFile(tgz);
+}
+
+// Serve tarballs at the registry layout /<name>/-/<basename>-<version>.tgz,
+// plus a packument per name at /<name> (scoped names as @scope%2Fname).
+// `packages` maps "name@version" -> tarball bytes; `manifests` optionally maps
+// "name@version" -> extra packument fields such as dependencies or bin. The
+// last version listed for a name is its `latest` tag.
+// `requests` and `auth` record each request's URL, Authorization header and
+// full header set.
+export async function startRegistry(packages, manifests = {}) {
+  const byPath = new Map();
+  const requests = [];
+  const auth = [];
+  const server = await startServer((req, res) => {
+    requests.push(req.url);
+    auth.push({ url: req.url, authorization: req.headers.authorization ?? null, headers: req.headers });
+    const body = byPath.get(req.url);
+    if (!body) {
+      res.writeHead(404);
+      res.end();
+      return;
+    }
+    const json = !req.url.endsWith(".tgz");
+    res.writeHead(200, { "content-type": json ? "application/json" : "application/octet-stream" });
+    res.end(body);
+  });
+  const tarballUrl = (name, version) => `${server.url}/${name}/-/${name.split("/").pop()}-${version}.tgz`;
+  const packuments = new Map();
+  for (const [key, body] of Object.entries(packages)) {
+    const at = key.lastIndexOf("@");
+    const name = key.slice(0, at);
+    const version = key.slice(at + 1);
+    byPath.set(new URL(tarballUrl(name, version)).pathname, body);
+    const packument = packuments.get(name) ?? { name, "dist-tags": {}, versions: {} };
+    packument.versions[version] = {
+      name,
+      version,
+      ...manifests[key],
+      dist: { tarball: tarballUrl(name, version), integrity: sha512Integrity(body) }
+    };
+    packument["dist-tags"].latest = version;
+    packuments.set(name, packument);
+  }
+  for (const [name, packument] of packuments) {
+    byPath.set(`/${name.replace("/", "%2F")}`, JSON.stringify(packument));
+  }
+  return { ...server, requests, auth, tarballUrl };
+}
+
+// package-lock.json v3 for flat `entries`: { "node_modules/x": { version, resolved, integrity, ... } }
+export function npmLockfile(entries, root = { name: "proj", version: "1.0.0" }) {
+  return { name: root.name, version: root.version, lockfileVersion: 3, requires: true, packages: { "": root, ...entries } };
+}
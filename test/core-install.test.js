@@ -0,0 +1,1837 @@
+import test from "node:test";
+import assert from "node:assert/strict";
+import path from "node:path";
+import fs from "node:fs/promises";
+import crypto from "node:crypto";
+import { makeTempDir, rmrf, writeFile, writeJson } from "./helpers.js";
+import { ensureCoreBuilt, execFileAsync, exists, hasTar, makeTarball, npmLockfile, runCore, sha512Integrity, startRegistry, startServer } from "./core-helpers.js";
+
+const skip = !(await hasTar());
+// The files .bin holds for one bin: a symlink, or npm's shim trio on Windows.
+const binShims = (name) => (process.platform === "win32" ? [name, `${name}.cmd`, `${name}.ps1`] : [name]);
+
+test("install --staged swaps in the new node_modules only after it is complete", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-staged-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0", bin: { foo: "cli.js" } }, { "cli.js": "#!/usr/bin/env node\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    await writeFile(path.join(project, "node_modules", "stale", "index.js"), "old tree\n");
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--staged", "--no-scripts"]);
+    assert.equal(code, 0);
+    assert.deepEqual(json.staged, { atomic: true, replacedExisting: true });
+    assert.ok(await exists(path.join(project, "node_modules", "foo", "cli.js")));
+    assert.ok(await exists(path.join(project, "node_modules", ".bin", process.platform === "win32" ? "foo.cmd" : "foo")));
+    assert.equal(await exists(path.join(project, "node_modules", "stale")), false);
+    assert.deepEqual((await fs.readdir(project)).sort(), ["node_modules", "package-lock.json"]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("an aborted --staged install leaves the original node_modules intact", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-staged-abort-");
+  // "a" ships a regular file named "sub", but the lockfile also nests a package at a/sub,
+  // so materializing the staged tree fails part-way through.
+  const a = await makeTarball(dir, { name: "a", version: "1.0.0" }, { sub: "file, not a directory\n" });
+  const sub = await makeTarball(dir, { name: "sub", version: "1.0.0" }, { "index.js": "module.exports = 1;\n" });
+  const registry = await startRegistry({ "a@1.0.0": a, "sub@1.0.0": sub });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/a": { version: "1.0.0", resolved: registry.tarballUrl("a", "1.0.0"), integrity: sha512Integrity(a) },
+      "node_modules/a/sub": { name: "sub", version: "1.0.0", resolved: registry.tarballUrl("sub", "1.0.0"), integrity: sha512Integrity(sub) }
+    }));
+    await writeFile(path.join(project, "node_modules", "keep", "index.js"), "original\n");
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--staged", "--no-scripts", "--link-strategy", "copy"]);
+    assert.equal(code, 1);
+    assert.match(json.reason, /Failed to materialize/);
+    assert.equal(await fs.readFile(path.join(project, "node_modules", "keep", "index.js"), "utf8"), "original\n");
+    assert.equal(await exists(path.join(project, "node_modules", "a")), false);
+    assert.equal(await exists(path.join(project, "node_modules.better-tmp")), false);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install links bins declared through exports conditions and publishConfig", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-exports-bin-");
+  const dual = await makeTarball(dir, {
+    name: "@scope/dual",
+    version: "1.0.0",
+    exports: {
+      ".": { import: "./index.mjs", require: "./index.cjs", bin: "./cli.mjs" },
+      "./tools/fmt": { node: { bin: "./fmt.js" } }
+    }
+  }, { "cli.mjs": "#!/usr/bin/env node\n", "fmt.js": "#!/usr/bin/env node\n" });
+  const published = await makeTarball(dir, {
+    name: "published",
+    version: "1.0.0",
+    bin: { published: "src/cli.ts" },
+    publishConfig: { bin: { published: "dist/cli.js" } }
+  }, { "dist/cli.js": "#!/usr/bin/env node\n" });
+  const registry = await startRegistry({ "@scope/dual@1.0.0": dual, "published@1.0.0": published });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/@scope/dual": { version: "1.0.0", resolved: registry.tarballUrl("@scope/dual", "1.0.0"), integrity: sha512Integrity(dual) },
+      "node_modules/published": { version: "1.0.0", resolved: registry.tarballUrl("published", "1.0.0"), integrity: sha512Integrity(published) }
+    }));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    const binDir = path.join(project, "node_modules", ".bin");
+    const link = (name) => path.join(binDir, process.platform === "win32" ? `${name}.cmd` : name);
+    assert.ok(await exists(link("dual")));
+    assert.ok(await exists(link("fmt")));
+    if (process.platform !== "win32") {
+      assert.equal(await fs.readlink(link("dual")), path.join("..", "@scope", "dual", "cli.mjs"));
+      assert.equal(await fs.readlink(link("published")), path.join("..", "published", "dist", "cli.js"));
+    }
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install links every script under directories.bin and string bins with a leading ./", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-directories-bin-");
+  const legacy = await makeTarball(dir, {
+    name: "legacy",
+    version: "1.0.0",
+    directories: { bin: "./cli" }
+  }, { "cli/legacy-build": "#!/bin/sh\n", "cli/legacy-serve": "#!/bin/sh\n", "cli/.hidden": "#!/bin/sh\n" });
+  const dotted = await makeTarball(dir, { name: "dotted", version: "1.0.0", bin: "./bin/cli.js" }, { "bin/cli.js": "#!/usr/bin/env node\n" });
+  const registry = await startRegistry({ "legacy@1.0.0": legacy, "dotted@1.0.0": dotted });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/legacy": { version: "1.0.0", resolved: registry.tarballUrl("legacy", "1.0.0"), integrity: sha512Integrity(legacy) },
+      "node_modules/dotted": { version: "1.0.0", resolved: registry.tarballUrl("dotted", "1.0.0"), integrity: sha512Integrity(dotted) }
+    }));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    const binDir = path.join(project, "node_modules", ".bin");
+    assert.deepEqual((await fs.readdir(binDir)).sort(), ["dotted", "legacy-build", "legacy-serve"].flatMap(binShims).sort());
+    if (process.platform !== "win32") {
+      assert.equal(await fs.readlink(path.join(binDir, "legacy-serve")), path.join("..", "legacy", "cli", "legacy-serve"));
+      assert.equal(await fs.readlink(path.join(binDir, "dotted")), path.join("..", "dotted", "bin", "cli.js"));
+      assert.ok((await fs.stat(path.join(binDir, "legacy-build"))).mode & 0o111);
+    }
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install keeps the first package's bin on a name clash and skips missing scripts", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-bin-conflict-");
+  const alpha = await makeTarball(dir, { name: "alpha", version: "1.0.0", bin: { foo: "alpha.js" } }, { "alpha.js": "#!/usr/bin/env node\n" });
+  const zeta = await makeTarball(dir, { name: "zeta", version: "2.0.0", bin: { foo: "zeta.js", zeta: "zeta.js" } }, { "zeta.js": "#!/usr/bin/env node\n" });
+  const ghost = await makeTarball(dir, { name: "ghost", version: "1.0.0", bin: { ghost: "missing.js" } }, { "index.js": "\n" });
+  const registry = await startRegistry({ "alpha@1.0.0": alpha, "zeta@2.0.0": zeta, "ghost@1.0.0": ghost });
+  try {
+    const project = path.join(dir, "project");
+    // zeta is listed first, but alpha sorts first and wins regardless of lockfile order.
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/zeta": { version: "2.0.0", resolved: registry.tarballUrl("zeta", "2.0.0"), integrity: sha512Integrity(zeta) },
+      "node_modules/alpha": { version: "1.0.0", resolved: registry.tarballUrl("alpha", "1.0.0"), integrity: sha512Integrity(alpha) },
+      "node_modules/ghost": { version: "1.0.0", resolved: registry.tarballUrl("ghost", "1.0.0"), integrity: sha512Integrity(ghost) }
+    }));
+
+    const binDir = path.join(project, "node_modules", ".bin");
+    for (let run = 0; run < 2; run++) {
+      const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+      assert.equal(code, 0, JSON.stringify(json));
+      assert.equal(json.binLinks.created, 2);
+      assert.equal(json.binLinks.skipped, 1);
+      assert.deepEqual(json.binLinks.conflicts, [{ name: "foo", winner: "alpha@1.0.0", loser: "zeta@2.0.0" }]);
+      assert.ok(json.warnings.some((w) => w.code === "bin-conflict" && w.message.includes("foo")));
+      assert.deepEqual((await fs.readdir(binDir)).sort(), ["foo", "zeta"].flatMap(binShims).sort());
+      if (process.platform !== "win32") {
+        assert.equal(await fs.readlink(path.join(binDir, "foo")), path.join("..", "alpha", "alpha.js"));
+      }
+    }
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install writes .cmd, .ps1 and sh shims for bins on Windows", { skip: skip || process.platform !== "win32" }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-win-shims-");
+  const tool = await makeTarball(dir, { name: "tool", version: "1.0.0", bin: { tool: "bin/tool.js" } }, { "bin/tool.js": "#!/usr/bin/env node\n" });
+  const registry = await startRegistry({ "tool@1.0.0": tool });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/tool": { version: "1.0.0", resolved: registry.tarballUrl("tool", "1.0.0"), integrity: sha512Integrity(tool) }
+    }));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(json.binLinks.created, 3);
+    const binDir = path.join(project, "node_modules", ".bin");
+    const cmd = await fs.readFile(path.join(binDir, "tool.cmd"), "utf8");
+    assert.match(cmd, /"%_prog%" "%~dp0\\\.\.\\tool\\bin\\tool\.js" %\*/);
+    const ps1 = await fs.readFile(path.join(binDir, "tool.ps1"), "utf8");
+    assert.match(ps1, /& \$prog "\$basedir\/\.\.\/tool\/bin\/tool\.js" \$args/);
+    const sh = await fs.readFile(path.join(binDir, "tool"), "utf8");
+    assert.ok(sh.startsWith("#!/bin/sh\n"));
+    assert.match(sh, /exec node "\$basedir\/\.\.\/tool\/bin\/tool\.js" "\$@"/);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install shims Windows bins through the interpreter their shebang names", { skip: skip || process.platform !== "win32" }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-win-shebang-");
+  const pytool = await makeTarball(dir, { name: "pytool", version: "1.0.0", bin: { pytool: "bin/pytool" } }, { "bin/pytool": "#!/usr/bin/env python3\r\nprint('hi')\r\n" });
+  const registry = await startRegistry({ "pytool@1.0.0": pytool });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/pytool": { version: "1.0.0", resolved: registry.tarballUrl("pytool", "1.0.0"), integrity: sha512Integrity(pytool) }
+    }));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    const binDir = path.join(project, "node_modules", ".bin");
+    const cmd = await fs.readFile(path.join(binDir, "pytool.cmd"), "utf8");
+    assert.match(cmd, /SET "_prog=python3"/);
+    assert.doesNotMatch(cmd, /node/);
+    assert.match(await fs.readFile(path.join(binDir, "pytool"), "utf8"), /exec python3 "\$basedir\/\.\.\/pytool\/bin\/pytool" "\$@"/);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install runs lifecycle scripts natively in dependency order", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-lifecycle-");
+  const log = (event) => `node -e "require('fs').appendFileSync(process.env.INIT_CWD + '/order.log', '${event}:' + process.env.npm_package_name + '@' + process.env.npm_package_version + '\\n')"`;
+  const dep = await makeTarball(dir, {
+    name: "dep",
+    version: "1.0.0",
+    bin: { "dep-tool": "tool.js" },
+    scripts: { postinstall: `${log("postinstall")} && node -e "require('fs').writeFileSync('built', 'ok')"` }
+  }, { "tool.js": "#!/usr/bin/env node\nrequire('fs').writeFileSync('tool-ran', process.cwd());\n" });
+  const app = await makeTarball(dir, {
+    name: "app",
+    version: "2.0.0",
+    dependencies: { dep: "1.0.0" },
+    scripts: {
+      preinstall: `node -e "require('fs').accessSync('../dep/built')" && ${log("preinstall")}`,
+      install: `dep-tool && ${log("install")}`,
+      postinstall: log("postinstall")
+    }
+  });
+  const bad = await makeTarball(dir, { name: "bad", version: "1.0.0", scripts: { preinstall: "node -e \"process.exit(3)\"", postinstall: log("postinstall") } });
+  const registry = await startRegistry({ "dep@1.0.0": dep, "app@2.0.0": app, "bad@1.0.0": bad });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/app": { version: "2.0.0", resolved: registry.tarballUrl("app", "2.0.0"), integrity: sha512Integrity(app), dependencies: { dep: "1.0.0" } },
+      "node_modules/bad": { version: "1.0.0", resolved: registry.tarballUrl("bad", "1.0.0"), integrity: sha512Integrity(bad) },
+      "node_modules/dep": { version: "1.0.0", resolved: registry.tarballUrl("dep", "1.0.0"), integrity: sha512Integrity(dep) }
+    }));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--allow-all-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(await fs.readFile(path.join(project, "order.log"), "utf8"), [
+      "postinstall:dep@1.0.0",
+      "preinstall:app@2.0.0",
+      "install:app@2.0.0",
+      "postinstall:app@2.0.0",
+      ""
+    ].join("\n"));
+    // dep-tool ran from .bin on PATH, inside app's directory
+    assert.equal(await fs.readFile(path.join(project, "node_modules", "app", "tool-ran"), "utf8"), await fs.realpath(path.join(project, "node_modules", "app")));
+    assert.equal(json.scripts.run, 5);
+    assert.equal(json.scripts.succeeded, 4);
+    assert.equal(json.scripts.failed, 1);
+    assert.deepEqual(json.scripts.failures, [{ package: "bad", script: "preinstall", outcome: "failed", exitCode: 3 }]);
+    assert.ok(json.warnings.some((w) => w.code === "lifecycle-script-failed"));
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install runs only the lifecycle scripts the better.scripts policy allows", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-script-policy-");
+  const sentinel = (name) => `node -e "require('fs').writeFileSync(process.env.INIT_CWD + '/${name}.ran', '')"`;
+  const tarballs = {};
+  for (const name of ["good", "evil", "stranger"]) {
+    tarballs[name] = await makeTarball(dir, { name, version: "1.0.0", scripts: { postinstall: sentinel(name) } });
+  }
+  const registry = await startRegistry(Object.fromEntries(Object.entries(tarballs).map(([n, t]) => [`${n}@1.0.0`, t])));
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), {
+      name: "proj",
+      version: "1.0.0",
+      better: { scripts: { allowedPackages: ["good"], blockedPackages: ["evil"] } }
+    });
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile(Object.fromEntries(Object.entries(tarballs).map(([n, t]) => [
+      `node_modules/${n}`, { version: "1.0.0", resolved: registry.tarballUrl(n, "1.0.0"), integrity: sha512Integrity(t) }
+    ]))));
+    const install = async (...extra) => {
+      const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), ...extra]);
+      assert.equal(code, 0, JSON.stringify(json));
+      return json;
+    };
+    const ran = async () => (await fs.readdir(project)).filter((f) => f.endsWith(".ran")).sort();
+
+    const json = await install();
+    assert.deepEqual(await ran(), ["good.ran"]);
+    assert.equal(json.scripts.allowed, 1);
+    assert.equal(json.scripts.blocked, 2);
+    assert.deepEqual(json.scripts.blockedScripts, [
+      { package: "evil", script: "postinstall", reason: "package is in blocked list" },
+      { package: "stranger", script: "postinstall", reason: "package is not in allowed list" }
+    ]);
+    assert.ok(json.warnings.some((w) => w.code === "lifecycle-scripts-not-allowed"));
+
+    const all = await install("--allow-all-scripts");
+    assert.deepEqual(await ran(), ["good.ran", "stranger.ran"]);
+    assert.deepEqual(all.scripts.blockedScripts.map((b) => b.package), ["evil"]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install --capture-script-output reports the tail of a failed script's output", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-script-output-");
+  const broken = await makeTarball(dir, {
+    name: "broken",
+    version: "1.0.0",
+    scripts: { install: "node build.js" }
+  }, { "build.js": "console.log('building');\nprocess.stderr.write('x'.repeat(100 * 1024) + '\\ngyp ERR! boom\\n');\nprocess.exit(7);\n" });
+  const registry = await startRegistry({ "broken@1.0.0": broken });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/broken": { version: "1.0.0", resolved: registry.tarballUrl("broken", "1.0.0"), integrity: sha512Integrity(broken) }
+    }));
+    const install = async (...extra) => {
+      const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--allow-all-scripts", ...extra]);
+      assert.equal(code, 0, JSON.stringify(json));
+      return json.scripts.failures;
+    };
+
+    const [failure] = await install("--capture-script-output");
+    assert.equal(failure.exitCode, 7);
+    assert.equal(failure.stdout, "building\n");
+    assert.ok(failure.stderr.endsWith("\ngyp ERR! boom\n"));
+    assert.equal(failure.stderr.length, 64 * 1024);
+
+    const [inherited] = await install();
+    assert.equal(inherited.exitCode, 7);
+    assert.equal("stderr" in inherited, false);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install kills lifecycle scripts that outlive better.scriptTimeoutMs", { skip: skip || process.platform === "win32" }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-script-timeout-");
+  // The trailing command keeps sh around, so node runs as a grandchild of install
+  const hang = await makeTarball(dir, {
+    name: "hang",
+    version: "1.0.0",
+    scripts: { postinstall: "node hang.js; echo unreachable" }
+  }, { "hang.js": "require('fs').writeFileSync('pid', String(process.pid));\nsetTimeout(() => require('fs').writeFileSync('finished', ''), 3000);\n" });
+  const registry = await startRegistry({ "hang@1.0.0": hang });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "proj", version: "1.0.0", better: { scriptTimeoutMs: 500 } });
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/hang": { version: "1.0.0", resolved: registry.tarballUrl("hang", "1.0.0"), integrity: sha512Integrity(hang) }
+    }));
+
+    const started = Date.now();
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--allow-all-scripts", "--capture-script-output"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.ok(Date.now() - started < 3000);
+    assert.equal(json.scripts.timedOut, 1);
+    assert.deepEqual(json.scripts.failures.map((f) => [f.package, f.outcome, f.exitCode]), [["hang", "timed-out", null]]);
+    assert.ok(json.warnings.some((w) => w.code === "lifecycle-script-failed" && w.message.includes("timed out")));
+
+    const pkgDir = path.join(project, "node_modules", "hang");
+    // Gone, or a zombie waiting for an init that may never reap it
+    const pid = Number(await fs.readFile(path.join(pkgDir, "pid"), "utf8"));
+    const state = await fs.readFile(`/proc/${pid}/stat`, "utf8").then((s) => s.slice(s.lastIndexOf(")") + 2)[0], () => "gone");
+    assert.ok(["gone", "Z", "X"].includes(state) || process.platform !== "linux", state);
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+    assert.equal(await exists(path.join(pkgDir, "finished")), false);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install --script-jobs caps how many packages run scripts at once", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-script-jobs-");
+  const step = "node -e \"const fs = require('fs'), log = process.env.INIT_CWD + '/jobs.log', n = process.env.npm_package_name; fs.appendFileSync(log, 'start:' + n + '\\n'); setTimeout(() => fs.appendFileSync(log, 'end:' + n + '\\n'), 300)\"";
+  const tarballs = {};
+  for (const name of ["one", "two", "three"]) {
+    tarballs[name] = await makeTarball(dir, { name, version: "1.0.0", scripts: { install: step } });
+  }
+  const registry = await startRegistry(Object.fromEntries(Object.entries(tarballs).map(([n, t]) => [`${n}@1.0.0`, t])));
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile(Object.fromEntries(Object.entries(tarballs).map(([n, t]) => [
+      `node_modules/${n}`, { version: "1.0.0", resolved: registry.tarballUrl(n, "1.0.0"), integrity: sha512Integrity(t) }
+    ]))));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--allow-all-scripts", "--script-jobs", "1"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(json.scripts.succeeded, 3);
+    const lines = (await fs.readFile(path.join(project, "jobs.log"), "utf8")).trim().split("\n");
+    assert.equal(lines.length, 6);
+    for (let i = 0; i < lines.length; i += 2) {
+      assert.equal(lines[i].replace("start:", "end:"), lines[i + 1]);
+    }
+
+    await assert.rejects(execFileAsync(corePath, ["install", "--project-root", project, "--script-jobs", "0"]), (err) => {
+      assert.equal(err.code, 2);
+      assert.match(err.stderr, /invalid --script-jobs '0'/);
+      return true;
+    });
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install reuses the parsed lockfile until its bytes change", async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-resolve-cache-");
+  try {
+    const project = path.join(dir, "project");
+    const cacheRoot = path.join(dir, "cache");
+    const lockfile = path.join(project, "package-lock.json");
+    await writeJson(lockfile, npmLockfile({}));
+    const install = async () => {
+      const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", cacheRoot, "--no-scripts"]);
+      assert.equal(code, 0, JSON.stringify(json));
+      return json.stats.resolveCacheHit;
+    };
+
+    assert.equal(await install(), false);
+    assert.equal(await install(), true);
+    assert.equal((await fs.readdir(path.join(cacheRoot, "resolve"))).length, 1);
+
+    await fs.appendFile(lockfile, "\n");
+    assert.equal(await install(), false);
+    assert.equal(await install(), true);
+  } finally {
+    await rmrf(dir);
+  }
+});
+
+// A writable directory on a different device than the OS tmp dir, if the host has one
+async function otherDeviceDir(than) {
+  const { dev } = await fs.stat(than);
+  for (const candidate of ["/dev/shm", "/run/user"]) {
+    try {
+      if ((await fs.stat(candidate)).dev === dev) continue;
+      return await fs.mkdtemp(path.join(candidate, "better-exdev-"));
+    } catch {
+      // not present or not writable
+    }
+  }
+  return null;
+}
+
+test("install moves downloads into the store when the cache tmp dir is on another device", { skip }, async (t) => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-exdev-");
+  const elsewhere = await otherDeviceDir(dir);
+  if (!elsewhere) {
+    await rmrf(dir);
+    t.skip("no second device available");
+    return;
+  }
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "module.exports = 1;\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const cacheRoot = path.join(dir, "cache");
+    await fs.mkdir(cacheRoot, { recursive: true });
+    // Every final rename out of tmp/ into store/ now crosses devices
+    await fs.symlink(elsewhere, path.join(cacheRoot, "tmp"));
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", cacheRoot, "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.ok(await exists(path.join(project, "node_modules", "foo", "index.js")));
+    assert.deepEqual(await fs.readdir(elsewhere), []);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+    await rmrf(elsewhere);
+  }
+});
+
+test("install keeps bundleDependencies shipped inside the parent tarball", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-bundled-");
+  const parent = await makeTarball(dir, { name: "parent", version: "1.0.0", bundleDependencies: ["inner", "@scope/deep"] }, {
+    "index.js": "module.exports = require('inner');\n",
+    "node_modules/inner/package.json": JSON.stringify({ name: "inner", version: "2.0.0" }),
+    "node_modules/inner/index.js": "module.exports = 2;\n",
+    "node_modules/inner/node_modules/leaf/package.json": JSON.stringify({ name: "leaf", version: "1.0.0" }),
+    "node_modules/@scope/deep/package.json": JSON.stringify({ name: "@scope/deep", version: "1.0.0" }),
+    "node_modules/stray/package.json": JSON.stringify({ name: "stray", version: "1.0.0" })
+  });
+  const registry = await startRegistry({ "parent@1.0.0": parent });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/parent": { version: "1.0.0", resolved: registry.tarballUrl("parent", "1.0.0"), integrity: sha512Integrity(parent), bundleDependencies: ["inner", "@scope/deep"] },
+      "node_modules/parent/node_modules/inner": { version: "2.0.0", inBundle: true },
+      "node_modules/parent/node_modules/inner/node_modules/leaf": { version: "1.0.0", inBundle: true },
+      "node_modules/parent/node_modules/@scope/deep": { version: "1.0.0", inBundle: true }
+    }));
+
+    for (const dedup of [[], ["--dedup"]]) {
+      const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts", ...dedup]);
+      assert.equal(code, 0, JSON.stringify(json));
+      const nm = path.join(project, "node_modules", "parent", "node_modules");
+      assert.ok(await exists(path.join(nm, "inner", "index.js")));
+      assert.ok(await exists(path.join(nm, "inner", "node_modules", "leaf", "package.json")));
+      assert.ok(await exists(path.join(nm, "@scope", "deep", "package.json")));
+      assert.equal(await exists(path.join(nm, "stray")), false);
+    }
+    // Bundled entries are never fetched on their own
+    assert.deepEqual(registry.requests, [new URL(registry.tarballUrl("parent", "1.0.0")).pathname]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install --dry-run reports fetches and materializations without touching disk", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-install-dry-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "module.exports = 1;\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const cacheRoot = path.join(dir, "cache");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    const args = ["install", "--project-root", project, "--cache-root", cacheRoot, "--no-scripts"];
+
+    const { code, json } = await runCore(corePath, [...args, "--dry-run"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(json.dryRun, true);
+    assert.deepEqual(json.plan.map((c) => c.action), ["mkdir", "fetch", "materialize"]);
+    assert.equal(json.plan[2].path, path.join(project, "node_modules", "foo"));
+    assert.deepEqual(await fs.readdir(project), ["package-lock.json"]);
+    assert.equal(await exists(cacheRoot), false);
+    assert.deepEqual(registry.requests, []);
+
+    // Once installed, a dry run has nothing left to fetch
+    assert.equal((await runCore(corePath, args)).code, 0);
+    const again = await runCore(corePath, [...args, "--dry-run", "--staged"]);
+    assert.deepEqual(again.json.plan.map((c) => c.action), ["replace", "materialize"]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install --verify-store rejects and removes a store blob whose bytes do not match its hash", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-verify-store-");
+  const content = "module.exports = 'good';\n";
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": content });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const storeRoot = path.join(dir, "store");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    // A blob already sitting at index.js's address but holding other bytes
+    const hex = crypto.createHash("sha256").update(content).digest("hex");
+    const blob = path.join(storeRoot, "files", "sha256", hex.slice(0, 2), hex.slice(2, 4), hex);
+    await writeFile(blob, "module.exports = 'rotten';\n");
+
+    const args = ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--store-root", storeRoot, "--no-scripts", "--verify-store"];
+    const failed = await runCore(corePath, args);
+    assert.equal(failed.code, 1);
+    assert.match(failed.json.reason, new RegExp(`index\\.js failed verification: expected sha256 ${hex}`));
+    assert.equal(await exists(blob), false);
+
+    const { code, json } = await runCore(corePath, args);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(await fs.readFile(blob, "utf8"), content);
+    assert.equal(await fs.readFile(path.join(project, "node_modules", "foo", "index.js"), "utf8"), content);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install --frozen fails on store blobs missing from a manifest instead of re-ingesting", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-frozen-store-");
+  const content = "module.exports = 'kept';\n";
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": content, "lib/util.js": "exports.x = 1;\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const storeRoot = path.join(dir, "store");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    const args = ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--store-root", storeRoot, "--no-scripts", "--dedup"];
+    const first = await runCore(corePath, args);
+    assert.equal(first.code, 0, JSON.stringify(first.json));
+
+    const hex = crypto.createHash("sha256").update(content).digest("hex");
+    const blob = path.join(storeRoot, "files", "sha256", hex.slice(0, 2), hex.slice(2, 4), hex);
+    await fs.rm(blob);
+    await rmrf(path.join(project, "node_modules"));
+
+    const failed = await runCore(corePath, [...args, "--frozen"]);
+    assert.equal(failed.code, 1);
+    assert.equal(failed.json.reason, "Store is missing 1 blob(s) for foo: index.js");
+    assert.equal(await exists(path.join(project, "node_modules", "foo")), false);
+
+    const { code, json } = await runCore(corePath, args);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(json.stats.casReingested, 1);
+    assert.equal(await fs.readFile(blob, "utf8"), content);
+    assert.equal(await fs.readFile(path.join(project, "node_modules", "foo", "index.js"), "utf8"), content);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install with engine-strict aborts before fetching when engines are unmet", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-engine-strict-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "proj", version: "1.0.0", engines: { node: ">=999.0.0" } });
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    const args = ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"];
+
+    const flagged = await runCore(corePath, [...args, "--engine-strict"]);
+    assert.equal(flagged.code, 3);
+    assert.equal(flagged.json.engines.length, 1);
+    assert.equal(flagged.json.engines[0].tool, "node");
+    assert.equal(flagged.json.engines[0].required, ">=999.0.0");
+    assert.match(flagged.json.reason, /^Unsupported engine: node v\S+ \(wanted >=999\.0\.0\)$/);
+
+    await writeFile(path.join(project, ".npmrc"), "engine-strict=true\n");
+    const configured = await runCore(corePath, args);
+    assert.equal(configured.code, 3);
+    assert.equal(registry.requests.length, 0);
+    assert.equal(await exists(path.join(project, "node_modules")), false);
+
+    await writeFile(path.join(project, ".npmrc"), "engine-strict=false\n");
+    const { code, json } = await runCore(corePath, args);
+    assert.equal(code, 0, JSON.stringify(json));
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+
+test("env check and install compare packageManager with the running better-core", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-package-manager-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const version = (await execFileAsync(corePath, ["version"])).stdout.trim();
+    const project = path.join(dir, "project");
+    const pin = async (packageManager) => {
+      await writeJson(path.join(project, "package.json"), { name: "proj", version: "1.0.0", packageManager });
+      await rmrf(path.join(project, "node_modules"));
+    };
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    const args = ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"];
+    const envCheck = () => runCore(corePath, ["env", "check", "--project-root", project]);
+    const mismatchWarnings = (json) => json.warnings.filter((w) => w.code === "package-manager-mismatch");
+
+    // Absent: nothing to check
+    await pin(undefined);
+    assert.deepEqual((await envCheck()).json.checks, []);
+    let res = await runCore(corePath, [...args, "--package-manager-strict"]);
+    assert.equal(res.code, 0, JSON.stringify(res.json));
+    assert.deepEqual(mismatchWarnings(res.json), []);
+
+    // Matching, with Corepack's hash suffix
+    await pin(`better@${version}+sha512.abc`);
+    res = await envCheck();
+    assert.equal(res.code, 0);
+    assert.deepEqual(res.json.checks.map((c) => [c.tool, c.required, c.satisfied]), [["packageManager", `better@${version}`, true]]);
+    res = await runCore(corePath, [...args, "--package-manager-strict"]);
+    assert.equal(res.code, 0, JSON.stringify(res.json));
+    assert.deepEqual(mismatchWarnings(res.json), []);
+
+    // Mismatching: env check fails, install warns unless strict
+    await pin("npm@10.2.3");
+    res = await envCheck();
+    assert.equal(res.code, 3);
+    assert.deepEqual(res.json.checks.map((c) => [c.current, c.required, c.satisfied]), [[`better@${version}`, "npm@10.2.3", false]]);
+    res = await runCore(corePath, args);
+    assert.equal(res.code, 0, JSON.stringify(res.json));
+    assert.equal(mismatchWarnings(res.json)[0].message, `packageManager pins npm@10.2.3, but this is better@${version}`);
+
+    await rmrf(path.join(project, "node_modules"));
+    const requests = registry.requests.length;
+    await writeFile(path.join(project, ".npmrc"), "package-manager-strict=true\n");
+    res = await runCore(corePath, args);
+    assert.equal(res.code, 3);
+    assert.equal(res.json.reason, `packageManager pins npm@10.2.3, but this is better@${version}`);
+    assert.equal(registry.requests.length, requests);
+    assert.equal(await exists(path.join(project, "node_modules")), false);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install and outdated send environment auth tokens only to the matching registry", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-env-auth-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  const bar = await makeTarball(dir, { name: "@corp/bar", version: "1.0.0" });
+  const publicRegistry = await startRegistry({ "foo@1.0.0": foo });
+  const corpRegistry = await startRegistry({ "@corp/bar@1.0.0": bar });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "proj", version: "1.0.0", dependencies: { foo: "^1.0.0", "@corp/bar": "^1.0.0" } });
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: publicRegistry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) },
+      "node_modules/@corp/bar": { version: "1.0.0", resolved: corpRegistry.tarballUrl("@corp/bar", "1.0.0"), integrity: sha512Integrity(bar) }
+    }));
+    const publicHost = new URL(publicRegistry.url).host;
+    const corpHost = new URL(corpRegistry.url).host;
+    // The env token must beat this one; ${CORP_TOKEN} is expanded from the environment
+    await writeFile(path.join(project, ".npmrc"), `//${publicHost}/:_authToken=from-npmrc\n//${corpHost}/:_authToken=\${CORP_TOKEN}\n`);
+
+    const env = {
+      ...process.env,
+      HOME: dir,
+      NPM_CONFIG_REGISTRY: publicRegistry.url,
+      "npm_config_@corp:registry": corpRegistry.url,
+      NODE_AUTH_TOKEN: "node-auth-secret",
+      CORP_TOKEN: "corp-secret"
+    };
+    const install = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"], { env });
+    assert.equal(install.code, 0, JSON.stringify(install.json));
+    const outdated = await runCore(corePath, ["outdated", "--project-root", project], { env });
+    assert.equal(outdated.code, 0, JSON.stringify(outdated.json));
+
+    const tokens = (registry) => [...new Set(registry.auth.map((r) => r.authorization))];
+    assert.deepEqual(publicRegistry.requests.sort(), ["/foo", "/foo/-/foo-1.0.0.tgz"]);
+    assert.deepEqual(tokens(publicRegistry), ["Bearer node-auth-secret"]);
+    assert.deepEqual(corpRegistry.requests.sort(), ["/@corp%2Fbar", "/@corp/bar/-/bar-1.0.0.tgz"]);
+    assert.deepEqual(tokens(corpRegistry), ["Bearer corp-secret"]);
+    for (const json of [install.json, outdated.json]) {
+      assert.doesNotMatch(JSON.stringify(json), /secret/);
+    }
+
+    // A per-host npm_config_ token overrides the npmrc entry for that host
+    corpRegistry.auth.length = 0;
+    await runCore(corePath, ["outdated", "--project-root", project, "--no-cache"], { env: { ...env, [`npm_config_//${corpHost}/:_authToken`]: "env-corp-secret" } });
+    assert.deepEqual(tokens(corpRegistry), ["Bearer env-corp-secret"]);
+  } finally {
+    await publicRegistry.close();
+    await corpRegistry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install fetches scoped packages locked against the public registry from the scope's registry", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-scoped-registry-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  const bar = await makeTarball(dir, { name: "@corp/bar", version: "1.0.0" });
+  const publicRegistry = await startRegistry({ "foo@1.0.0": foo });
+  const corpRegistry = await startRegistry({ "@corp/bar@1.0.0": bar });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: "https://registry.npmjs.org/foo/-/foo-1.0.0.tgz", integrity: sha512Integrity(foo) },
+      "node_modules/@corp/bar": { version: "1.0.0", resolved: "https://registry.npmjs.org/@corp/bar/-/bar-1.0.0.tgz", integrity: sha512Integrity(bar) }
+    }));
+    const corpHost = new URL(corpRegistry.url).host;
+    await writeFile(path.join(project, ".npmrc"), [
+      `registry=${publicRegistry.url}/`,
+      `@corp:registry=${corpRegistry.url}/`,
+      `//${corpHost}/:_authToken=corp-secret`,
+      ""
+    ].join("\n"));
+
+    const env = { ...process.env, HOME: dir };
+    const install = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"], { env });
+    assert.equal(install.code, 0, JSON.stringify(install.json));
+    assert.deepEqual(publicRegistry.requests, ["/foo/-/foo-1.0.0.tgz"]);
+    assert.deepEqual(publicRegistry.auth.map((r) => r.authorization), [null]);
+    assert.deepEqual(corpRegistry.requests, ["/@corp/bar/-/bar-1.0.0.tgz"]);
+    assert.deepEqual(corpRegistry.auth.map((r) => r.authorization), ["Bearer corp-secret"]);
+
+    // Credentials written into a URL stay out of the failure report
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/gone": { version: "1.0.0", resolved: `http://ci:hunter2@${corpHost}/gone/-/gone-1.0.0.tgz`, integrity: sha512Integrity(Buffer.from("gone")) }
+    }));
+    const failed = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"], { env });
+    assert.equal(failed.code, 4);
+    assert.match(failed.json.reason, /^Failed to download gone: http:\/\/\*\*\*@/);
+    assert.doesNotMatch(JSON.stringify(failed.json), /hunter2|secret/);
+  } finally {
+    await publicRegistry.close();
+    await corpRegistry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install exits with the kind of failure: integrity, network, or a local one while downloading", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-failure-kinds-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    await writeFile(path.join(project, ".npmrc"), "fetch-retries=0\n");
+    const env = { ...process.env, HOME: dir };
+    const install = (cacheRoot) => runCore(corePath, ["install", "--project-root", project, "--cache-root", cacheRoot, "--no-scripts"], { env });
+    const lockWith = (integrity) => writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity }
+    }));
+
+    await lockWith(sha512Integrity(Buffer.from("something else")));
+    const mismatch = await install(path.join(dir, "cache-mismatch"));
+    assert.equal(mismatch.code, 5, JSON.stringify(mismatch.json));
+    assert.match(mismatch.json.reason, /^Integrity mismatch for foo/);
+
+    await lockWith(sha512Integrity(foo));
+    await registry.close();
+    const unreachable = await install(path.join(dir, "cache-unreachable"));
+    assert.equal(unreachable.code, 4, JSON.stringify(unreachable.json));
+
+    if (process.platform === "linux") {
+      // /proc takes no new files, even from root: the tmp file cannot be created
+      const cacheRoot = path.join(dir, "cache-local");
+      await fs.mkdir(cacheRoot);
+      await fs.symlink("/proc/self", path.join(cacheRoot, "tmp"));
+      const retry = await startRegistry({ "foo@1.0.0": foo });
+      try {
+        await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+          "node_modules/foo": { version: "1.0.0", resolved: retry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+        }));
+        const local = await install(cacheRoot);
+        assert.equal(local.code, 1, JSON.stringify(local.json));
+        assert.match(local.json.reason, /^Failed to download foo: Failed to create tmp file/);
+      } finally {
+        await retry.close();
+      }
+    }
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install --keep-going materializes what it can and reports every failed package", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-keep-going-");
+  const tarballs = {};
+  for (const name of ["bad-a", "bad-b", "good"]) {
+    tarballs[`${name}@1.0.0`] = await makeTarball(dir, { name, version: "1.0.0" }, { "index.js": "\n" });
+  }
+  const registry = await startRegistry(tarballs);
+  try {
+    const project = path.join(dir, "project");
+    const entry = (name) => ({ version: "1.0.0", resolved: registry.tarballUrl(name, "1.0.0"), integrity: sha512Integrity(tarballs[`${name}@1.0.0`]) });
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/bad-a": entry("bad-a"),
+      "node_modules/bad-b": entry("bad-b"),
+      "node_modules/good": entry("good")
+    }));
+    // Regular files where the package directories should go make both "bad" packages fail
+    await writeFile(path.join(project, "node_modules", "bad-a"), "not a directory\n");
+    await writeFile(path.join(project, "node_modules", "bad-b"), "not a directory\n");
+
+    const args = ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts", "--link-strategy", "copy", "--keep-going"];
+    const { code, json } = await runCore(corePath, args);
+    assert.equal(code, 1);
+    assert.equal(json.ok, false);
+    assert.equal(json.reason, "2 packages failed to materialize");
+    assert.deepEqual(json.failures.map((f) => [f.name, f.path]), [["bad-a", "node_modules/bad-a"], ["bad-b", "node_modules/bad-b"]]);
+    assert.match(json.failures[0].reason, /^Failed to materialize bad-a: /);
+    assert.ok(await exists(path.join(project, "node_modules", "good", "index.js")));
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("ci refuses a missing or stale lockfile and otherwise replaces node_modules from it", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-ci-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.2.0" }, { "index.js": "\n" });
+  const registry = await startRegistry({ "foo@1.2.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const args = ["ci", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"];
+    const manifest = { name: "proj", version: "1.0.0", dependencies: { foo: "^1.0.0" } };
+    await writeJson(path.join(project, "package.json"), manifest);
+
+    const missing = await runCore(corePath, args);
+    assert.equal(missing.code, 1);
+    assert.equal(missing.json.kind, "better.ci.report");
+    assert.match(missing.json.reason, /requires an existing lockfile/);
+
+    const lock = npmLockfile(
+      { "node_modules/foo": { version: "1.2.0", resolved: registry.tarballUrl("foo", "1.2.0"), integrity: sha512Integrity(foo) } },
+      manifest
+    );
+    await writeJson(path.join(project, "package-lock.json"), lock);
+    await writeJson(path.join(project, "package.json"), { ...manifest, dependencies: { foo: "^2.0.0", bar: "^1.0.0" } });
+    const stale = await runCore(corePath, args);
+    assert.equal(stale.code, 3);
+    assert.match(stale.json.reason, /out of sync with package.json/);
+    assert.deepEqual(stale.json.problems, [
+      "dependencies.bar: ^1.0.0 is missing from the lockfile",
+      "dependencies.foo: package.json wants ^2.0.0, lockfile has ^1.0.0",
+      "bar: node_modules/bar has no entry in the lockfile",
+      "foo: locked version 1.2.0 does not satisfy ^2.0.0"
+    ]);
+    assert.equal(registry.requests.length, 0);
+
+    await writeJson(path.join(project, "package.json"), manifest);
+    await writeFile(path.join(project, "node_modules", "stale", "index.js"), "old tree\n");
+    const before = await Promise.all(["package.json", "package-lock.json"].map((f) => fs.readFile(path.join(project, f), "utf8")));
+    const { code, json } = await runCore(corePath, args);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(json.kind, "better.ci.report");
+    assert.ok(await exists(path.join(project, "node_modules", "foo", "index.js")));
+    assert.equal(await exists(path.join(project, "node_modules", "stale")), false);
+    const after = await Promise.all(["package.json", "package-lock.json"].map((f) => fs.readFile(path.join(project, f), "utf8")));
+    assert.deepEqual(after, before);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+async function findFiles(dir, name) {
+  const found = [];
+  for (const entry of await fs.readdir(dir, { withFileTypes: true, recursive: true })) {
+    if (entry.isFile() && entry.name === name) found.push(path.join(entry.parentPath, entry.name));
+  }
+  return found;
+}
+
+test("file-store manifests are byte-identical regardless of directory read order", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-manifest-order-");
+  const names = ["a.js", "m.js", "z.js", "lib/b.js", "lib/y.js", "README.md"];
+  const pkg = await makeTarball(dir, { name: "foo", version: "1.0.0" }, Object.fromEntries(names.map((n) => [n, `// ${n}\n`])));
+  const registry = await startRegistry({ "foo@1.0.0": pkg });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(pkg) }
+    }));
+    const install = async (cache, store) => {
+      const res = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, cache), "--store-root", path.join(dir, store), "--no-scripts"]);
+      assert.equal(res.code, 0, JSON.stringify(res.json));
+    };
+
+    await install("cache-a", "store-a");
+
+    // Extract into a second cache, then recreate the unpacked files in reverse order
+    // so the next ingest walks them in a different order.
+    await install("cache-b", "store-scratch");
+    const [unpackedPkgJson] = await findFiles(path.join(dir, "cache-b", "store", "unpacked"), "package.json");
+    const unpacked = path.dirname(unpackedPkgJson);
+    for (const name of [...names].reverse()) {
+      const file = path.join(unpacked, name);
+      const content = await fs.readFile(file);
+      await fs.rm(file);
+      await writeFile(file, content);
+    }
+    await install("cache-b", "store-b");
+
+    const [manifestA] = await findFiles(path.join(dir, "store-a"), "manifest.json");
+    const [manifestB] = await findFiles(path.join(dir, "store-b"), "manifest.json");
+    const bytesA = await fs.readFile(manifestA, "utf8");
+    assert.equal(await fs.readFile(manifestB, "utf8"), bytesA);
+    const keys = Object.keys(JSON.parse(bytesA).files);
+    assert.deepEqual(keys, [...keys].sort());
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install records tarball URLs in the store's URL index for cache verify --by-url", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-url-index-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "module.exports = 1;\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const cache = path.join(dir, "cache");
+    const url = registry.tarballUrl("foo", "1.0.0");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: url, integrity: sha512Integrity(foo) }
+    }));
+
+    const install = await runCore(corePath, ["install", "--project-root", project, "--cache-root", cache, "--no-scripts"]);
+    assert.equal(install.code, 0);
+
+    const hex = crypto.createHash("sha512").update(foo).digest("hex");
+    const index = await fs.readFile(path.join(cache, "store", "url-index"), "utf8");
+    assert.equal(index, `${url}\tsha512\t${hex}\n`);
+
+    const verified = await runCore(corePath, ["cache", "verify", "--cache-root", cache, "--by-url", url, `${url}.missing`]);
+    assert.equal(verified.code, 5);
+    assert.equal(verified.json.kind, "better.cache.verify");
+    assert.deepEqual(verified.json.entries.map((e) => [e.url, e.hex, e.status]), [
+      [url, hex, "ok"],
+      [`${url}.missing`, "", "not-indexed"]
+    ]);
+
+    const tarball = verified.json.entries[0].path;
+    await fs.appendFile(tarball, "tampered");
+    const all = await runCore(corePath, ["cache", "verify", "--cache-root", cache]);
+    assert.equal(all.code, 5);
+    assert.deepEqual(all.json.entries.map((e) => e.status), ["mismatch"]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("registry requests identify better-core and carry headers configured in npmrc", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-headers-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "proj", version: "1.0.0", dependencies: { foo: "^1.0.0" } });
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    await writeFile(path.join(project, ".npmrc"), "header[]=X-Proxy-Team: web\nheader[]=X-Trace: on\n");
+
+    const env = { ...process.env, HOME: dir, NPM_CONFIG_REGISTRY: registry.url };
+    const install = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"], { env });
+    assert.equal(install.code, 0, JSON.stringify(install.json));
+    const outdated = await runCore(corePath, ["outdated", "--project-root", project], { env });
+    assert.equal(outdated.code, 0, JSON.stringify(outdated.json));
+
+    assert.deepEqual(registry.requests.sort(), ["/foo", "/foo/-/foo-1.0.0.tgz"]);
+    for (const { headers } of registry.auth) {
+      assert.match(headers["user-agent"], /^better-core\/\d+\.\d+\.\d+/);
+      assert.equal(headers["x-proxy-team"], "web");
+      assert.equal(headers["x-trace"], "on");
+    }
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("cache export and import move a usable cache to another machine", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-cache-export-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "module.exports = 1;\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const url = registry.tarballUrl("foo", "1.0.0");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: url, integrity: sha512Integrity(foo) }
+    }));
+    const source = path.join(dir, "source-cache");
+    const install = await runCore(corePath, ["install", "--project-root", project, "--cache-root", source, "--dedup", "--no-scripts"]);
+    assert.equal(install.code, 0, JSON.stringify(install.json));
+
+    const archive = path.join(dir, "cache.tgz");
+    const exported = await runCore(corePath, ["cache", "export", archive, "--cache-root", source]);
+    assert.equal(exported.code, 0, JSON.stringify(exported.json));
+    assert.equal(exported.json.tarballs, 1);
+    assert.ok(exported.json.blobs >= 2);
+    assert.equal(exported.json.packages, 1);
+
+    const target = path.join(dir, "target-cache");
+    const imported = await runCore(corePath, ["cache", "import", archive, "--cache-root", target]);
+    assert.equal(imported.code, 0, JSON.stringify(imported.json));
+    assert.equal(imported.json.tarballsImported, 1);
+    assert.equal(imported.json.blobsImported, exported.json.blobs);
+    assert.equal(imported.json.packagesImported, 1);
+    assert.deepEqual(imported.json.rejected, []);
+    const again = await runCore(corePath, ["cache", "import", archive, "--cache-root", target]);
+    assert.equal(again.json.skipped, 1 + exported.json.blobs + 1);
+
+    // The imported cache installs with the registry gone
+    await registry.close();
+    await rmrf(path.join(project, "node_modules"));
+    const offline = await runCore(corePath, ["install", "--project-root", project, "--cache-root", target, "--dedup", "--frozen", "--no-scripts"]);
+    assert.equal(offline.code, 0, JSON.stringify(offline.json));
+    assert.equal(await fs.readFile(path.join(project, "node_modules", "foo", "index.js"), "utf8"), "module.exports = 1;\n");
+    const verified = await runCore(corePath, ["cache", "verify", "--cache-root", target, "--by-url", url]);
+    assert.equal(verified.json.entries[0].status, "ok");
+
+    // A tampered tarball in the archive is refused
+    const stored = (await runCore(corePath, ["cache", "verify", "--cache-root", source])).json.entries[0].path;
+    await fs.appendFile(stored, "tampered");
+    await runCore(corePath, ["cache", "export", archive, "--cache-root", source]);
+    const refused = await runCore(corePath, ["cache", "import", archive, "--cache-root", path.join(dir, "third-cache")]);
+    assert.equal(refused.code, 1);
+    assert.equal(refused.json.tarballsImported, 0);
+    assert.equal(refused.json.rejected.length, 1);
+    assert.match(refused.json.rejected[0], /^tarballs\/sha512\/[0-9a-f]+\.tgz$/);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install falls back to yarn.lock, nesting versions that conflict with the hoisted one", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-yarn-lock-");
+  const util = await makeTarball(dir, { name: "@scope/util", version: "1.0.0", dependencies: { foo: "^1.0.0" } });
+  const foo1 = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  const foo2 = await makeTarball(dir, { name: "foo", version: "2.1.0" });
+  const registry = await startRegistry({ "@scope/util@1.0.0": util, "foo@1.0.0": foo1, "foo@2.1.0": foo2 });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "app", dependencies: { "@scope/util": "^1.0.0", foo: "^2.0.0" } });
+    const yarnLock = [
+      "# THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.",
+      "# yarn lockfile v1",
+      "",
+      "",
+      `"@scope/util@^1.0.0":`,
+      `  version "1.0.0"`,
+      `  resolved "${registry.tarballUrl("@scope/util", "1.0.0")}#0123456789abcdef0123456789abcdef01234567"`,
+      `  integrity ${sha512Integrity(util)}`,
+      "  dependencies:",
+      `    foo "^1.0.0"`,
+      "",
+      "foo@^1.0.0:",
+      `  version "1.0.0"`,
+      `  resolved "${registry.tarballUrl("foo", "1.0.0")}"`,
+      `  integrity ${sha512Integrity(foo1)}`,
+      "",
+      `foo@^2.0.0, "foo@>=2.1.0 <3":`,
+      `  version "2.1.0"`,
+      `  resolved "${registry.tarballUrl("foo", "2.1.0")}"`,
+      `  integrity ${sha512Integrity(foo2)}`,
+      ""
+    ].join("\n");
+    await writeFile(path.join(project, "yarn.lock"), yarnLock);
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    const version = async (...rel) => JSON.parse(await fs.readFile(path.join(project, "node_modules", ...rel, "package.json"), "utf8")).version;
+    assert.equal(await version("@scope", "util"), "1.0.0");
+    assert.equal(await version("foo"), "2.1.0");
+    assert.equal(await version("@scope", "util", "node_modules", "foo"), "1.0.0");
+
+    await writeFile(path.join(project, "yarn.lock"), yarnLock + [
+      "",
+      `"left-pad@github:left-pad/left-pad":`,
+      `  version "1.3.0"`,
+      `  resolved "git+https://github.com/left-pad/left-pad.git#abc123"`,
+      ""
+    ].join("\n"));
+    const git = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts", "--dry-run"]);
+    assert.equal(git.code, 1);
+    assert.match(git.json.reason, /git dependencies, which are not supported: left-pad \(git\+https:/);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install falls back to pnpm-lock.yaml, building tarball URLs from the npmrc registry", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-pnpm-lock-");
+  const util = await makeTarball(dir, { name: "@scope/util", version: "1.0.0", dependencies: { foo: "^1.0.0" } });
+  const foo1 = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  const foo2 = await makeTarball(dir, { name: "foo", version: "2.1.0" });
+  const registry = await startRegistry({ "@scope/util@1.0.0": util, "foo@1.0.0": foo1, "foo@2.1.0": foo2 });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "app", dependencies: { "@scope/util": "^1.0.0", foo: "^2.0.0" } });
+    await writeFile(path.join(project, ".npmrc"), `registry=${registry.url}/\n`);
+    await writeFile(path.join(project, "pnpm-lock.yaml"), [
+      "lockfileVersion: '6.0'",
+      "",
+      "settings:",
+      "  autoInstallPeers: true",
+      "  excludeLinksFromLockfile: false",
+      "",
+      "dependencies:",
+      "  '@scope/util':",
+      "    specifier: ^1.0.0",
+      "    version: 1.0.0(foo@2.1.0)",
+      "  foo:",
+      "    specifier: ^2.0.0",
+      "    version: 2.1.0",
+      "",
+      "packages:",
+      "",
+      "  /@scope/util@1.0.0(foo@2.1.0):",
+      `    resolution: {integrity: ${sha512Integrity(util)}}`,
+      "    peerDependencies:",
+      "      foo: '*'",
+      "    dependencies:",
+      "      foo: 1.0.0",
+      "    dev: false",
+      "",
+      "  /foo@1.0.0:",
+      `    resolution: {integrity: ${sha512Integrity(foo1)}}`,
+      "    dev: false",
+      "",
+      "  /foo@2.1.0:",
+      `    resolution: {integrity: ${sha512Integrity(foo2)}}`,
+      "    engines: {node: '>=18'}",
+      "    os: [darwin, linux, win32]",
+      "    dev: false",
+      ""
+    ].join("\n"));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    const version = async (...rel) => JSON.parse(await fs.readFile(path.join(project, "node_modules", ...rel, "package.json"), "utf8")).version;
+    assert.equal(await version("@scope", "util"), "1.0.0");
+    assert.equal(await version("foo"), "2.1.0");
+    assert.equal(await version("@scope", "util", "node_modules", "foo"), "1.0.0");
+    assert.ok(registry.requests.includes("/@scope/util/-/util-1.0.0.tgz"));
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install reads pnpm v5 keys whose peer suffix names a version", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-pnpm-v5-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0", peerDependencies: { react: "*" } });
+  const util = await makeTarball(dir, { name: "@scope/util", version: "2.0.0", peerDependencies: { react: "*" } });
+  const react = await makeTarball(dir, { name: "react", version: "18.0.0" });
+  const registry = await startRegistry({ "foo@1.0.0": foo, "@scope/util@2.0.0": util, "react@18.0.0": react });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "app", dependencies: { foo: "^1.0.0", "@scope/util": "^2.0.0", react: "^18.0.0" } });
+    await writeFile(path.join(project, ".npmrc"), `registry=${registry.url}/\n`);
+    await writeFile(path.join(project, "pnpm-lock.yaml"), [
+      "lockfileVersion: 5.4",
+      "",
+      "specifiers:",
+      "  '@scope/util': ^2.0.0",
+      "  foo: ^1.0.0",
+      "  react: ^18.0.0",
+      "",
+      "dependencies:",
+      "  '@scope/util': 2.0.0_react@18.0.0",
+      "  foo: 1.0.0_react@18.0.0",
+      "  react: 18.0.0",
+      "",
+      "packages:",
+      "",
+      "  /@scope/util/2.0.0_react@18.0.0:",
+      `    resolution: {integrity: ${sha512Integrity(util)}}`,
+      "    peerDependencies:",
+      "      react: '*'",
+      "    dependencies:",
+      "      react: 18.0.0",
+      "    dev: false",
+      "",
+      "  /foo/1.0.0_react@18.0.0:",
+      `    resolution: {integrity: ${sha512Integrity(foo)}}`,
+      "    peerDependencies:",
+      "      react: '*'",
+      "    dependencies:",
+      "      react: 18.0.0",
+      "    dev: false",
+      "",
+      "  /react/18.0.0:",
+      `    resolution: {integrity: ${sha512Integrity(react)}}`,
+      "    dev: false",
+      ""
+    ].join("\n"));
+
+    const { code, json } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"]);
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.deepEqual((await fs.readdir(path.join(project, "node_modules"))).filter((n) => !n.startsWith(".")).sort(), ["@scope", "foo", "react"]);
+    const version = async (...rel) => JSON.parse(await fs.readFile(path.join(project, "node_modules", ...rel, "package.json"), "utf8")).version;
+    assert.equal(await version("foo"), "1.0.0");
+    assert.equal(await version("@scope", "util"), "2.0.0");
+    assert.equal(await version("react"), "18.0.0");
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("verify --integrity detects installed files changed after install", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-installed-integrity-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "module.exports = 1;\n" });
+  const bar = await makeTarball(dir, { name: "bar", version: "1.0.0" }, { "index.js": "module.exports = 2;\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo, "bar@1.0.0": bar });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) },
+      "node_modules/foo/node_modules/bar": { version: "1.0.0", resolved: registry.tarballUrl("bar", "1.0.0"), integrity: sha512Integrity(bar) }
+    }));
+    // Copies, so editing the installed file cannot reach the store through a hardlink
+    const install = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts", "--link-strategy", "copy"]);
+    assert.equal(install.code, 0, JSON.stringify(install.json));
+    assert.equal(install.json.integrity.packages, 2);
+
+    const clean = await runCore(corePath, ["verify", "--integrity", "--project-root", project]);
+    assert.equal(clean.code, 0, JSON.stringify(clean.json));
+    assert.deepEqual({ checked: clean.json.packagesChecked, mismatches: clean.json.mismatches }, { checked: 2, mismatches: [] });
+
+    // A tampered file in the nested package flags only that package
+    const nested = path.join(project, "node_modules", "foo", "node_modules", "bar", "index.js");
+    await fs.rm(nested);
+    await writeFile(nested, "module.exports = require('child_process');\n");
+    const tampered = await runCore(corePath, ["verify", "--integrity", "--project-root", project]);
+    assert.equal(tampered.code, 5);
+    assert.deepEqual(tampered.json.mismatches.map((m) => [m.path, m.reason]), [["node_modules/foo/node_modules/bar", "modified"]]);
+
+    await rmrf(path.join(project, "node_modules", "foo", "node_modules", "bar"));
+    const missing = await runCore(corePath, ["verify", "--integrity", "--project-root", project]);
+    assert.deepEqual(missing.json.mismatches.map((m) => [m.path, m.reason, m.actual]), [["node_modules/foo/node_modules/bar", "missing", null]]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install retries tarball downloads on 503 but not on 404 or an integrity mismatch", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-fetch-retry-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  // /flaky/* fails twice with 503 before serving the tarball
+  const hits = {};
+  const server = await startServer((req, res) => {
+    hits[req.url] = (hits[req.url] ?? 0) + 1;
+    if (req.url.startsWith("/flaky/") && hits[req.url] <= 2) {
+      res.writeHead(503);
+      res.end();
+    } else if (req.url.startsWith("/missing/")) {
+      res.writeHead(404);
+      res.end();
+    } else {
+      res.writeHead(200, { "content-type": "application/octet-stream" });
+      res.end(foo);
+    }
+  });
+  try {
+    const install = async (name, integrity = sha512Integrity(foo), npmrc = "") => {
+      const project = path.join(dir, name);
+      await writeFile(path.join(project, ".npmrc"), npmrc);
+      await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+        "node_modules/foo": { version: "1.0.0", resolved: `${server.url}/${name}/foo-1.0.0.tgz`, integrity }
+      }));
+      return runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, `cache-${name}`), "--no-scripts"]);
+    };
+
+    const flaky = await install("flaky");
+    assert.equal(flaky.code, 0, JSON.stringify(flaky.json));
+    assert.equal(hits["/flaky/foo-1.0.0.tgz"], 3);
+
+    const missing = await install("missing");
+    assert.equal(missing.code, 4);
+    assert.match(missing.json.reason, /Failed to download foo: .*404/);
+    assert.equal(hits["/missing/foo-1.0.0.tgz"], 1);
+
+    const tampered = await install("tampered", sha512Integrity(Buffer.from("something else")));
+    assert.equal(tampered.code, 5);
+    assert.match(tampered.json.reason, /Integrity mismatch for foo/);
+    assert.equal(hits["/tampered/foo-1.0.0.tgz"], 1);
+
+    // fetch-retries=1 allows one retry, which is not enough for /flaky/
+    delete hits["/flaky/foo-1.0.0.tgz"];
+    await rmrf(path.join(dir, "cache-flaky"));
+    const fewer = await install("flaky", sha512Integrity(foo), "fetch-retries=1\nfetch-retry-mintimeout=10\n");
+    assert.equal(fewer.code, 4);
+    assert.match(fewer.json.reason, /503/);
+    assert.equal(hits["/flaky/foo-1.0.0.tgz"], 2);
+  } finally {
+    await server.close();
+    await rmrf(dir);
+  }
+});
+
+test("install downloads through HTTP_PROXY over npmrc proxy, except for NO_PROXY hosts", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-proxy-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" });
+  // Only reachable through the proxy, which sees the absolute URL
+  const resolved = "http://registry.invalid/foo/-/foo-1.0.0.tgz";
+  const proxied = [];
+  const proxy = await startServer((req, res) => {
+    proxied.push(req.url);
+    res.writeHead(200, { "content-type": "application/octet-stream" });
+    res.end(foo);
+  });
+  try {
+    const install = async (name, npmrc, env = {}) => {
+      const project = path.join(dir, name);
+      await writeFile(path.join(project, ".npmrc"), `fetch-retries=0\n${npmrc}`);
+      await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+        "node_modules/foo": { version: "1.0.0", resolved, integrity: sha512Integrity(foo) }
+      }));
+      const args = ["install", "--project-root", project, "--cache-root", path.join(dir, `cache-${name}`), "--no-scripts"];
+      const clean = Object.fromEntries(Object.entries(process.env).filter(([key]) => !/_proxy$/i.test(key)));
+      return runCore(corePath, args, { env: { ...clean, ...env } });
+    };
+
+    const fromNpmrc = await install("npmrc", `proxy=${proxy.url}\n`);
+    assert.equal(fromNpmrc.code, 0, JSON.stringify(fromNpmrc.json));
+    assert.deepEqual(proxied, [resolved]);
+
+    // The environment wins over a dead npmrc proxy
+    const fromEnv = await install("env", "proxy=http://127.0.0.1:1\n", { HTTP_PROXY: proxy.url });
+    assert.equal(fromEnv.code, 0, JSON.stringify(fromEnv.json));
+    assert.equal(proxied.length, 2);
+
+    const bypassed = await install("bypassed", "", { http_proxy: proxy.url, NO_PROXY: "localhost,.invalid" });
+    assert.equal(bypassed.code, 4);
+    assert.match(bypassed.json.reason, /Failed to download foo/);
+    assert.equal(proxied.length, 2);
+  } finally {
+    await proxy.close();
+    await rmrf(dir);
+  }
+});
+
+test("install --offline installs from the cache and fails on the first uncached package", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-offline-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "\n" });
+  const bar = await makeTarball(dir, { name: "bar", version: "1.0.0" }, { "index.js": "\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo, "bar@1.0.0": bar });
+  try {
+    const cache = path.join(dir, "cache");
+    const entry = (name, tarball) => ({ version: "1.0.0", resolved: registry.tarballUrl(name, "1.0.0"), integrity: sha512Integrity(tarball) });
+    const install = async (name, packages, ...flags) => {
+      const project = path.join(dir, name);
+      await writeJson(path.join(project, "package-lock.json"), npmLockfile(packages));
+      return runCore(corePath, ["install", "--project-root", project, "--cache-root", cache, "--no-scripts", ...flags]);
+    };
+
+    // Populate the cache with foo only
+    const warm = await install("warm", { "node_modules/foo": entry("foo", foo) });
+    assert.equal(warm.code, 0, JSON.stringify(warm.json));
+    const requests = registry.requests.length;
+
+    const cached = await install("cached", { "node_modules/foo": entry("foo", foo) }, "--offline");
+    assert.equal(cached.code, 0, JSON.stringify(cached.json));
+    assert.equal(cached.json.stats.packagesFetched, 0);
+    assert.equal(cached.json.stats.packagesCached, 1);
+    assert.equal(await exists(path.join(dir, "cached", "node_modules", "foo", "index.js")), true);
+
+    const missing = await install("missing", { "node_modules/foo": entry("foo", foo), "node_modules/bar": entry("bar", bar) }, "--offline");
+    assert.equal(missing.code, 1);
+    assert.equal(missing.json.reason, "offline: bar@1.0.0 not cached");
+    assert.equal(registry.requests.length, requests);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install keeps at most maxsockets tarball downloads in flight", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-maxsockets-");
+  const tarballs = {};
+  for (let i = 0; i < 6; i++) {
+    tarballs[`pkg${i}`] = await makeTarball(dir, { name: `pkg${i}`, version: "1.0.0" });
+  }
+  // Each response is held back so concurrent downloads overlap
+  let inFlight = 0;
+  let peak = 0;
+  const server = await startServer((req, res) => {
+    inFlight++;
+    peak = Math.max(peak, inFlight);
+    setTimeout(() => {
+      inFlight--;
+      res.writeHead(200, { "content-type": "application/octet-stream" });
+      res.end(tarballs[path.basename(req.url, "-1.0.0.tgz")]);
+    }, 150);
+  });
+  try {
+    const install = async (name, maxsockets) => {
+      const project = path.join(dir, name);
+      await writeFile(path.join(project, ".npmrc"), `maxsockets=${maxsockets}\n`);
+      await writeJson(path.join(project, "package-lock.json"), npmLockfile(Object.fromEntries(Object.entries(tarballs).map(([pkg, tarball]) => [
+        `node_modules/${pkg}`, { version: "1.0.0", resolved: `${server.url}/${pkg}-1.0.0.tgz`, integrity: sha512Integrity(tarball) }
+      ]))));
+      peak = 0;
+      // Enough workers that the limit, not the pool size, bounds downloads
+      const env = { ...process.env, RAYON_NUM_THREADS: "8" };
+      const res = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, `cache-${name}`), "--no-scripts"], { env });
+      assert.equal(res.code, 0, JSON.stringify(res.json));
+      assert.equal(res.json.stats.packagesFetched, 6);
+      return peak;
+    };
+
+    const limited = await install("limited", 2);
+    assert.ok(limited >= 1 && limited <= 2, `peak ${limited}`);
+    // 0 lifts the limit
+    assert.ok((await install("unlimited", 0)) > 2);
+  } finally {
+    await server.close();
+    await rmrf(dir);
+  }
+});
+
+test("install without a lockfile resolves package.json against the registry, nesting conflicting versions", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-manifest-resolve-");
+  const tarball = (name, version) => makeTarball(dir, { name, version });
+  // a wants c@^1 and b wants c@^2: c@1 is hoisted and c@2 nests under b
+  const registry = await startRegistry({
+    "c@1.0.0": await tarball("c", "1.0.0"),
+    "c@1.4.0": await tarball("c", "1.4.0"),
+    "c@2.0.0": await tarball("c", "2.0.0"),
+    "a@1.0.0": await tarball("a", "1.0.0"),
+    "b@1.0.0": await tarball("b", "1.0.0")
+  }, {
+    "a@1.0.0": { dependencies: { c: "^1.0.0" } },
+    "b@1.0.0": { dependencies: { c: "^2.0.0" } }
+  });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), {
+      name: "app",
+      version: "1.0.0",
+      dependencies: { a: "^1.0.0" },
+      devDependencies: { b: "1.x" }
+    });
+    await writeFile(path.join(project, ".npmrc"), `registry=${registry.url}/\n`);
+    const env = { ...process.env, HOME: dir };
+    const args = ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"];
+
+    const { code, json } = await runCore(corePath, args, { env });
+    assert.equal(code, 0, JSON.stringify(json));
+    assert.equal(json.resolvedFrom, "package.json");
+    const version = async (rel) => JSON.parse(await fs.readFile(path.join(project, rel, "package.json"), "utf8")).version;
+    assert.equal(await version("node_modules/a"), "1.0.0");
+    assert.equal(await version("node_modules/b"), "1.0.0");
+    assert.equal(await version("node_modules/c"), "1.4.0");
+    assert.equal(await version("node_modules/b/node_modules/c"), "2.0.0");
+    assert.equal(await exists(path.join(project, "node_modules", "a", "node_modules")), false);
+
+    // --frozen still insists on a lockfile
+    await fs.rm(path.join(project, "package-lock.json"));
+    const frozen = await runCore(corePath, [...args, "--frozen"], { env });
+    assert.equal(frozen.code, 1);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install without a lockfile writes a package-lock.json that resolves to the same tree", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-lockfile-write-");
+  const tarball = (name, version) => makeTarball(dir, { name, version });
+  const registry = await startRegistry({
+    "c@1.0.0": await tarball("c", "1.0.0"),
+    "c@2.0.0": await tarball("c", "2.0.0"),
+    "a@1.0.0": await tarball("a", "1.0.0"),
+    "b@1.0.0": await tarball("b", "1.0.0"),
+    "d@1.0.0": await tarball("d", "1.0.0"),
+    "e@1.0.0": await tarball("e", "1.0.0"),
+    "f@1.0.0": await tarball("f", "1.0.0")
+  }, {
+    "a@1.0.0": { dependencies: { c: "^1.0.0" } },
+    "b@1.0.0": { dependencies: { c: "^2.0.0" } },
+    "d@1.0.0": { dependencies: { f: "^1.0.0" } },
+    "e@1.0.0": { dependencies: { f: "^1.0.0" } }
+  });
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), {
+      name: "app",
+      version: "1.0.0",
+      dependencies: { b: "^1.0.0", a: "^1.0.0" },
+      devDependencies: { c: "^1.0.0", d: "^1.0.0" },
+      optionalDependencies: { e: "^1.0.0" }
+    });
+    await writeFile(path.join(project, ".npmrc"), `registry=${registry.url}/\n`);
+    const env = { ...process.env, HOME: dir };
+    const lockPath = path.join(project, "package-lock.json");
+
+    const first = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"], { env });
+    assert.equal(first.code, 0, JSON.stringify(first.json));
+    assert.equal(first.json.lockfileWritten, true);
+    const written = await fs.readFile(lockPath, "utf8");
+    const lock = JSON.parse(written);
+    assert.equal(lock.lockfileVersion, 3);
+    assert.deepEqual(lock.packages[""], {
+      name: "app",
+      version: "1.0.0",
+      dependencies: { a: "^1.0.0", b: "^1.0.0" },
+      devDependencies: { c: "^1.0.0", d: "^1.0.0" },
+      optionalDependencies: { e: "^1.0.0" }
+    });
+    assert.deepEqual(Object.keys(lock.packages), [
+      "",
+      "node_modules/a",
+      "node_modules/b",
+      "node_modules/b/node_modules/c",
+      "node_modules/c",
+      "node_modules/d",
+      "node_modules/e",
+      "node_modules/f"
+    ]);
+    // c is a dev dependency but a needs it too; f is needed by both d and e
+    const flags = Object.entries(lock.packages)
+      .filter(([key, entry]) => key && (entry.dev || entry.optional || entry.devOptional))
+      .map(([key, entry]) => [key, ["dev", "optional", "devOptional"].filter((flag) => entry[flag] === true)]);
+    assert.deepEqual(flags, [
+      ["node_modules/d", ["dev"]],
+      ["node_modules/e", ["optional"]],
+      ["node_modules/f", ["devOptional"]]
+    ]);
+    assert.deepEqual(lock.packages["node_modules/b"].dependencies, { c: "^2.0.0" });
+    assert.equal(lock.packages["node_modules/b/node_modules/c"].resolved, registry.tarballUrl("c", "2.0.0"));
+
+    // Reading it back gives the tree that was installed
+    const resolved = await runCore(corePath, ["resolve", "--project-root", project]);
+    assert.equal(resolved.code, 0, JSON.stringify(resolved.json));
+    assert.deepEqual(resolved.json.packages.map((p) => `${p.path} ${p.name}@${p.version}`), [
+      "node_modules/a a@1.0.0",
+      "node_modules/b b@1.0.0",
+      "node_modules/b/node_modules/c c@2.0.0",
+      "node_modules/c c@1.0.0",
+      "node_modules/d d@1.0.0",
+      "node_modules/e e@1.0.0",
+      "node_modules/f f@1.0.0"
+    ]);
+
+    // The next install uses the lockfile and leaves it untouched
+    const second = await runCore(corePath, ["install", "--project-root", project, "--cache-root", path.join(dir, "cache"), "--no-scripts"], { env });
+    assert.equal(second.code, 0, JSON.stringify(second.json));
+    assert.equal(second.json.resolvedFrom, "lockfile");
+    assert.equal(second.json.lockfileWritten, false);
+    assert.equal(await fs.readFile(lockPath, "utf8"), written);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("verify --store flags installed files that no longer match the store manifest", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-verify-store-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, {
+    "index.js": "module.exports = 1;\n",
+    "lib/util.js": "module.exports = 2;\n",
+    "README.md": "# foo\n"
+  });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const cache = path.join(dir, "cache");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    const install = await runCore(corePath, ["install", "--project-root", project, "--cache-root", cache, "--no-scripts"]);
+    assert.equal(install.code, 0, JSON.stringify(install.json));
+
+    const clean = await runCore(corePath, ["verify", "--store", "--project-root", project, "--cache-root", cache]);
+    assert.equal(clean.code, 0, JSON.stringify(clean.json));
+    assert.equal(clean.json.kind, "better.verify.store");
+    assert.equal(clean.json.packagesChecked, 1);
+    assert.equal(clean.json.filesChecked, 4);
+    assert.deepEqual(clean.json.counts, { mismatched: 0, missing: 0, extra: 0 });
+
+    // Edited in place (through the hardlink), deleted, and added
+    const pkg = path.join(project, "node_modules", "foo");
+    await fs.appendFile(path.join(pkg, "index.js"), "require('child_process');\n");
+    await fs.rm(path.join(pkg, "README.md"));
+    await writeFile(path.join(pkg, "lib", "extra.js"), "1;\n");
+
+    const { code, json } = await runCore(corePath, ["verify", "--store", "--project-root", project, "--cache-root", cache]);
+    assert.equal(code, 5);
+    assert.equal(json.ok, false);
+    assert.deepEqual(json.counts, { mismatched: 1, missing: 1, extra: 1 });
+    assert.deepEqual(json.mismatched, ["node_modules/foo/index.js"]);
+    assert.deepEqual(json.missing, ["node_modules/foo/README.md"]);
+    assert.deepEqual(json.extra, ["node_modules/foo/lib/extra.js"]);
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
+
+test("install sweeps stale tmp files from the cache and the file store", { skip }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-sweep-");
+  const foo = await makeTarball(dir, { name: "foo", version: "1.0.0" }, { "index.js": "module.exports = 1;\n" });
+  const registry = await startRegistry({ "foo@1.0.0": foo });
+  try {
+    const project = path.join(dir, "project");
+    const cacheRoot = path.join(dir, "cache");
+    await writeJson(path.join(project, "package-lock.json"), npmLockfile({
+      "node_modules/foo": { version: "1.0.0", resolved: registry.tarballUrl("foo", "1.0.0"), integrity: sha512Integrity(foo) }
+    }));
+    // What a crashed run would leave: two hours old, past the one-hour cutoff.
+    const hoursAgo = new Date(Date.now() - 2 * 60 * 60 * 1000);
+    const tmpDirs = [path.join(cacheRoot, "tmp"), path.join(cacheRoot, "file-store", "tmp")];
+    for (const tmpDir of tmpDirs) {
+      await writeFile(path.join(tmpDir, "orphan.tmp-1-0"), "partial");
+      await fs.utimes(path.join(tmpDir, "orphan.tmp-1-0"), hoursAgo, hoursAgo);
+      await writeFile(path.join(tmpDir, "busy.tmp-2-0"), "another install's");
+    }
+
+    const { code } = await runCore(corePath, ["install", "--project-root", project, "--cache-root", cacheRoot, "--no-scripts"]);
+    assert.equal(code, 0);
+    for (const tmpDir of tmpDirs) {
+      assert.deepEqual(await fs.readdir(tmpDir), ["busy.tmp-2-0"]);
+    }
+    // Blobs and manifests are staged in the store's tmp dir, never beside their destination.
+    const stray = (await fs.readdir(path.join(cacheRoot, "file-store"), { recursive: true })).filter(
+      (name) => name.includes(".tmp-") && path.dirname(name) !== "tmp"
+    );
+    assert.deepEqual(stray, []);
+    assert.ok(await exists(path.join(cacheRoot, "file-store", "packages")));
+  } finally {
+    await registry.close();
+    await rmrf(dir);
+  }
+});
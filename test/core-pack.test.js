@@ -0,0 +1,135 @@
+import test from "node:test";
+import assert from "node:assert/strict";
+import path from "node:path";
+import fs from "node:fs/promises";
+import { makeTempDir, rmrf, writeFile, writeJson } from "./helpers.js";
+import { ensureCoreBuilt, execFileAsync, hasTar, runCore, sha512Integrity } from "./core-helpers.js";
+
+async function packFiles(corePath, dir) {
+  const { code, json } = await runCore(corePath, ["pack", "--project-root", dir, "--dry-run"]);
+  assert.equal(code, 0, JSON.stringify(json));
+  return json.files;
+}
+
+test("pack always includes manifest docs and always excludes VCS, deps and lockfiles", async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-pack-defaults-");
+  try {
+    await writeJson(path.join(dir, "package.json"), { name: "pkg", version: "1.0.0" });
+    await writeFile(path.join(dir, "README.md"), "# pkg\n");
+    await writeFile(path.join(dir, "LICENSE"), "MIT\n");
+    await writeFile(path.join(dir, "index.js"), "module.exports = 1;\n");
+    await writeFile(path.join(dir, "notes.log"), "debug\n");
+    await writeFile(path.join(dir, ".git", "HEAD"), "ref: refs/heads/main\n");
+    await writeFile(path.join(dir, "node_modules", "dep", "index.js"), "\n");
+    await writeFile(path.join(dir, "package-lock.json"), "{}\n");
+    await writeFile(path.join(dir, ".npmrc"), "//registry/:_authToken=secret\n");
+    await writeFile(path.join(dir, "index.js.orig"), "\n");
+    await writeFile(path.join(dir, "docs", "package-lock.json"), "{}\n");
+    // .gitignore applies when there is no .npmignore, but cannot drop README/LICENSE
+    await writeFile(path.join(dir, ".gitignore"), "*.log\nREADME.md\nLICENSE\n");
+
+    assert.deepEqual(await packFiles(corePath, dir), ["LICENSE", "README.md", "docs/package-lock.json", "index.js", "package.json"]);
+
+    // .npmignore replaces .gitignore rather than adding to it
+    await writeFile(path.join(dir, ".npmignore"), "docs/\n");
+    assert.deepEqual(await packFiles(corePath, dir), ["LICENSE", "README.md", "index.js", "notes.log", "package.json"]);
+  } finally {
+    await rmrf(dir);
+  }
+});
+
+test("pack keeps only `files` matches, ignoring the root .npmignore but not nested ones", async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-pack-files-");
+  try {
+    await writeJson(path.join(dir, "package.json"), {
+      name: "pkg",
+      version: "1.0.0",
+      main: "./main.js",
+      bin: { pkg: "bin/cli.js" },
+      files: ["lib", "!lib/secret.js", "*.d.ts"]
+    });
+    await writeFile(path.join(dir, "readme"), "docs\n");
+    await writeFile(path.join(dir, "main.js"), "\n");
+    await writeFile(path.join(dir, "bin", "cli.js"), "#!/usr/bin/env node\n");
+    await writeFile(path.join(dir, "bin", "other.js"), "\n");
+    await writeFile(path.join(dir, "index.d.ts"), "\n");
+    await writeFile(path.join(dir, "types", "nested.d.ts"), "\n");
+    await writeFile(path.join(dir, "lib", "a.js"), "\n");
+    await writeFile(path.join(dir, "lib", "secret.js"), "\n");
+    await writeFile(path.join(dir, "lib", "fixtures", "big.json"), "{}\n");
+    await writeFile(path.join(dir, "lib", "fixtures", ".npmignore"), "*.json\n");
+    await writeFile(path.join(dir, "lib", "node_modules", "x", "index.js"), "\n");
+    await writeFile(path.join(dir, "src", "a.ts"), "\n");
+    await writeFile(path.join(dir, ".npmignore"), "lib/\n");
+
+    assert.deepEqual(await packFiles(corePath, dir), [
+      "bin/cli.js",
+      "index.d.ts",
+      "lib/a.js",
+      "main.js",
+      "package.json",
+      "readme"
+    ]);
+  } finally {
+    await rmrf(dir);
+  }
+});
+
+test("pack writes a reproducible npm-style tarball", { skip: !(await hasTar()) }, async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-pack-tgz-");
+  try {
+    const project = path.join(dir, "project");
+    await writeJson(path.join(project, "package.json"), { name: "@scope/pkg", version: "2.1.0", files: ["index.js"] });
+    await writeFile(path.join(project, "index.js"), "module.exports = 1;\n");
+    await writeFile(path.join(project, "test.js"), "\n");
+
+    const out = path.join(dir, "out");
+    const first = await runCore(corePath, ["pack", "--project-root", project, "--pack-destination", out]);
+    assert.equal(first.code, 0, JSON.stringify(first.json));
+    assert.equal(first.json.kind, "better.pack");
+    assert.equal(path.basename(first.json.filename), "scope-pkg-2.1.0.tgz");
+    assert.equal(first.json.entryCount, 2);
+    const tgz = await fs.readFile(first.json.filename);
+    assert.equal(first.json.integrity, sha512Integrity(tgz));
+
+    const { stdout } = await execFileAsync("tar", ["-tzf", first.json.filename]);
+    assert.deepEqual(stdout.trim().split("\n").sort(), ["package/index.js", "package/package.json"]);
+
+    const second = await runCore(corePath, ["pack", "--project-root", project, "--pack-destination", out]);
+    assert.equal(second.json.integrity, first.json.integrity);
+  } finally {
+    await rmrf(dir);
+  }
+});
+
+test("pack includes bundleDependencies from node_modules", async () => {
+  const corePath = await ensureCoreBuilt();
+  if (!corePath) return;
+
+  const dir = await makeTempDir("better-core-pack-bundled-");
+  try {
+    await writeJson(path.join(dir, "package.json"), { name: "pkg", version: "1.0.0", files: ["index.js"], bundleDependencies: ["inner"] });
+    await writeFile(path.join(dir, "index.js"), "\n");
+    await writeFile(path.join(dir, "node_modules", "inner", "package.json"), "{}\n");
+    await writeFile(path.join(dir, "node_modules", "inner", "node_modules", "leaf", "index.js"), "\n");
+    await writeFile(path.join(dir, "node_modules", "other", "index.js"), "\n");
+
+    assert.deepEqual(await packFiles(corePath, dir), [
+      "index.js",
+      "node_modules/inner/node_modules/leaf/index.js",
+      "node_modules/inner/package.json",
+      "package.json"
+    ]);
+  } finally {
+    await rmrf(dir);
+  }
+});
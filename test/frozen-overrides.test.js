@@ -287,6 +287,74 @@ test("overrides: flattens nested npm overrides", async () => {
   }
 });
 
+test("overrides: nested overrides only cover the copy their parent resolves", () => {
+  const flatOverrides = [
+    { pattern: "express > debug", path: ["express", "debug"], version: "4.3.5", source: "overrides" },
+    { pattern: "koa > debug", path: ["koa", "debug"], version: "4.3.5", source: "overrides" },
+    { pattern: "express@4 > ms", path: ["express@4", "ms"], version: "2.1.3", source: "overrides" }
+  ];
+  const lockPackages = {
+    "": { name: "app", dependencies: { express: "^4.18.0", koa: "^2.0.0", debug: "^2.6.0" } },
+    // express gets its own nested copy; koa uses the hoisted one
+    "node_modules/express": { version: "4.18.2", dependencies: { debug: "4.3.5", ms: "2.1.3" } },
+    "node_modules/express/node_modules/debug": { version: "4.3.5" },
+    "node_modules/koa": { version: "2.15.0", dependencies: { debug: "^2.6.0" } },
+    "node_modules/debug": { version: "2.6.9" },
+    "node_modules/ms": { version: "2.1.3" }
+  };
+
+  const result = validateOverrides(flatOverrides, lockPackages);
+  assert.deepEqual(result.valid.map(v => v.pattern), ["express > debug", "express@4 > ms"]);
+  assert.equal(result.warnings.length, 1);
+  assert.equal(result.warnings[0].pattern, "koa > debug");
+  assert.equal(result.warnings[0].issue, "version_mismatch");
+  assert.equal(result.warnings[0].lockPath, "node_modules/debug");
+
+  // A selector that does not match the parent's version scopes nothing
+  const missed = validateOverrides([{ pattern: "express@5 > ms", version: "2.1.3", source: "overrides" }], lockPackages);
+  assert.equal(missed.warnings[0].issue, "package_not_in_lockfile");
+});
+
+test("overrides: $name references resolve to the root dependency spec", async () => {
+  const dir = await makeTempDir("overrides-ref-");
+  try {
+    await writeJson(path.join(dir, "package.json"), {
+      name: "test-project",
+      version: "1.0.0",
+      dependencies: { react: "18.2.0" },
+      devDependencies: { typescript: "5.4.5" },
+      overrides: {
+        "react-dom": { react: "$react" },
+        typescript: "$typescript"
+      }
+    });
+
+    const result = await loadOverrides(dir);
+    assert.equal(result.ok, true);
+    assert.deepEqual(result.errors, []);
+    const byPattern = Object.fromEntries(result.flat.map(f => [f.pattern, f]));
+    assert.equal(byPattern["react-dom > react"].version, "18.2.0");
+    assert.deepEqual(byPattern["react-dom > react"].path, ["react-dom", "react"]);
+    assert.equal(byPattern["react-dom > react"].reference, "$react");
+    assert.equal(byPattern.typescript.version, "5.4.5");
+
+    await writeJson(path.join(dir, "package.json"), {
+      name: "test-project",
+      version: "1.0.0",
+      dependencies: { react: "18.2.0" },
+      overrides: { "react-dom": { react: "$preact" } }
+    });
+    const broken = await loadOverrides(dir);
+    assert.equal(broken.ok, false);
+    assert.equal(broken.reason, "invalid_override_reference");
+    assert.equal(broken.count, 0);
+    assert.equal(broken.errors[0].issue, "unresolved_reference");
+    assert.match(broken.errors[0].message, /\$preact/);
+  } finally {
+    await rmrf(dir);
+  }
+});
+
 test("overrides: suggestOverridesForVulns generates suggestions", () => {
   const vulnNodes = [
     {
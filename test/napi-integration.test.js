@@ -1,12 +1,18 @@
 import { describe, it } from "node:test";
 import assert from "node:assert/strict";
 import path from "node:path";
+import os from "node:os";
 import fs from "node:fs/promises";
 import { createRequire } from "node:module";
 import { fileURLToPath } from "node:url";
+import { execFile } from "node:child_process";
+import { promisify } from "node:util";
+import { spawn } from "node:child_process";
+import { hasTar, makeTarball, npmLockfile, sha512Integrity } from "./core-helpers.js";
 
 const __dirname = path.dirname(fileURLToPath(import.meta.url));
 const repoRoot = path.resolve(__dirname, "..");
+const execFileAsync = promisify(execFile);
 const napiNodePath = path.join(repoRoot, "crates", "better-napi", "better-core.darwin-arm64.node");
 
 let addon = null;
@@ -79,6 +85,34 @@ describe("napi addon", { skip: shouldSkip }, () => {
       assert.equal(result.ok, false);
       assert.equal(typeof result.reason, "string");
     });
+
+    it("exposes dependency edges and cycles when the graph is requested", async () => {
+      if (!addon) return;
+      const tmpBase = path.join(repoRoot, ".better-napi-test-graph-" + Date.now());
+      const nm = path.join(tmpBase, "node_modules");
+      const writePkg = async (dir, pkg) => {
+        await fs.mkdir(dir, { recursive: true });
+        await fs.writeFile(path.join(dir, "package.json"), JSON.stringify(pkg));
+      };
+      try {
+        await writePkg(path.join(nm, "a"), { name: "a", version: "1.0.0", dependencies: { b: "^1.0.0" } });
+        await writePkg(path.join(nm, "b"), { name: "b", version: "1.0.0", dependencies: { c: "^2.0.0" } });
+        await writePkg(path.join(nm, "b", "node_modules", "c"), { name: "c", version: "2.0.0", dependencies: { b: "^1.0.0" } });
+        await writePkg(path.join(nm, "c"), { name: "c", version: "1.0.0" });
+
+        const withGraph = addon.analyze(tmpBase, true);
+        assert.equal(withGraph.ok, true);
+        const edges = withGraph.edges.map((e) => `${e.from}->${e.to}`).sort();
+        assert.deepEqual(edges, ["a@1.0.0->b@1.0.0", "b@1.0.0->c@2.0.0", "c@2.0.0->b@1.0.0"]);
+        assert.deepEqual(withGraph.cycles, [["b@1.0.0", "c@2.0.0"]]);
+
+        const withoutGraph = addon.analyze(tmpBase, false);
+        assert.deepEqual(withoutGraph.edges, []);
+        assert.deepEqual(withoutGraph.cycles, []);
+      } finally {
+        await fs.rm(tmpBase, { recursive: true, force: true });
+      }
+    });
   });
 
   describe("materialize()", () => {
@@ -139,4 +173,130 @@ describe("napi addon", { skip: shouldSkip }, () => {
       }
     });
   });
+
+  describe("hooks", () => {
+    it("installs hooks into a git repo and uninstalls only its own", async () => {
+      if (!addon) return;
+      const tmpBase = await fs.mkdtemp(path.join(os.tmpdir(), "napi-hooks-"));
+      try {
+        await execFileAsync("git", ["init", "-q", tmpBase]);
+        await fs.writeFile(
+          path.join(tmpBase, "package.json"),
+          JSON.stringify({ name: "proj", better: { hooks: { "pre-commit": "npm run lint", "commit-msg": "conventional-commit" } } })
+        );
+        const hooksDir = path.join(tmpBase, ".git", "hooks");
+        await fs.writeFile(path.join(hooksDir, "post-merge"), "#!/bin/sh\necho mine\n");
+
+        const planned = addon.hooksInstall(tmpBase, true);
+        assert.equal(planned.ok, true);
+        await assert.rejects(fs.access(path.join(hooksDir, "commit-msg")));
+
+        const installed = addon.hooksInstall(tmpBase);
+        assert.equal(installed.ok, true);
+        assert.equal(installed.fromConfig, true);
+        assert.deepEqual(installed.hooks.map((h) => h.type).sort(), ["commit-msg", "pre-commit"]);
+
+        // The commit-msg hook enforces the same format validateConventionalCommit checks
+        const msgFile = path.join(tmpBase, "MSG");
+        await fs.writeFile(msgFile, "feat(cli)!: drop node 16\n");
+        await execFileAsync(path.join(hooksDir, "commit-msg"), [msgFile]);
+        await fs.writeFile(msgFile, "updated stuff\n");
+        await assert.rejects(execFileAsync(path.join(hooksDir, "commit-msg"), [msgFile]));
+
+        const removed = addon.hooksUninstall(tmpBase);
+        assert.equal(removed.ok, true);
+        assert.deepEqual(removed.removed, ["commit-msg", "pre-commit"]);
+        assert.deepEqual(removed.kept, ["post-merge"]);
+        await fs.access(path.join(hooksDir, "post-merge"));
+        await assert.rejects(fs.access(path.join(hooksDir, "pre-commit")));
+
+        const notRepo = addon.hooksUninstall(path.join(tmpBase, "MSG-dir"));
+        assert.equal(notRepo.ok, false);
+        assert.equal(notRepo.reason, "Not a git repository");
+      } finally {
+        await fs.rm(tmpBase, { recursive: true, force: true });
+      }
+    });
+
+    it("validateConventionalCommit returns the parsed header or a coded error", () => {
+      if (!addon) return;
+      const ok = addon.validateConventionalCommit("feat(cli)!: drop node 16\n\nBREAKING CHANGE: engines bumped");
+      assert.equal(ok.ok, true);
+      assert.equal(ok.type, "feat");
+      assert.equal(ok.scope, "cli");
+      assert.equal(ok.breaking, true);
+      assert.equal(ok.description, "drop node 16");
+
+      const cases = {
+        "": "empty",
+        "update readme": "missing-colon",
+        "fix(: typo": "malformed-scope",
+        "feature: add x": "invalid-type",
+        "fix:   ": "missing-description"
+      };
+      for (const [message, code] of Object.entries(cases)) {
+        const result = addon.validateConventionalCommit(message);
+        assert.equal(result.ok, false, message);
+        assert.equal(result.code, code, message);
+        assert.equal(typeof result.reason, "string");
+      }
+    });
+  });
+
+  describe("fetchAndExtract()", () => {
+    it("reports one terminal event per package: extracted when downloaded, cached after", async () => {
+      if (!addon || !(await hasTar())) return;
+      const tmpBase = await fs.mkdtemp(path.join(os.tmpdir(), "napi-fetch-events-"));
+      // fetchAndExtract blocks this thread, so the registry runs in a child process
+      const server = spawn(process.execPath, ["-e", `
+        const http = require("node:http"), fs = require("node:fs"), path = require("node:path");
+        http.createServer((req, res) => fs.readFile(path.join(${JSON.stringify(tmpBase)}, path.basename(req.url)), (err, body) => {
+          res.writeHead(err ? 404 : 200);
+          res.end(body);
+        })).listen(0, "127.0.0.1", function () { console.log(this.address().port); });
+      `]);
+      try {
+        const port = await new Promise((resolve, reject) => {
+          server.stdout.once("data", (chunk) => resolve(String(chunk).trim()));
+          server.once("error", reject);
+        });
+        const packages = {};
+        for (const name of ["alpha", "beta", "gamma"]) {
+          const tgz = await makeTarball(tmpBase, { name, version: "1.0.0" }, { "index.js": `module.exports = "${name}";\n` });
+          await fs.writeFile(path.join(tmpBase, `${name}.tgz`), tgz);
+          packages[`node_modules/${name}`] = { version: "1.0.0", resolved: `http://127.0.0.1:${port}/${name}.tgz`, integrity: sha512Integrity(tgz) };
+        }
+        const lockfile = path.join(tmpBase, "package-lock.json");
+        await fs.writeFile(lockfile, JSON.stringify(npmLockfile(packages)));
+        const cache = path.join(tmpBase, "cache");
+
+        const terminalKinds = (events) => {
+          const byName = {};
+          for (const event of events) {
+            byName[event.name] ??= [];
+            byName[event.name].push(event.kind);
+            assert.equal(event.terminal, event.kind === "cached" || event.kind === "extracted");
+          }
+          for (const kinds of Object.values(byName)) {
+            assert.equal(kinds[0], "started");
+            assert.equal(kinds.filter((k) => k === "cached" || k === "extracted").length, 1, kinds.join());
+          }
+          return Object.fromEntries(Object.entries(byName).map(([name, kinds]) => [name, kinds.at(-1)]));
+        };
+
+        const first = addon.fetchAndExtract(lockfile, cache, { events: true });
+        assert.equal(first.ok, true, first.reason);
+        assert.deepEqual(terminalKinds(first.events), { alpha: "extracted", beta: "extracted", gamma: "extracted" });
+        assert.ok(first.events.filter((e) => e.kind === "downloaded").every((e) => e.bytes > 0));
+        assert.equal(first.events.filter((e) => e.kind === "verified").length, 3);
+
+        const second = addon.fetchAndExtract(lockfile, cache, { events: true });
+        assert.deepEqual(terminalKinds(second.events), { alpha: "cached", beta: "cached", gamma: "cached" });
+        assert.equal(addon.fetchAndExtract(lockfile, cache).events, undefined);
+      } finally {
+        server.kill();
+        await fs.rm(tmpBase, { recursive: true, force: true });
+      }
+    });
+  });
 });